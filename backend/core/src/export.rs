@@ -0,0 +1,254 @@
+//! The CSV the `export` and `import` subcommands exchange: the access list with every field
+//! and when each key was last seen, readable without a hex editor.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+
+use crate::{keylist::ParseIssue, pin::PinHash, privacy, Credential, Key};
+
+pub const HEADER: &str = "id,name,last_seen,expiry,schedule,group,pin";
+
+/// `list` as CSV, sorted by id, with a header line. Times are RFC3339 in UTC, to the second
+/// like the persisted list; a key never seen has an empty `last_seen`.
+pub fn to_csv(
+    list: &DashMap<Credential, Key>,
+    last_seen: &DashMap<Credential, SystemTime>,
+) -> String {
+    let mut entries: Vec<_> = list
+        .iter()
+        .map(|entry| (*entry.key(), entry.value().clone()))
+        .collect();
+    entries.sort_by_key(|(id, _)| *id);
+    let time = |time: Option<SystemTime>| {
+        time.map(|time| humantime::format_rfc3339_seconds(time).to_string())
+            .unwrap_or_default()
+    };
+    let mut csv = format!("{HEADER}\n");
+    for (id, key) in entries {
+        let fields = [
+            id.to_string(),
+            key.name,
+            time(last_seen.get(&id).map(|seen| *seen)),
+            time(key.expiry),
+            key.schedule.unwrap_or_default(),
+            key.group.unwrap_or_default(),
+            key.pin.unwrap_or_default(),
+        ];
+        let fields: Vec<_> = fields.iter().map(|field| quote(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// What an exported list holds.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Import {
+    pub keys: HashMap<Credential, Key>,
+    pub last_seen: HashMap<Credential, SystemTime>,
+    /// Records that were skipped, by the line they start on.
+    pub issues: Vec<ParseIssue>,
+}
+
+/// Parses CSV as [`to_csv`] writes it, the header being optional. Records with an invalid
+/// field or a repeated id are reported in `issues` and left out.
+pub fn parse_csv(body: &str) -> Import {
+    let mut import = Import::default();
+    let mut first_seen = HashMap::new();
+    for (line, fields) in records(body) {
+        let record = match fields {
+            Ok(fields) if line == 1 && fields.join(",") == HEADER => continue,
+            Ok(fields) => parse_record(&fields),
+            Err(e) => Err(e),
+        };
+        let reason = match record {
+            Ok((id, key, seen)) => match first_seen.entry(id) {
+                Entry::Occupied(first) => format!(
+                    "{} already listed on line {}",
+                    privacy::id(&id),
+                    first.get()
+                ),
+                Entry::Vacant(first) => {
+                    first.insert(line);
+                    import.keys.insert(id, key);
+                    if let Some(seen) = seen {
+                        import.last_seen.insert(id, seen);
+                    }
+                    continue;
+                }
+            },
+            Err(e) => format!("{e:#}"),
+        };
+        import.issues.push(ParseIssue { line, reason });
+    }
+    import
+}
+
+fn parse_record(fields: &[String]) -> anyhow::Result<(Credential, Key, Option<SystemTime>)> {
+    let [id, name, last_seen, expiry, schedule, group, pin] = fields else {
+        anyhow::bail!("has {} fields instead of 7", fields.len());
+    };
+    let id = Credential::parse(id).context(format!("invalid ID {:?}", privacy::raw(id)))?;
+    let optional = |field: &String| Some(field.clone()).filter(|field| !field.is_empty());
+    let last_seen = optional(last_seen)
+        .map(|seen| humantime::parse_rfc3339(&seen))
+        .transpose()
+        .context(format!("invalid last_seen of {}", privacy::id(&id)))?;
+    let expiry = optional(expiry)
+        .map(|expiry| crate::keylist::parse_expiry(&expiry))
+        .transpose()
+        .context(format!("invalid expiry of {}", privacy::id(&id)))?;
+    if !pin.is_empty() {
+        PinHash::parse(pin).context(format!("invalid PIN hash of {}", privacy::id(&id)))?;
+    }
+    let key = Key {
+        name: name.clone(),
+        expiry,
+        schedule: optional(schedule),
+        group: optional(group),
+        pin: optional(pin),
+    };
+    Ok((id, key, last_seen))
+}
+
+/// `field`, quoted if it holds a separator, quote or line break.
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// The records of `body` with the 1-based line each starts on, skipping blank lines. Quoted
+/// fields may span lines.
+fn records(body: &str) -> Vec<(usize, anyhow::Result<Vec<String>>)> {
+    let mut records = Vec::new();
+    let mut chars = body.chars().peekable();
+    let mut line = 1;
+    while chars.peek().is_some() {
+        let start = line;
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut result = Ok(());
+        loop {
+            match chars.next() {
+                None => break,
+                Some('\n') => {
+                    line += 1;
+                    break;
+                }
+                Some('\r') if chars.peek() == Some(&'\n') => {}
+                Some(',') => fields.push(std::mem::take(&mut field)),
+                Some('"') if field.is_empty() => loop {
+                    match chars.next() {
+                        None => {
+                            result = Err(anyhow::anyhow!("unterminated quote"));
+                            break;
+                        }
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                    }
+                },
+                Some(c) => field.push(c),
+            }
+        }
+        fields.push(field);
+        if fields.len() == 1 && fields[0].is_empty() && result.is_ok() {
+            continue;
+        }
+        records.push((start, result.map(|()| fields)));
+    }
+    records
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use dashmap::DashMap;
+
+    use super::{parse_csv, to_csv, HEADER};
+    use crate::{Credential, Key};
+
+    #[test]
+    fn roundtrip_test() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let card = Credential::Wiegand(1234567);
+        let list = DashMap::from_iter([
+            (alice, Key::named("Alice")),
+            (
+                card,
+                Key {
+                    name: "Smith, \"Bob\"\nJr.".to_owned(),
+                    expiry: Some(at(1_900_000_000)),
+                    schedule: Some("daytime".to_owned()),
+                    group: Some("board".to_owned()),
+                    pin: Some(
+                        "pbkdf2-sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk"
+                            .to_owned(),
+                    ),
+                },
+            ),
+            (
+                Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]),
+                Key::default(),
+            ),
+        ]);
+        let last_seen = DashMap::from_iter([(alice, at(1_700_000_000))]);
+
+        let csv = to_csv(&list, &last_seen);
+        assert!(csv.starts_with(&format!("{HEADER}\n01-000000000042,,,,,,\n")));
+        assert!(
+            csv.contains("\nwiegand:1234567,\"Smith, \"\"Bob\"\"\nJr.\""),
+            "{csv}"
+        );
+        assert!(
+            csv.contains("\n33-00000392c6ea,Alice,2023-11-14T22:13:20Z,,,,\n"),
+            "{csv}"
+        );
+        let import = parse_csv(&csv);
+        assert!(import.issues.is_empty(), "{:?}", import.issues);
+        assert_eq!(import.keys, list.into_iter().collect());
+        assert_eq!(import.last_seen, last_seen.into_iter().collect());
+    }
+
+    #[test]
+    fn invalid_records_test() {
+        let csv = "\
+01-000000000042,Bob,,,,,
+01-00000000zz42,Bad hex,,,,,
+
+01-000000000043,Carol,yesterday,,,,
+01-000000000044,Short
+01-000000000045,\"Unterminated,,,,,
+";
+        let import = parse_csv(csv);
+        assert_eq!(import.keys.len(), 1);
+        let lines: Vec<_> = import.issues.iter().map(|issue| issue.line).collect();
+        assert_eq!(lines, [2, 4, 5, 6]);
+        assert!(
+            import.issues[1].reason.contains("last_seen"),
+            "{}",
+            import.issues[1]
+        );
+        // The header is only one on the first line.
+        assert_eq!(parse_csv(&format!("{HEADER}\n")).issues.len(), 0);
+        assert_eq!(parse_csv(&format!("\n{HEADER}\n")).issues.len(), 1);
+    }
+}
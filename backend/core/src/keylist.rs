@@ -0,0 +1,762 @@
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    io::BufRead,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+
+use crate::{config, pin::PinHash, privacy, Credential, Key};
+
+/// Tried when `thing.csv.delimiter` isn't set.
+const DELIMITERS: [char; 3] = [',', ';', '\t'];
+
+/// Columns of the line format, up to the PIN.
+const FIELDS: usize = 6;
+
+/// A line of the key list that was skipped.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseIssue {
+    /// 1-based, counting blank lines and comments.
+    pub line: usize,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ParseIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+/// How applying a fetched list changed the access list.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Ids and names of the new keys, sorted by id.
+    pub added: Vec<(Credential, String)>,
+    /// Ids and names of the keys that are gone, sorted by id.
+    pub removed: Vec<(Credential, String)>,
+    /// Keys whose name, expiry, schedule, group or PIN changed.
+    pub changed: usize,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        *self == Diff::default()
+    }
+}
+
+/// A key of a fetched list, borrowing what it can from the line it was read from, so keys the
+/// access list already has cost no allocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listed<'a> {
+    pub id: Credential,
+    pub name: Cow<'a, str>,
+    pub expiry: Option<SystemTime>,
+    pub schedule: Option<Cow<'a, str>>,
+    pub group: Option<Cow<'a, str>>,
+    pub pin: Option<Cow<'a, str>>,
+}
+
+impl<'a> Listed<'a> {
+    /// `key` as listed under `id`.
+    pub fn of(id: Credential, key: &'a Key) -> Listed<'a> {
+        Listed {
+            id,
+            name: Cow::Borrowed(&key.name),
+            expiry: key.expiry,
+            schedule: key.schedule.as_deref().map(Cow::Borrowed),
+            group: key.group.as_deref().map(Cow::Borrowed),
+            pin: key.pin.as_deref().map(Cow::Borrowed),
+        }
+    }
+
+    /// Whether `key` is what this lists.
+    pub fn matches(&self, key: &Key) -> bool {
+        self.name == key.name
+            && self.expiry == key.expiry
+            && self.schedule.as_deref() == key.schedule.as_deref()
+            && self.group.as_deref() == key.group.as_deref()
+            && self.pin.as_deref() == key.pin.as_deref()
+    }
+
+    pub fn to_key(&self) -> Key {
+        Key {
+            name: self.name.clone().into_owned(),
+            expiry: self.expiry,
+            schedule: self.schedule.clone().map(Cow::into_owned),
+            group: self.group.clone().map(Cow::into_owned),
+            pin: self.pin.clone().map(Cow::into_owned),
+        }
+    }
+}
+
+/// Parses the `id,name[,expiry[,schedule[,group[,pin]]]]` line format, skipping blank lines, `#`
+/// comments and keys that already expired; an empty expiry means the key doesn't expire. Lines with an invalid
+/// id, expiry or PIN hash and repeated ids are reported and skipped; the first occurrence of an id wins.
+///
+/// Fields are split as `csv` says and may be quoted; columns past the PIN are ignored. A first
+/// line whose first field is a word instead of an id, like `id`, is taken for a header.
+pub fn parse_key_list(
+    body: &str,
+    csv: &config::Csv,
+    now: SystemTime,
+) -> (HashMap<Credential, Key>, Vec<ParseIssue>) {
+    let mut ids = HashMap::new();
+    let issues = read_key_list(
+        body.as_bytes(),
+        csv,
+        config::InvalidUtf8::Reject,
+        now,
+        |key| {
+            ids.insert(key.id, key.to_key());
+        },
+    )
+    .expect("a str is valid UTF-8 and reading it can't fail");
+    (ids, issues)
+}
+
+/// Reads the line format as [`parse_key_list`] parses it, one line at a time, handing each key
+/// to `each` instead of collecting them; only the ids are kept, to find repeated ones. A line
+/// that isn't valid UTF-8 fails the whole list, unless `invalid_utf8` says to decode it lossily.
+pub fn read_key_list(
+    mut reader: impl BufRead,
+    csv: &config::Csv,
+    invalid_utf8: config::InvalidUtf8,
+    now: SystemTime,
+    mut each: impl FnMut(Listed<'_>),
+) -> anyhow::Result<Vec<ParseIssue>> {
+    let mut first_seen = HashMap::new();
+    let mut issues = Vec::new();
+    let mut first_line = true;
+    let mut buf = Vec::new();
+    let mut lossy = false;
+    for number in 1.. {
+        buf.clear();
+        if reader
+            .read_until(b'\n', &mut buf)
+            .context("Failed reading key list")?
+            == 0
+        {
+            break;
+        }
+        let line = match std::str::from_utf8(&buf) {
+            Ok(line) => Cow::Borrowed(line),
+            Err(e) if invalid_utf8 == config::InvalidUtf8::Lossy => {
+                if !std::mem::replace(&mut lossy, true) {
+                    log::warn!(
+                        "Key list line {number} is not valid UTF-8 ({e}), decoding it and any \
+                         further ones lossily"
+                    );
+                }
+                String::from_utf8_lossy(&buf)
+            }
+            Err(e) => return Err(e).context(format!("Key list line {number} is not valid UTF-8")),
+        };
+        let mut issue = |reason| {
+            issues.push(ParseIssue {
+                line: number,
+                reason,
+            })
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let header = std::mem::take(&mut first_line);
+        let [id, name, expiry, schedule, group, pin] = match split_line(line, csv) {
+            Ok(fields) => fields,
+            Err(e) => {
+                issue(format!("{e:#}"));
+                continue;
+            }
+        };
+        let id = match Credential::parse(&id) {
+            Ok(id) => id,
+            Err(_) if header && id.chars().all(|c| c.is_alphabetic() || " _".contains(c)) => {
+                log::debug!("Skipping header line {number} of the key list");
+                continue;
+            }
+            Err(e) => {
+                issue(format!("invalid ID {:?}: {e}", privacy::raw(&id)));
+                continue;
+            }
+        };
+        let expiry = match optional(expiry).map(|e| parse_expiry(&e)).transpose() {
+            Ok(expiry) => expiry,
+            Err(e) => {
+                issue(format!("invalid expiry of {}: {e}", privacy::id(&id)));
+                continue;
+            }
+        };
+        let pin = optional(pin);
+        // Never quoted, it's as good as the PIN to anyone with time to guess.
+        if let Some(Err(e)) = pin.as_deref().map(PinHash::parse) {
+            issue(format!("invalid PIN hash of {}: {e}", privacy::id(&id)));
+            continue;
+        }
+        match first_seen.entry(id) {
+            Entry::Occupied(first) => {
+                issue(format!(
+                    "{} already listed on line {}",
+                    privacy::id(&id),
+                    first.get()
+                ));
+                continue;
+            }
+            Entry::Vacant(first) => {
+                first.insert(number);
+            }
+        }
+        if expiry.is_some_and(|expiry| expiry <= now) {
+            log::debug!("Skipping key {name:?} ({}), expired", privacy::id(&id));
+            continue;
+        }
+        each(Listed {
+            id,
+            name,
+            expiry,
+            schedule: optional(schedule),
+            group: optional(group),
+            pin,
+        });
+    }
+    Ok(issues)
+}
+
+/// The leading fields of `line`, trimmed unless quoted, borrowed unless unquoting changed them.
+fn split_line<'a>(line: &'a str, csv: &config::Csv) -> anyhow::Result<[Cow<'a, str>; FIELDS]> {
+    let quote = csv.quote;
+    let delimiter = csv.delimiter.or_else(|| {
+        let mut quoted = false;
+        line.chars().find(|&c| {
+            quoted ^= c == quote;
+            !quoted && DELIMITERS.contains(&c)
+        })
+    });
+    let split = |field: &'a str| match delimiter.and_then(|d| field.split_once(d)) {
+        Some((field, rest)) => (field, Some(rest)),
+        None => (field, None),
+    };
+    let mut fields: [Cow<'a, str>; FIELDS] = Default::default();
+    let mut rest = line;
+    for index in 0.. {
+        let start = rest.trim_start_matches(|c: char| c.is_whitespace() && Some(c) != delimiter);
+        let (field, next) = match start.strip_prefix(quote) {
+            Some(mut quoted) => {
+                let mut field = Cow::Borrowed("");
+                loop {
+                    let Some((chunk, after)) = quoted.split_once(quote) else {
+                        anyhow::bail!("unterminated quote in field {}", index + 1);
+                    };
+                    append(&mut field, chunk);
+                    match after.strip_prefix(quote) {
+                        Some(after) => {
+                            field.to_mut().push(quote);
+                            quoted = after;
+                        }
+                        None => {
+                            quoted = after;
+                            break;
+                        }
+                    }
+                }
+                // Anything after the closing quote is kept, an export glitch shouldn't lose a
+                // name.
+                let (tail, next) = split(quoted);
+                append(&mut field, tail.trim_end());
+                (field, next)
+            }
+            None => {
+                let (field, next) = split(start);
+                (Cow::Borrowed(field.trim()), next)
+            }
+        };
+        if let Some(slot) = fields.get_mut(index) {
+            *slot = field;
+        }
+        match next {
+            Some(next) => rest = next,
+            None => break,
+        }
+    }
+    Ok(fields)
+}
+
+/// `field`, unless it's empty.
+fn optional(field: Cow<'_, str>) -> Option<Cow<'_, str>> {
+    (!field.is_empty()).then_some(field)
+}
+
+/// Appends `more` to `field`, copying only if both are non-empty.
+fn append<'a>(field: &mut Cow<'a, str>, more: &'a str) {
+    match field.is_empty() {
+        true => *field = Cow::Borrowed(more),
+        false if !more.is_empty() => field.to_mut().push_str(more),
+        false => {}
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct JsonEntry {
+    id: String,
+    #[serde(default)]
+    name: String,
+    valid_until: Option<String>,
+    schedule: Option<String>,
+    group: Option<String>,
+    pin: Option<String>,
+}
+
+/// Parses the JSON format, skipping invalid and expired entries.
+pub fn parse_json(body: &str, now: SystemTime) -> anyhow::Result<HashMap<Credential, Key>> {
+    let entries: Vec<serde_json::Value> =
+        serde_json::from_str(body).context("Key list is not a JSON array")?;
+    let mut ids = HashMap::new();
+    for (idx, entry) in entries.into_iter().enumerate() {
+        let entry: JsonEntry = match serde_json::from_value(entry) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::error!("Failed to parse key list entry {idx}: {e}");
+                continue;
+            }
+        };
+        let id = match Credential::parse(entry.id.trim()) {
+            Ok(id) => id,
+            Err(e) => {
+                log::error!(
+                    "Failed to parse ID {:?} of entry {idx}: {e:?}",
+                    privacy::raw(&entry.id)
+                );
+                continue;
+            }
+        };
+        let expiry = match entry.valid_until.as_deref().map(parse_expiry).transpose() {
+            Ok(expiry) => expiry,
+            Err(e) => {
+                log::error!("Failed to parse valid_until of entry {idx}: {e:?}");
+                continue;
+            }
+        };
+        let pin = entry
+            .pin
+            .map(|pin| pin.trim().to_owned())
+            .filter(|pin| !pin.is_empty());
+        if let Some(Err(e)) = pin.as_deref().map(PinHash::parse) {
+            log::error!("Failed to parse the PIN hash of entry {idx}: {e}");
+            continue;
+        }
+        let key = Key {
+            name: entry.name.trim().to_owned(),
+            expiry,
+            schedule: entry
+                .schedule
+                .map(|schedule| schedule.trim().to_owned())
+                .filter(|schedule| !schedule.is_empty()),
+            group: entry
+                .group
+                .map(|group| group.trim().to_owned())
+                .filter(|group| !group.is_empty()),
+            pin,
+        };
+        if key.is_expired(now) {
+            log::debug!("Skipping key {:?} of entry {idx}, expired", key.name);
+            continue;
+        }
+        ids.insert(id, key);
+    }
+    Ok(ids)
+}
+
+/// Replaces the contents of `access_list` with `new`, updating changed entries in place.
+///
+/// ```
+/// use std::{collections::HashMap, time::SystemTime};
+///
+/// use cellardoor_core::{keylist, Credential, Key};
+/// use dashmap::DashMap;
+///
+/// let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+/// let access_list = DashMap::from_iter([(alice, Key::named("Alice"))]);
+/// let (fetched, issues) = keylist::parse_key_list(
+///     "33-00000392c6ea,Alice\n01-000000000042,Bob\n",
+///     &Default::default(),
+///     SystemTime::now(),
+/// );
+/// assert!(issues.is_empty());
+/// let diff = keylist::apply_key_list(&access_list, fetched);
+/// let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+/// assert_eq!(diff.added, [(bob, "Bob".to_owned())]);
+/// assert_eq!((diff.removed.len(), diff.changed), (0, 0));
+/// assert_eq!(access_list.len(), 2);
+/// ```
+pub fn apply_key_list(
+    access_list: &DashMap<Credential, Key>,
+    mut new: HashMap<Credential, Key>,
+) -> Diff {
+    let mut removed = Vec::new();
+    let mut changed = 0;
+    access_list.retain(|button, key| match new.remove(button) {
+        Some(new_key) => {
+            if *key != new_key {
+                *key = new_key;
+                changed += 1;
+            }
+            true
+        }
+        None => {
+            removed.push((*button, key.name.clone()));
+            false
+        }
+    });
+    let mut added: Vec<_> = new
+        .into_iter()
+        .map(|(id, key)| {
+            let name = key.name.clone();
+            access_list.insert(id, key);
+            (id, name)
+        })
+        .collect();
+    added.sort();
+    removed.sort();
+    Diff {
+        added,
+        removed,
+        changed,
+    }
+}
+
+/// Like [`apply_key_list`], for a list that was only kept as far as it differs: removes the keys
+/// not in `listed` and puts in `changes`, the new keys and those whose entry changed.
+pub fn apply_changes(
+    access_list: &DashMap<Credential, Key>,
+    listed: &HashSet<Credential>,
+    changes: HashMap<Credential, Key>,
+) -> Diff {
+    let mut removed = Vec::new();
+    access_list.retain(|button, key| {
+        let keep = listed.contains(button);
+        if !keep {
+            removed.push((*button, key.name.clone()));
+        }
+        keep
+    });
+    let mut added = Vec::new();
+    let mut changed = 0;
+    for (id, key) in changes {
+        let name = key.name.clone();
+        match access_list.insert(id, key) {
+            Some(_) => changed += 1,
+            None => added.push((id, name)),
+        }
+    }
+    added.sort();
+    removed.sort();
+    Diff {
+        added,
+        removed,
+        changed,
+    }
+}
+
+/// Parses an RFC3339 datetime, or a date which is then valid until the end of that day (UTC).
+pub(crate) fn parse_expiry(value: &str) -> anyhow::Result<SystemTime> {
+    if let Ok(datetime) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(datetime.into());
+    }
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .context(format!("{value:?} is neither an RFC3339 date nor datetime"))?;
+    let end_of_day = date
+        .succ_opt()
+        .and_then(|next| next.and_hms_opt(0, 0, 0))
+        .context(format!("{value:?} is out of range"))?;
+    Ok(end_of_day.and_utc().into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use dashmap::DashMap;
+
+    use super::{apply_key_list, parse_expiry, parse_json, parse_key_list, Diff, ParseIssue};
+    use crate::{config::Csv, Credential, Key};
+
+    const LINES: &str = "# MOS key list
+33-00000392c6ea,Alice
+01-000000000042 , Bob
+
+not-an-id,Mallory
+01-000000000043
+01-000000000044,Visitor,2030-01-01T12:00:00Z
+01-000000000045,Past visitor,2024-12-31
+01-000000000046,Typo,2030-13-01
+01-000000000047,Cleaner,,daytime
+01-000000000048,Carol,,,keyholder
+wiegand:1234567,Dana
+01-000000000049,Erin,,,,pbkdf2-sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk
+01-00000000004a,Frank,,,,1234
+";
+
+    /// The export with a header, semicolons, quoted names and columns MOS added later.
+    const SEMICOLONS: &str = "id;name;valid_until;schedule;group;pin;member_since
+33-00000392c6ea;\"Smith; Alice\";;;;;2019
+01-000000000042;Bob;2030-01-01T12:00:00Z;;keyholder;;2021
+01-000000000043;\"Carol \"\"CJ\"\"\";;;;;
+01-000000000044;\"Dana
+";
+
+    const TABS: &str = "ID\tName
+33-00000392c6ea\tSmith, Alice
+01-000000000042\t  Bob  \t\tdaytime
+";
+
+    const MIXED: &str = "# hand-edited
+33-00000392c6ea,\"Smith, Alice\"
+01-000000000042;Bob
+01-000000000043\tCarol
+01-000000000044,'Dana, Erin'
+";
+
+    /// Of the PIN `0000`.
+    const PIN_HASH: &str = "pbkdf2-sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk";
+
+    const JSON: &str = r#"[
+        {"id": "33-00000392c6ea", "name": "Alice", "valid_until": "2030-12-31"},
+        {"id": "01-000000000042", "name": "Bob", "valid_until": null, "schedule": "daytime",
+         "group": "board"},
+        {"id": "01-000000000043"},
+        {"id": "01-000000000044", "name": "Expired", "valid_until": "2024-12-31"},
+        {"id": "not-an-id", "name": "Mallory"},
+        {"name": "No id"},
+        {"id": "01-000000000045", "name": "Bad date", "valid_until": "soon"}
+    ]"#;
+
+    fn sorted(ids: HashMap<crate::Credential, Key>) -> Vec<(crate::Credential, Key)> {
+        let mut ids: Vec<_> = ids.into_iter().collect();
+        ids.sort_by_key(|(id, _)| *id);
+        ids
+    }
+
+    fn lines(issues: &[ParseIssue]) -> Vec<usize> {
+        issues.iter().map(|issue| issue.line).collect()
+    }
+
+    #[test]
+    fn parse_lines_test() {
+        let now = humantime::parse_rfc3339("2025-06-01T00:00:00Z").unwrap();
+        let (ids, issues) = parse_key_list(LINES, &Csv::default(), now);
+        assert_eq!(
+            sorted(ids),
+            [
+                (
+                    Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]),
+                    Key::named("Bob")
+                ),
+                (
+                    Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]),
+                    Key::default()
+                ),
+                (
+                    Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x44]),
+                    Key {
+                        name: "Visitor".to_owned(),
+                        expiry: Some(humantime::parse_rfc3339("2030-01-01T12:00:00Z").unwrap()),
+                        schedule: None,
+                        group: None,
+                        pin: None,
+                    }
+                ),
+                (
+                    Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x47]),
+                    Key {
+                        schedule: Some("daytime".to_owned()),
+                        ..Key::named("Cleaner")
+                    }
+                ),
+                (
+                    Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x48]),
+                    Key {
+                        group: Some("keyholder".to_owned()),
+                        ..Key::named("Carol")
+                    }
+                ),
+                (
+                    Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x49]),
+                    Key {
+                        pin: Some(PIN_HASH.to_owned()),
+                        ..Key::named("Erin")
+                    }
+                ),
+                (
+                    Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+                    Key::named("Alice")
+                ),
+                (Credential::Wiegand(1234567), Key::named("Dana")),
+            ]
+        );
+        // Comments, blank lines and expired keys aren't issues.
+        assert_eq!(lines(&issues), [5, 9, 14]);
+        // A bad PIN hash is reported without repeating it.
+        assert!(!issues[2].reason.contains("1234"), "{}", issues[2]);
+    }
+
+    #[test]
+    fn dialects_test() {
+        let now = humantime::parse_rfc3339("2025-06-01T00:00:00Z").unwrap();
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let carol = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]);
+        let dana = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x44]);
+
+        let (ids, issues) = parse_key_list(SEMICOLONS, &Csv::default(), now);
+        assert_eq!(
+            sorted(ids),
+            [
+                (
+                    bob,
+                    Key {
+                        expiry: Some(humantime::parse_rfc3339("2030-01-01T12:00:00Z").unwrap()),
+                        group: Some("keyholder".to_owned()),
+                        ..Key::named("Bob")
+                    }
+                ),
+                (carol, Key::named("Carol \"CJ\"")),
+                (alice, Key::named("Smith; Alice")),
+            ]
+        );
+        assert_eq!(lines(&issues), [5]);
+        assert_eq!(issues[0].reason, "unterminated quote in field 2");
+
+        let (ids, issues) = parse_key_list(TABS, &Csv::default(), now);
+        assert!(issues.is_empty(), "{issues:?}");
+        assert_eq!(ids[&alice], Key::named("Smith, Alice"));
+        assert_eq!(ids[&bob].name, "Bob");
+        assert_eq!(ids[&bob].schedule.as_deref(), Some("daytime"));
+
+        // Each line finds its own delimiter, unless one is configured.
+        let single: Csv = serde_yaml_ng::from_str("quote: \"'\"").unwrap();
+        let (ids, issues) = parse_key_list(MIXED, &single, now);
+        assert_eq!(lines(&issues), [2]);
+        assert_eq!(ids[&dana].name, "Dana, Erin");
+        let (ids, issues) = parse_key_list(MIXED, &Csv::default(), now);
+        assert_eq!(lines(&issues), [5]);
+        assert_eq!(ids[&alice].name, "Smith, Alice");
+        assert_eq!(ids[&bob].name, "Bob");
+        assert_eq!(ids[&carol].name, "Carol");
+        let commas: Csv = serde_yaml_ng::from_str("delimiter: ','").unwrap();
+        let (ids, issues) = parse_key_list(MIXED, &commas, now);
+        assert_eq!(lines(&issues), [3, 4, 5]);
+        assert_eq!(ids.len(), 1);
+
+        // Only the first line can be a header, and only if it doesn't look like an id.
+        let (ids, issues) = parse_key_list("id,name\nid,name\n", &Csv::default(), now);
+        assert!(ids.is_empty());
+        assert_eq!(lines(&issues), [2]);
+        let (_, issues) = parse_key_list("33-zz,Typo\n", &Csv::default(), now);
+        assert_eq!(lines(&issues), [1]);
+    }
+
+    #[test]
+    fn parse_issues_test() {
+        let now = humantime::parse_rfc3339("2025-06-01T00:00:00Z").unwrap();
+        let body = "01-000000000042,Bob\n01-00000000zz42,Bad hex\n01-000000000042,Bob again\n";
+        let (ids, issues) = parse_key_list(body, &Csv::default(), now);
+        assert_eq!(
+            sorted(ids),
+            [(
+                Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]),
+                Key::named("Bob")
+            )]
+        );
+        assert_eq!(lines(&issues), [2, 3]);
+        assert!(issues[1].reason.contains("line 1"), "{}", issues[1]);
+
+        // Something that isn't a key list at all yields nothing but issues.
+        let (ids, issues) = parse_key_list(
+            "<html>\n<body>Bad Gateway</body>\n</html>\n",
+            &Csv::default(),
+            now,
+        );
+        assert!(ids.is_empty());
+        assert_eq!(lines(&issues), [1, 2, 3]);
+        assert_eq!(
+            parse_key_list("", &Csv::default(), now),
+            (HashMap::new(), Vec::new())
+        );
+    }
+
+    #[test]
+    fn parse_json_test() {
+        let now = humantime::parse_rfc3339("2025-06-01T00:00:00Z").unwrap();
+        assert_eq!(
+            sorted(parse_json(JSON, now).unwrap()),
+            [
+                (
+                    Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]),
+                    Key {
+                        schedule: Some("daytime".to_owned()),
+                        group: Some("board".to_owned()),
+                        ..Key::named("Bob")
+                    }
+                ),
+                (
+                    Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]),
+                    Key::default()
+                ),
+                (
+                    Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+                    Key {
+                        name: "Alice".to_owned(),
+                        expiry: Some(humantime::parse_rfc3339("2031-01-01T00:00:00Z").unwrap()),
+                        schedule: None,
+                        group: None,
+                        pin: None,
+                    }
+                ),
+            ]
+        );
+        assert!(parse_json("{}", now).is_err());
+
+        // A date is valid for the whole day.
+        let expiry = parse_expiry("2024-12-31").unwrap();
+        assert_eq!(
+            expiry,
+            humantime::parse_rfc3339("2025-01-01T00:00:00Z").unwrap()
+        );
+    }
+
+    #[test]
+    fn apply_key_list_test() {
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let carol = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]);
+        let access_list =
+            DashMap::from_iter([(alice, Key::named("Alice")), (bob, Key::named("Bob"))]);
+
+        let diff = apply_key_list(
+            &access_list,
+            HashMap::from([
+                (alice, Key::named("Alice B.")),
+                (carol, Key::named("Carol")),
+            ]),
+        );
+        assert_eq!(
+            diff,
+            Diff {
+                added: vec![(carol, "Carol".to_owned())],
+                removed: vec![(bob, "Bob".to_owned())],
+                changed: 1,
+            }
+        );
+        assert_eq!(access_list.get(&alice).unwrap().name, "Alice B.");
+        assert!(!access_list.contains_key(&bob));
+
+        let same = HashMap::from([
+            (alice, Key::named("Alice B.")),
+            (carol, Key::named("Carol")),
+        ]);
+        assert!(apply_key_list(&access_list, same).is_empty());
+    }
+}
@@ -0,0 +1,436 @@
+//! The parts of cellardoor that don't touch hardware: key ids, the key list and how it is
+//! fetched, applied and persisted, and the configuration.
+
+use std::{fmt, time::SystemTime};
+
+use anyhow::Context;
+
+pub mod config;
+pub mod export;
+pub mod keylist;
+pub mod persistence;
+pub mod pin;
+pub mod privacy;
+pub mod schedule;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+
+/// A 1-Wire device id: the family code followed by the 48-bit serial, highest byte first.
+pub type OneWireId = [u8; 7];
+
+/// What a reader identified someone by, which the access list and everything else about keys
+/// is keyed by. Cards and tags are apart from 1-Wire devices, whatever their numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Credential {
+    OneWire(OneWireId),
+    /// A card number from a Wiegand reader.
+    Wiegand(u64),
+    /// An ISO 14443A tag, see [`Credential::nfc`].
+    Nfc(NfcUid),
+}
+
+/// The whole 4 or 7 byte UID of an NFC tag, so UIDs of different lengths never compare equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NfcUid {
+    len: u8,
+    bytes: [u8; 7],
+}
+
+impl NfcUid {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..usize::from(self.len)]
+    }
+}
+
+impl From<OneWireId> for Credential {
+    fn from(id: OneWireId) -> Credential {
+        Credential::OneWire(id)
+    }
+}
+
+impl Credential {
+    /// The tag with the 4 or 7 byte UID `uid`, all of which is kept.
+    pub fn nfc(uid: &[u8]) -> Option<Credential> {
+        if !matches!(uid.len(), 4 | 7) {
+            return None;
+        }
+        let mut bytes = [0; 7];
+        bytes[..uid.len()].copy_from_slice(uid);
+        Some(Credential::Nfc(NfcUid {
+            len: uid.len() as u8,
+            bytes,
+        }))
+    }
+
+    /// Parses a 1-Wire id in any of the forms of [`parse_1w_id`], a Wiegand card number in
+    /// decimal after `wiegand:`, or an NFC tag UID of 4 or 7 bytes in hex after `nfc:`. Six
+    /// bytes are refused rather than guessed to be a 7 byte UID without its first byte.
+    ///
+    /// ```
+    /// use cellardoor_core::Credential;
+    ///
+    /// let id = [0x33, 0x00, 0x00, 0x03, 0x92, 0xc6, 0xea];
+    /// assert_eq!(Credential::parse("33-00000392c6ea")?, Credential::OneWire(id));
+    /// assert_eq!(Credential::parse("wiegand:1234567")?, Credential::Wiegand(1234567));
+    /// assert_eq!(Credential::parse("wiegand:1234567")?.to_string(), "wiegand:1234567");
+    /// # anyhow::Ok(())
+    /// ```
+    pub fn parse(id: &str) -> anyhow::Result<Credential> {
+        let id = id.trim();
+        if let Some(card) = strip_prefix(id, "wiegand:") {
+            return Ok(Credential::Wiegand(
+                card.parse().context("Wrong card number format")?,
+            ));
+        }
+        if let Some(uid) = strip_prefix(id, "nfc:") {
+            anyhow::ensure!(
+                uid.len() != 12,
+                "6 byte UID, the tag's full 7 byte UID is needed"
+            );
+            anyhow::ensure!(
+                matches!(uid.len(), 8 | 14) && uid.bytes().all(|b| b.is_ascii_hexdigit()),
+                "Wrong UID format"
+            );
+            let bytes: Vec<u8> = (0..uid.len() / 2)
+                .map(|idx| u8::from_str_radix(&uid[idx * 2..idx * 2 + 2], 16))
+                .collect::<Result<_, _>>()?;
+            return Ok(Credential::nfc(&bytes).unwrap());
+        }
+        parse_1w_id(id).map(Credential::OneWire)
+    }
+
+    /// As bare hex digits for a 1-Wire id, as the audit log and hooks write it. Cards and tags
+    /// are written as they are listed, having no bare form.
+    pub fn hex(&self) -> String {
+        match self {
+            Credential::OneWire(id) => hex_1w_id(id),
+            credential => credential.to_string(),
+        }
+    }
+
+    /// As stored in binary fields: the 7 bytes of a 1-Wire id like before cards had ids of
+    /// their own, cards and tags as they are listed, which is always longer.
+    pub fn bytes(&self) -> Vec<u8> {
+        match self {
+            Credential::OneWire(id) => id.to_vec(),
+            credential => credential.to_string().into_bytes(),
+        }
+    }
+
+    /// The inverse of [`Credential::bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Credential> {
+        if let Ok(id) = OneWireId::try_from(bytes) {
+            return Ok(Credential::OneWire(id));
+        }
+        let parsed = std::str::from_utf8(bytes)
+            .ok()
+            .and_then(|text| Credential::parse(text).ok());
+        match parsed {
+            Some(credential) if !matches!(credential, Credential::OneWire(_)) => Ok(credential),
+            _ => anyhow::bail!("Invalid key id of {} bytes", bytes.len()),
+        }
+    }
+}
+
+impl fmt::Display for Credential {
+    /// The inverse of [`Credential::parse`]: the kernel's name for a 1-Wire id, `wiegand:` or
+    /// `nfc:` and the number for cards and tags.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credential::OneWire(id) => f.write_str(&format_1w_id(id)),
+            Credential::Wiegand(card) => write!(f, "wiegand:{card}"),
+            Credential::Nfc(uid) => {
+                f.write_str("nfc:")?;
+                uid.as_bytes().iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
+        }
+    }
+}
+
+/// `id` without `prefix`, in any case.
+fn strip_prefix<'a>(id: &'a str, prefix: &str) -> Option<&'a str> {
+    id.get(..prefix.len())
+        .filter(|start| start.eq_ignore_ascii_case(prefix))
+        .map(|_| &id[prefix.len()..])
+}
+
+/// An entry of the access list.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Key {
+    pub name: String,
+    /// After this the key no longer opens the door, e.g. for visitors.
+    pub expiry: Option<SystemTime>,
+    /// Label of the `schedules` entry limiting when the key opens the door; `None` is 24/7.
+    pub schedule: Option<String>,
+    /// MOS group such as `keyholder`, which readers with `allow_groups` check.
+    pub group: Option<String>,
+    /// Hash of the PIN to enter on the keypad after presenting the key, see [`pin`]; a key
+    /// with one is `pin_required`.
+    pub pin: Option<String>,
+}
+
+impl Key {
+    /// A key that never expires.
+    pub fn named(name: impl Into<String>) -> Key {
+        Key {
+            name: name.into(),
+            expiry: None,
+            schedule: None,
+            group: None,
+            pin: None,
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expiry.is_some_and(|expiry| expiry <= now)
+    }
+}
+
+/// Parses a 1-Wire id as the kernel names it (`33-00000392c6ea`), the same without the dash, or
+/// as the 16-digit ROM code in bus order (family, serial starting with its lowest byte, CRC) as
+/// owfs prints it. Case and surrounding whitespace don't matter; the ROM code's CRC is checked.
+/// See [`Credential::parse`] for the ids of cards and tags.
+///
+/// ```
+/// use cellardoor_core::parse_1w_id;
+///
+/// let id = [0x33, 0x00, 0x00, 0x03, 0x92, 0xc6, 0xea];
+/// assert_eq!(parse_1w_id("33-00000392c6ea")?, id);
+/// assert_eq!(parse_1w_id("3300000392C6EA")?, id);
+/// assert!(parse_1w_id("33-0000").is_err());
+/// assert!(parse_1w_id("wiegand:1234567").is_err());
+/// # anyhow::Ok(())
+/// ```
+pub fn parse_1w_id(id: &str) -> anyhow::Result<OneWireId> {
+    let id = id.trim();
+    let digits = id.replacen('-', "", usize::from(id.find('-') == Some(2)));
+    anyhow::ensure!(
+        digits.len().is_multiple_of(2) && digits.bytes().all(|b| b.is_ascii_hexdigit()),
+        "Wrong id format"
+    );
+    let bytes: Vec<u8> = (0..digits.len() / 2)
+        .map(|idx| u8::from_str_radix(&digits[idx * 2..idx * 2 + 2], 16))
+        .collect::<Result<_, _>>()?;
+    let dashed = digits.len() != id.len();
+    match bytes.len() {
+        7 => Ok(bytes.try_into().unwrap()),
+        8 if !dashed => {
+            let crc = crc8(&bytes[..7]);
+            anyhow::ensure!(
+                crc == bytes[7],
+                "CRC mismatch, expected {crc:02x} but got {:02x}",
+                bytes[7]
+            );
+            let mut result = [bytes[0], 0, 0, 0, 0, 0, 0];
+            for idx in 0..6 {
+                result[idx + 1] = bytes[6 - idx];
+            }
+            Ok(result)
+        }
+        _ => anyhow::bail!("Wrong id format"),
+    }
+}
+
+/// The Dallas/Maxim CRC8 (polynomial x^8 + x^5 + x^4 + 1) that ends every 1-Wire ROM code.
+pub fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0x8c
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// The kernel's name for `id`, the inverse of [`parse_1w_id`].
+pub fn format_1w_id(id: &OneWireId) -> String {
+    let serial: String = id[1..].iter().map(|b| format!("{b:02x}")).collect();
+    format!("{:02x}-{serial}", id[0])
+}
+
+/// `id` as bare hex digits.
+pub fn hex_1w_id(id: &OneWireId) -> String {
+    id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::Credential;
+
+    /// Cases tried by each property; a failure names the seed that reproduces it.
+    const CASES: u64 = 1024;
+
+    #[test]
+    fn parse_1w_id_test() {
+        let id = "33-00000392c6ea";
+        let id_bytes = super::parse_1w_id(id).unwrap();
+        assert_eq!(id_bytes, [0x33, 0x00, 0x00, 0x03, 0x92, 0xc6, 0xea]);
+    }
+
+    #[test]
+    fn parse_1w_id_forms_test() {
+        let id = [0x33, 0x00, 0x00, 0x03, 0x92, 0xc6, 0xea];
+        assert_eq!(super::parse_1w_id(" 33-00000392C6EA\n").unwrap(), id);
+        assert_eq!(super::parse_1w_id("3300000392c6ea").unwrap(), id);
+
+        // The example ROM code of Maxim's application note 27.
+        assert_eq!(
+            super::parse_1w_id("021CB801000000A2").unwrap(),
+            [0x02, 0x00, 0x00, 0x00, 0x01, 0xb8, 0x1c]
+        );
+        assert!(
+            format!("{:#}", super::parse_1w_id("021cb801000000a3").unwrap_err())
+                .contains("CRC mismatch")
+        );
+
+        for bad in [
+            "",
+            "33-",
+            "33-0000",
+            "3-300000392c6ea",
+            "33-00000392c6ea00",
+            "3300000392c6ea0",
+            "33-+0000392c6ea",
+            "33-00000392c6eä",
+            "021cb801-000000a2",
+        ] {
+            assert!(super::parse_1w_id(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn arbitrary_ids_never_panic_test() {
+        // Pieces of the forms ids take, so most inputs get past the first checks.
+        let pieces = [
+            "wiegand:", "nfc:", "NFC:", "-", "0", "3", "9", "a", "F", "g", " ", "\n", "ä", "€", ":",
+        ];
+        for seed in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let id: String = (0..rng.gen_range(0..24))
+                .map(|_| *pieces.choose(&mut rng).unwrap())
+                .collect();
+            let _ = Credential::parse(&id);
+            let id: String = (0..rng.gen_range(0..24))
+                .map(|_| rng.gen::<char>())
+                .collect();
+            let _ = Credential::parse(&id);
+            let _ = Credential::from_bytes(id.as_bytes());
+        }
+    }
+
+    #[test]
+    fn formatted_ids_roundtrip_test() {
+        for seed in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut id: super::OneWireId = rng.gen();
+            if rng.gen_bool(0.2) {
+                id[1..3].fill(0);
+            }
+            let formatted = super::format_1w_id(&id);
+            assert_eq!(super::parse_1w_id(&formatted).unwrap(), id, "{formatted}");
+            let hex = super::hex_1w_id(&id);
+            assert_eq!(super::parse_1w_id(&hex).unwrap(), id, "{hex}");
+
+            let credential = match rng.gen_range(0..3) {
+                0 => Credential::OneWire(id),
+                1 => Credential::Wiegand(rng.gen()),
+                _ => Credential::nfc(&id[..*[4, 7].choose(&mut rng).unwrap()]).unwrap(),
+            };
+            let formatted = credential.to_string();
+            assert_eq!(
+                Credential::parse(&formatted).unwrap(),
+                credential,
+                "{formatted}"
+            );
+            let hex = credential.hex();
+            assert_eq!(Credential::parse(&hex).unwrap(), credential, "{hex}");
+            let bytes = credential.bytes();
+            assert_eq!(
+                Credential::from_bytes(&bytes).unwrap(),
+                credential,
+                "{formatted}"
+            );
+        }
+    }
+
+    #[test]
+    fn format_1w_id_test() {
+        let id = [0x33, 0x00, 0x00, 0x03, 0x92, 0xc6, 0xea];
+        assert_eq!(super::format_1w_id(&id), "33-00000392c6ea");
+    }
+
+    #[test]
+    fn wiegand_id_test() {
+        let card = Credential::parse(" Wiegand:1234567 ").unwrap();
+        assert_eq!(card, Credential::Wiegand(1234567));
+        assert_eq!(card.to_string(), "wiegand:1234567");
+        assert_eq!(card.hex(), "wiegand:1234567");
+        // Not the 1-Wire device with the same digits, nor one of family 00.
+        assert_ne!(card, Credential::OneWire([0, 0, 0, 0, 0x12, 0xd6, 0x87]));
+        assert_eq!(
+            Credential::parse("00-00000012d687").unwrap(),
+            Credential::OneWire([0, 0, 0, 0, 0x12, 0xd6, 0x87])
+        );
+        // Every bit of a long card number is kept.
+        assert_eq!(
+            Credential::parse(&format!("wiegand:{}", u64::MAX)).unwrap(),
+            Credential::Wiegand(u64::MAX)
+        );
+        assert_ne!(
+            Credential::parse("wiegand:281474976710657").unwrap(),
+            Credential::Wiegand(1)
+        );
+        for bad in [
+            "wiegand:",
+            "wiegand:-1",
+            "wiegand:0x12",
+            "wiegand:18446744073709551616",
+        ] {
+            assert!(Credential::parse(bad).is_err(), "{bad:?}");
+        }
+        assert!(super::parse_1w_id("wiegand:1234567").is_err());
+        assert!(Credential::from_bytes(b"33-00000392c6ea").is_err());
+    }
+
+    #[test]
+    fn nfc_id_test() {
+        let uid = [0x04, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x80];
+        let tag = Credential::parse("NFC:04aabbccddee80").unwrap();
+        assert_eq!(Credential::nfc(&uid), Some(tag));
+        assert_eq!(tag.to_string(), "nfc:04aabbccddee80");
+        // Tags of another manufacturer with the same six last bytes are different tags.
+        let mut other = uid;
+        other[0] = 0x05;
+        assert_ne!(Credential::nfc(&other), Some(tag));
+        assert_ne!(
+            tag,
+            Credential::OneWire([0x04, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x80])
+        );
+
+        let short = Credential::parse("nfc:deadbeef").unwrap();
+        assert_eq!(Credential::nfc(&[0xde, 0xad, 0xbe, 0xef]), Some(short));
+        assert_eq!(short.to_string(), "nfc:deadbeef");
+        // Nor does a 4 byte UID match a 7 byte one ending in it.
+        assert_ne!(
+            Credential::nfc(&[0x04, 0, 0, 0xde, 0xad, 0xbe, 0xef]),
+            Some(short)
+        );
+        assert_eq!(Credential::nfc(&[1, 2, 3]), None);
+        assert_eq!(Credential::nfc(&[1; 6]), None);
+        let err = Credential::parse("nfc:aabbccddee80").unwrap_err();
+        assert!(err.to_string().contains("7 byte UID"), "{err}");
+        for bad in [
+            "nfc:",
+            "nfc:deadbee",
+            "nfc:aabbccddee80",
+            "nfc:0xdeadbeef",
+            "nfc:04aabbccddee8g",
+        ] {
+            assert!(Credential::parse(bad).is_err(), "{bad:?}");
+        }
+    }
+}
@@ -0,0 +1,1190 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+use ring::{
+    aead, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{Credential, Key};
+
+/// Leading bytes of the versioned file formats. Files without them are treated as the legacy
+/// format, a bare concatenation of 7-byte ids.
+const MAGIC: &[u8; 4] = b"CDKL";
+/// Version 1 is magic, version and name-carrying records without any integrity check.
+/// Version 2 adds a record count after the version and a trailing CRC32 over everything
+/// before it. Version 3 appends the expiry to each record as unix seconds, 0 meaning never.
+/// Version 4 appends the schedule label, length-prefixed like the name and empty for none.
+/// Version 5 appends the group the same way, and version 6 the PIN hash. Version 7 prefixes
+/// each id with its length, see [`put_id`], so cards and tags have ids of their own.
+const VERSION: u8 = 7;
+
+/// Leading bytes of the last-seen file: count, `(id, unix seconds)` records and a CRC32 like
+/// version 2 of the key list. Version 2 prefixes the ids with their length like version 7 of
+/// the key list.
+const LAST_SEEN_MAGIC: &[u8; 4] = b"CDLS";
+const LAST_SEEN_VERSION: u8 = 2;
+
+/// Leading bytes of an encrypted key list: magic, version, then a 12-byte nonce and the
+/// ChaCha20-Poly1305 sealed file, whose tag also covers magic, version and nonce.
+const ENCRYPTED_MAGIC: &[u8; 4] = b"CDKE";
+const ENCRYPTED_VERSION: u8 = 1;
+
+/// How a key list file is protected on disk; neither by default.
+#[derive(Default)]
+pub struct Protection {
+    pub signing: Option<Signing>,
+    pub encryption: Option<Encryption>,
+}
+
+/// The 256-bit key a key list file is encrypted with, with a fresh random nonce for every save.
+pub struct Encryption {
+    key: aead::LessSafeKey,
+}
+
+impl Encryption {
+    pub fn new(key: &[u8; 32]) -> Encryption {
+        Encryption {
+            key: aead::LessSafeKey::new(
+                aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key).unwrap(),
+            ),
+        }
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = [0; aead::NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow::anyhow!("No randomness for the key list nonce"))?;
+        let mut data = ENCRYPTED_MAGIC.to_vec();
+        data.push(ENCRYPTED_VERSION);
+        data.extend_from_slice(&nonce);
+        let mut sealed = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(&data[..]),
+                &mut sealed,
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt the key list"))?;
+        data.extend_from_slice(&sealed);
+        Ok(data)
+    }
+
+    fn open(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let header_len = ENCRYPTED_MAGIC.len() + 1 + aead::NONCE_LEN;
+        anyhow::ensure!(
+            data.len() >= header_len,
+            "Encrypted key list file is truncated"
+        );
+        let (header, sealed) = data.split_at(header_len);
+        let version = header[ENCRYPTED_MAGIC.len()];
+        anyhow::ensure!(
+            version == ENCRYPTED_VERSION,
+            "Unsupported encrypted key list version {version}"
+        );
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(&header[ENCRYPTED_MAGIC.len() + 1..]).unwrap();
+        let mut sealed = sealed.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(nonce, aead::Aad::from(header), &mut sealed)
+            .map_err(|_| {
+                anyhow::anyhow!("Key list decryption failed, wrong key or corrupted file")
+            })?;
+        Ok(plaintext.to_vec())
+    }
+}
+
+/// HMAC-SHA256 keys a key list file is signed with: the first one signs, any of them verifies,
+/// so a new key can be rolled out ahead of the old one being retired. A signed file carries the
+/// tag over everything before it in its last 32 bytes.
+pub struct Signing {
+    keys: Vec<hmac::Key>,
+}
+
+impl Signing {
+    pub fn new(secrets: &[Vec<u8>]) -> anyhow::Result<Signing> {
+        anyhow::ensure!(!secrets.is_empty(), "at least one HMAC key is required");
+        Ok(Signing {
+            keys: secrets
+                .iter()
+                .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret))
+                .collect(),
+        })
+    }
+
+    fn sign(&self, data: &mut Vec<u8>) {
+        let tag = hmac::sign(&self.keys[0], data);
+        data.extend_from_slice(tag.as_ref());
+    }
+
+    /// The signed part of `data`, if one of the keys produced its tag.
+    fn verify<'a>(&self, data: &'a [u8]) -> anyhow::Result<&'a [u8]> {
+        let len = hmac::HMAC_SHA256.digest_algorithm().output_len();
+        anyhow::ensure!(data.len() >= len, "Key list file is too short to be signed");
+        let (payload, tag) = data.split_at(data.len() - len);
+        anyhow::ensure!(
+            self.keys
+                .iter()
+                .any(|key| hmac::verify(key, payload, tag).is_ok()),
+            "Key list file signature matches none of the HMAC keys"
+        );
+        Ok(payload)
+    }
+}
+
+/// Writes `list` to `destination` in the current format, sorted by id and replacing the file
+/// atomically.
+///
+/// ```
+/// use cellardoor_core::{persistence, Credential, Key};
+/// use dashmap::DashMap;
+///
+/// let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+/// let path = std::env::temp_dir().join(format!("doctest-{}.bin", std::process::id()));
+/// let list = DashMap::from_iter([(alice, Key::named("Alice"))]);
+/// persistence::serialize_1w_devices(&list, &path)?;
+/// let loaded = persistence::deserialize_1w_devices(&path)?;
+/// assert_eq!(loaded.get(&alice).unwrap().name, "Alice");
+/// # std::fs::remove_file(path)?;
+/// # anyhow::Ok(())
+/// ```
+pub fn serialize_1w_devices(
+    list: &DashMap<Credential, Key>,
+    destination: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    serialize_1w_devices_with_backups(list, destination, 0, &Protection::default())
+}
+
+/// Like [`serialize_1w_devices`], but keeps the replaced file as `<destination>.1`, shifting
+/// older ones up to `<destination>.<backups>`, and signs and encrypts it as `protection` says.
+/// A file that already holds the list is left alone, so saving it again doesn't push a
+/// different generation out of the backups.
+pub fn serialize_1w_devices_with_backups(
+    list: &DashMap<Credential, Key>,
+    destination: impl AsRef<Path>,
+    backups: usize,
+    protection: &Protection,
+) -> anyhow::Result<()> {
+    let mut data = encode(list);
+    if let Some(signing) = &protection.signing {
+        signing.sign(&mut data);
+    }
+    if holds(destination.as_ref(), &data, protection.encryption.as_ref()) {
+        log::debug!("Key list {:?} is unchanged", destination.as_ref());
+        return Ok(());
+    }
+    if let Some(encryption) = &protection.encryption {
+        data = encryption.seal(&data)?;
+    }
+    write_rotating(destination, backups, |file| Ok(file.write_all(&data)?))
+}
+
+/// Whether `destination` holds `data` already, sealed with `encryption` if that is given. Every
+/// seal has a fresh nonce, so encrypted files are compared once opened.
+fn holds(destination: &Path, data: &[u8], encryption: Option<&Encryption>) -> bool {
+    let Ok(current) = std::fs::read(destination) else {
+        return false;
+    };
+    match encryption {
+        Some(encryption) => {
+            current.starts_with(ENCRYPTED_MAGIC)
+                && encryption.open(&current).is_ok_and(|plain| plain == data)
+        }
+        None => current == data,
+    }
+}
+
+/// Reads a key list written by [`serialize_1w_devices`] in any version, or a legacy bare list
+/// of ids.
+pub fn deserialize_1w_devices(
+    destination: impl AsRef<Path>,
+) -> anyhow::Result<DashMap<Credential, Key>> {
+    load_1w_devices(destination.as_ref(), &Protection::default())
+}
+
+/// Reads the key list at `destination`, or if that fails the newest of its `backups` that loads,
+/// see [`serialize_1w_devices_with_backups`]. With `protection.signing`, files without a valid
+/// signature are refused. The error is that of `destination` if none loads.
+pub fn deserialize_1w_devices_with_backups(
+    destination: impl AsRef<Path>,
+    backups: usize,
+    protection: &Protection,
+) -> anyhow::Result<DashMap<Credential, Key>> {
+    let destination = destination.as_ref();
+    let error = match load_1w_devices(destination, protection) {
+        Ok(list) => return Ok(list),
+        Err(e) => e,
+    };
+    for generation in 1..=backups {
+        let path = backup_path(destination, generation);
+        match load_1w_devices(&path, protection) {
+            Ok(list) => {
+                log::warn!(
+                    "Failed to load key list {destination:?} ({error:#}), using backup {path:?}"
+                );
+                return Ok(list);
+            }
+            Err(e) => log::debug!("Backup {path:?} doesn't load either: {e:#}"),
+        }
+    }
+    Err(error)
+}
+
+fn load_1w_devices(
+    path: &Path,
+    protection: &Protection,
+) -> anyhow::Result<DashMap<Credential, Key>> {
+    let mut data = std::fs::read(path)?;
+    if data.starts_with(ENCRYPTED_MAGIC) {
+        let encryption = protection.encryption.as_ref().context(
+            "Key list file is encrypted, but persistence.encryption_key_file is not set",
+        )?;
+        data = encryption.open(&data)?;
+    } else if protection.encryption.is_some() {
+        log::warn!("Key list {path:?} is not encrypted yet, it will be once saved again");
+    }
+    let Some(signing) = &protection.signing else {
+        return decode(&data);
+    };
+    match signing.verify(&data) {
+        Ok(payload) => decode(payload),
+        Err(e) => {
+            log::error!("Refusing key list {path:?}, it may have been tampered with: {e:#}");
+            Err(e)
+        }
+    }
+}
+
+/// Where the `generation`th newest backup of `destination` is kept, e.g. `keys.bin.1`.
+pub fn backup_path(destination: &Path, generation: usize) -> PathBuf {
+    let mut path = destination.as_os_str().to_owned();
+    path.push(format!(".{generation}"));
+    PathBuf::from(path)
+}
+
+fn encode(list: &DashMap<Credential, Key>) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    data.extend_from_slice(&(list.len() as u32).to_le_bytes());
+    // Sorted, so the same list always yields the same file.
+    let mut entries: Vec<_> = list.iter().collect();
+    entries.sort_by_key(|entry| *entry.key());
+    for entry in entries {
+        let name = truncate_name(&entry.name);
+        let expiry = entry.expiry.map_or(0, |expiry| {
+            expiry
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(1, |since| since.as_secs().max(1))
+        });
+        put_id(&mut data, entry.key());
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(&expiry.to_le_bytes());
+        let schedule = truncate_name(entry.schedule.as_deref().unwrap_or_default());
+        data.extend_from_slice(&(schedule.len() as u16).to_le_bytes());
+        data.extend_from_slice(schedule.as_bytes());
+        let group = truncate_name(entry.group.as_deref().unwrap_or_default());
+        data.extend_from_slice(&(group.len() as u16).to_le_bytes());
+        data.extend_from_slice(group.as_bytes());
+        let pin = truncate_name(entry.pin.as_deref().unwrap_or_default());
+        data.extend_from_slice(&(pin.len() as u16).to_le_bytes());
+        data.extend_from_slice(pin.as_bytes());
+    }
+    let crc = crc32(&data);
+    data.extend_from_slice(&crc.to_le_bytes());
+    data
+}
+
+fn decode(data: &[u8]) -> anyhow::Result<DashMap<Credential, Key>> {
+    let Some(mut payload) = data.strip_prefix(MAGIC) else {
+        // Every list written since the legacy format has the magic, so an empty file is one
+        // that got truncated rather than a legacy list without keys.
+        anyhow::ensure!(!data.is_empty(), "Key list file is empty");
+        anyhow::ensure!(
+            data.len().is_multiple_of(7),
+            "Key list file has no valid magic and is not a legacy list either ({} bytes)",
+            data.len()
+        );
+        return Ok(data
+            .chunks_exact(7)
+            .map(|id| (Credential::OneWire(id.try_into().unwrap()), Key::default()))
+            .collect());
+    };
+
+    let version = take(&mut payload, 1)?[0];
+    let count = match version {
+        1 => None,
+        2..=7 => {
+            payload = verify_checksum(data, MAGIC.len() + 1)?;
+            Some(u32::from_le_bytes(take(&mut payload, 4)?.try_into().unwrap()) as usize)
+        }
+        _ => anyhow::bail!("Unsupported key list version {version}"),
+    };
+
+    let map = DashMap::new();
+    while !payload.is_empty() {
+        let (id, key) = decode_record(&mut payload, version)?;
+        map.insert(id, key);
+    }
+    if let Some(count) = count {
+        anyhow::ensure!(
+            map.len() == count,
+            "Key list header announces {count} records but contains {}",
+            map.len()
+        );
+    }
+
+    Ok(map)
+}
+
+/// Takes one record of a key list in `version` off the front of `payload`.
+fn decode_record(payload: &mut &[u8], version: u8) -> anyhow::Result<(Credential, Key)> {
+    let id = match version {
+        7 => take_id(payload)?,
+        _ => Credential::OneWire(take(payload, 7)?.try_into().unwrap()),
+    };
+    let name_len = u16::from_le_bytes(take(payload, 2)?.try_into().unwrap());
+    let name = String::from_utf8_lossy(take(payload, name_len.into())?).into_owned();
+    let expiry = match version {
+        3..=7 => match u64::from_le_bytes(take(payload, 8)?.try_into().unwrap()) {
+            0 => None,
+            secs => Some(take_time(secs)?),
+        },
+        _ => None,
+    };
+    let schedule = match version {
+        4..=7 => take_label(payload)?,
+        _ => None,
+    };
+    let group = match version {
+        5..=7 => take_label(payload)?,
+        _ => None,
+    };
+    let pin = match version {
+        6 | 7 => take_label(payload)?,
+        _ => None,
+    };
+    let key = Key {
+        name,
+        expiry,
+        schedule,
+        group,
+        pin,
+    };
+    Ok((id, key))
+}
+
+/// What [`inspect_1w_devices`] made of a key list file, however damaged.
+#[derive(Debug, Default)]
+pub struct Inspection {
+    /// Size of the file as stored.
+    pub len: usize,
+    pub encrypted: bool,
+    /// Whether one of the HMAC keys produced the signature, `None` without signing keys.
+    pub signed: Option<bool>,
+    /// `None` for the legacy bare list of ids.
+    pub version: Option<u8>,
+    /// Whether the trailing CRC32 matched, `None` for versions without one.
+    pub checksum: Option<bool>,
+    /// The record count in the header, from version 2 on.
+    pub announced: Option<usize>,
+    /// The records in file order, up to the first that failed to decode.
+    pub keys: Vec<(Credential, Key)>,
+    /// Why decoding stopped, with the offset it stopped at in the decrypted file.
+    pub error: Option<(usize, String)>,
+}
+
+/// Decodes the key list at `path` as far as it goes, for `cellardoor inspect`. Unlike
+/// [`deserialize_1w_devices`] it keeps going past a checksum or signature mismatch and
+/// returns the records before a damaged one; only reading the file can fail.
+pub fn inspect_1w_devices(path: &Path, protection: &Protection) -> anyhow::Result<Inspection> {
+    let mut data = std::fs::read(path).context(format!("Failed to read {path:?}"))?;
+    let mut inspection = Inspection {
+        len: data.len(),
+        encrypted: data.starts_with(ENCRYPTED_MAGIC),
+        ..Default::default()
+    };
+    if inspection.encrypted {
+        let opened = match &protection.encryption {
+            Some(encryption) => encryption.open(&data),
+            None => Err(anyhow::anyhow!(
+                "File is encrypted, but no encryption key was given"
+            )),
+        };
+        match opened {
+            Ok(plaintext) => data = plaintext,
+            Err(e) => {
+                inspection.error = Some((0, format!("{e:#}")));
+                return Ok(inspection);
+            }
+        }
+    }
+    if let Some(signing) = &protection.signing {
+        let verified = signing.verify(&data).ok().map(<[u8]>::len);
+        inspection.signed = Some(verified.is_some());
+        // Strip the tag either way, it would pass for the checksum otherwise.
+        let tag_len = hmac::HMAC_SHA256.digest_algorithm().output_len();
+        data.truncate(verified.unwrap_or(data.len().saturating_sub(tag_len)));
+    }
+    inspect_decoded(&data, &mut inspection);
+    Ok(inspection)
+}
+
+fn inspect_decoded(data: &[u8], inspection: &mut Inspection) {
+    let Some(rest) = data.strip_prefix(MAGIC) else {
+        let whole = data.len() - data.len() % 7;
+        inspection.keys = data[..whole]
+            .chunks_exact(7)
+            .map(|id| (Credential::OneWire(id.try_into().unwrap()), Key::default()))
+            .collect();
+        if whole < data.len() {
+            let error = format!("{} bytes left over, not a whole id", data.len() - whole);
+            inspection.error = Some((whole, error));
+        }
+        return;
+    };
+    let Some((&version, rest)) = rest.split_first() else {
+        inspection.error = Some((MAGIC.len(), "File is truncated".to_owned()));
+        return;
+    };
+    inspection.version = Some(version);
+    let mut payload = match version {
+        1 => rest,
+        2..=7 => {
+            let header_len = MAGIC.len() + 1;
+            if data.len() < header_len + 8 {
+                inspection.error = Some((header_len, "File is truncated".to_owned()));
+                return;
+            }
+            inspection.checksum = Some(verify_checksum(data, header_len).is_ok());
+            let mut payload = &data[header_len..data.len() - 4];
+            let count = take(&mut payload, 4).unwrap();
+            inspection.announced = Some(u32::from_le_bytes(count.try_into().unwrap()) as usize);
+            payload
+        }
+        _ => {
+            let error = format!("Unsupported key list version {version}");
+            inspection.error = Some((MAGIC.len(), error));
+            return;
+        }
+    };
+    // Where the records end, before the checksum of those versions that have one.
+    let end = data.len() - inspection.checksum.map_or(0, |_| 4);
+    while !payload.is_empty() {
+        let offset = end - payload.len();
+        match decode_record(&mut payload, version) {
+            Ok(record) => inspection.keys.push(record),
+            Err(e) => {
+                let error = format!("record {}: {e:#}", inspection.keys.len() + 1);
+                inspection.error = Some((offset, error));
+                return;
+            }
+        }
+    }
+}
+
+pub fn serialize_last_seen(
+    seen: &DashMap<Credential, SystemTime>,
+    destination: impl AsRef<Path>,
+) -> anyhow::Result<()> {
+    let mut data = Vec::new();
+    data.extend_from_slice(LAST_SEEN_MAGIC);
+    data.push(LAST_SEEN_VERSION);
+    data.extend_from_slice(&(seen.len() as u32).to_le_bytes());
+    for entry in seen.iter() {
+        let secs = entry
+            .value()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        put_id(&mut data, entry.key());
+        data.extend_from_slice(&secs.to_le_bytes());
+    }
+    let crc = crc32(&data);
+    data.extend_from_slice(&crc.to_le_bytes());
+    write_atomically(destination, |file| Ok(file.write_all(&data)?))
+}
+
+pub fn deserialize_last_seen(
+    destination: impl AsRef<Path>,
+) -> anyhow::Result<DashMap<Credential, SystemTime>> {
+    let data = std::fs::read(destination)?;
+    let mut payload = data
+        .strip_prefix(LAST_SEEN_MAGIC)
+        .context("Last-seen file has no valid magic")?;
+    let version = take(&mut payload, 1)?[0];
+    anyhow::ensure!(
+        matches!(version, 1 | LAST_SEEN_VERSION),
+        "Unsupported last-seen version {version}"
+    );
+    payload = verify_checksum(&data, LAST_SEEN_MAGIC.len() + 1)?;
+    let count = u32::from_le_bytes(take(&mut payload, 4)?.try_into().unwrap()) as usize;
+
+    let map = DashMap::new();
+    while !payload.is_empty() {
+        let id = match version {
+            1 => Credential::OneWire(take(&mut payload, 7)?.try_into().unwrap()),
+            _ => take_id(&mut payload)?,
+        };
+        let secs = u64::from_le_bytes(take(&mut payload, 8)?.try_into().unwrap());
+        map.insert(id, take_time(secs)?);
+    }
+    anyhow::ensure!(
+        map.len() == count,
+        "Last-seen header announces {count} records but contains {}",
+        map.len()
+    );
+    Ok(map)
+}
+
+/// Appends `id` as its length and [`Credential::bytes`].
+fn put_id(data: &mut Vec<u8>, id: &Credential) {
+    let bytes = id.bytes();
+    data.push(bytes.len() as u8);
+    data.extend_from_slice(&bytes);
+}
+
+/// Takes an id written by [`put_id`] off the front of `payload`.
+fn take_id(payload: &mut &[u8]) -> anyhow::Result<Credential> {
+    let len = take(payload, 1)?[0];
+    Credential::from_bytes(take(payload, len.into())?)
+}
+
+/// Checks the trailing CRC32 of a file in one of the checksummed formats and returns what lies
+/// between the `header_len` leading bytes and the checksum.
+fn verify_checksum(data: &[u8], header_len: usize) -> anyhow::Result<&[u8]> {
+    anyhow::ensure!(data.len() >= header_len + 4, "File is truncated");
+    let (body, crc) = data.split_at(data.len() - 4);
+    let expected = u32::from_le_bytes(crc.try_into().unwrap());
+    let actual = crc32(body);
+    anyhow::ensure!(
+        actual == expected,
+        "File checksum mismatch (stored {expected:08x}, computed {actual:08x})"
+    );
+    Ok(&body[header_len..])
+}
+
+/// CRC-32 (IEEE 802.3, as used by zlib and PNG).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> anyhow::Result<&'a [u8]> {
+    anyhow::ensure!(data.len() >= len, "File is truncated");
+    let (head, tail) = data.split_at(len);
+    *data = tail;
+    Ok(head)
+}
+
+/// The time `secs` after the epoch, failing where a corrupt file would overflow it.
+fn take_time(secs: u64) -> anyhow::Result<SystemTime> {
+    SystemTime::UNIX_EPOCH
+        .checked_add(Duration::from_secs(secs))
+        .context(format!("Time {secs} is out of range"))
+}
+
+/// A length-prefixed label, `None` if empty.
+fn take_label(data: &mut &[u8]) -> anyhow::Result<Option<String>> {
+    let len = u16::from_le_bytes(take(data, 2)?.try_into().unwrap());
+    let label = String::from_utf8_lossy(take(data, len.into())?);
+    Ok(Some(label.into_owned()).filter(|label| !label.is_empty()))
+}
+
+fn truncate_name(name: &str) -> &str {
+    let mut len = name.len().min(u16::MAX.into());
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    &name[..len]
+}
+
+/// An exclusive `flock` on a file holding the owner's PID, released when dropped or when the
+/// owner dies, so a crash never leaves a stale lock behind. Only the PID may be stale then, and
+/// it is only read while the lock is held.
+pub struct Lock {
+    file: File,
+}
+
+impl Lock {
+    /// Locks `path`, creating it if missing, or fails right away naming the process holding it.
+    pub fn acquire(path: impl AsRef<Path>) -> anyhow::Result<Lock> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+            .context(format!("Failed to open lock file {path:?}"))?;
+        // SAFETY: `file` stays open for the duration of the call.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                let mut owner = String::new();
+                let _ = file.read_to_string(&mut owner);
+                match owner.trim().parse::<u32>() {
+                    Ok(pid) => anyhow::bail!("{path:?} is locked by cellardoor with PID {pid}"),
+                    Err(_) => anyhow::bail!("{path:?} is locked, is cellardoor running?"),
+                }
+            }
+            return Err(e).context(format!("Failed to lock {path:?}"));
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(Lock { file })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Closing the file then unlocks it.
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Records when the key list was last fetched from MOS.
+pub fn save_fetch_time(time: SystemTime, destination: impl AsRef<Path>) -> anyhow::Result<()> {
+    let time = humantime::format_rfc3339_seconds(time).to_string();
+    write_atomically(destination, |file| Ok(writeln!(file, "{time}")?))
+}
+
+/// The time of the last fetch, or `None` if none was recorded yet.
+pub fn load_fetch_time(destination: impl AsRef<Path>) -> anyhow::Result<Option<SystemTime>> {
+    let destination = destination.as_ref();
+    if !destination.exists() {
+        return Ok(None);
+    }
+    let time = std::fs::read_to_string(destination)?;
+    let time = humantime::parse_rfc3339(time.trim())
+        .context(format!("Invalid fetch time in {destination:?}"))?;
+    Ok(Some(time))
+}
+
+/// Replaces `destination` with whatever `write` produces, so that readers only ever see either
+/// the previous or the complete new contents: the data goes to a sibling temp file which is
+/// fsynced and then renamed over the destination, followed by an fsync of the directory.
+pub fn write_atomically(
+    destination: impl AsRef<Path>,
+    write: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    write_rotating(destination, 0, write)
+}
+
+/// [`write_atomically`], moving the replaced file to the first of `backups` generations once
+/// the new contents are safely on disk. The replaced file is hard linked as the newest backup
+/// before the new one is renamed over it, so a crash in between never leaves the destination
+/// missing.
+fn write_rotating(
+    destination: impl AsRef<Path>,
+    backups: usize,
+    write: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    // The refresh thread and the shutdown path may both save, keep them off each other's temp file.
+    static WRITE_LOCK: Mutex<()> = Mutex::new(());
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let destination = destination.as_ref();
+    let file_name = destination
+        .file_name()
+        .context(format!("{destination:?} is not a file path"))?;
+    let dir = match destination.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir).context(format!("Failed to create directory {dir:?}"))?;
+
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let tmp_path = dir.join(tmp_name);
+
+    let result = (|| {
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        write(&mut file)?;
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        if backups > 0 && destination.exists() {
+            for generation in (1..backups).rev() {
+                let from = backup_path(destination, generation);
+                if from.exists() {
+                    std::fs::rename(&from, backup_path(destination, generation + 1))?;
+                }
+            }
+            let newest = backup_path(destination, 1);
+            match std::fs::remove_file(&newest) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            std::fs::hard_link(destination, newest)?;
+        }
+        std::fs::rename(&tmp_path, destination)?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use dashmap::DashMap;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use crate::{testutil::test_dir, Credential, Key};
+
+    /// Cases tried by each property; a failure names the seed that reproduces it.
+    const CASES: u64 = 256;
+
+    /// A 1-Wire id, card or tag.
+    fn arbitrary_id(rng: &mut StdRng) -> Credential {
+        match rng.gen_range(0..3) {
+            0 => Credential::OneWire(rng.gen()),
+            1 => Credential::Wiegand(rng.gen()),
+            _ => {
+                let uid: [u8; 7] = rng.gen();
+                Credential::nfc(&uid[..if rng.gen_bool(0.5) { 4 } else { 7 }]).unwrap()
+            }
+        }
+    }
+
+    /// A key with any name and, half of the time, each of the other fields.
+    fn arbitrary_key(rng: &mut StdRng) -> Key {
+        let label = |rng: &mut StdRng| {
+            let len = rng.gen_range(0..12);
+            (rng.gen_bool(0.5) && len > 0).then(|| (0..len).map(|_| rng.gen::<char>()).collect())
+        };
+        Key {
+            name: (0..rng.gen_range(0..40))
+                .map(|_| rng.gen::<char>())
+                .collect(),
+            expiry: rng
+                .gen_bool(0.5)
+                .then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(rng.gen_range(1..1 << 40))),
+            schedule: label(rng),
+            group: label(rng),
+            pin: label(rng),
+        }
+    }
+
+    fn to_vec(list: &DashMap<crate::Credential, Key>) -> Vec<(crate::Credential, Key)> {
+        let mut ids: Vec<_> = list
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect();
+        ids.sort_by_key(|(id, _)| *id);
+        ids
+    }
+
+    #[test]
+    fn roundtrip_creates_parent_dirs_test() {
+        let dir = test_dir("roundtrip");
+        let path = dir.join("nested").join("keys.bin");
+        let list = DashMap::from_iter([
+            (
+                Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+                Key::named("Alice"),
+            ),
+            (Credential::OneWire([1, 2, 3, 4, 5, 6, 7]), Key::default()),
+            (
+                Credential::OneWire([1, 2, 3, 4, 5, 6, 8]),
+                Key {
+                    name: "Visitor".to_owned(),
+                    expiry: Some(
+                        std::time::SystemTime::UNIX_EPOCH
+                            + std::time::Duration::from_secs(1_700_000_000),
+                    ),
+                    schedule: Some("daytime".to_owned()),
+                    group: Some("member".to_owned()),
+                    pin: Some(
+                        "pbkdf2-sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk"
+                            .to_owned(),
+                    ),
+                },
+            ),
+        ]);
+
+        super::serialize_1w_devices(&list, &path).unwrap();
+        let loaded = super::deserialize_1w_devices(&path).unwrap();
+
+        assert_eq!(to_vec(&loaded), to_vec(&list));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_write_keeps_previous_file_test() {
+        let dir = test_dir("interrupted");
+        let path = dir.join("keys.bin");
+        let list = DashMap::from_iter([(
+            Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+            Key::named("Alice"),
+        )]);
+        super::serialize_1w_devices(&list, &path).unwrap();
+
+        let result = super::write_atomically(&path, |file| {
+            file.write_all(&[0x01, 0x02, 0x03])?;
+            anyhow::bail!("simulated crash mid-write");
+        });
+
+        assert!(result.is_err());
+        assert_eq!(
+            to_vec(&super::deserialize_1w_devices(&path).unwrap()),
+            to_vec(&list)
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backup_rotation_depth_test() {
+        let dir = test_dir("backup-rotation");
+        let path = dir.join("keys.bin");
+        for count in 1..=5 {
+            let list = DashMap::from_iter(
+                (0..count).map(|i| (Credential::OneWire([1, 0, 0, 0, 0, 0, i]), Key::default())),
+            );
+            super::serialize_1w_devices_with_backups(&list, &path, 3, &Default::default()).unwrap();
+        }
+
+        let len = |generation| {
+            super::deserialize_1w_devices(super::backup_path(&path, generation))
+                .unwrap()
+                .len()
+        };
+        assert_eq!(super::deserialize_1w_devices(&path).unwrap().len(), 5);
+        assert_eq!([len(1), len(2), len(3)], [4, 3, 2]);
+        assert!(!super::backup_path(&path, 4).exists());
+
+        // Saving the same list again keeps the backups as they are.
+        let list = DashMap::from_iter(
+            (0..5).map(|i| (Credential::OneWire([1, 0, 0, 0, 0, 0, i]), Key::default())),
+        );
+        super::serialize_1w_devices_with_backups(&list, &path, 3, &Default::default()).unwrap();
+        assert_eq!([len(1), len(2), len(3)], [4, 3, 2]);
+
+        // With a single generation, the newest backup is replaced each time.
+        let list =
+            DashMap::from_iter([(Credential::OneWire([1, 0, 0, 0, 0, 0, 9]), Key::default())]);
+        super::serialize_1w_devices_with_backups(&list, &path, 1, &Default::default()).unwrap();
+        assert_eq!(super::deserialize_1w_devices(&path).unwrap().len(), 1);
+        assert_eq!(len(1), 5);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_list_falls_back_to_backup_test() {
+        let dir = test_dir("backup-fallback");
+        let path = dir.join("keys.bin");
+        for name in ["Alice", "Bob", "Carol"] {
+            let list = DashMap::from_iter([(
+                Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+                Key::named(name),
+            )]);
+            super::serialize_1w_devices_with_backups(&list, &path, 3, &Default::default()).unwrap();
+        }
+        std::fs::write(&path, b"").unwrap();
+        std::fs::write(super::backup_path(&path, 1), b"CDKL\x04garbage").unwrap();
+
+        let loaded =
+            super::deserialize_1w_devices_with_backups(&path, 3, &Default::default()).unwrap();
+        assert_eq!(
+            to_vec(&loaded),
+            [(
+                Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+                Key::named("Alice")
+            )]
+        );
+        assert!(super::deserialize_1w_devices_with_backups(&path, 1, &Default::default()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn signed_list_rejects_tampering_test() {
+        let dir = test_dir("signed");
+        let path = dir.join("keys.bin");
+        let signing = |keys: &[&str]| {
+            let keys: Vec<_> = keys.iter().map(|key| key.as_bytes().to_vec()).collect();
+            super::Protection {
+                signing: Some(super::Signing::new(&keys).unwrap()),
+                encryption: None,
+            }
+        };
+        let load = |protection| super::deserialize_1w_devices_with_backups(&path, 0, &protection);
+        let list = DashMap::from_iter([(
+            Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+            Key::named("Alice"),
+        )]);
+        super::serialize_1w_devices_with_backups(&list, &path, 0, &signing(&["old"])).unwrap();
+
+        assert_eq!(to_vec(&load(signing(&["old"])).unwrap()), to_vec(&list));
+        // Verification accepts every key, so the old one works while the new one signs.
+        assert!(load(signing(&["new", "old"])).is_ok());
+        assert!(load(signing(&["new"])).is_err());
+        // Inspection sees past a wrong key, and the tag isn't taken for the checksum.
+        let inspection = super::inspect_1w_devices(&path, &signing(&["new"])).unwrap();
+        assert_eq!(inspection.signed, Some(false));
+        assert_eq!(
+            (inspection.checksum, inspection.keys.len()),
+            (Some(true), 1)
+        );
+
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7]);
+        std::fs::write(&path, &data).unwrap();
+        assert!(load(signing(&["old"])).is_err());
+
+        super::serialize_1w_devices(&list, &path).unwrap();
+        assert!(load(signing(&["old"])).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_list_test() {
+        let dir = test_dir("encrypted");
+        let path = dir.join("keys.bin");
+        let encryption = |key| super::Protection {
+            signing: None,
+            encryption: Some(super::Encryption::new(&[key; 32])),
+        };
+        let list = DashMap::from_iter([(
+            Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+            Key::named("Alice"),
+        )]);
+
+        // A plaintext list still loads, and is encrypted when saved again.
+        super::serialize_1w_devices(&list, &path).unwrap();
+        let loaded = super::deserialize_1w_devices_with_backups(&path, 0, &encryption(1)).unwrap();
+        assert_eq!(to_vec(&loaded), to_vec(&list));
+        super::serialize_1w_devices_with_backups(&loaded, &path, 0, &encryption(1)).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        assert!(data.starts_with(b"CDKE\x01"));
+        assert!(!data.windows(5).any(|window| window == b"Alice"));
+        super::serialize_1w_devices_with_backups(&loaded, &path, 0, &encryption(1)).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        let loaded = super::deserialize_1w_devices_with_backups(&path, 0, &encryption(1)).unwrap();
+        assert_eq!(to_vec(&loaded), to_vec(&list));
+
+        let message = super::deserialize_1w_devices_with_backups(&path, 0, &encryption(2))
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("decryption failed"), "{message}");
+        assert!(super::deserialize_1w_devices(&path).is_err());
+        let inspection = super::inspect_1w_devices(&path, &encryption(1)).unwrap();
+        assert!(inspection.encrypted && inspection.error.is_none());
+        assert_eq!(inspection.keys.len(), 1);
+        let inspection = super::inspect_1w_devices(&path, &Default::default()).unwrap();
+        assert_eq!(inspection.error.unwrap().0, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_format_migration_test() {
+        let dir = test_dir("legacy");
+        let path = dir.join("keys.bin");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            &path,
+            [0x33, 0, 0, 3, 0x92, 0xc6, 0xea, 1, 2, 3, 4, 5, 6, 7],
+        )
+        .unwrap();
+
+        let loaded = super::deserialize_1w_devices(&path).unwrap();
+        assert_eq!(
+            to_vec(&loaded),
+            [
+                (Credential::OneWire([1, 2, 3, 4, 5, 6, 7]), Key::default()),
+                (
+                    Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+                    Key::default()
+                ),
+            ]
+        );
+
+        super::serialize_1w_devices(&loaded, &path).unwrap();
+        assert!(std::fs::read(&path).unwrap().starts_with(super::MAGIC));
+        assert_eq!(
+            to_vec(&super::deserialize_1w_devices(&path).unwrap()),
+            to_vec(&loaded)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn crc32_test() {
+        assert_eq!(super::crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn version_1_still_loads_test() {
+        let mut data = b"CDKL\x01".to_vec();
+        data.extend_from_slice(&[0x33, 0, 0, 3, 0x92, 0xc6, 0xea, 5, 0]);
+        data.extend_from_slice(b"Alice");
+
+        let loaded = super::decode(&data).unwrap();
+        assert_eq!(
+            to_vec(&loaded),
+            [(
+                Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+                Key::named("Alice")
+            )]
+        );
+    }
+
+    #[test]
+    fn corruption_is_rejected_test() {
+        // Names chosen so the file isn't a multiple of 7 bytes long, which with the magic
+        // corrupted would pass for a legacy list.
+        let list = DashMap::from_iter([
+            (
+                Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+                Key::named("Alice"),
+            ),
+            (
+                Credential::OneWire([1, 2, 3, 4, 5, 6, 7]),
+                Key::named("Bobby"),
+            ),
+        ]);
+        let data = super::encode(&list);
+        assert_eq!(to_vec(&super::decode(&data).unwrap()), to_vec(&list));
+
+        for idx in 0..data.len() {
+            let mut corrupted = data.clone();
+            corrupted[idx] ^= 0x40;
+            assert!(super::decode(&corrupted).is_err(), "flipped byte {idx}");
+        }
+        for len in 0..data.len() {
+            if len == 0 || len % 7 != 0 {
+                assert!(super::decode(&data[..len]).is_err(), "truncated to {len}");
+            }
+        }
+
+        let message = super::decode(b"CDKL\x02garbage").unwrap_err().to_string();
+        assert!(message.contains("checksum"), "{message}");
+        let message = super::decode(b"not a list").unwrap_err().to_string();
+        assert!(message.contains("magic"), "{message}");
+    }
+
+    #[test]
+    fn arbitrary_lists_roundtrip_test() {
+        for seed in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let list: DashMap<_, _> = (0..rng.gen_range(0..64))
+                .map(|_| (arbitrary_id(&mut rng), arbitrary_key(&mut rng)))
+                .collect();
+            let decoded = super::decode(&super::encode(&list)).unwrap();
+            assert_eq!(to_vec(&decoded), to_vec(&list), "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn arbitrary_data_never_panics_test() {
+        for seed in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let bytes: Vec<u8> = (0..rng.gen_range(0..256)).map(|_| rng.gen()).collect();
+            let _ = super::decode(&bytes);
+
+            // Past the magic and a valid checksum, so the records themselves get decoded.
+            let mut data = super::MAGIC.to_vec();
+            data.push(rng.gen_range(1..=super::VERSION + 1));
+            data.extend_from_slice(&bytes);
+            let _ = super::decode(&data);
+            data.extend_from_slice(&super::crc32(&data).to_le_bytes());
+            let _ = super::decode(&data);
+
+            // A saved list with a byte changed and the checksum mended.
+            let list: DashMap<_, _> = (0..rng.gen_range(1..8))
+                .map(|_| (arbitrary_id(&mut rng), arbitrary_key(&mut rng)))
+                .collect();
+            let mut data = super::encode(&list);
+            data.truncate(data.len() - 4);
+            let idx = rng.gen_range(super::MAGIC.len() + 1..data.len());
+            data[idx] = rng.gen();
+            data.extend_from_slice(&super::crc32(&data).to_le_bytes());
+            let _ = super::decode(&data);
+        }
+
+        // An expiry past what SystemTime holds is an error, not an overflow.
+        let list = DashMap::from_iter([(
+            Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+            Key::named("A"),
+        )]);
+        let mut data = super::encode(&list);
+        data.truncate(data.len() - 4);
+        let expiry = super::MAGIC.len() + 1 + 4 + 1 + 7 + 2 + 1;
+        data[expiry..expiry + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        data.extend_from_slice(&super::crc32(&data).to_le_bytes());
+        let message = super::decode(&data).unwrap_err().to_string();
+        assert!(message.contains("out of range"), "{message}");
+    }
+
+    #[test]
+    fn last_seen_roundtrip_test() {
+        let dir = test_dir("last-seen");
+        let path = dir.join("last_seen.bin");
+        let seen = DashMap::from_iter([(
+            Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000),
+        )]);
+
+        super::serialize_last_seen(&seen, &path).unwrap();
+        let loaded = super::deserialize_last_seen(&path).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(
+            *loaded
+                .get(&Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]))
+                .unwrap(),
+            *seen
+                .get(&Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]))
+                .unwrap()
+        );
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[10] ^= 1;
+        std::fs::write(&path, data).unwrap();
+        assert!(super::deserialize_last_seen(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lock_test() {
+        let dir = test_dir("lock");
+        let path = dir.join("keys.lock");
+        let lock = super::Lock::acquire(&path).unwrap();
+        let message = super::Lock::acquire(&path).err().unwrap().to_string();
+        let pid = std::process::id();
+        assert!(
+            message.ends_with(&format!("locked by cellardoor with PID {pid}")),
+            "{message}"
+        );
+        drop(lock);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        // A PID left behind by a crash doesn't keep the lock.
+        std::fs::write(&path, "999999\n").unwrap();
+        let _lock = super::Lock::acquire(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{pid}\n"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
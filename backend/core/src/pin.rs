@@ -0,0 +1,134 @@
+//! Salted PIN hashes, as they appear in the config and the key list:
+//! `pbkdf2-sha256$<iterations>$<salt>$<hash>` with salt and hash in unpadded base64.
+
+use std::num::NonZeroU32;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use ring::{
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+
+const SCHEME: &str = "pbkdf2-sha256";
+/// Enough to slow down guessing from a leaked config without making a Pi wait long at the door.
+const ITERATIONS: u32 = 20_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// Hashes `pin` with a fresh random salt.
+pub fn hash(pin: &str) -> anyhow::Result<String> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new()
+        .fill(&mut salt)
+        .ok()
+        .context("Failed to generate a salt")?;
+    Ok(hash_with_salt(
+        pin,
+        &salt,
+        NonZeroU32::new(ITERATIONS).unwrap(),
+    ))
+}
+
+fn hash_with_salt(pin: &str, salt: &[u8], iterations: NonZeroU32) -> String {
+    let mut hash = [0; HASH_LEN];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        pin.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "{SCHEME}${iterations}${}${}",
+        STANDARD_NO_PAD.encode(salt),
+        STANDARD_NO_PAD.encode(hash)
+    )
+}
+
+/// A parsed PIN hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PinHash {
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PinHash {
+    pub fn parse(encoded: &str) -> anyhow::Result<PinHash> {
+        let mut parts = encoded.trim().split('$');
+        anyhow::ensure!(
+            parts.next() == Some(SCHEME),
+            "PIN hash doesn't start with {SCHEME}$"
+        );
+        let (Some(iterations), Some(salt), Some(hash), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("PIN hash needs iterations, salt and hash");
+        };
+        let iterations = iterations
+            .parse()
+            .context("PIN hash has invalid iterations")?;
+        let salt = STANDARD_NO_PAD
+            .decode(salt)
+            .context("PIN hash has an invalid salt")?;
+        let hash = STANDARD_NO_PAD
+            .decode(hash)
+            .context("PIN hash has an invalid hash")?;
+        anyhow::ensure!(hash.len() == HASH_LEN, "PIN hash has the wrong length");
+        Ok(PinHash {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    /// Whether `pin` is the PIN hashed, compared in constant time.
+    pub fn verify(&self, pin: &str) -> bool {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            self.iterations,
+            &self.salt,
+            pin.as_bytes(),
+            &self.hash,
+        )
+        .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZeroU32;
+
+    use super::{hash, hash_with_salt, PinHash};
+
+    #[test]
+    fn pin_hash_test() {
+        let encoded = hash("1234").unwrap();
+        let hash = PinHash::parse(&encoded).unwrap();
+        assert!(hash.verify("1234"));
+        assert!(!hash.verify("1235"));
+        assert!(!hash.verify(""));
+        // Salted, so the same PIN never hashes the same twice.
+        assert_ne!(encoded, super::hash("1234").unwrap());
+
+        let fixed = hash_with_salt("0000", b"salt", NonZeroU32::new(1).unwrap());
+        assert_eq!(
+            fixed,
+            "pbkdf2-sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk"
+        );
+        assert!(PinHash::parse(&fixed).unwrap().verify("0000"));
+
+        for bad in [
+            "",
+            "1234",
+            "sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk",
+            "pbkdf2-sha256$0$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk",
+            "pbkdf2-sha256$1$c2FsdA",
+            "pbkdf2-sha256$1$c2FsdA$c2FsdA",
+            "pbkdf2-sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk$",
+        ] {
+            assert!(PinHash::parse(bad).is_err(), "{bad:?}");
+        }
+    }
+}
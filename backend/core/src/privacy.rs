@@ -0,0 +1,144 @@
+use std::sync::OnceLock;
+
+use ring::hmac;
+
+use crate::{config, Credential};
+
+static PRIVACY: OnceLock<Privacy> = OnceLock::new();
+
+/// How key ids appear in logs, the audit log and the event stream.
+pub struct Privacy {
+    mode: config::PrivacyMode,
+    /// Keyed with the site secret, so hashes can't be reversed by hashing all possible ids.
+    key: Option<hmac::Key>,
+}
+
+/// Sets the process-wide privacy settings; until then ids are logged in full.
+pub fn init(config: &config::Privacy) -> anyhow::Result<()> {
+    let privacy = Privacy::new(config.mode, config.secret()?.as_deref());
+    if PRIVACY.set(privacy).is_err() {
+        anyhow::bail!("Privacy settings already initialized");
+    }
+    Ok(())
+}
+
+fn current() -> &'static Privacy {
+    static FULL: Privacy = Privacy {
+        mode: config::PrivacyMode::Full,
+        key: None,
+    };
+    PRIVACY.get().unwrap_or(&FULL)
+}
+
+/// `id` as it should appear in a log line.
+pub fn id(id: &Credential) -> String {
+    current().id(id)
+}
+
+/// `id` in the audit log's hex format, unless masked.
+pub fn audit_id(id: &Credential) -> String {
+    current().audit_id(id)
+}
+
+/// A device sysname or id string from the bus or MOS, masked like the id it names.
+pub fn raw(raw: &str) -> String {
+    current().raw(raw)
+}
+
+/// A stable pseudonym for `id`, for outputs that never carry serials.
+pub fn hash(id: &Credential) -> String {
+    current().hash(id)
+}
+
+impl Privacy {
+    fn new(mode: config::PrivacyMode, secret: Option<&str>) -> Privacy {
+        Privacy {
+            mode,
+            key: secret.map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())),
+        }
+    }
+
+    fn id(&self, id: &Credential) -> String {
+        self.mask(id).unwrap_or_else(|| id.to_string())
+    }
+
+    fn audit_id(&self, id: &Credential) -> String {
+        self.mask(id).unwrap_or_else(|| id.hex())
+    }
+
+    fn raw(&self, raw: &str) -> String {
+        if self.mode == config::PrivacyMode::Full {
+            return raw.to_owned();
+        }
+        match Credential::parse(raw) {
+            Ok(id) => self.id(&id),
+            Err(_) => self.mask_bytes(raw.as_bytes()).unwrap_or_default(),
+        }
+    }
+
+    fn hash(&self, id: &Credential) -> String {
+        self.hash_bytes(&id.bytes())
+    }
+
+    /// The first 8 hex digits of the HMAC, or of a plain SHA-256 without a site secret.
+    fn hash_bytes(&self, bytes: &[u8]) -> String {
+        let tag = match &self.key {
+            Some(key) => hmac::sign(key, bytes).as_ref().to_vec(),
+            None => ring::digest::digest(&ring::digest::SHA256, bytes)
+                .as_ref()
+                .to_vec(),
+        };
+        tag[..4].iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn mask(&self, id: &Credential) -> Option<String> {
+        self.mask_bytes(&id.bytes())
+    }
+
+    fn mask_bytes(&self, bytes: &[u8]) -> Option<String> {
+        match self.mode {
+            config::PrivacyMode::Full => None,
+            config::PrivacyMode::Hashed => Some(self.hash_bytes(bytes)),
+            config::PrivacyMode::Redacted => Some("<redacted>".to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Privacy;
+    use crate::{config::PrivacyMode, Credential};
+
+    const KEY: Credential = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+
+    #[test]
+    fn hashed_ids_are_stable_per_site_test() {
+        let site = Privacy::new(PrivacyMode::Hashed, Some("site secret"));
+        let hashed = site.id(&KEY);
+        assert_eq!(hashed.len(), 8);
+        assert_eq!(site.id(&KEY), hashed);
+        assert_eq!(site.audit_id(&KEY), hashed);
+        assert_eq!(site.raw("33-00000392c6ea"), hashed);
+        assert_ne!(
+            site.id(&Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42])),
+            hashed
+        );
+        assert_eq!(
+            site.raw("wiegand:1234567"),
+            site.id(&Credential::Wiegand(1234567))
+        );
+        assert_ne!(
+            Privacy::new(PrivacyMode::Hashed, Some("other site")).id(&KEY),
+            hashed
+        );
+
+        let full = Privacy::new(PrivacyMode::Full, None);
+        assert_eq!(full.id(&KEY), "33-00000392c6ea");
+        assert_eq!(full.audit_id(&KEY), "3300000392c6ea");
+        assert_eq!(full.raw("33-garbage"), "33-garbage");
+
+        let redacted = Privacy::new(PrivacyMode::Redacted, None);
+        assert_eq!(redacted.id(&KEY), "<redacted>");
+        assert_eq!(redacted.raw("33-garbage"), "<redacted>");
+    }
+}
@@ -0,0 +1,180 @@
+//! Weekly access windows, such as `Mon-Fri 08:00-20:00`, that scheduled keys are limited to.
+
+use std::fmt;
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A time span on some days of the week, in local wall-clock time. A span ending before it
+/// starts runs past midnight into the following day.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Window {
+    /// Indexed from Monday.
+    days: [bool; 7],
+    /// Minutes since midnight; `end` may be 1440 for `24:00`.
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    /// Parses `<days> <HH:MM>-<HH:MM>`, where days are a comma-separated list of days like
+    /// `Sat` and ranges like `Mon-Fri` or `Fri-Mon`.
+    ///
+    /// ```
+    /// use cellardoor_core::schedule::Window;
+    ///
+    /// assert!(Window::parse("Mon-Fri 08:00-20:00").is_ok());
+    /// assert!(Window::parse("Fri,Sat 22:00-02:00").is_ok());
+    /// assert!(Window::parse("Mon 08:00").is_err());
+    /// ```
+    pub fn parse(window: &str) -> anyhow::Result<Window> {
+        let (days, span) = window
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow::anyhow!("expected \"<days> <HH:MM>-<HH:MM>\""))?;
+        let (start, end) = span
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("expected a time span like 08:00-20:00"))?;
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        anyhow::ensure!(start < MINUTES_PER_DAY, "a window can't start at 24:00");
+        anyhow::ensure!(start != end, "a window must not be empty");
+        let mut selected = [false; 7];
+        for part in days.split(',') {
+            match part.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (parse_day(first)?, parse_day(last)?);
+                    let len = (last + 7 - first) % 7;
+                    for offset in 0..=len {
+                        selected[(first + offset) % 7] = true;
+                    }
+                }
+                None => selected[parse_day(part)?] = true,
+            }
+        }
+        Ok(Window {
+            days: selected,
+            start,
+            end,
+        })
+    }
+
+    fn contains(&self, day: usize, minute: u32) -> bool {
+        let yesterday = (day + 6) % 7;
+        if self.start < self.end {
+            self.days[day] && (self.start..self.end).contains(&minute)
+        } else {
+            (self.days[day] && minute >= self.start) || (self.days[yesterday] && minute < self.end)
+        }
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = anyhow::Error;
+
+    fn try_from(window: String) -> anyhow::Result<Window> {
+        Window::parse(&window).map_err(|e| anyhow::anyhow!("invalid window {window:?}: {e}"))
+    }
+}
+
+impl fmt::Debug for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days: Vec<_> = DAYS
+            .iter()
+            .zip(self.days)
+            .filter_map(|(name, selected)| selected.then_some(*name))
+            .collect();
+        write!(
+            f,
+            "\"{} {:02}:{:02}-{:02}:{:02}\"",
+            days.join(","),
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Whether the local wall-clock time `at` falls into any of the windows of `schedule`.
+///
+/// Wall-clock time makes DST transitions behave as the windows read: `08:00-20:00` starts at
+/// 08:00 on the day clocks change, too, and a window in a skipped hour is simply not reached.
+pub fn is_within_schedule(schedule: &[Window], at: NaiveDateTime) -> bool {
+    let day = at.weekday().num_days_from_monday() as usize;
+    let minute = at.hour() * 60 + at.minute();
+    schedule.iter().any(|window| window.contains(day, minute))
+}
+
+fn parse_day(day: &str) -> anyhow::Result<usize> {
+    let day = day.trim();
+    DAYS.iter()
+        .position(|name| name.eq_ignore_ascii_case(day))
+        .ok_or_else(|| anyhow::anyhow!("unknown day {day:?}, expected one of {DAYS:?}"))
+}
+
+fn parse_time(time: &str) -> anyhow::Result<u32> {
+    let time = time.trim();
+    let parsed = time.split_once(':').and_then(|(hours, minutes)| {
+        let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+        (minutes < 60 && (hours < 24 || (hours, minutes) == (24, 0)))
+            .then_some(hours * 60 + minutes)
+    });
+    parsed.ok_or_else(|| anyhow::anyhow!("invalid time {time:?}, expected HH:MM"))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::NaiveDate;
+
+    use super::{is_within_schedule, Window};
+
+    fn at(day: u32, time: &str) -> chrono::NaiveDateTime {
+        // 2024-06-03 was a Monday.
+        let (hours, minutes) = time.split_once(':').unwrap();
+        NaiveDate::from_ymd_opt(2024, 6, day)
+            .unwrap()
+            .and_hms_opt(hours.parse().unwrap(), minutes.parse().unwrap(), 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn schedule_windows_test() {
+        let daytime = [Window::parse("Mon-Fri 08:00-20:00").unwrap()];
+        assert!(is_within_schedule(&daytime, at(3, "08:00")));
+        assert!(is_within_schedule(&daytime, at(7, "19:59")));
+        assert!(!is_within_schedule(&daytime, at(7, "20:00")));
+        assert!(!is_within_schedule(&daytime, at(8, "12:00")));
+
+        // Past midnight the window belongs to the day it started on.
+        let nights = [
+            Window::parse("Fri,Sat 22:00-02:00").unwrap(),
+            Window::parse("sun 10:00-24:00").unwrap(),
+        ];
+        assert!(is_within_schedule(&nights, at(7, "23:30")));
+        assert!(is_within_schedule(&nights, at(8, "01:59")));
+        assert!(is_within_schedule(&nights, at(9, "01:00")));
+        assert!(!is_within_schedule(&nights, at(10, "01:00")));
+        assert!(is_within_schedule(&nights, at(9, "23:59")));
+        assert!(!is_within_schedule(&nights, at(7, "02:00")));
+
+        // Ranges may wrap around the week.
+        let weekend = [Window::parse("Sat-Mon 00:00-24:00").unwrap()];
+        assert!(is_within_schedule(&weekend, at(3, "12:00")));
+        assert!(!is_within_schedule(&weekend, at(4, "12:00")));
+
+        for invalid in [
+            "Mon",
+            "Mon 8-20",
+            "Mon 08:00-08:00",
+            "Xyz 08:00-20:00",
+            "Mon 24:00-02:00",
+        ] {
+            assert!(Window::parse(invalid).is_err(), "{invalid}");
+        }
+        assert_eq!(format!("{:?}", nights[0]), "\"Fri,Sat 22:00-02:00\"");
+    }
+}
@@ -0,0 +1,9 @@
+use std::path::PathBuf;
+
+/// Returns an empty scratch directory unique to this test process and `name`.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cellardoor-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(id) = std::str::from_utf8(data) {
+        let _ = cellardoor_core::parse_1w_id(id);
+    }
+});
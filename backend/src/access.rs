@@ -0,0 +1,1523 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use dashmap::DashMap;
+
+use crate::{
+    audit::{AuditLog, Crc, Decision},
+    auto_unlock::AutoUnlock,
+    clock::Clock,
+    config, crc8,
+    debounce::Debounce,
+    denials::Denials,
+    door::Door,
+    enroll::Enroller,
+    events::EventLog,
+    feedback::{self, Feedback},
+    guests::Guests,
+    hooks::Hooks,
+    keypad::Press,
+    last_seen::LastSeen,
+    lockdown::Lockdown,
+    metrics::Metrics,
+    mqtt::Mqtt,
+    parse_1w_id,
+    passback::Passback,
+    pins::{Entry, Gate, Pins},
+    presence::Presence,
+    privacy,
+    ratelimit::RateLimit,
+    reader_health::ReaderHealth,
+    schedule::{is_within_schedule, Window},
+    sha_auth::{Authenticator, Verdict, DS1961S_FAMILY},
+    sightings::Sightings,
+    staleness::{Level, Staleness},
+    store::KeyStore,
+    usage::Usage,
+    wiegand, Credential, Key, OneWireId,
+};
+
+/// What a reader reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReaderKind {
+    /// 1-Wire devices showing up on a w1 bus.
+    W1,
+    /// Wiegand cards, see [`Access::handle_card`].
+    Wiegand,
+    /// NFC tags, see [`Access::handle_tag`].
+    Nfc,
+}
+
+/// A key reader and the door it opens.
+pub struct Reader {
+    /// `None` for the implicit reader of configs without `readers`.
+    pub name: Option<String>,
+    pub door: Door,
+    /// When non-empty, only these keys and master keys open `door`.
+    pub restrict_to: HashSet<Credential>,
+    /// When non-empty, only keys in these groups and master keys open `door`.
+    pub allow_groups: HashSet<String>,
+    /// Only a w1 reader takes w1 devices.
+    pub kind: ReaderKind,
+    bus_master: Option<String>,
+    parent: Option<String>,
+}
+
+impl Reader {
+    /// Whether a device whose ancestors' sysnames are `ancestors`, nearest first, belongs here.
+    fn matches(&self, ancestors: &[String]) -> bool {
+        self.kind == ReaderKind::W1
+            && self
+                .bus_master
+                .as_ref()
+                .is_none_or(|bus_master| ancestors.first() == Some(bus_master))
+            && self
+                .parent
+                .as_ref()
+                .is_none_or(|parent| ancestors.contains(parent))
+    }
+
+    /// Whether a listed key `id` in `group` may open this reader's door; master keys always may.
+    fn permits(&self, id: &Credential, group: Option<&str>) -> bool {
+        (self.restrict_to.is_empty() || self.restrict_to.contains(id))
+            && (self.allow_groups.is_empty()
+                || group.is_some_and(|group| self.allow_groups.contains(group)))
+    }
+
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+
+    /// A reader taking every device, with a door that isn't connected to anything.
+    #[cfg(test)]
+    pub fn unconnected(name: Option<&str>) -> Reader {
+        Reader {
+            name: name.map(str::to_owned),
+            door: Door::unconnected(),
+            restrict_to: HashSet::new(),
+            allow_groups: HashSet::new(),
+            kind: ReaderKind::W1,
+            bus_master: None,
+            parent: None,
+        }
+    }
+}
+
+/// Creates the configured readers, or the implicit one; readers sharing a door config share the
+/// GPIO line.
+pub fn readers(
+    configs: &[config::NamedReader],
+    default_door: &config::Door,
+    dry_run: bool,
+) -> anyhow::Result<Vec<Reader>> {
+    if configs.is_empty() {
+        return Ok(vec![Reader {
+            name: None,
+            door: Door::new(default_door, dry_run)?,
+            restrict_to: HashSet::new(),
+            allow_groups: HashSet::new(),
+            kind: ReaderKind::W1,
+            bus_master: None,
+            parent: None,
+        }]);
+    }
+    let mut doors: Vec<(&config::Door, Door)> = Vec::new();
+    let mut readers = Vec::new();
+    for reader in configs {
+        let door_config = reader.door.as_ref().unwrap_or(default_door);
+        let door = match doors.iter().find(|(config, _)| *config == door_config) {
+            Some((_, door)) => door.clone(),
+            None => {
+                let door = Door::new(door_config, dry_run)?;
+                doors.push((door_config, door.clone()));
+                door
+            }
+        };
+        readers.push(Reader {
+            name: Some(reader.name.clone()),
+            door,
+            restrict_to: reader.restrict_to.clone(),
+            allow_groups: reader.allow_groups.clone(),
+            kind: match (&reader.wiegand, &reader.nfc) {
+                (Some(_), _) => ReaderKind::Wiegand,
+                (None, Some(_)) => ReaderKind::Nfc,
+                (None, None) => ReaderKind::W1,
+            },
+            bus_master: reader.bus_master.clone(),
+            parent: reader.parent.clone(),
+        });
+    }
+    Ok(readers)
+}
+
+/// Why [`Access::remote_open`] left the door shut.
+#[derive(Debug, PartialEq, Eq)]
+pub enum OpenRefusal {
+    Lockdown,
+    /// `open_rate_limit` is used up for this long.
+    RateLimited(Duration),
+}
+
+/// The grant/deny decision shared by every source of key presentations.
+pub struct Access {
+    pub access_list: Arc<DashMap<Credential, Key>>,
+    /// Keys from the config that are honoured regardless of `access_list`.
+    pub master_keys: RwLock<HashSet<Credential>>,
+    /// Keys from the config that are refused even if they are listed or master keys.
+    pub deny_keys: RwLock<HashSet<Credential>>,
+    /// Device families that are considered keys at all; empty accepts every device.
+    pub allowed_family_codes: RwLock<HashSet<u8>>,
+    /// Never empty; the first one is the door opened by the control interface by default.
+    pub readers: Vec<Reader>,
+    pub metrics: Arc<Metrics>,
+    pub audit: Arc<AuditLog>,
+    pub last_seen: Arc<LastSeen>,
+    pub debounce: Mutex<Debounce>,
+    pub denials: Mutex<Denials>,
+    /// Keys granted within `anti_passback`.
+    pub passback: Mutex<Passback>,
+    pub hooks: Arc<Hooks>,
+    pub mqtt: Arc<Mqtt>,
+    /// Set remotely to refuse every key until cleared.
+    pub lockdown: Lockdown,
+    pub auto_unlock: AutoUnlock,
+    pub enroller: Arc<Enroller>,
+    /// The second factor of keys with a PIN.
+    pub pins: Pins,
+    /// Keys lent out at runtime, apart from `access_list`.
+    pub guests: Guests,
+    /// Counts grants for `cellardoor stats` and `GET /stats`.
+    pub usage: Usage,
+    /// Keys holding doors unlocked by staying on their reader.
+    pub presence: Presence,
+    /// When the udev monitor last heard from the bus, for `reader.watchdog`.
+    pub reader_health: ReaderHealth,
+    /// Limits opens from the API, the control socket and MQTT.
+    pub open_limit: RateLimit,
+    /// Unknown keys, for entering them into MOS.
+    pub sightings: Sightings,
+    pub staleness: RwLock<Staleness>,
+    /// Until it is trusted, expiry, schedules and staleness are left aside.
+    pub clock: Arc<Clock>,
+    /// LEDs and buzzer showing the outcome of presentations.
+    pub feedback: Feedback,
+    /// `schedules` from the config.
+    pub schedules: RwLock<BTreeMap<String, Vec<Window>>>,
+    pub events: Arc<EventLog>,
+    /// Where the kernel exposes each device's ROM code as `<sysname>/id`.
+    pub w1_devices: PathBuf,
+    /// Set in challenge mode, see [`config::AuthMode`].
+    pub authenticator: Option<Authenticator>,
+    pub store: Arc<dyn KeyStore>,
+    /// Nothing is actuated or persisted, see `--dry-run`.
+    pub dry_run: bool,
+}
+
+impl Access {
+    /// A w1 device left the bus, see `reader.udev.events`. It is only reported; keys are decided
+    /// when they arrive.
+    pub fn key_departed(&self, sysname: &str, ancestors: &[String]) {
+        let id = match parse_1w_id(sysname).map(Credential::OneWire) {
+            Ok(id) if family_allowed(&self.allowed_family_codes.read().unwrap(), &id) => id,
+            _ => {
+                log::debug!("Ignoring the removal of device {:?}", privacy::raw(sysname));
+                return;
+            }
+        };
+        // Udev has usually forgotten the parents of a removed device, leaving only readers
+        // without matches to attribute it to.
+        let reader = self.readers.iter().find(|reader| reader.matches(ancestors));
+        log::debug!(
+            "Key {} departed from reader {}",
+            privacy::id(&id),
+            reader.map_or("-", |reader| reader.label())
+        );
+        self.events
+            .departed(&id, reader.and_then(|reader| reader.name.as_deref()));
+    }
+
+    /// Evaluates a w1 device that appeared on the bus and opens its reader's door for known keys.
+    ///
+    /// `ancestors` are the sysnames of the device's parents, nearest first, which attribute it to
+    /// a reader.
+    pub fn handle_device(&self, sysname: &str, ancestors: &[String]) {
+        let Some(reader) = self.readers.iter().find(|reader| reader.matches(ancestors)) else {
+            log::warn!(
+                "Ignoring device {:?} on {ancestors:?}, which no reader matches",
+                privacy::raw(sysname)
+            );
+            return;
+        };
+        log::debug!(
+            "device recognized: {:?} at reader {}",
+            privacy::raw(sysname),
+            reader.label()
+        );
+        let reader_name = reader.name.as_deref();
+        match parse_1w_id(sysname) {
+            Ok(id) if !family_allowed(&self.allowed_family_codes.read().unwrap(), &id.into()) => {
+                log::debug!(
+                    "Ignoring device {} of family {:02x}",
+                    privacy::id(&id.into()),
+                    id[0]
+                );
+            }
+            Ok(id) => self.handle_key(sysname, id.into(), self.check_crc(sysname, &id), reader),
+            Err(e) => {
+                log::warn!("Failed to parse device id: {e:?}");
+                self.metrics.unparsable.inc();
+                self.audit.record(
+                    None,
+                    Decision::ParseError,
+                    Crc::Unchecked,
+                    reader_name,
+                    None,
+                );
+                self.events
+                    .access(None, "", reader_name, Decision::ParseError);
+            }
+        }
+    }
+
+    /// Evaluates a frame read by the Wiegand `reader`, dropping it if it isn't a valid card.
+    pub fn handle_card(&self, frame: &[bool], reader: &Reader) {
+        match wiegand::decode(frame) {
+            Ok(card) => {
+                let id = Credential::Wiegand(card);
+                log::debug!(
+                    "card recognized: {} at reader {}",
+                    privacy::id(&id),
+                    reader.label()
+                );
+                // Parity took the place of the CRC.
+                self.handle_key(&id.to_string(), id, Crc::Valid, reader);
+            }
+            Err(e) => {
+                log::warn!("Dropping Wiegand frame at reader {}: {e}", reader.label());
+                self.metrics.wiegand_bad_frames.inc();
+            }
+        }
+    }
+
+    /// Evaluates the UID of a tag read by the NFC `reader`.
+    pub fn handle_tag(&self, uid: &[u8], reader: &Reader) {
+        let Some(id) = Credential::nfc(uid) else {
+            log::warn!(
+                "Ignoring {} byte NFC UID at reader {}",
+                uid.len(),
+                reader.label()
+            );
+            return;
+        };
+        log::debug!(
+            "tag recognized: {} at reader {}",
+            privacy::id(&id),
+            reader.label()
+        );
+        // The reader checked the UID's BCC and the frame's CRC.
+        self.handle_key(&id.to_string(), id, Crc::Valid, reader);
+    }
+
+    fn handle_key(&self, sysname: &str, id: Credential, crc: Crc, reader: &Reader) {
+        let reader_name = reader.name.as_deref();
+        // Checked before debouncing, so a glitch doesn't suppress the next proper read.
+        if crc == Crc::Invalid {
+            log::warn!("Ignoring key {} with a bad CRC", privacy::id(&id));
+            self.metrics.bad_crc.inc();
+            self.audit
+                .record(Some(&id), Decision::BadCrc, crc, reader_name, None);
+            return;
+        }
+        let presented = Instant::now();
+        if !self.debounce.lock().unwrap().accept(&id, presented) {
+            log::trace!("Ignoring repeated sighting of {}", privacy::id(&id));
+            return;
+        }
+        let group = self.access_list.get(&id).and_then(|key| key.group.clone());
+        let decision = match self.decide(&id) {
+            Decision::Granted | Decision::GrantedGuest
+                if !reader.permits(&id, group.as_deref()) =>
+            {
+                log::info!(
+                    "Key {} in group {:?} is not permitted at reader {}",
+                    privacy::id(&id),
+                    group.as_deref().unwrap_or_default(),
+                    reader.label()
+                );
+                Decision::Restricted
+            }
+            Decision::Denied if self.enroller.is_active(Instant::now()) => {
+                log::info!(
+                    "Unknown key {} presented during enrollment",
+                    privacy::id(&id)
+                );
+                self.enroller.offer(&id, Instant::now());
+                Decision::Enrolled
+            }
+            Decision::Denied => {
+                self.sightings.record(&id, SystemTime::now());
+                Decision::Denied
+            }
+            decision if decision.is_granted() => self.authenticate(sysname, &id, decision),
+            decision => decision,
+        };
+        let decision = match decision {
+            decision @ (Decision::Granted | Decision::GrantedGuest) => {
+                self.check_passback(&id, decision)
+            }
+            decision => decision,
+        };
+        let decision = match decision {
+            decision if decision.is_granted() => self.require_pin(&id, decision, crc, reader),
+            decision => decision,
+        };
+        self.metrics.decision_seconds.observe(presented.elapsed());
+        self.conclude(&id, decision, crc, reader);
+    }
+
+    /// Records a decision about the key `id` at `reader` everywhere and opens the door if it is
+    /// a grant.
+    fn conclude(&self, id: &Credential, decision: Decision, crc: Crc, reader: &Reader) {
+        let id = *id;
+        // Opened before anything is recorded, none of which is worth a slower door.
+        if decision.is_granted() {
+            reader.door.unlock();
+        }
+        let reader_name = reader.name.as_deref();
+        let group = self.access_list.get(&id).and_then(|key| key.group.clone());
+        self.audit
+            .record(Some(&id), decision, crc, reader_name, group.as_deref());
+        if let Err(e) = self
+            .store
+            .append_event(SystemTime::now(), Some(&id), decision, reader_name)
+        {
+            log::error!("Failed to store access event: {e:?}");
+        }
+        let name = match decision {
+            Decision::GrantedGuest => self
+                .guests
+                .get(&id, SystemTime::now())
+                .map(|guest| guest.name),
+            _ => self.access_list.get(&id).map(|key| key.name.clone()),
+        };
+        let name = name.as_deref().unwrap_or_default();
+        log::info!(
+            event = "access",
+            key_id = privacy::id(&id).as_str(),
+            reader = reader.label(),
+            decision = decision.to_string().as_str(),
+            group = group.as_deref();
+            "Access {decision}"
+        );
+        self.hooks.access(&id, name, reader.label(), decision);
+        self.mqtt.access(&id, name, decision);
+        self.events.access(Some(&id), name, reader_name, decision);
+        if decision.is_granted() {
+            if decision != Decision::GrantedMaster {
+                self.passback.lock().unwrap().granted(&id, Instant::now());
+            }
+            self.metrics.granted.inc();
+            self.last_seen.touch(&id);
+            self.usage.granted(&id, chrono::Local::now());
+            if reader.kind == ReaderKind::W1 && self.may_hold(&id, reader) {
+                self.present(&id, reader);
+            }
+        } else if decision != Decision::PinRequired {
+            self.metrics.denied.inc();
+        }
+        if let Some(event) = feedback::Event::of(decision) {
+            self.feedback.play(event, Instant::now());
+        }
+    }
+
+    /// Refuses a key granted again within `anti_passback`; master keys are never checked.
+    fn check_passback(&self, id: &Credential, decision: Decision) -> Decision {
+        let Some(left) = self
+            .passback
+            .lock()
+            .unwrap()
+            .refused_for(id, Instant::now())
+        else {
+            return decision;
+        };
+        log::info!(
+            "Key {} was granted just before, refusing it for another {}",
+            privacy::id(id),
+            humantime::format_duration(Duration::from_secs(left.as_secs().max(1)))
+        );
+        Decision::AntiPassback
+    }
+
+    /// Holds back the grant of a key with a PIN until [`Access::handle_keypress`] completes it.
+    fn require_pin(
+        &self,
+        id: &Credential,
+        decision: Decision,
+        crc: Crc,
+        reader: &Reader,
+    ) -> Decision {
+        let listed = self.access_list.get(id).and_then(|key| key.pin.clone());
+        let index = self
+            .readers
+            .iter()
+            .position(|other| std::ptr::eq(other, reader))
+            .unwrap_or_default();
+        match self
+            .pins
+            .challenge(id, listed.as_deref(), index, decision, crc, Instant::now())
+        {
+            Gate::Open => decision,
+            Gate::Challenged => {
+                log::info!(
+                    "Key {} at reader {} waits for its PIN",
+                    privacy::id(id),
+                    reader.label()
+                );
+                Decision::PinRequired
+            }
+            Gate::CoolingDown => {
+                log::warn!("Refusing key {} after too many wrong PINs", privacy::id(id));
+                Decision::PinCooldown
+            }
+        }
+    }
+
+    /// Takes a key pressed on the keypad, opening the door once the PIN a key waits for is
+    /// complete and right. The PIN itself is never logged.
+    pub fn handle_keypress(&self, press: Press) {
+        match self.pins.press(press, Instant::now()) {
+            None => {}
+            Some(Entry::Unexpected) => {
+                log::info!("Ignoring a PIN entered without a key waiting for one");
+            }
+            Some(Entry::Verified(challenge)) => {
+                let reader = &self.readers[challenge.reader];
+                // A lockdown or block may have come in while the PIN was typed.
+                let decision = match self.decide(&challenge.id) {
+                    decision if decision.is_granted() => challenge.decision,
+                    decision => decision,
+                };
+                log::info!(
+                    "PIN of key {} entered at reader {}",
+                    privacy::id(&challenge.id),
+                    reader.label()
+                );
+                self.conclude(&challenge.id, decision, challenge.crc, reader);
+            }
+            Some(Entry::Wrong {
+                challenge,
+                cooldown,
+            }) => {
+                let reader = &self.readers[challenge.reader];
+                log::warn!(
+                    "Wrong PIN for key {} at reader {}",
+                    privacy::id(&challenge.id),
+                    reader.label()
+                );
+                if cooldown {
+                    log::warn!(
+                        "Refusing key {} for {} after too many wrong PINs",
+                        privacy::id(&challenge.id),
+                        humantime::format_duration(self.pins.cooldown())
+                    );
+                }
+                self.conclude(&challenge.id, Decision::WrongPin, challenge.crc, reader);
+            }
+        }
+    }
+
+    /// Challenges DS1961S buttons about to be granted in challenge mode, even listed ones.
+    fn authenticate(&self, sysname: &str, id: &Credential, decision: Decision) -> Decision {
+        let (Some(authenticator), Credential::OneWire(one_wire)) = (&self.authenticator, id) else {
+            return decision;
+        };
+        if one_wire[0] != DS1961S_FAMILY {
+            return decision;
+        }
+        match authenticator.verify(sysname, one_wire) {
+            Ok(Verdict::Genuine) => decision,
+            Ok(Verdict::Clone) => {
+                log::error!(
+                    "Clone suspected: key {} failed the challenge-response",
+                    privacy::id(id)
+                );
+                Decision::CloneSuspected
+            }
+            Err(e) => {
+                log::warn!("Failed to authenticate key {}: {e:?}", privacy::id(id));
+                Decision::AuthError
+            }
+        }
+    }
+
+    /// Verifies the CRC of the ROM code the kernel read for `sysname`, and that it is `id`.
+    fn check_crc(&self, sysname: &str, id: &OneWireId) -> Crc {
+        let path = self.w1_devices.join(sysname).join("id");
+        let rom = match std::fs::read(&path) {
+            Ok(rom) => rom,
+            Err(e) => {
+                log::debug!(
+                    "No ROM code for {}, not checking its CRC: {e}",
+                    privacy::raw(sysname)
+                );
+                return Crc::Unchecked;
+            }
+        };
+        let Ok(rom) = <[u8; 8]>::try_from(rom.as_slice()) else {
+            log::warn!(
+                "ROM code of {} has {} bytes",
+                privacy::id(&(*id).into()),
+                rom.len()
+            );
+            return Crc::Invalid;
+        };
+        if crc8(&rom[..7]) != rom[7] {
+            return Crc::Invalid;
+        }
+        // The ROM code has the serial lowest byte first, the sysname highest byte first.
+        if rom[0] != id[0] || !rom[1..7].iter().rev().eq(&id[1..]) {
+            log::warn!(
+                "ROM code of {} doesn't match its name",
+                privacy::id(&(*id).into())
+            );
+            return Crc::Invalid;
+        }
+        Crc::Valid
+    }
+
+    /// Applies the parts of a reloaded configuration that decisions depend on: the key lists,
+    /// staleness limits, schedules, auto-unlock windows and the unlock duration of readers
+    /// using the top-level `door`.
+    pub fn reload(&self, config: &config::Config) {
+        *self.master_keys.write().unwrap() = config.master_keys.clone();
+        *self.deny_keys.write().unwrap() = config.deny_keys.clone();
+        self.pins.set_required(config.pin_required.clone());
+        *self.allowed_family_codes.write().unwrap() = config.allowed_family_codes.clone();
+        *self.staleness.write().unwrap() = Staleness::new(&config.thing);
+        *self.schedules.write().unwrap() = config.schedules.clone();
+        self.auto_unlock.set_windows(config.auto_unlock.clone());
+        self.access_list.retain(|id, _| {
+            !config.deny_keys.contains(id) && family_allowed(&config.allowed_family_codes, id)
+        });
+        self.metrics
+            .access_list_size
+            .set(self.access_list.len() as u64);
+        for reader in &self.readers {
+            let default_door = match &reader.name {
+                None => true,
+                Some(name) => config
+                    .readers
+                    .iter()
+                    .any(|named| named.name == *name && named.door.is_none()),
+            };
+            if default_door {
+                reader.door.set_unlock(config.door.unlock);
+            }
+        }
+        self.check_auto_unlock();
+    }
+
+    /// Enables or lifts the lockdown, see [`Lockdown::set`].
+    pub fn set_lockdown(&self, active: bool) -> anyhow::Result<()> {
+        let result = self.lockdown.set(active);
+        self.metrics.lockdown.set(active as u64);
+        self.check_auto_unlock();
+        self.hold_present();
+        result
+    }
+
+    /// Catches up with the clock having become trusted: a fetch before the jump was stamped with
+    /// the bogus time and would now count as ancient, and `auto_unlock` windows apply again.
+    pub fn clock_trusted(&self) {
+        self.metrics.restamp_refresh(self.clock.not_before());
+        self.check_auto_unlock();
+    }
+
+    /// Holds or releases the door of the first reader as the `auto_unlock` windows and the
+    /// lockdown demand, reporting transitions. Called on startup and every minute.
+    pub fn check_auto_unlock(&self) {
+        let now = chrono::Local::now().naive_local();
+        // An untrusted clock holds no door open, as a lockdown wouldn't.
+        let suppressed = self.lockdown.is_active() || !self.clock.is_trusted();
+        let Some(active) = self.auto_unlock.update(now, suppressed) else {
+            return;
+        };
+        let reader = &self.readers[0];
+        reader.door.hold(active || self.presence.holds(0));
+        let decision = if active {
+            log::info!("Auto-unlock window started, holding the door unlocked");
+            Decision::AutoUnlockStart
+        } else {
+            log::info!("Auto-unlock ended, locking the door");
+            Decision::AutoUnlockEnd
+        };
+        let reader_name = reader.name.as_deref();
+        self.audit
+            .record(None, decision, Crc::Unchecked, reader_name, None);
+        if let Err(e) = self
+            .store
+            .append_event(SystemTime::now(), None, decision, reader_name)
+        {
+            log::error!("Failed to store access event: {e:?}");
+        }
+        self.metrics.auto_unlock.set(active as u64);
+        self.mqtt.auto_unlock(active);
+    }
+
+    /// Starts tracking the key `id` put on `reader` if it wasn't already.
+    fn present(&self, id: &Credential, reader: &Reader) {
+        let index = self
+            .readers
+            .iter()
+            .position(|other| std::ptr::eq(other, reader))
+            .unwrap_or_default();
+        if self.presence.arrived(id, index) {
+            log::info!(
+                "Key {} stays on reader {}, it may hold the door unlocked",
+                privacy::id(id),
+                reader.label()
+            );
+            self.record_presence(Some(id), Decision::PresenceArrived, reader);
+        }
+    }
+
+    /// Follows the keys allowed to hold doors by `presence` through a poll of the bus finding
+    /// the `listed` keys, then holds and releases the doors, auditing every change. Called
+    /// every second.
+    pub fn check_presence(&self, listed: &HashSet<Credential>) {
+        for (id, index) in self.presence.poll(listed) {
+            let reader = &self.readers[index];
+            log::info!(
+                "Key {} was taken off reader {}",
+                privacy::id(&id),
+                reader.label()
+            );
+            self.record_presence(Some(&id), Decision::PresenceDeparted, reader);
+        }
+        self.hold_present();
+    }
+
+    /// Counts a udev event of any kind at `now` as a sign of life of the reader, clearing the
+    /// alert of [`Access::check_reader_health`].
+    pub fn reader_activity(&self, now: Instant) {
+        let unix_secs = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.metrics.reader_last_event.set(unix_secs);
+        let Some(silent_for) = self.reader_health.activity(now) else {
+            return;
+        };
+        log::warn!(
+            "w1 events arrive again after {} of silence",
+            humantime::format_duration(Duration::from_secs(silent_for.as_secs()))
+        );
+        self.hooks.reader_silent(false, silent_for);
+        self.mqtt.reader_silent(false, silent_for);
+        self.metrics.reader_silent.set(0);
+    }
+
+    /// Probes the bus if `reader.watchdog.probe` is due and raises the alert once there was no
+    /// sign of life for `reader.watchdog.silence`. Called every second.
+    pub fn check_reader_health(&self, now: Instant) {
+        if self.reader_health.probe(&self.w1_devices, now) {
+            self.reader_activity(now);
+        }
+        let Some(silent_for) = self.reader_health.check(now) else {
+            return;
+        };
+        log::error!(
+            "No w1 events for {}, the reader may be dead or disconnected",
+            humantime::format_duration(Duration::from_secs(silent_for.as_secs()))
+        );
+        self.hooks.reader_silent(true, silent_for);
+        self.mqtt.reader_silent(true, silent_for);
+        self.metrics.reader_silent.set(1);
+    }
+
+    /// Holds the doors of readers with a key allowed to hold them and releases those without
+    /// after `presence.grace`.
+    fn hold_present(&self) {
+        // As for auto_unlock, neither a lockdown nor an untrusted clock leaves a door held.
+        let changes = match self.lockdown.is_active() || !self.clock.is_trusted() {
+            true => self
+                .presence
+                .release()
+                .into_iter()
+                .map(|index| (index, false))
+                .collect(),
+            false => self.presence.update(
+                |id, index| self.may_hold(id, &self.readers[index]),
+                Instant::now(),
+            ),
+        };
+        for (index, held) in changes {
+            let reader = &self.readers[index];
+            reader
+                .door
+                .hold(held || (index == 0 && self.auto_unlock.is_active()));
+            let decision = if held {
+                log::info!(
+                    "Key present on reader {}, holding the door unlocked",
+                    reader.label()
+                );
+                Decision::PresenceHoldStart
+            } else {
+                log::info!(
+                    "No key holds the door of reader {} any more, locking it",
+                    reader.label()
+                );
+                Decision::PresenceHoldEnd
+            };
+            self.record_presence(None, decision, reader);
+        }
+    }
+
+    fn record_presence(&self, id: Option<&Credential>, decision: Decision, reader: &Reader) {
+        let reader_name = reader.name.as_deref();
+        self.audit
+            .record(id, decision, Crc::Unchecked, reader_name, None);
+        if let Err(e) = self
+            .store
+            .append_event(SystemTime::now(), id, decision, reader_name)
+        {
+            log::error!("Failed to store access event: {e:?}");
+        }
+    }
+
+    /// Whether the listed key `id` may hold the door of `reader` by `presence`. The checks of
+    /// [`Access::decide`] that apply are repeated quietly, as this is asked every second.
+    fn may_hold(&self, id: &Credential, reader: &Reader) -> bool {
+        let Some(config) = self.presence.config() else {
+            return false;
+        };
+        let Some(key) = self.access_list.get(id) else {
+            return false;
+        };
+        let allowed = key
+            .group
+            .as_ref()
+            .is_some_and(|group| config.groups.contains(group))
+            || key
+                .schedule
+                .as_ref()
+                .is_some_and(|label| config.schedules.contains(label));
+        let now = SystemTime::now();
+        let local = chrono::DateTime::<chrono::Local>::from(now).naive_local();
+        let schedules = self.schedules.read().unwrap();
+        let within_schedule = key.schedule.as_ref().is_none_or(|label| {
+            schedules
+                .get(label)
+                .is_some_and(|windows| is_within_schedule(windows, local))
+        });
+        let level = self
+            .staleness
+            .read()
+            .unwrap()
+            .level(self.metrics.list_age(now));
+        allowed
+            && within_schedule
+            && !key.is_expired(now)
+            && level != Level::Restricted
+            && !self.deny_keys.read().unwrap().contains(id)
+            && reader.permits(id, key.group.as_deref())
+    }
+
+    /// Opens the door of `reader` as asked `via` the API, control socket or MQTT, unless a
+    /// lockdown is active or `open_rate_limit` is used up.
+    pub fn remote_open(&self, reader: &Reader, via: &str) -> Result<(), OpenRefusal> {
+        if self.lockdown.is_active() {
+            log::warn!(
+                "Lockdown active, not opening the door of reader {} via {via}",
+                reader.label()
+            );
+            return Err(OpenRefusal::Lockdown);
+        }
+        if let Err(retry) = self.open_limit.take(Instant::now()) {
+            self.metrics.opens_rate_limited.inc();
+            log::warn!(
+                "Not opening the door of reader {} via {via}, rate limited for another {}",
+                reader.label(),
+                humantime::format_duration(Duration::from_secs(retry_secs(retry)))
+            );
+            return Err(OpenRefusal::RateLimited(retry));
+        }
+        log::info!("Door of reader {} opened via {via}", reader.label());
+        reader.door.unlock();
+        Ok(())
+    }
+
+    /// Opens the door of the first reader for the exit button. Egress must not depend on MOS,
+    /// so neither lockdown nor the access list matter.
+    pub fn exit_button(&self) {
+        let reader = &self.readers[0];
+        reader.door.unlock();
+        log::info!("Exit button pressed, door opened");
+        let reader_name = reader.name.as_deref();
+        self.audit.record(
+            None,
+            Decision::ExitButton,
+            Crc::Unchecked,
+            reader_name,
+            None,
+        );
+        if let Err(e) =
+            self.store
+                .append_event(SystemTime::now(), None, Decision::ExitButton, reader_name)
+        {
+            log::error!("Failed to store access event: {e:?}");
+        }
+        self.metrics.exit_button.inc();
+    }
+
+    /// Decides whether `id` may open the door, logging the reason.
+    pub fn decide(&self, id: &Credential) -> Decision {
+        let key = self.access_list.get(id);
+        if self.lockdown.is_active()
+            && !(self.lockdown.exempt_master && self.master_keys.read().unwrap().contains(id))
+        {
+            log::info!("Lockdown active, refusing key {}", privacy::id(id));
+            Decision::Lockdown
+        } else if self.deny_keys.read().unwrap().contains(id) {
+            log::info!(
+                "Explicitly blocked key detected: {:?} ({})",
+                key.as_ref().map_or("", |key| key.name.as_str()),
+                privacy::id(id)
+            );
+            Decision::Blocked
+        } else if self.master_keys.read().unwrap().contains(id) {
+            log::info!("Master key detected: {}", privacy::id(id));
+            Decision::GrantedMaster
+        } else if let Some(key) = key {
+            let now = SystemTime::now();
+            let trusted = self.clock.is_trusted();
+            let level = match trusted {
+                true => self
+                    .staleness
+                    .read()
+                    .unwrap()
+                    .level(self.metrics.list_age(now)),
+                false => Level::Fresh,
+            };
+            let timed = key.expiry.is_some() || key.schedule.is_some();
+            if level == Level::Restricted {
+                log::warn!(
+                    "Key list is too old, refusing non-master key {:?} ({})",
+                    key.name,
+                    privacy::id(id)
+                );
+                Decision::Stale
+            } else if !trusted && timed {
+                match self.clock.untrusted() {
+                    config::Untrusted::Deny => {
+                        log::warn!(
+                            "Clock not trusted, refusing key {:?} ({}) with an expiry or schedule",
+                            key.name,
+                            privacy::id(id)
+                        );
+                        Decision::ClockUntrusted
+                    }
+                    config::Untrusted::Allow => {
+                        log::info!(
+                            "Clock not trusted, admitting key {:?} ({}) regardless of its expiry \
+                             and schedule",
+                            key.name,
+                            privacy::id(id)
+                        );
+                        Decision::Granted
+                    }
+                }
+            } else if key.is_expired(now) {
+                log::info!("Expired key detected: {:?} ({})", key.name, privacy::id(id));
+                Decision::Expired
+            } else if !self.within_schedule(id, &key, now) {
+                log::info!(
+                    "Key {:?} ({}) used outside its schedule {:?}",
+                    key.name,
+                    privacy::id(id),
+                    key.schedule.as_deref().unwrap_or_default()
+                );
+                Decision::OutsideSchedule
+            } else {
+                log::info!("Valid user detected: {:?} ({})", key.name, privacy::id(id));
+                Decision::Granted
+            }
+        } else if let Some(guest) = self.guests.get(id, SystemTime::now()) {
+            if !self.clock.is_trusted() && self.clock.untrusted() == config::Untrusted::Deny {
+                log::warn!(
+                    "Clock not trusted, refusing guest key {:?} ({})",
+                    guest.name,
+                    privacy::id(id)
+                );
+                return Decision::ClockUntrusted;
+            }
+            log::info!(
+                "Guest key detected: {:?} ({}), valid until {}",
+                guest.name,
+                privacy::id(id),
+                humantime::format_rfc3339_seconds(guest.expires)
+            );
+            Decision::GrantedGuest
+        } else {
+            if self.denials.lock().unwrap().record(id, Instant::now()) {
+                log::info!("Invalid user detected: {}", privacy::id(id));
+            }
+            Decision::Denied
+        }
+    }
+
+    /// Whether the local time `now` lies within the windows of `key`'s schedule, if it has one.
+    /// A label missing from the config never matches, so a typo fails closed.
+    fn within_schedule(&self, id: &Credential, key: &Key, now: SystemTime) -> bool {
+        let Some(label) = &key.schedule else {
+            return true;
+        };
+        let schedules = self.schedules.read().unwrap();
+        let Some(windows) = schedules.get(label) else {
+            log::warn!(
+                "Key {} has the unknown schedule {label:?}, refusing it",
+                privacy::id(id)
+            );
+            return false;
+        };
+        let local = chrono::DateTime::<chrono::Local>::from(now).naive_local();
+        is_within_schedule(windows, local)
+    }
+}
+
+/// `retry` rounded up to whole seconds, as told to clients of a rate limit.
+pub fn retry_secs(retry: Duration) -> u64 {
+    retry.as_secs_f64().ceil() as u64
+}
+
+/// Whether `id` belongs to one of the `allowed` families, which only 1-Wire devices have.
+pub fn family_allowed(allowed: &HashSet<u8>, id: &Credential) -> bool {
+    match id {
+        Credential::OneWire(id) => allowed.is_empty() || allowed.contains(&id[0]),
+        Credential::Wiegand(_) | Credential::Nfc(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashSet,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use chrono::Datelike;
+
+    use super::{Reader, ReaderKind};
+    use crate::{
+        audit::Decision, clock::Clock, config, schedule::Window, testutil::access, Credential, Key,
+    };
+
+    const KEY: Credential = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+
+    #[test]
+    fn decision_test() {
+        assert_eq!(access(&[KEY], &[], &[]).decide(&KEY), Decision::Granted);
+        assert_eq!(
+            access(&[], &[KEY], &[]).decide(&KEY),
+            Decision::GrantedMaster
+        );
+        assert_eq!(access(&[], &[], &[]).decide(&KEY), Decision::Denied);
+    }
+
+    #[test]
+    fn expired_key_is_rejected_at_badge_time_test() {
+        let access = access(&[KEY], &[], &[]);
+        let now = std::time::SystemTime::now();
+        access.access_list.get_mut(&KEY).unwrap().expiry =
+            Some(now + std::time::Duration::from_secs(60));
+        assert_eq!(access.decide(&KEY), Decision::Granted);
+
+        access.access_list.get_mut(&KEY).unwrap().expiry = Some(now);
+        assert_eq!(access.decide(&KEY), Decision::Expired);
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(!access.readers[0].door.is_unlocked());
+    }
+
+    #[test]
+    fn untrusted_clock_test() {
+        let boot = SystemTime::UNIX_EPOCH + Duration::from_secs(30);
+        let untrusted = |policy| {
+            let mut access = access(
+                &[KEY, Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42])],
+                &[],
+                &[],
+            );
+            let config = config::Clock {
+                untrusted: policy,
+                ..Default::default()
+            };
+            access.clock = Arc::new(Clock::new(config, boot));
+            // Expired by any clock, but that isn't known yet.
+            access.access_list.get_mut(&KEY).unwrap().expiry = Some(boot);
+            access
+        };
+        let access = untrusted(config::Untrusted::Deny);
+        assert_eq!(access.decide(&KEY), Decision::ClockUntrusted);
+        // Keys without an expiry or schedule don't depend on the clock.
+        assert_eq!(
+            access.decide(&Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42])),
+            Decision::Granted
+        );
+        assert_eq!(
+            untrusted(config::Untrusted::Allow).decide(&KEY),
+            Decision::Granted
+        );
+
+        // A fetch before the jump doesn't make the list look decades old after it.
+        access.metrics.last_refresh.set(60);
+        access.clock.check(SystemTime::now());
+        access.clock_trusted();
+        assert!(access.metrics.list_age(SystemTime::now()).unwrap() < Duration::from_secs(60));
+        assert_eq!(access.decide(&KEY), Decision::Expired);
+    }
+
+    #[test]
+    fn schedule_is_checked_at_badge_time_test() {
+        let mut access = access(&[KEY], &[], &[]);
+        access.access_list.get_mut(&KEY).unwrap().schedule = Some("daytime".to_owned());
+        // Unknown labels fail closed.
+        assert_eq!(access.decide(&KEY), Decision::OutsideSchedule);
+
+        let always = Window::parse("Mon-Sun 00:00-24:00").unwrap();
+        access
+            .schedules
+            .get_mut()
+            .unwrap()
+            .insert("daytime".to_owned(), vec![always]);
+        assert_eq!(access.decide(&KEY), Decision::Granted);
+
+        // Every weekday except today, so the window never matches now.
+        let tomorrow = chrono::Local::now().weekday().succ();
+        let others = format!("{tomorrow:?}-{:?} 00:00-24:00", tomorrow.pred().pred());
+        *access
+            .schedules
+            .get_mut()
+            .unwrap()
+            .get_mut("daytime")
+            .unwrap() = vec![Window::parse(&others).unwrap()];
+        assert_eq!(access.decide(&KEY), Decision::OutsideSchedule);
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(!access.readers[0].door.is_unlocked());
+    }
+
+    #[test]
+    fn deny_list_overrides_access_list_test() {
+        let access = access(&[KEY], &[KEY], &[KEY]);
+        assert_eq!(access.decide(&KEY), Decision::Blocked);
+
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(!access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.denied.get(), 1);
+        assert_eq!(access.metrics.granted.get(), 0);
+        assert_eq!(access.metrics.decision_seconds.count(), 1);
+    }
+
+    #[test]
+    fn lockdown_refuses_master_keys_test() {
+        let mut access = access(&[KEY], &[KEY], &[]);
+        access.set_lockdown(true).unwrap();
+        assert_eq!(access.decide(&KEY), Decision::Lockdown);
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(!access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.lockdown.get(), 1);
+
+        access.set_lockdown(false).unwrap();
+        assert_eq!(access.decide(&KEY), Decision::GrantedMaster);
+
+        access.lockdown.exempt_master = true;
+        access.set_lockdown(true).unwrap();
+        assert_eq!(access.decide(&KEY), Decision::GrantedMaster);
+    }
+
+    #[test]
+    fn lockdown_ends_auto_unlock_test() {
+        let access = access(&[KEY], &[], &[]);
+        access
+            .auto_unlock
+            .set_windows(vec![Window::parse("Mon-Sun 00:00-24:00").unwrap()]);
+        access.check_auto_unlock();
+        assert!(access.readers[0].door.is_held());
+        assert_eq!(access.metrics.auto_unlock.get(), 1);
+
+        // Grants are recorded, but leave the door to the hold.
+        access.handle_device("33-00000392c6ea", &[]);
+        assert_eq!(access.metrics.granted.get(), 1);
+        assert!(!access.readers[0].door.is_unlocked());
+
+        access.set_lockdown(true).unwrap();
+        assert!(!access.readers[0].door.is_held());
+        assert_eq!(access.metrics.auto_unlock.get(), 0);
+        access.set_lockdown(false).unwrap();
+        assert!(access.readers[0].door.is_held());
+    }
+
+    #[test]
+    fn presence_holds_door_test() {
+        let late = Key {
+            group: Some("late".to_owned()),
+            ..Key::named("Alice")
+        };
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let mut access = access(&[bob], &[], &[]);
+        access.access_list.insert(KEY, late);
+        access.presence = crate::presence::Presence::new(Some(
+            serde_yaml_ng::from_str("groups: [late]\ngrace: 0s\nmissed_polls: 2").unwrap(),
+        ));
+        access.handle_device("01-000000000042", &[]);
+        access.handle_device("33-00000392c6ea", &[]);
+        let on_bus = HashSet::from([KEY, bob]);
+        access.check_presence(&on_bus);
+        assert!(access.readers[0].door.is_held());
+
+        access.check_presence(&HashSet::new());
+        assert!(access.readers[0].door.is_held());
+        access.check_presence(&HashSet::new());
+        assert!(!access.readers[0].door.is_held());
+
+        // A lockdown ends the hold right away.
+        access.handle_device("33-00000392c6ea", &[]);
+        access.check_presence(&on_bus);
+        assert!(access.readers[0].door.is_held());
+        access.set_lockdown(true).unwrap();
+        assert!(!access.readers[0].door.is_held());
+        access.set_lockdown(false).unwrap();
+        assert!(access.readers[0].door.is_held());
+    }
+
+    #[test]
+    fn exit_button_opens_during_lockdown_test() {
+        let access = access(&[], &[], &[]);
+        access.set_lockdown(true).unwrap();
+        access.exit_button();
+        assert!(access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.exit_button.get(), 1);
+    }
+
+    #[test]
+    fn stale_list_honours_only_master_keys_test() {
+        let master = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let mut access = access(&[KEY], &[master], &[]);
+        access.staleness.get_mut().unwrap().deny_after = Some(Duration::from_secs(7 * 24 * 3600));
+        let fetched = SystemTime::now() - Duration::from_secs(8 * 24 * 3600);
+        let secs = fetched.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        access.metrics.last_refresh.set(secs.as_secs());
+        assert_eq!(access.decide(&KEY), Decision::Stale);
+        assert_eq!(access.decide(&master), Decision::GrantedMaster);
+
+        access.metrics.refreshed(1);
+        assert_eq!(access.decide(&KEY), Decision::Granted);
+    }
+
+    #[test]
+    fn foreign_family_is_ignored_test() {
+        let mut access = access(&[KEY], &[], &[]);
+        *access.allowed_family_codes.get_mut().unwrap() = HashSet::from([0x01]);
+
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(!access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.granted.get(), 0);
+
+        access.allowed_family_codes.get_mut().unwrap().insert(0x33);
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(access.readers[0].door.is_unlocked());
+    }
+
+    #[test]
+    fn readers_apply_their_own_policy_test() {
+        let other = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let mut access = access(&[KEY, other], &[], &[]);
+        let mut outer = Reader::unconnected(Some("outer"));
+        outer.bus_master = Some("w1_bus_master1".to_owned());
+        let mut inner = Reader::unconnected(Some("inner"));
+        inner.parent = Some("1-1.2".to_owned());
+        inner.restrict_to = HashSet::from([other]);
+        access.readers = vec![outer, inner];
+        let bus = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        access.handle_device("33-00000392c6ea", &bus(&["w1_bus_master2", "1-1.2"]));
+        assert!(!access.readers[0].door.is_unlocked());
+        assert!(!access.readers[1].door.is_unlocked());
+        assert_eq!(access.metrics.denied.get(), 1);
+
+        access.handle_device("33-00000392c6ea", &bus(&["w1_bus_master1", "1-1.1"]));
+        assert!(access.readers[0].door.is_unlocked());
+        assert!(!access.readers[1].door.is_unlocked());
+
+        access.handle_device("01-000000000042", &bus(&["w1_bus_master2", "1-1.2"]));
+        assert!(access.readers[1].door.is_unlocked());
+
+        // Matches neither reader.
+        access.handle_device("01-000000000042", &bus(&["w1_bus_master3", "1-1.3"]));
+        assert_eq!(access.metrics.granted.get(), 2);
+    }
+
+    #[test]
+    fn readers_check_groups_test() {
+        let member = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let ungrouped = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]);
+        let master = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x44]);
+        let mut access = access(&[KEY, member, ungrouped], &[master], &[]);
+        access.access_list.get_mut(&KEY).unwrap().group = Some("keyholder".to_owned());
+        access.access_list.get_mut(&member).unwrap().group = Some("member".to_owned());
+        let mut inner = Reader::unconnected(Some("inner"));
+        inner.allow_groups = HashSet::from(["keyholder".to_owned(), "board".to_owned()]);
+        access.readers = vec![inner];
+
+        for id in ["01-000000000042", "01-000000000043"] {
+            access.handle_device(id, &[]);
+            assert!(!access.readers[0].door.is_unlocked(), "{id}");
+        }
+        assert_eq!(access.metrics.granted.get(), 0);
+
+        access.handle_device("01-000000000044", &[]);
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.granted.get(), 2);
+
+        // Without allow_groups any listed key opens the door.
+        access.readers = vec![Reader::unconnected(None)];
+        access.handle_device("01-000000000043", &[]);
+        assert!(access.readers[0].door.is_unlocked());
+    }
+
+    #[test]
+    fn wiegand_cards_test() {
+        let mut access = access(&[Credential::Wiegand(1234567)], &[], &[]);
+        let mut garage = Reader::unconnected(Some("garage"));
+        garage.kind = ReaderKind::Wiegand;
+        access.readers = vec![garage];
+        // Card 1234567 in Wiegand 26, between its even and odd parity bits.
+        let frame: Vec<bool> = concat!("1", "000100101101011010000111", "1")
+            .chars()
+            .map(|c| c == '1')
+            .collect();
+
+        let mut corrupted = frame.clone();
+        corrupted[3] = !corrupted[3];
+        access.handle_card(&corrupted, &access.readers[0]);
+        assert_eq!(access.metrics.wiegand_bad_frames.get(), 1);
+        assert!(!access.readers[0].door.is_unlocked());
+
+        access.handle_card(&frame, &access.readers[0]);
+        assert!(access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.granted.get(), 1);
+
+        // A Wiegand reader takes no w1 devices.
+        access.handle_device("00-00000012d687", &[]);
+        assert_eq!(access.metrics.granted.get(), 1);
+
+        // A w1 device with the card's digits is a key of its own.
+        access.readers.push(Reader::unconnected(Some("front")));
+        access.handle_device("00-00000012d687", &[]);
+        assert_eq!(access.metrics.denied.get(), 1);
+        access.access_list.insert(
+            Credential::OneWire([0, 0, 0, 0, 0x12, 0xd6, 0x87]),
+            Key::named("Sensor"),
+        );
+        access.handle_device("00-00000012d687", &[]);
+        assert_eq!(access.metrics.granted.get(), 2);
+        assert!(access.readers[1].door.is_unlocked());
+    }
+
+    #[test]
+    fn guest_keys_test() {
+        let guest = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let access = access(&[KEY], &[], &[]);
+        assert_eq!(access.decide(&guest), Decision::Denied);
+        let now = SystemTime::now();
+        access
+            .guests
+            .add(&guest, Duration::from_secs(3600), "Workshop", now)
+            .unwrap();
+        assert_eq!(access.decide(&guest), Decision::GrantedGuest);
+        access.handle_device("01-000000000042", &[]);
+        assert!(access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.granted.get(), 1);
+
+        // A refresh replacing the list leaves guests alone.
+        access.access_list.clear();
+        assert_eq!(access.decide(&guest), Decision::GrantedGuest);
+        access.guests.revoke(&guest).unwrap();
+        assert_eq!(access.decide(&guest), Decision::Denied);
+    }
+
+    #[test]
+    fn anti_passback_test() {
+        let master = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let mut access = access(&[KEY], &[master], &[]);
+        access.passback = Mutex::new(crate::passback::Passback::new(Some(Duration::from_secs(
+            300,
+        ))));
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(access.readers[0].door.is_unlocked());
+        access.readers[0]
+            .door
+            .relock(Instant::now() + Duration::from_secs(60));
+
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(!access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.denied.get(), 1);
+        assert_eq!(
+            access.check_passback(&KEY, Decision::Granted),
+            Decision::AntiPassback
+        );
+
+        // Master keys may come and go as they like.
+        for _ in 0..2 {
+            access.handle_device("01-000000000042", &[]);
+            assert!(access.readers[0].door.is_unlocked());
+            access.readers[0]
+                .door
+                .relock(Instant::now() + Duration::from_secs(60));
+        }
+
+        assert!(access.passback.lock().unwrap().clear(&KEY, Instant::now()));
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.granted.get(), 4);
+    }
+
+    #[test]
+    fn pin_completes_the_grant_test() {
+        use crate::keypad::Press;
+
+        let mut access = access(&[KEY], &[], &[]);
+        let keypad = crate::config::Keypad {
+            path: Default::default(),
+            window: Duration::from_secs(10),
+            max_attempts: 3,
+            cooldown: Duration::from_secs(300),
+        };
+        // Of the PIN `0000`.
+        let hash = "pbkdf2-sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk";
+        access.pins = crate::pins::Pins::new(Some(keypad), [(KEY, hash.to_owned())].into());
+        let enter = |pin: &str| {
+            for digit in pin.chars() {
+                access.handle_keypress(Press::Digit(digit));
+            }
+            access.handle_keypress(Press::Enter);
+        };
+
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(!access.readers[0].door.is_unlocked());
+        enter("1234");
+        assert!(!access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.denied.get(), 1);
+
+        access.handle_device("33-00000392c6ea", &[]);
+        enter("0000");
+        assert!(access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.granted.get(), 1);
+        // Waiting for the PIN wasn't a denial.
+        assert_eq!(access.metrics.denied.get(), 1);
+    }
+
+    #[test]
+    fn nfc_tags_test() {
+        let tag = Credential::parse("nfc:04aabbccddee80").unwrap();
+        let mut access = access(&[tag], &[], &[]);
+        *access.debounce.lock().unwrap() = crate::debounce::Debounce::new(Duration::from_secs(3));
+        let mut office = Reader::unconnected(Some("office"));
+        office.kind = ReaderKind::Nfc;
+        access.readers = vec![office];
+
+        access.handle_tag(
+            &[0x04, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x80],
+            &access.readers[0],
+        );
+        assert!(access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.granted.get(), 1);
+        // The reader reports a tag for as long as it's held there.
+        access.handle_tag(
+            &[0x04, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x80],
+            &access.readers[0],
+        );
+        assert_eq!(access.metrics.granted.get(), 1);
+
+        access.handle_tag(&[0xde, 0xad, 0xbe, 0xef], &access.readers[0]);
+        assert_eq!(access.metrics.denied.get(), 1);
+        // Only the manufacturer byte differs.
+        access.handle_tag(
+            &[0x05, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x80],
+            &access.readers[0],
+        );
+        assert_eq!(access.metrics.denied.get(), 2);
+        access.handle_tag(&[1; 10], &access.readers[0]);
+        assert_eq!(access.metrics.denied.get(), 2);
+    }
+
+    #[test]
+    fn bad_crc_is_rejected_before_the_access_list_test() {
+        let dir = crate::testutil::test_dir("access-crc");
+        let mut access = access(&[KEY], &[], &[]);
+        access.w1_devices = dir.clone();
+        std::fs::create_dir(dir.join("33-00000392c6ea")).unwrap();
+        let mut rom = vec![0x33, 0xea, 0xc6, 0x92, 0x03, 0x00, 0x00];
+        rom.push(crate::crc8(&rom) ^ 1);
+        std::fs::write(dir.join("33-00000392c6ea/id"), &rom).unwrap();
+
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(!access.readers[0].door.is_unlocked());
+        assert_eq!(access.metrics.bad_crc.get(), 1);
+        assert_eq!(access.metrics.denied.get(), 0);
+
+        rom[7] ^= 1;
+        std::fs::write(dir.join("33-00000392c6ea/id"), &rom).unwrap();
+        access.handle_device("33-00000392c6ea", &[]);
+        assert!(access.readers[0].door.is_unlocked());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reload_replaces_key_lists_test() {
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let access = access(&[KEY, bob], &[KEY], &[]);
+        let config = crate::config::Config::from_yaml(
+            "
+thing:
+  url: http://localhost
+  token: abc
+  refresh_secs: 60
+persistence:
+  path: keys.bin
+door:
+  chip: /dev/gpiochip0
+  line: 17
+  unlock_ms: 3000
+deny_keys:
+  - 01-000000000042
+logging: {}
+",
+        )
+        .unwrap();
+        access.reload(&config);
+        assert_eq!(access.decide(&KEY), Decision::Granted);
+        assert_eq!(access.decide(&bob), Decision::Blocked);
+        assert!(!access.access_list.contains_key(&bob));
+    }
+
+    #[test]
+    fn key_departed_test() {
+        let access = access(&[KEY], &[], &[]);
+        access.key_departed("33-00000392c6ea", &[]);
+        access.key_departed("w1_bus_master1", &[]);
+        let history = access.events.history(10);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].event, crate::events::EventType::Departed);
+        assert!(history[0]
+            .to_string()
+            .ends_with(" departed 3300000392c6ea -"));
+        // Departures decide nothing.
+        assert_eq!(access.metrics.granted.get(), 0);
+    }
+}
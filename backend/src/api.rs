@@ -0,0 +1,499 @@
+use std::{
+    io::Write,
+    net::{TcpListener, TcpStream},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
+};
+
+use clap::ValueEnum;
+use serde_json::json;
+
+use crate::{
+    access::{retry_secs, Access, OpenRefusal},
+    broadcast::Subscription,
+    http::{self, BadRequest},
+    privacy,
+    usage::GroupBy,
+    wakeup::Wakeup,
+    Credential,
+};
+
+/// Longest request body accepted, which is plenty for `POST /open`.
+const MAX_BODY: usize = 4096;
+/// How many `GET /events` streams may be open at once, each holding a thread.
+const MAX_STREAMS: usize = 16;
+/// Idle streams get a comment this often, which also finds clients that went away.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+/// A stream whose client doesn't read for this long is dropped, rather than holding its thread
+/// and a place under `MAX_STREAMS` for good.
+const STREAM_WRITE_TIMEOUT: Duration = KEEPALIVE.saturating_mul(2);
+/// How many events `GET /history` returns without a limit.
+const DEFAULT_HISTORY: usize = 20;
+
+/// Who may use the API: the write endpoints always need `token`, the read ones only without
+/// `public_reads`.
+pub struct Auth {
+    pub token: String,
+    pub public_reads: bool,
+}
+
+/// Serves the JSON API on `listener` from a dedicated thread, so requests never hold up the
+/// event loop; it only shares the state the control socket works on.
+pub fn serve(listener: TcpListener, access: Arc<Access>, wakeup: Arc<Wakeup>, auth: Auth) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_request(stream, &access, &wakeup, &auth) {
+                        log::debug!("API request failed: {e:?}");
+                    }
+                }
+                Err(e) => log::warn!("Failed to accept API connection: {e:?}"),
+            }
+        }
+    });
+}
+
+struct Request {
+    method: String,
+    path: String,
+    /// Everything after `?`, if anything.
+    query: String,
+    token: Option<String>,
+    last_event_id: Option<u64>,
+    body: Vec<u8>,
+}
+
+enum Response {
+    Json(u16, serde_json::Value),
+    /// Server-sent events, resuming after the given sequence number.
+    Events(Option<u64>),
+}
+
+fn handle_request(
+    mut stream: TcpStream,
+    access: &Access,
+    wakeup: &Wakeup,
+    auth: &Auth,
+) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let response = match read_request(&stream) {
+        Ok(request) => respond(&request, access, wakeup, auth),
+        Err(BadRequest { status, error }) => {
+            Response::Json(status, json!({ "error": format!("{error:#}") }))
+        }
+    };
+    let (status, body) = match response {
+        Response::Json(status, body) => (status, body),
+        Response::Events(_) if access.events.stream.subscribers() >= MAX_STREAMS => {
+            (503, json!({ "error": "too many event streams" }))
+        }
+        Response::Events(last_seen) => {
+            // Subscribed before the thread starts, so nothing published meanwhile is missed.
+            let subscription = access.events.stream.subscribe(last_seen);
+            std::thread::spawn(move || {
+                if let Err(e) = stream_events(stream, &subscription) {
+                    log::debug!("Event stream ended: {e}");
+                }
+            });
+            return Ok(());
+        }
+    };
+    let reason = http::reason(status);
+    let challenge = match status {
+        401 => "WWW-Authenticate: Bearer\r\n".to_owned(),
+        429 => format!("Retry-After: {}\r\n", body["retry_after"]),
+        _ => String::new(),
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\n{challenge}\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn stream_events(mut stream: TcpStream, subscription: &Subscription) -> std::io::Result<()> {
+    stream.set_write_timeout(Some(STREAM_WRITE_TIMEOUT))?;
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    loop {
+        let event = match subscription.next(KEEPALIVE) {
+            Some(message) => format!("id: {}\ndata: {}\n\n", message.seq, message.data),
+            None => ": keepalive\n\n".to_owned(),
+        };
+        stream.write_all(event.as_bytes())?;
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Result<Request, BadRequest> {
+    let request = http::read_request(stream, MAX_BODY)?;
+    Ok(Request {
+        token: request
+            .header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_owned),
+        last_event_id: request
+            .header("last-event-id")
+            .and_then(|value| value.parse().ok()),
+        method: request.method,
+        path: request.path,
+        query: request.query,
+        body: request.body,
+    })
+}
+
+fn respond(request: &Request, access: &Access, wakeup: &Wakeup, auth: &Auth) -> Response {
+    let write = match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status" | "/keys" | "/events" | "/history" | "/guests" | "/stats") => false,
+        ("POST", "/refresh" | "/open" | "/guests") | ("DELETE", "/guests") => true,
+        (
+            _,
+            "/status" | "/keys" | "/events" | "/history" | "/refresh" | "/open" | "/guests"
+            | "/stats",
+        ) => {
+            return Response::Json(405, json!({ "error": "method not allowed" }));
+        }
+        _ => return Response::Json(404, json!({ "error": "not found" })),
+    };
+    let authorized = request.token.as_ref().is_some_and(|token| {
+        ring::constant_time::verify_slices_are_equal(token.as_bytes(), auth.token.as_bytes())
+            .is_ok()
+    });
+    if !authorized && (write || !auth.public_reads) {
+        return Response::Json(401, json!({ "error": "missing or wrong bearer token" }));
+    }
+    let (status, body) = match request.path.as_str() {
+        "/status" => (200, status(access)),
+        "/keys" => (200, keys(access)),
+        "/events" => return Response::Events(request.last_event_id),
+        "/history" => history(access, &request.query),
+        "/stats" => stats(access, &request.query),
+        "/guests" => match request.method.as_str() {
+            "GET" => (200, guests(access)),
+            "POST" => add_guest(access, &request.body),
+            _ => revoke_guest(access, &request.body),
+        },
+        "/refresh" => {
+            if wakeup.request_refresh() {
+                log::info!("Key list refresh requested via the API");
+                (202, json!({ "refresh": "requested" }))
+            } else {
+                (409, json!({ "error": "refresh already running" }))
+            }
+        }
+        _ => open(access, &request.body),
+    };
+    Response::Json(status, body)
+}
+
+fn status(access: &Access) -> serde_json::Value {
+    let timestamp = |secs| match secs {
+        0 => None,
+        secs => Some(
+            humantime::format_rfc3339_seconds(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .to_string(),
+        ),
+    };
+    let last_refresh = timestamp(access.metrics.last_refresh.get());
+    let age = access.metrics.list_age(SystemTime::now());
+    let door = access.metrics.door_sensor.load(Ordering::Relaxed).then(|| {
+        json!({
+            "open": access.metrics.door_open.get() != 0,
+            "alarm": access.metrics.door_alarm.get() != 0,
+        })
+    });
+    json!({
+        "keys": access.access_list.len(),
+        "last_refresh": last_refresh,
+        "next_refresh": timestamp(access.metrics.next_refresh.get()),
+        "refresh_failures": access.metrics.consecutive_fetch_failures.get(),
+        "list_source": access.metrics.list_source.lock().unwrap().as_ref().map(|(_, url)| url),
+        "staleness": access.staleness.read().unwrap().level(age).to_string(),
+        "lockdown": access.lockdown.is_active(),
+        "dry_run": access.dry_run,
+        "auto_unlock": access.auto_unlock.is_active(),
+        "door": door,
+    })
+}
+
+/// The access list sorted by id, with ids masked as `privacy` demands.
+fn keys(access: &Access) -> serde_json::Value {
+    let mut keys: Vec<_> = access
+        .access_list
+        .iter()
+        .map(|entry| (*entry.key(), entry.name.clone()))
+        .collect();
+    keys.sort();
+    keys.iter()
+        .map(|(id, name)| json!({ "id": privacy::audit_id(id), "name": name }))
+        .collect()
+}
+
+/// The most recent events, newest first; `?limit=<n>` picks how many.
+fn history(access: &Access, query: &str) -> (u16, serde_json::Value) {
+    let mut limit = DEFAULT_HISTORY;
+    for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        if name == "limit" {
+            match value.parse() {
+                Ok(value) => limit = value,
+                Err(e) => return (400, json!({ "error": format!("invalid limit: {e}") })),
+            }
+        }
+    }
+    (200, json!(access.events.history(limit)))
+}
+
+/// Unique keys and grants per period, `?since=<day>&group_by=day|month|year`, by month from
+/// the first day kept by default.
+fn stats(access: &Access, query: &str) -> (u16, serde_json::Value) {
+    let mut since = None;
+    let mut group_by = GroupBy::Month;
+    for (name, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match name {
+            "since" => match value.parse() {
+                Ok(value) => since = Some(value),
+                Err(e) => return (400, json!({ "error": format!("invalid since: {e}") })),
+            },
+            "group_by" => match GroupBy::from_str(value, true) {
+                Ok(value) => group_by = value,
+                Err(e) => return (400, json!({ "error": format!("invalid group_by: {e}") })),
+            },
+            _ => {}
+        }
+    }
+    match access.usage.stats(since, group_by) {
+        Ok(periods) => (
+            200,
+            json!({
+                "grants": periods.iter().map(|period| period.grants).sum::<u64>(),
+                "periods": periods,
+            }),
+        ),
+        Err(e) => (409, json!({ "error": format!("{e:#}") })),
+    }
+}
+
+fn guests(access: &Access) -> serde_json::Value {
+    access
+        .guests
+        .entries()
+        .iter()
+        .map(|guest| {
+            json!({
+                "id": privacy::audit_id(&guest.id),
+                "name": guest.name,
+                "expires": humantime::format_rfc3339_seconds(guest.expires).to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Adds the guest `{"id": "<id>", "ttl": "2h", "name": "<name>"}`, the name being optional.
+fn add_guest(access: &Access, body: &[u8]) -> (u16, serde_json::Value) {
+    #[derive(serde::Deserialize)]
+    struct Add {
+        id: String,
+        ttl: String,
+        #[serde(default)]
+        name: String,
+    }
+    let add: Add = match serde_json::from_slice(body) {
+        Ok(add) => add,
+        Err(e) => return (400, json!({ "error": format!("invalid body: {e}") })),
+    };
+    let id = match Credential::parse(&add.id) {
+        Ok(id) => id,
+        Err(e) => return (400, json!({ "error": format!("invalid id: {e}") })),
+    };
+    let ttl = match humantime::parse_duration(&add.ttl) {
+        Ok(ttl) => ttl,
+        Err(e) => return (400, json!({ "error": format!("invalid ttl: {e}") })),
+    };
+    match access.guests.add(&id, ttl, &add.name, SystemTime::now()) {
+        Ok(guest) => {
+            let expires = humantime::format_rfc3339_seconds(guest.expires).to_string();
+            log::info!(
+                "Guest key {} ({:?}) added via the API until {expires}",
+                privacy::id(&id),
+                guest.name
+            );
+            (201, json!({ "expires": expires }))
+        }
+        Err(e) => (400, json!({ "error": format!("{e:#}") })),
+    }
+}
+
+/// Revokes the guest `{"id": "<id>"}`.
+fn revoke_guest(access: &Access, body: &[u8]) -> (u16, serde_json::Value) {
+    #[derive(serde::Deserialize)]
+    struct Revoke {
+        id: String,
+    }
+    let id = match serde_json::from_slice::<Revoke>(body) {
+        Ok(revoke) => Credential::parse(&revoke.id),
+        Err(e) => return (400, json!({ "error": format!("invalid body: {e}") })),
+    };
+    let id = match id {
+        Ok(id) => id,
+        Err(e) => return (400, json!({ "error": format!("invalid id: {e}") })),
+    };
+    match access.guests.revoke(&id) {
+        Ok(true) => {
+            log::info!("Guest key {} revoked via the API", privacy::id(&id));
+            (200, json!({ "revoked": true }))
+        }
+        Ok(false) => (404, json!({ "error": "not a guest" })),
+        Err(e) => (500, json!({ "error": format!("{e:#}") })),
+    }
+}
+
+/// Opens the first reader's door, or that of `{"reader": "<name>"}`.
+fn open(access: &Access, body: &[u8]) -> (u16, serde_json::Value) {
+    #[derive(serde::Deserialize, Default)]
+    struct Open {
+        reader: Option<String>,
+    }
+    let open: Open = match body {
+        [] => Open::default(),
+        body => match serde_json::from_slice(body) {
+            Ok(open) => open,
+            Err(e) => return (400, json!({ "error": format!("invalid body: {e}") })),
+        },
+    };
+    let reader = match &open.reader {
+        None => &access.readers[0],
+        Some(name) => match access.readers.iter().find(|reader| {
+            reader
+                .name
+                .as_ref()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        }) {
+            Some(reader) => reader,
+            None => return (404, json!({ "error": format!("unknown reader {name:?}") })),
+        },
+    };
+    match access.remote_open(reader, "the API") {
+        Ok(()) => (200, json!({ "opened": reader.name })),
+        Err(OpenRefusal::Lockdown) => (409, json!({ "error": "lockdown" })),
+        Err(OpenRefusal::RateLimited(retry)) => (
+            429,
+            json!({ "error": "rate limited", "retry_after": retry_secs(retry) }),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::Arc,
+    };
+
+    use super::Auth;
+    use crate::{testutil::access, wakeup::Wakeup, Credential};
+
+    fn request(addr: SocketAddr, request: &str) -> (u16, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn api_requires_token_for_writes_test() {
+        let access = Arc::new(access(
+            &[Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea])],
+            &[],
+            &[],
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let auth = Auth {
+            token: "secret".to_owned(),
+            public_reads: true,
+        };
+        super::serve(listener, access.clone(), Arc::new(Wakeup::default()), auth);
+
+        let (status, body) = request(addr, "GET /status HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 200);
+        assert_eq!(body["keys"], 1);
+        assert_eq!(body["lockdown"], false);
+        let (status, body) = request(addr, "GET /keys HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            serde_json::json!([{ "id": "3300000392c6ea", "name": "Alice" }])
+        );
+
+        let (status, _) = request(addr, "POST /open HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 401);
+        assert!(!access.readers[0].door.is_unlocked());
+        let (status, _) = request(
+            addr,
+            "POST /open HTTP/1.1\r\nAuthorization: Bearer wrong\r\n\r\n",
+        );
+        assert_eq!(status, 401);
+        let body = r#"{"reader":"back"}"#;
+        let (status, _) = request(
+            addr,
+            &format!(
+                "POST /open HTTP/1.1\r\nAuthorization: Bearer secret\r\n\
+                 Content-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+        );
+        assert_eq!(status, 404);
+        let (status, _) = request(
+            addr,
+            "POST /open HTTP/1.1\r\nauthorization: Bearer secret\r\n\r\n",
+        );
+        assert_eq!(status, 200);
+        assert!(access.readers[0].door.is_unlocked());
+
+        let refresh = "POST /refresh HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n";
+        assert_eq!(request(addr, refresh).0, 202);
+        assert_eq!(request(addr, "GET /open HTTP/1.1\r\n\r\n").0, 405);
+        assert_eq!(request(addr, "GET /nope HTTP/1.1\r\n\r\n").0, 404);
+
+        // The stream resumes after the last event the client saw.
+        access.events.refreshed(1);
+        access.events.refreshed(2);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nLast-Event-ID: 1\r\n\r\n")
+            .unwrap();
+        access.events.door(true, false);
+        let mut received = String::new();
+        let mut buf = [0; 1024];
+        while !(received.contains("id: 3\n") && received.ends_with("\n\n")) {
+            let len = stream.read(&mut buf).unwrap();
+            assert_ne!(len, 0, "{received}");
+            received.push_str(std::str::from_utf8(&buf[..len]).unwrap());
+        }
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream"));
+        assert!(!received.contains("id: 1\n"));
+        assert!(received.contains("id: 2\ndata: {\"seq\":2,"));
+        assert!(received.contains("\"open\":true"));
+
+        let (status, body) = request(addr, "GET /history?limit=2 HTTP/1.1\r\n\r\n");
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["event"], "door");
+        assert_eq!(body[1]["list_size"], 2);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(
+            request(addr, "GET /history?limit=x HTTP/1.1\r\n\r\n").0,
+            400
+        );
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+
+use crate::{clock::Clock, config, keylist, privacy, Credential};
+
+/// Outcome of an access attempt as recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Granted,
+    GrantedMaster,
+    /// A key added with `GUEST ADD`, before it expired.
+    GrantedGuest,
+    Denied,
+    Blocked,
+    Expired,
+    /// Valid, but outside the windows of the key's schedule.
+    OutsideSchedule,
+    /// Listed with an expiry or schedule, which can't be checked before the clock is trusted.
+    ClockUntrusted,
+    /// Valid, but not for this reader.
+    Restricted,
+    /// Unknown, and captured by an enrollment.
+    Enrolled,
+    /// Listed, but the list is older than `deny_after`.
+    Stale,
+    Lockdown,
+    ParseError,
+    /// The ROM code read from the bus failed its CRC, most likely a glitch.
+    BadCrc,
+    /// A DS1961S failed the challenge-response.
+    CloneSuspected,
+    /// The challenge-response couldn't be completed.
+    AuthError,
+    /// Valid, but the door waits for the key's PIN on the keypad.
+    PinRequired,
+    /// The PIN entered after the key was wrong.
+    WrongPin,
+    /// Refused after too many wrong PINs in a row, for `keypad.cooldown`.
+    PinCooldown,
+    /// Granted too recently, within `anti_passback`.
+    AntiPassback,
+    /// Opened from the inside, without a key.
+    ExitButton,
+    /// An `auto_unlock` window began holding the door unlocked.
+    AutoUnlockStart,
+    /// The window ended, or a lockdown cut it short.
+    AutoUnlockEnd,
+    /// A key allowed to hold its door by `presence` was put on the reader.
+    PresenceArrived,
+    /// It was missing from `presence.missed_polls` polls of the bus in a row.
+    PresenceDeparted,
+    /// A present key began holding the door unlocked.
+    PresenceHoldStart,
+    /// The grace after the last such key ended, or a lockdown cut the hold short.
+    PresenceHoldEnd,
+}
+
+impl Decision {
+    pub fn is_granted(self) -> bool {
+        matches!(
+            self,
+            Decision::Granted | Decision::GrantedMaster | Decision::GrantedGuest
+        )
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Decision::Granted => "granted",
+            Decision::GrantedMaster => "granted_master",
+            Decision::GrantedGuest => "granted_guest",
+            Decision::Denied => "denied",
+            Decision::Blocked => "blocked",
+            Decision::Expired => "expired",
+            Decision::OutsideSchedule => "outside_schedule",
+            Decision::ClockUntrusted => "clock_untrusted",
+            Decision::Restricted => "restricted",
+            Decision::Enrolled => "enrolled",
+            Decision::Stale => "stale",
+            Decision::Lockdown => "lockdown",
+            Decision::ParseError => "parse_error",
+            Decision::BadCrc => "bad_crc",
+            Decision::CloneSuspected => "clone_suspected",
+            Decision::AuthError => "auth_error",
+            Decision::PinRequired => "pin_required",
+            Decision::WrongPin => "wrong_pin",
+            Decision::PinCooldown => "pin_cooldown",
+            Decision::AntiPassback => "anti_passback",
+            Decision::ExitButton => "exit_button",
+            Decision::AutoUnlockStart => "auto_unlock_start",
+            Decision::AutoUnlockEnd => "auto_unlock_end",
+            Decision::PresenceArrived => "presence_arrived",
+            Decision::PresenceDeparted => "presence_departed",
+            Decision::PresenceHoldStart => "presence_hold_start",
+            Decision::PresenceHoldEnd => "presence_hold_end",
+        })
+    }
+}
+
+/// Whether the CRC of the ROM code the kernel read from the bus could be verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crc {
+    Valid,
+    Invalid,
+    /// The device has no `id` attribute, or there was no device at all.
+    Unchecked,
+}
+
+impl fmt::Display for Crc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Crc::Valid => "crc_ok",
+            Crc::Invalid => "crc_bad",
+            Crc::Unchecked => "crc_unchecked",
+        })
+    }
+}
+
+/// Append-only record of every access attempt, independent of the application log.
+///
+/// Each line reads `<RFC3339 timestamp> <hex id or -> <decision> <crc>`, followed by the reader's
+/// name when several are configured and `group=<group>` for keys MOS lists in a group. Refreshes add `<timestamp> <hex id> refresh added|removed`
+/// for every key they add or remove. While the system clock isn't trusted, timestamps are
+/// whatever it reads and every line ends in `clock=untrusted`.
+pub struct AuditLog {
+    writer: Option<Mutex<Writer>>,
+    /// Records are logged instead of written.
+    dry_run: bool,
+    clock: Arc<Clock>,
+}
+
+struct Writer {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl AuditLog {
+    pub fn new(
+        config: Option<&config::Audit>,
+        dry_run: bool,
+        clock: Arc<Clock>,
+    ) -> anyhow::Result<AuditLog> {
+        let Some(config) = config.filter(|_| !dry_run) else {
+            return Ok(AuditLog {
+                writer: None,
+                dry_run: dry_run && config.is_some(),
+                clock,
+            });
+        };
+        let file = open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditLog {
+            writer: Some(Mutex::new(Writer {
+                path: config.path.clone(),
+                file,
+                size,
+                max_bytes: config.max_bytes,
+                keep: config.keep,
+            })),
+            dry_run: false,
+            clock,
+        })
+    }
+
+    /// `reader` is left out for the implicit reader of single-reader setups.
+    pub fn record(
+        &self,
+        id: Option<&Credential>,
+        decision: Decision,
+        crc: Crc,
+        reader: Option<&str>,
+        group: Option<&str>,
+    ) {
+        if self.writer.is_none() && !self.dry_run {
+            return;
+        }
+        let id = id.map_or_else(|| "-".to_owned(), privacy::audit_id);
+        let mut line = format!(
+            "{} {id} {decision} {crc}",
+            humantime::format_rfc3339_millis(SystemTime::now())
+        );
+        if let Some(reader) = reader {
+            line.push(' ');
+            line.push_str(reader);
+        }
+        if let Some(group) = group {
+            line.push_str(" group=");
+            line.push_str(group);
+        }
+        self.write(line);
+    }
+
+    pub fn refresh(&self, diff: &keylist::Diff) {
+        if self.writer.is_none() && !self.dry_run {
+            return;
+        }
+        let now = humantime::format_rfc3339_millis(SystemTime::now());
+        let added = diff.added.iter().map(|(id, _)| (id, "added"));
+        let removed = diff.removed.iter().map(|(id, _)| (id, "removed"));
+        for (id, change) in added.chain(removed) {
+            self.write(format!("{now} {} refresh {change}", privacy::audit_id(id)));
+        }
+    }
+
+    fn write(&self, mut line: String) {
+        if !self.clock.is_trusted() {
+            line.push_str(" clock=untrusted");
+        }
+        let Some(writer) = &self.writer else {
+            log::info!("[dry-run] Not writing audit record {line:?}");
+            return;
+        };
+        line.push('\n');
+        let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writer.append(line.as_bytes()) {
+            log::error!("Failed to write audit record {line:?}: {e:?}");
+        }
+    }
+}
+
+impl Writer {
+    fn append(&mut self, line: &[u8]) -> anyhow::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.as_os_str().to_owned();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let from = rotated(n);
+                if from.exists() {
+                    std::fs::rename(from, rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("Failed to open audit log {path:?}"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, sync::Arc, time::SystemTime};
+
+    use super::{AuditLog, Crc, Decision};
+    use crate::{clock::Clock, config, keylist, testutil::test_dir, Credential};
+
+    #[test]
+    fn line_format_test() {
+        let dir = test_dir("audit-format");
+        let path = dir.join("audit.log");
+        let audit = AuditLog::new(
+            Some(&config::Audit {
+                path: path.clone(),
+                max_bytes: 1 << 20,
+                keep: 3,
+            }),
+            false,
+            Default::default(),
+        )
+        .unwrap();
+
+        audit.record(
+            Some(&Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea])),
+            Decision::Granted,
+            Crc::Valid,
+            None,
+            None,
+        );
+        audit.record(
+            Some(&Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42])),
+            Decision::Denied,
+            Crc::Unchecked,
+            None,
+            None,
+        );
+        audit.record(None, Decision::ParseError, Crc::Unchecked, None, None);
+        audit.record(
+            Some(&Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea])),
+            Decision::Restricted,
+            Crc::Valid,
+            Some("inner"),
+            Some("member"),
+        );
+        audit.record(
+            Some(&Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea])),
+            Decision::BadCrc,
+            Crc::Invalid,
+            None,
+            None,
+        );
+        audit.refresh(&keylist::Diff {
+            added: vec![(
+                Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]),
+                "Carol".to_owned(),
+            )],
+            removed: vec![(
+                Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]),
+                "Bob".to_owned(),
+            )],
+            changed: 0,
+        });
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Vec<&str>> = contents.lines().map(|l| l.split(' ').collect()).collect();
+        assert_eq!(lines.len(), 7);
+        for line in &lines {
+            humantime::parse_rfc3339(line[0]).unwrap();
+        }
+        assert_eq!(lines[0][1..], ["3300000392c6ea", "granted", "crc_ok"]);
+        assert_eq!(lines[1][1..], ["01000000000042", "denied", "crc_unchecked"]);
+        assert_eq!(lines[2][1..], ["-", "parse_error", "crc_unchecked"]);
+        assert_eq!(
+            lines[3][1..],
+            [
+                "3300000392c6ea",
+                "restricted",
+                "crc_ok",
+                "inner",
+                "group=member"
+            ]
+        );
+        assert_eq!(lines[4][1..], ["3300000392c6ea", "bad_crc", "crc_bad"]);
+        assert_eq!(lines[5][1..], ["01000000000043", "refresh", "added"]);
+        assert_eq!(lines[6][1..], ["01000000000042", "refresh", "removed"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_test() {
+        let dir = test_dir("audit-rotation");
+        let path = dir.join("audit.log");
+        let config = config::Audit {
+            path: path.clone(),
+            // Room for two records per file.
+            max_bytes: 120,
+            keep: 2,
+        };
+        let audit = AuditLog::new(Some(&config), false, Default::default()).unwrap();
+        for _ in 0..7 {
+            audit.record(
+                Some(&Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea])),
+                Decision::Granted,
+                Crc::Valid,
+                None,
+                None,
+            );
+        }
+
+        let count = |p: PathBuf| std::fs::read_to_string(p).unwrap().lines().count();
+        assert_eq!(count(path.clone()), 1);
+        assert_eq!(count(dir.join("audit.log.1")), 2);
+        assert_eq!(count(dir.join("audit.log.2")), 2);
+        assert!(!dir.join("audit.log.3").exists());
+
+        // Reopening continues the current file instead of truncating it.
+        drop(audit);
+        let audit = AuditLog::new(Some(&config), false, Default::default()).unwrap();
+        audit.record(None, Decision::ParseError, Crc::Unchecked, None, None);
+        assert_eq!(count(path.clone()), 2);
+
+        // A dry run leaves the file alone.
+        drop(audit);
+        let audit = AuditLog::new(Some(&config), true, Default::default()).unwrap();
+        audit.record(None, Decision::ParseError, Crc::Unchecked, None, None);
+        assert_eq!(count(path), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn untrusted_clock_test() {
+        let dir = test_dir("audit-clock");
+        let path = dir.join("audit.log");
+        let config = config::Audit {
+            path: path.clone(),
+            max_bytes: 1 << 20,
+            keep: 1,
+        };
+        let clock = Arc::new(Clock::new(config::Clock::default(), SystemTime::UNIX_EPOCH));
+        let audit = AuditLog::new(Some(&config), false, clock.clone()).unwrap();
+        audit.record(None, Decision::ExitButton, Crc::Unchecked, None, None);
+        clock.check(SystemTime::now());
+        audit.record(None, Decision::ExitButton, Crc::Unchecked, None, None);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert!(lines[0].ends_with(" exit_button crc_unchecked clock=untrusted"));
+        assert!(lines[1].ends_with(" exit_button crc_unchecked"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, Timelike};
+
+use crate::schedule::{is_within_schedule, Window};
+
+/// Holds the door of the first reader unlocked during the `auto_unlock` windows, e.g. for
+/// public events. Whether a window is on is derived from the clock alone, so a restart inside
+/// or after one ends up in the right state.
+#[derive(Default)]
+pub struct AutoUnlock {
+    windows: RwLock<Vec<Window>>,
+    active: AtomicBool,
+}
+
+impl AutoUnlock {
+    pub fn new(windows: Vec<Window>) -> AutoUnlock {
+        AutoUnlock {
+            windows: RwLock::new(windows),
+            active: AtomicBool::new(false),
+        }
+    }
+
+    /// Replaces the windows, for a reloaded configuration.
+    pub fn set_windows(&self, windows: Vec<Window>) {
+        *self.windows.write().unwrap() = windows;
+    }
+
+    pub fn is_configured(&self) -> bool {
+        !self.windows.read().unwrap().is_empty()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Decides whether the door should be held at the local time `at`, returning the new state
+    /// if it changed. A lockdown always wins.
+    pub fn update(&self, at: NaiveDateTime, lockdown: bool) -> Option<bool> {
+        let due = !lockdown && is_within_schedule(&self.windows.read().unwrap(), at);
+        (self.active.swap(due, Ordering::Relaxed) != due).then_some(due)
+    }
+
+    /// Time until the next minute starts, when windows may begin or end, for use as the event
+    /// loop's timeout.
+    pub fn timeout(&self, at: NaiveDateTime) -> Option<Duration> {
+        if !self.is_configured() {
+            return None;
+        }
+        let into_minute = Duration::new(at.second().into(), at.nanosecond() % 1_000_000_000);
+        Some(Duration::from_secs(60).saturating_sub(into_minute))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use chrono::NaiveDate;
+
+    use super::AutoUnlock;
+    use crate::schedule::Window;
+
+    #[test]
+    fn auto_unlock_follows_windows_test() {
+        let auto_unlock = AutoUnlock::new(vec![Window::parse("Sat 18:00-23:00").unwrap()]);
+        // 2024-06-08 was a Saturday.
+        let at = |hours, minutes, secs| {
+            NaiveDate::from_ymd_opt(2024, 6, 8)
+                .unwrap()
+                .and_hms_opt(hours, minutes, secs)
+                .unwrap()
+        };
+
+        // Starting inside a window holds the door right away.
+        assert_eq!(auto_unlock.update(at(19, 0, 0), false), Some(true));
+        assert_eq!(auto_unlock.update(at(19, 1, 0), false), None);
+        assert!(auto_unlock.is_active());
+        assert_eq!(
+            auto_unlock.timeout(at(19, 1, 15)),
+            Some(Duration::from_secs(45))
+        );
+
+        // A lockdown ends it, lifting the lockdown resumes it.
+        assert_eq!(auto_unlock.update(at(19, 2, 0), true), Some(false));
+        assert_eq!(auto_unlock.update(at(19, 3, 0), false), Some(true));
+        assert_eq!(auto_unlock.update(at(23, 0, 0), false), Some(false));
+
+        assert_eq!(AutoUnlock::default().timeout(at(19, 0, 0)), None);
+    }
+}
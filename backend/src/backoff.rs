@@ -0,0 +1,118 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::config;
+
+/// Exponential backoff with jitter as configured under `thing.backoff`.
+pub struct Backoff {
+    policy: config::Backoff,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(policy: &config::Backoff) -> Backoff {
+        Backoff {
+            policy: policy.clone(),
+            current: policy.initial,
+        }
+    }
+
+    /// Returns the delay to wait before the next attempt and grows the delay for the one after.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        let max = self.policy.max;
+        self.current = delay.mul_f64(self.policy.multiplier.max(1.0)).min(max);
+
+        jitter(delay, self.policy.jitter)
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.policy.initial;
+    }
+}
+
+/// Spreads `delay` randomly by up to the fraction `jitter` either way.
+pub fn jitter(delay: Duration, jitter: f64) -> Duration {
+    if jitter > 0.0 {
+        let jitter = jitter.min(1.0);
+        delay.mul_f64(rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter))
+    } else {
+        delay
+    }
+}
+
+/// How long an identical error is kept quiet before it is logged again.
+const REPEAT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Suppresses consecutive identical error messages so a persistent failure doesn't flood the log.
+#[derive(Default)]
+pub struct ErrorThrottle {
+    last: Option<(String, Instant)>,
+    suppressed: u64,
+}
+
+impl ErrorThrottle {
+    pub fn error(&mut self, message: String) {
+        if let Some((last, logged_at)) = &self.last {
+            if *last == message && logged_at.elapsed() < REPEAT_INTERVAL {
+                self.suppressed += 1;
+                return;
+            }
+        }
+        self.flush();
+        log::error!("{message}");
+        self.last = Some((message, Instant::now()));
+    }
+
+    /// Forgets the last error, e.g. after a success, reporting how often it was suppressed.
+    pub fn reset(&mut self) {
+        self.flush();
+        self.last = None;
+    }
+
+    fn flush(&mut self) {
+        if self.suppressed > 0 {
+            if let Some((last, _)) = &self.last {
+                log::error!(
+                    "Previous error repeated {} more times: {last}",
+                    self.suppressed
+                );
+            }
+            self.suppressed = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::config;
+
+    #[test]
+    fn backoff_grows_and_resets_test() {
+        let policy = config::Backoff {
+            initial: Duration::from_secs(1),
+            multiplier: 2.0,
+            max: Duration::from_secs(5),
+            jitter: 0.0,
+        };
+        let mut backoff = super::Backoff::new(&policy);
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs).to_vec());
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_test() {
+        let delay = Duration::from_secs(60);
+        assert_eq!(super::jitter(delay, 0.0), delay);
+        let delays: Vec<_> = (0..100).map(|_| super::jitter(delay, 0.1)).collect();
+        assert!(delays
+            .iter()
+            .all(|d| (Duration::from_secs(53)..=Duration::from_secs(67)).contains(d)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+}
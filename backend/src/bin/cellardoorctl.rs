@@ -0,0 +1,106 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+
+/// Sends a command to a running cellardoor over its control socket.
+#[derive(Parser, Debug)]
+struct Args {
+    #[clap(
+        short = 's',
+        long,
+        default_value = "/run/cellardoor/control.sock",
+        env = "CELLARDOOR_SOCKET"
+    )]
+    socket: PathBuf,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Show the access list size, the time of the last successful refresh and its staleness.
+    Status,
+    /// Fetch the key list from MOS right away.
+    Refresh {
+        /// Apply the list even if it removes more keys than the safety limits allow.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Open the door, by default that of the first reader.
+    Open {
+        /// Name of the reader whose door to open.
+        reader: Option<String>,
+    },
+    /// Refuse every key, including master keys, until lifted again.
+    Lockdown {
+        /// Lift the lockdown instead.
+        #[clap(long)]
+        off: bool,
+    },
+    /// Wait for an unknown key and record it as pending enrollment.
+    Enroll {
+        /// How long to wait for the key.
+        #[clap(long, default_value_t = 60)]
+        seconds: u64,
+        /// Name recorded with the key.
+        name: Option<String>,
+    },
+    /// Print the ids of all keys on the access list.
+    List,
+    /// Print when each key last opened the door.
+    Seen,
+    /// Print the unknown keys touched to a reader, with when they were first seen and how often.
+    Pending {
+        /// Forget this key, e.g. once it was entered into MOS.
+        #[clap(long)]
+        clear: Option<String>,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let command = match args.command {
+        Command::Status => "STATUS".to_owned(),
+        Command::Refresh { force: false } => "REFRESH".to_owned(),
+        Command::Refresh { force: true } => "REFRESH FORCE".to_owned(),
+        Command::Open { reader: None } => "OPEN".to_owned(),
+        Command::Open {
+            reader: Some(reader),
+        } => format!("OPEN {reader}"),
+        Command::Lockdown { off: false } => "LOCKDOWN ON".to_owned(),
+        Command::Lockdown { off: true } => "LOCKDOWN OFF".to_owned(),
+        Command::Enroll {
+            seconds,
+            name: None,
+        } => format!("ENROLL {seconds}"),
+        Command::Enroll {
+            seconds,
+            name: Some(name),
+        } => format!("ENROLL {seconds} {name}"),
+        Command::List => "LIST".to_owned(),
+        Command::Seen => "SEEN".to_owned(),
+        Command::Pending { clear: None } => "PENDING".to_owned(),
+        Command::Pending { clear: Some(id) } => format!("PENDING CLEAR {id}"),
+    };
+
+    let mut stream = UnixStream::connect(&args.socket)
+        .context(format!("Failed to connect to {:?}", args.socket))?;
+    writeln!(stream, "{command}")?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == "OK" {
+            return Ok(());
+        }
+        if let Some(reason) = line.strip_prefix("ERR ") {
+            anyhow::bail!("{reason}");
+        }
+        println!("{line}");
+    }
+    anyhow::bail!("Connection closed before the command completed")
+}
@@ -0,0 +1,165 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex, Weak},
+    time::Duration,
+};
+
+/// A published message and its sequence number, which counts up from 1 without gaps.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Message {
+    pub seq: u64,
+    pub data: String,
+}
+
+/// Fans messages out to subscribers without ever blocking the publisher: every subscriber has
+/// a queue of its own, bounded like the replay buffer, which drops its oldest message when a
+/// new one doesn't fit. Subscribers notice from the gap in the sequence numbers.
+pub struct Broadcast {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    next_seq: u64,
+    /// The last `capacity` messages, for subscribers resuming after a message they saw.
+    recent: VecDeque<Arc<Message>>,
+    subscribers: Vec<Weak<Queue>>,
+}
+
+#[derive(Default)]
+struct Queue {
+    messages: Mutex<VecDeque<Arc<Message>>>,
+    cvar: Condvar,
+}
+
+/// Receives the messages published after subscribing; dropping it unsubscribes.
+pub struct Subscription {
+    queue: Arc<Queue>,
+}
+
+impl Broadcast {
+    pub fn new(capacity: usize) -> Broadcast {
+        Broadcast {
+            capacity,
+            state: Mutex::new(State {
+                next_seq: 1,
+                recent: VecDeque::with_capacity(capacity),
+                subscribers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Publishes what `data` makes of the next sequence number, if anything, and returns it.
+    pub fn publish(&self, data: impl FnOnce(u64) -> Option<String>) -> Option<Arc<Message>> {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.next_seq;
+        let message = Arc::new(Message {
+            seq,
+            data: data(seq)?,
+        });
+        state.next_seq += 1;
+        push(&mut state.recent, message.clone(), self.capacity);
+        state.subscribers.retain(|subscriber| {
+            let Some(queue) = subscriber.upgrade() else {
+                return false;
+            };
+            push(
+                &mut queue.messages.lock().unwrap(),
+                message.clone(),
+                self.capacity,
+            );
+            queue.cvar.notify_one();
+            true
+        });
+        Some(message)
+    }
+
+    /// Subscribes to later messages, first replaying the buffered ones after `last_seen`.
+    pub fn subscribe(&self, last_seen: Option<u64>) -> Subscription {
+        let mut state = self.state.lock().unwrap();
+        let replay = last_seen.map_or(VecDeque::new(), |last_seen| {
+            state
+                .recent
+                .iter()
+                .filter(|message| message.seq > last_seen)
+                .cloned()
+                .collect()
+        });
+        let queue = Arc::new(Queue {
+            messages: Mutex::new(replay),
+            cvar: Condvar::new(),
+        });
+        state.subscribers.push(Arc::downgrade(&queue));
+        Subscription { queue }
+    }
+
+    /// How many subscriptions are alive.
+    pub fn subscribers(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.strong_count() > 0);
+        state.subscribers.len()
+    }
+}
+
+impl Default for Broadcast {
+    fn default() -> Self {
+        Broadcast::new(256)
+    }
+}
+
+impl Subscription {
+    /// The next message, waiting at most `timeout` for one.
+    pub fn next(&self, timeout: Duration) -> Option<Arc<Message>> {
+        let messages = self.queue.messages.lock().unwrap();
+        let (mut messages, _) = self
+            .queue
+            .cvar
+            .wait_timeout_while(messages, timeout, |messages| messages.is_empty())
+            .unwrap();
+        messages.pop_front()
+    }
+}
+
+fn push(queue: &mut VecDeque<Arc<Message>>, message: Arc<Message>, capacity: usize) {
+    while queue.len() >= capacity.max(1) {
+        queue.pop_front();
+    }
+    queue.push_back(message);
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Broadcast;
+
+    #[test]
+    fn slow_subscribers_lose_oldest_messages_test() {
+        let broadcast = Broadcast::new(3);
+        let publish = |data: &str| broadcast.publish(|_| Some(data.to_owned())).unwrap().seq;
+        assert_eq!(publish("a"), 1);
+        assert!(broadcast.publish(|_| None).is_none());
+        assert_eq!(publish("b"), 2);
+
+        let fresh = broadcast.subscribe(None);
+        let resumed = broadcast.subscribe(Some(1));
+        for data in ["c", "d", "e"] {
+            publish(data);
+        }
+        let seqs = |subscription: &super::Subscription| {
+            std::iter::from_fn(|| subscription.next(Duration::ZERO))
+                .map(|message| message.seq)
+                .collect::<Vec<_>>()
+        };
+        // Both kept only the newest three of what they hadn't read.
+        assert_eq!(seqs(&fresh), [3, 4, 5]);
+        assert_eq!(seqs(&resumed), [3, 4, 5]);
+        assert_eq!(seqs(&broadcast.subscribe(Some(3))), [4, 5]);
+
+        assert_eq!(broadcast.subscribers(), 2);
+        drop(fresh);
+        assert_eq!(broadcast.subscribers(), 1);
+    }
+}
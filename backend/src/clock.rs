@@ -0,0 +1,94 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::SystemTime,
+};
+
+use crate::config;
+
+/// Whether the system clock can be believed yet, see [`config::Clock`]. Once trusted it stays
+/// so; everything timed relative rather than by the wall clock runs on `Instant` regardless.
+pub struct Clock {
+    config: config::Clock,
+    trusted: AtomicBool,
+}
+
+impl Default for Clock {
+    /// A trusted clock.
+    fn default() -> Clock {
+        Clock {
+            config: config::Clock::default(),
+            trusted: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Clock {
+    pub fn new(config: config::Clock, now: SystemTime) -> Clock {
+        let trusted = now >= config.not_before;
+        if !trusted {
+            log::warn!(
+                "System clock reads {}, before {}; not trusting it until it jumps forward",
+                humantime::format_rfc3339_seconds(now),
+                humantime::format_rfc3339_seconds(config.not_before)
+            );
+        }
+        Clock {
+            config,
+            trusted: AtomicBool::new(trusted),
+        }
+    }
+
+    pub fn is_trusted(&self) -> bool {
+        self.trusted.load(Ordering::Relaxed)
+    }
+
+    /// What keys with an expiry or schedule get while the clock isn't trusted.
+    pub fn untrusted(&self) -> config::Untrusted {
+        self.config.untrusted
+    }
+
+    pub fn not_before(&self) -> SystemTime {
+        self.config.not_before
+    }
+
+    /// Looks at the clock again, returning `true` once when it has just become trusted.
+    pub fn check(&self, now: SystemTime) -> bool {
+        if self.is_trusted() || now < self.config.not_before {
+            return false;
+        }
+        self.trusted.store(true, Ordering::Relaxed);
+        log::info!(
+            "System clock jumped forward to {}, trusting it from now on",
+            humantime::format_rfc3339_seconds(now)
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::Clock;
+    use crate::config;
+
+    #[test]
+    fn clock_jump_test() {
+        let config = config::Clock::default();
+        let boot = SystemTime::UNIX_EPOCH + Duration::from_secs(5);
+        let clock = Clock::new(config.clone(), boot);
+        assert!(!clock.is_trusted());
+        assert!(!clock.check(boot + Duration::from_secs(60)));
+        assert!(!clock.is_trusted());
+
+        let synced = config.not_before + Duration::from_secs(86400 * 600);
+        assert!(clock.check(synced));
+        assert!(clock.is_trusted());
+        // Only the jump itself is reported, and a step back doesn't undo it.
+        assert!(!clock.check(synced));
+        assert!(!clock.check(boot));
+        assert!(clock.is_trusted());
+
+        assert!(Clock::new(config, synced).is_trusted());
+    }
+}
@@ -0,0 +1,683 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs::Permissions,
+    io::{self, Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use mio::{
+    net::{UnixListener, UnixStream},
+    Interest, Registry, Token,
+};
+
+use crate::{
+    access::{retry_secs, Access, OpenRefusal, Reader},
+    enroll::Outcome,
+    guests::Guest,
+    privacy,
+    wakeup::Wakeup,
+    Credential,
+};
+
+/// Longest command line accepted before a client is disconnected.
+const MAX_LINE: usize = 256;
+/// How many of the most denied keys STATUS lists.
+const TOP_DENIED: usize = 5;
+/// How many events HISTORY lists without a count.
+const DEFAULT_HISTORY: usize = 20;
+/// Client connections get tokens from here upwards; lower ones belong to the main loop.
+const FIRST_CLIENT: usize = 1 << 16;
+
+/// Line-based command socket served from the main event loop.
+///
+/// Every command is answered by zero or more lines of output followed by `OK` or
+/// `ERR <reason>`. Commands only touch shared state, so they never block key handling.
+pub struct Control {
+    path: PathBuf,
+    listener: UnixListener,
+    token: Token,
+    clients: HashMap<Token, Client>,
+    next_client: usize,
+}
+
+struct Client {
+    stream: UnixStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    closing: bool,
+    /// Waiting for an `ENROLL` to finish; further commands are held back until then.
+    enrolling: bool,
+}
+
+impl Control {
+    /// Binds the socket at `path`, replacing a stale one, and registers it under `token`.
+    pub fn bind(path: &Path, registry: &Registry, token: Token) -> anyhow::Result<Control> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context(format!(
+                "Failed to create control socket directory {parent:?}"
+            ))?;
+        }
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).context(format!("Failed to remove stale control socket {path:?}"));
+            }
+            _ => {}
+        }
+        let mut listener =
+            UnixListener::bind(path).context(format!("Failed to bind control socket {path:?}"))?;
+        std::fs::set_permissions(path, Permissions::from_mode(0o660))?;
+        registry.register(&mut listener, token, Interest::READABLE)?;
+        Ok(Control {
+            path: path.to_owned(),
+            listener,
+            token,
+            clients: HashMap::new(),
+            next_client: FIRST_CLIENT,
+        })
+    }
+
+    /// Whether `token` belongs to the listener or one of its connections.
+    pub fn handles(&self, token: Token) -> bool {
+        token == self.token || self.clients.contains_key(&token)
+    }
+
+    pub fn ready(&mut self, registry: &Registry, token: Token, access: &Access, wakeup: &Wakeup) {
+        if token == self.token {
+            self.accept(registry);
+            return;
+        }
+        let Some(client) = self.clients.get_mut(&token) else {
+            return;
+        };
+        if !client.ready(access, wakeup) {
+            let _ = registry.deregister(&mut client.stream);
+            self.clients.remove(&token);
+        }
+    }
+
+    /// Answers the client waiting on `ENROLL` once the enrollment has ended.
+    pub fn finish_enrollment(&mut self, registry: &Registry, access: &Access, wakeup: &Wakeup) {
+        let Some(outcome) = access.enroller.finish(Instant::now()) else {
+            return;
+        };
+        let Some((&token, client)) = self.clients.iter_mut().find(|(_, c)| c.enrolling) else {
+            log::debug!("Enrollment client went away, dropping {outcome:?}");
+            return;
+        };
+        let response = match outcome {
+            Outcome::Enrolled(id) => format!("{}\nOK\n", id).to_string(),
+            Outcome::Failed(reason) => format!("ERR {reason}\n"),
+            Outcome::TimedOut => "ERR timeout\n".to_owned(),
+        };
+        client.output.extend_from_slice(response.as_bytes());
+        client.enrolling = false;
+        if !client.ready(access, wakeup) {
+            let _ = registry.deregister(&mut client.stream);
+            self.clients.remove(&token);
+        }
+    }
+
+    fn accept(&mut self, registry: &Registry) {
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, _)) => {
+                    let token = Token(self.next_client);
+                    self.next_client += 1;
+                    let interest = Interest::READABLE | Interest::WRITABLE;
+                    if let Err(e) = registry.register(&mut stream, token, interest) {
+                        log::error!("Failed to register control connection: {e:?}");
+                        continue;
+                    }
+                    self.clients.insert(
+                        token,
+                        Client {
+                            stream,
+                            input: Vec::new(),
+                            output: Vec::new(),
+                            closing: false,
+                            enrolling: false,
+                        },
+                    );
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log::error!("Failed to accept control connection: {e:?}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Client {
+    /// Reads and answers whatever is available; returns whether the connection stays open.
+    fn ready(&mut self, access: &Access, wakeup: &Wakeup) -> bool {
+        let mut buf = [0u8; 256];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.closing = true;
+                    break;
+                }
+                Ok(len) => self.input.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log::debug!("Control connection failed: {e:?}");
+                    return false;
+                }
+            }
+        }
+
+        while let Some(end) = self.input.iter().position(|&b| b == b'\n') {
+            if self.enrolling {
+                break;
+            }
+            let line: Vec<u8> = self.input.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if line.to_ascii_uppercase().starts_with("ENROLL") {
+                match start_enrollment(line, access) {
+                    Ok(()) => self.enrolling = true,
+                    Err(e) => self.output.extend_from_slice(format!("{e}\n").as_bytes()),
+                }
+                continue;
+            }
+            let response = execute(line, access, wakeup, "control socket");
+            self.output.extend_from_slice(response.as_bytes());
+        }
+        if self.input.len() > MAX_LINE {
+            self.output.extend_from_slice(b"ERR line too long\n");
+            self.input.clear();
+            self.closing = true;
+        }
+
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(len) => {
+                    self.output.drain(..len);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    log::debug!("Control connection failed: {e:?}");
+                    return false;
+                }
+            }
+        }
+        !self.closing || self.enrolling
+    }
+}
+
+/// Handles `ENROLL <seconds> [name]`, whose answer is sent by `Control::finish_enrollment`.
+fn start_enrollment(line: &str, access: &Access) -> Result<(), String> {
+    let mut args = line.splitn(3, ' ').skip(1);
+    let seconds = args
+        .next()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .filter(|&seconds| seconds > 0)
+        .ok_or("ERR usage: ENROLL <seconds> [name]")?;
+    let name = args.next().map(|name| name.trim().to_owned());
+    if access
+        .enroller
+        .start(Duration::from_secs(seconds), name, Instant::now())
+    {
+        Ok(())
+    } else {
+        Err("ERR enrollment already running".to_owned())
+    }
+}
+
+/// Parses `<id> <ttl> [name]` and adds the guest.
+fn guest_add(args: &str, access: &Access) -> Result<Guest, String> {
+    const USAGE: &str = "ERR usage: GUEST ADD <id> <ttl> [name]";
+    let mut args = args.trim().splitn(3, ' ');
+    let id =
+        Credential::parse(args.next().ok_or(USAGE)?).map_err(|e| format!("ERR invalid id: {e}"))?;
+    let ttl = humantime::parse_duration(args.next().ok_or(USAGE)?)
+        .map_err(|e| format!("ERR invalid ttl: {e}"))?;
+    let name = args.next().unwrap_or_default().trim();
+    access
+        .guests
+        .add(&id, ttl, name, SystemTime::now())
+        .map_err(|e| format!("ERR {e:#}"))
+}
+
+fn open_door(out: &mut String, access: &Access, reader: &Reader, via: &str) {
+    match access.remote_open(reader, via) {
+        Ok(()) => out.push_str("OK\n"),
+        Err(OpenRefusal::Lockdown) => out.push_str("ERR lockdown\n"),
+        Err(OpenRefusal::RateLimited(retry)) => {
+            let _ = writeln!(out, "ERR rate limited, retry after {}s", retry_secs(retry));
+        }
+    }
+}
+
+/// Runs a single command and returns its complete response.
+pub fn execute(command: &str, access: &Access, wakeup: &Wakeup, via: &str) -> String {
+    let mut out = String::new();
+    match command.to_ascii_uppercase().as_str() {
+        "STATUS" => {
+            let last_refresh = match access.metrics.last_refresh.get() {
+                0 => "never".to_owned(),
+                secs => humantime::format_rfc3339_seconds(
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                )
+                .to_string(),
+            };
+            if access.dry_run {
+                let _ = writeln!(out, "dry_run");
+            }
+            let _ = writeln!(out, "keys {}", access.access_list.len());
+            let _ = writeln!(out, "last_refresh {last_refresh}");
+            match access.metrics.next_refresh.get() {
+                0 => {}
+                secs => {
+                    let next = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+                    let _ = writeln!(
+                        out,
+                        "next_refresh {}",
+                        humantime::format_rfc3339_seconds(next)
+                    );
+                }
+            }
+            if let Some((_, url)) = &*access.metrics.list_source.lock().unwrap() {
+                let _ = writeln!(out, "list_source {url}");
+            }
+            match access.metrics.consecutive_fetch_failures.get() {
+                0 => {}
+                failures => {
+                    let _ = writeln!(out, "refresh_failures {failures}");
+                }
+            }
+            let lockdown = if access.lockdown.is_active() {
+                "on"
+            } else {
+                "off"
+            };
+            let _ = writeln!(out, "lockdown {lockdown}");
+            if access.auto_unlock.is_configured() {
+                let active = if access.auto_unlock.is_active() {
+                    "on"
+                } else {
+                    "off"
+                };
+                let _ = writeln!(out, "auto_unlock {active}");
+            }
+            if let Some((tokens, burst)) = access.open_limit.level(Instant::now()) {
+                let _ = writeln!(out, "open_tokens {tokens:.1}/{burst}");
+            }
+            let age = access.metrics.list_age(SystemTime::now());
+            let _ = writeln!(
+                out,
+                "staleness {}",
+                access.staleness.read().unwrap().level(age)
+            );
+            if access.metrics.door_sensor.load(Ordering::Relaxed) {
+                let door = match access.metrics.door_open.get() {
+                    0 => "closed",
+                    _ => "open",
+                };
+                let _ = writeln!(out, "door {door}");
+                if access.metrics.door_alarm.get() != 0 {
+                    let _ = writeln!(out, "door_alarm");
+                }
+            }
+            if access.reader_health.is_configured() {
+                let reader = match access.reader_health.is_silent() {
+                    true => "silent",
+                    false => "active",
+                };
+                let _ = writeln!(out, "reader {reader}");
+            }
+            for (id, count) in access.denials.lock().unwrap().top(TOP_DENIED) {
+                let _ = writeln!(out, "denied {} {count}", privacy::id(&id));
+            }
+            out.push_str("OK\n");
+        }
+        "REFRESH" => {
+            if wakeup.request_refresh() {
+                out.push_str("OK\n");
+            } else {
+                out.push_str("ERR refresh already running\n");
+            }
+        }
+        "REFRESH FORCE" => {
+            if wakeup.request_forced_refresh() {
+                log::warn!("Forced key list refresh requested via {via}");
+                out.push_str("OK\n");
+            } else {
+                out.push_str("ERR refresh already running\n");
+            }
+        }
+        "OPEN" => open_door(&mut out, access, &access.readers[0], via),
+        open if open.starts_with("OPEN ") => {
+            let name = command["OPEN ".len()..].trim();
+            match access.readers.iter().find(|reader| {
+                reader
+                    .name
+                    .as_ref()
+                    .is_some_and(|n| n.eq_ignore_ascii_case(name))
+            }) {
+                Some(reader) => open_door(&mut out, access, reader, via),
+                None => {
+                    let _ = writeln!(out, "ERR unknown reader {name:?}");
+                }
+            }
+        }
+        "LOCKDOWN ON" => {
+            log::warn!("Lockdown enabled via {via}, refusing all keys");
+            match access.set_lockdown(true) {
+                Ok(()) => out.push_str("OK\n"),
+                Err(e) => {
+                    let _ = writeln!(out, "ERR lockdown enabled, but {e:#}");
+                }
+            }
+        }
+        "LOCKDOWN OFF" => {
+            log::warn!("Lockdown lifted via {via}");
+            match access.set_lockdown(false) {
+                Ok(()) => out.push_str("OK\n"),
+                Err(e) => {
+                    let _ = writeln!(out, "ERR lockdown lifted, but {e:#}");
+                }
+            }
+        }
+        "LIST" => {
+            for entry in access.access_list.iter() {
+                let _ = writeln!(out, "{}", entry.key().hex());
+            }
+            out.push_str("OK\n");
+        }
+        "PENDING" => {
+            for sighting in access.sightings.entries() {
+                let _ = writeln!(
+                    out,
+                    "{} {} {}",
+                    sighting.id.hex(),
+                    humantime::format_rfc3339_seconds(sighting.first_seen),
+                    sighting.count
+                );
+            }
+            out.push_str("OK\n");
+        }
+        clear if clear.starts_with("PENDING CLEAR ") => {
+            let id = command["PENDING CLEAR ".len()..].trim();
+            match Credential::parse(id).map(|id| access.sightings.clear(&id)) {
+                Ok(Ok(true)) => {
+                    log::info!("Pending key {} cleared via {via}", privacy::raw(id));
+                    out.push_str("OK\n");
+                }
+                Ok(Ok(false)) => out.push_str("ERR not pending\n"),
+                Ok(Err(e)) => {
+                    let _ = writeln!(out, "ERR {e:#}");
+                }
+                Err(e) => {
+                    let _ = writeln!(out, "ERR invalid id: {e}");
+                }
+            }
+        }
+        add if add.starts_with("GUEST ADD ") => {
+            match guest_add(&command["GUEST ADD ".len()..], access) {
+                Ok(guest) => {
+                    log::info!(
+                        "Guest key {} ({:?}) added via {via} until {}",
+                        privacy::id(&guest.id),
+                        guest.name,
+                        humantime::format_rfc3339_seconds(guest.expires)
+                    );
+                    let _ = writeln!(
+                        out,
+                        "{}\nOK",
+                        humantime::format_rfc3339_seconds(guest.expires)
+                    );
+                }
+                Err(e) => {
+                    let _ = writeln!(out, "{e}");
+                }
+            }
+        }
+        "GUEST LIST" => {
+            for guest in access.guests.entries() {
+                let _ = writeln!(
+                    out,
+                    "{} {} {}",
+                    guest.id.hex(),
+                    humantime::format_rfc3339_seconds(guest.expires),
+                    guest.name
+                );
+            }
+            out.push_str("OK\n");
+        }
+        revoke if revoke.starts_with("GUEST REVOKE ") => {
+            let id = command["GUEST REVOKE ".len()..].trim();
+            match Credential::parse(id).map(|id| access.guests.revoke(&id)) {
+                Ok(Ok(true)) => {
+                    log::info!("Guest key {} revoked via {via}", privacy::raw(id));
+                    out.push_str("OK\n");
+                }
+                Ok(Ok(false)) => out.push_str("ERR not a guest\n"),
+                Ok(Err(e)) => {
+                    let _ = writeln!(out, "ERR {e:#}");
+                }
+                Err(e) => {
+                    let _ = writeln!(out, "ERR invalid id: {e}");
+                }
+            }
+        }
+        clear if clear.starts_with("PASSBACK CLEAR ") => {
+            let id = command["PASSBACK CLEAR ".len()..].trim();
+            match Credential::parse(id) {
+                Ok(parsed)
+                    if access
+                        .passback
+                        .lock()
+                        .unwrap()
+                        .clear(&parsed, Instant::now()) =>
+                {
+                    log::info!(
+                        "Anti-passback of key {} cleared via {via}",
+                        privacy::raw(id)
+                    );
+                    out.push_str("OK\n");
+                }
+                Ok(_) => out.push_str("ERR not refused\n"),
+                Err(e) => {
+                    let _ = writeln!(out, "ERR invalid id: {e}");
+                }
+            }
+        }
+        history if history == "HISTORY" || history.starts_with("HISTORY ") => {
+            let limit = command["HISTORY".len()..].trim();
+            let limit = match limit {
+                "" => Ok(DEFAULT_HISTORY),
+                limit => limit.parse::<usize>(),
+            };
+            match limit {
+                Ok(limit) => {
+                    for event in access.events.history(limit) {
+                        let _ = writeln!(out, "{event}");
+                    }
+                    out.push_str("OK\n");
+                }
+                Err(e) => {
+                    let _ = writeln!(out, "ERR invalid count: {e}");
+                }
+            }
+        }
+        "SEEN" => {
+            for (id, seen) in access.last_seen.entries() {
+                let _ = writeln!(
+                    out,
+                    "{} {}",
+                    id.hex(),
+                    humantime::format_rfc3339_seconds(seen)
+                );
+            }
+            out.push_str("OK\n");
+        }
+        _ => {
+            let _ = writeln!(out, "ERR unknown command {command:?}");
+        }
+    }
+    out
+}
+
+/// Sends `command` to the daemon whose socket is at `path`, as `cellardoorctl` does, returning
+/// the lines answered before `OK`, or the reason after `ERR` as the inner error. Connecting
+/// fails with `NotFound` or `ConnectionRefused` when no daemon listens there.
+pub fn request(path: &Path, command: &str) -> io::Result<Result<Vec<String>, String>> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    writeln!(stream, "{command}")?;
+    let mut lines = Vec::new();
+    for line in io::BufRead::lines(io::BufReader::new(stream)) {
+        let line = line?;
+        if line == "OK" {
+            return Ok(Ok(lines));
+        }
+        if let Some(reason) = line.strip_prefix("ERR ") {
+            return Ok(Err(reason.to_owned()));
+        }
+        lines.push(line);
+    }
+    Err(io::ErrorKind::UnexpectedEof.into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        time::Duration,
+    };
+
+    use mio::{Events, Poll, Token};
+
+    use super::Control;
+    use crate::{
+        access::Access,
+        ratelimit::RateLimit,
+        testutil::{access, test_dir},
+        wakeup::Wakeup,
+        Credential,
+    };
+
+    #[test]
+    fn socket_commands_test() {
+        let dir = test_dir("control");
+        let path = dir.join("control.sock");
+        let access = access(
+            &[Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea])],
+            &[],
+            &[],
+        );
+        let wakeup = Wakeup::default();
+
+        let mut poll = Poll::new().unwrap();
+        let mut control = Control::bind(&path, poll.registry(), Token(0)).unwrap();
+        let client = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let mut stream = std::os::unix::net::UnixStream::connect(path).unwrap();
+                stream
+                    .write_all(
+                        b"STATUS\nlist\nOPEN\nREFRESH\nREFRESH\nHISTORY 5\nHISTORY x\nBOGUS\n",
+                    )
+                    .unwrap();
+                stream.shutdown(std::net::Shutdown::Write).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            }
+        });
+
+        let mut events = Events::with_capacity(16);
+        while !client.is_finished() {
+            poll.poll(&mut events, Some(Duration::from_millis(100)))
+                .unwrap();
+            for event in &events {
+                assert!(control.handles(event.token()));
+                control.ready(poll.registry(), event.token(), &access, &wakeup);
+            }
+        }
+
+        assert_eq!(
+            client.join().unwrap(),
+            "keys 1\nlast_refresh never\nlockdown off\nstaleness fresh\nOK\n\
+             3300000392c6ea\nOK\n\
+             OK\n\
+             OK\n\
+             OK\n\
+             OK\n\
+             ERR invalid count: invalid digit found in string\n\
+             ERR unknown command \"BOGUS\"\n"
+        );
+        assert!(access.readers[0].door.is_unlocked());
+
+        // Remote opens share one bucket; the next is refused until it refills.
+        let limit = serde_yaml_ng::from_str("burst: 1\nrefill: 1h").unwrap();
+        let access = Access {
+            open_limit: RateLimit::new(Some(limit)),
+            ..access
+        };
+        assert_eq!(super::execute("OPEN", &access, &wakeup, "test"), "OK\n");
+        assert_eq!(
+            super::execute("OPEN", &access, &wakeup, "test"),
+            "ERR rate limited, retry after 3600s\n"
+        );
+
+        // A lockdown keeps the door shut before the limit is asked.
+        access.lockdown.set(true).unwrap();
+        assert_eq!(
+            super::execute("OPEN", &access, &wakeup, "test"),
+            "ERR lockdown\n"
+        );
+        access.lockdown.set(false).unwrap();
+        drop(control);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn request_test() {
+        let dir = test_dir("control-request");
+        let path = dir.join("control.sock");
+        let err = super::request(&path, "REFRESH").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut commands = Vec::new();
+            for reply in ["keys 1\nOK\n", "ERR refresh already running\n"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut command = [0; 8];
+                stream.read_exact(&mut command).unwrap();
+                commands.push(String::from_utf8(command.to_vec()).unwrap());
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            commands
+        });
+        assert_eq!(
+            super::request(&path, "REFRESH").unwrap(),
+            Ok(vec!["keys 1".to_owned()])
+        );
+        assert_eq!(
+            super::request(&path, "REFRESH").unwrap(),
+            Err("refresh already running".to_owned())
+        );
+        assert_eq!(server.join().unwrap(), ["REFRESH\n"; 2]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
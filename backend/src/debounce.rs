@@ -0,0 +1,77 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::Credential;
+
+/// Suppresses repeated sightings of the same key, e.g. the add/remove bursts of a key held
+/// against the reader.
+#[derive(Default)]
+pub struct Debounce {
+    window: Duration,
+    /// When each key was last let through; entries older than `window` are removed by
+    /// [`Debounce::prune`].
+    accepted: HashMap<Credential, Instant>,
+}
+
+impl Debounce {
+    pub fn new(window: Duration) -> Debounce {
+        Debounce {
+            window,
+            accepted: HashMap::new(),
+        }
+    }
+
+    /// Whether a sighting of `id` at `now` should be processed.
+    pub fn accept(&mut self, id: &Credential, now: Instant) -> bool {
+        let window = self.window;
+        if self
+            .accepted
+            .get(id)
+            .is_some_and(|accepted| now.saturating_duration_since(*accepted) < window)
+        {
+            return false;
+        }
+        if !window.is_zero() {
+            self.accepted.insert(*id, now);
+        }
+        true
+    }
+
+    /// Forgets keys whose window has ended at `now`, so the state stays bounded.
+    pub fn prune(&mut self, now: Instant) {
+        let window = self.window;
+        self.accepted
+            .retain(|_, accepted| now.saturating_duration_since(*accepted) < window);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::Debounce;
+    use crate::Credential;
+
+    #[test]
+    fn interleaved_keys_do_not_suppress_each_other_test() {
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let mut debounce = Debounce::new(Duration::from_secs(3));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(debounce.accept(&alice, at(0)));
+        assert!(debounce.accept(&bob, at(1)));
+        assert!(!debounce.accept(&alice, at(1)));
+        assert!(!debounce.accept(&bob, at(2)));
+        assert!(debounce.accept(&alice, at(3)));
+        assert!(debounce.accept(&bob, at(4)));
+
+        // Expired entries are pruned, so the state stays bounded.
+        assert!(debounce.accept(&alice, at(10)));
+        debounce.prune(at(10));
+        assert_eq!(debounce.accepted.len(), 1);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{config, privacy, Credential};
+
+/// Aggregates denials of unknown keys, so a stray sensor on the bus or someone trying a dead
+/// key over and over yields one log line per window rather than one per sighting.
+#[derive(Default)]
+pub struct Denials {
+    window: Duration,
+    /// Beyond this many keys the least recently denied one is forgotten.
+    capacity: usize,
+    keys: HashMap<Credential, Denial>,
+}
+
+struct Denial {
+    /// Start of the current window, in which only the first denial was logged.
+    since: Instant,
+    in_window: u64,
+    total: u64,
+    last: Instant,
+}
+
+impl Denials {
+    pub fn new(config: &config::Denials) -> Denials {
+        Denials {
+            window: config.window,
+            capacity: config.max_tracked,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Counts a denial of `id` and returns whether it should be logged, which is only the
+    /// case for the first one in a window.
+    pub fn record(&mut self, id: &Credential, now: Instant) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        if let Some(denial) = self.keys.get_mut(id) {
+            denial.total += 1;
+            denial.last = now;
+            if now.saturating_duration_since(denial.since) < self.window {
+                denial.in_window += 1;
+                return false;
+            }
+            summarize(id, denial, self.window);
+            denial.since = now;
+            denial.in_window = 1;
+            return true;
+        }
+        if self.keys.len() >= self.capacity {
+            let oldest = self.keys.iter().min_by_key(|(_, denial)| denial.last);
+            if let Some((&oldest, _)) = oldest {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.insert(
+            *id,
+            Denial {
+                since: now,
+                in_window: 1,
+                total: 1,
+                last: now,
+            },
+        );
+        true
+    }
+
+    /// Logs a summary for every key denied repeatedly in a window that has ended.
+    pub fn summarize(&mut self, now: Instant) {
+        let window = self.window;
+        for (id, denial) in &mut self.keys {
+            if now.saturating_duration_since(denial.since) >= window {
+                summarize(id, denial, window);
+            }
+        }
+    }
+
+    /// Time until the next summary is due, for use as the event loop's timeout.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.keys
+            .values()
+            .filter(|denial| denial.in_window > 1)
+            .map(|denial| (denial.since + self.window).saturating_duration_since(now))
+            .min()
+    }
+
+    /// The `count` most denied keys still tracked, with their number of denials.
+    pub fn top(&self, count: usize) -> Vec<(Credential, u64)> {
+        let mut top: Vec<_> = self
+            .keys
+            .iter()
+            .map(|(id, denial)| (*id, denial.total))
+            .collect();
+        top.sort_by(|(a_id, a), (b_id, b)| b.cmp(a).then(a_id.cmp(b_id)));
+        top.truncate(count);
+        top
+    }
+}
+
+fn summarize(id: &Credential, denial: &mut Denial, window: Duration) {
+    if denial.in_window > 1 {
+        log::info!(
+            "Key {} denied {} times in the last {}",
+            privacy::id(id),
+            denial.in_window,
+            humantime::format_duration(window)
+        );
+    }
+    denial.in_window = 0;
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::Denials;
+    use crate::{config, Credential};
+
+    #[test]
+    fn repeated_denials_are_aggregated_test() {
+        let mut denials = Denials::new(&config::Denials {
+            window: Duration::from_secs(3600),
+            max_tracked: 2,
+        });
+        let sensor = Credential::OneWire([0x28, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let dead = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let random = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(denials.record(&sensor, at(0)));
+        assert!(!denials.record(&sensor, at(10)));
+        assert!(!denials.record(&sensor, at(20)));
+        assert!(denials.record(&dead, at(30)));
+        assert_eq!(denials.timeout(at(30)), Some(Duration::from_secs(3570)));
+        assert_eq!(denials.top(5), [(sensor, 3), (dead, 1)]);
+
+        // The summary resets the window's count, the next denial is logged again.
+        denials.summarize(at(3600));
+        assert_eq!(denials.timeout(at(3600)), None);
+        assert!(denials.record(&sensor, at(3601)));
+        assert_eq!(denials.top(1), [(sensor, 4)]);
+
+        // At capacity the least recently denied key makes room.
+        assert!(denials.record(&random, at(3602)));
+        assert_eq!(denials.top(5), [(sensor, 4), (random, 1)]);
+
+        // Without tracking every denial is logged.
+        let mut untracked = Denials::default();
+        assert!(untracked.record(&sensor, at(0)));
+        assert!(untracked.record(&sensor, at(1)));
+        assert!(untracked.top(5).is_empty());
+    }
+}
@@ -0,0 +1,470 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{config, gpio};
+
+/// Electric strike driven by a single GPIO output line.
+///
+/// Unlocking energizes the line right away; the event loop re-locks it once
+/// [`Door::deadline`] has passed, see [`Door::relock`]. Repeated unlocks while the door is
+/// open push the re-lock deadline further out. Clones share the line and the unlock duration.
+///
+/// Every unlock and hold goes through [`Duty`], which enforces `door.safety` whichever code path
+/// asked for it.
+#[derive(Clone)]
+pub struct Door {
+    state: Arc<Mutex<State>>,
+    unlock_ms: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct State {
+    output: Output,
+    energized: bool,
+    /// When the running unlock ends.
+    until: Option<Instant>,
+    /// Unlocked until released, see [`Door::hold`].
+    held: bool,
+    /// When `door.safety` ends the hold, unless the strike is exempt.
+    hold_until: Option<Instant>,
+    /// An unlock, or a hold if `None`, waiting for `min_off` to pass.
+    pending: Option<(Instant, Option<Duration>)>,
+    duty: Duty,
+    /// Wakes the event loop for unlocks from other threads, whose deadline it must learn of.
+    waker: Option<Arc<mio::Waker>>,
+}
+
+#[derive(Default)]
+enum Output {
+    Line(gpio::Line),
+    /// Only logs what it would do.
+    DryRun,
+    #[default]
+    Unconnected,
+}
+
+impl Door {
+    /// In a dry run the GPIO line is left alone and unlocks are only logged.
+    pub fn new(config: &config::Door, dry_run: bool) -> anyhow::Result<Door> {
+        let door = |output| Door {
+            state: Arc::new(Mutex::new(State {
+                output,
+                duty: Duty::new(config.safety.clone()),
+                ..Default::default()
+            })),
+            unlock_ms: Arc::new(AtomicU64::new(config.unlock.as_millis() as u64)),
+        };
+        if dry_run {
+            return Ok(door(Output::DryRun));
+        }
+        let mut flags = gpio::GPIO_V2_LINE_FLAG_OUTPUT;
+        if config.active_level == config::ActiveLevel::Low {
+            flags |= gpio::GPIO_V2_LINE_FLAG_ACTIVE_LOW;
+        }
+        let line = gpio::Line::request(&config.chip, config.line, flags, "cellardoor").context(
+            format!(
+                "Failed to request GPIO line {} on {:?}",
+                config.line, config.chip
+            ),
+        )?;
+        line.set_value(false)?;
+        Ok(door(Output::Line(line)))
+    }
+
+    /// A door without a GPIO line, which only records unlock requests.
+    #[cfg(test)]
+    pub fn unconnected() -> Door {
+        Door {
+            state: Default::default(),
+            unlock_ms: Arc::new(AtomicU64::new(3000)),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked_until().is_some()
+    }
+
+    /// Whether the strike is energized, by an unlock or a hold.
+    pub fn is_energized(&self) -> bool {
+        self.state.lock().unwrap().energized
+    }
+
+    /// When the running unlock ends, if any.
+    pub fn unlocked_until(&self) -> Option<Instant> {
+        self.state.lock().unwrap().until
+    }
+
+    /// When [`Door::relock`] is next due: the running unlock ends, a hold is cut short or a
+    /// waiting unlock begins.
+    pub fn deadline(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        [
+            state.until,
+            state.hold_until,
+            state.pending.map(|(at, _)| at),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.state.lock().unwrap().held
+    }
+
+    /// Has unlocks from other threads wake the event loop through `waker`.
+    pub fn set_waker(&self, waker: Arc<mio::Waker>) {
+        self.state.lock().unwrap().waker = Some(waker);
+    }
+
+    /// Keeps the door unlocked until released again; a running unlock still ends as planned.
+    /// Unless exempt, the hold ends early where `door.safety` demands.
+    pub fn hold(&self, held: bool) {
+        self.hold_at(Instant::now(), held);
+    }
+
+    fn hold_at(&self, now: Instant, held: bool) {
+        let mut state = self.state.lock().unwrap();
+        if held {
+            state.start(now, None);
+        } else {
+            state.held = false;
+            state.hold_until = None;
+            if matches!(state.pending, Some((_, None))) {
+                state.pending = None;
+            }
+            let energized = state.until.is_some();
+            state.energize(energized, now);
+        }
+    }
+
+    /// Changes how long later unlocks last, for a reloaded configuration.
+    pub fn set_unlock(&self, unlock: Duration) {
+        self.unlock_ms
+            .store(unlock.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Unlocks the door for the configured duration, extending an already running unlock.
+    /// While held it already is unlocked, and stays so no longer than the hold.
+    pub fn unlock(&self) {
+        let duration = Duration::from_millis(self.unlock_ms.load(Ordering::Relaxed));
+        self.unlock_at(Instant::now(), duration);
+    }
+
+    fn unlock_at(&self, now: Instant, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if state.held {
+            log::debug!("Door is held unlocked already");
+            return;
+        }
+        state.start(now, Some(duration));
+        if let Some(waker) = &state.waker {
+            if let Err(e) = waker.wake() {
+                log::error!("Failed to wake the event loop for the door: {e:?}");
+            }
+        }
+    }
+
+    /// Ends the running unlock if it is due at `now`, unless the door is held, ends a hold
+    /// `door.safety` cuts short and starts an unlock that waited for the strike to rest.
+    pub fn relock(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if state.until.is_some_and(|until| until <= now) {
+            state.until = None;
+        }
+        if state.hold_until.is_some_and(|until| until <= now) {
+            log::warn!("Ending the hold of the door early, door.safety limits the strike");
+            state.held = false;
+            state.hold_until = None;
+        }
+        if !state.held && state.until.is_none() {
+            state.energize(false, now);
+        }
+        if let Some((_, wanted)) = state.pending.filter(|(at, _)| *at <= now) {
+            state.pending = None;
+            state.start(now, wanted);
+        }
+    }
+}
+
+impl State {
+    /// Energizes the strike for `wanted`, or holds it if `None`, as far as `door.safety`
+    /// allows at `now`.
+    fn start(&mut self, now: Instant, wanted: Option<Duration>) {
+        let exempt = wanted.is_none() && self.duty.config.fail_secure_capable;
+        if !self.energized && !exempt {
+            if let Some(rested) = self.duty.rested_at().filter(|rested| *rested > now) {
+                log::info!(
+                    "Door strike is resting, unlocking in {} ms",
+                    (rested - now).as_millis()
+                );
+                // A hold outranks an unlock waiting alongside it.
+                let wanted = match self.pending {
+                    Some((_, None)) => None,
+                    _ => wanted,
+                };
+                self.pending = Some((rested, wanted));
+                return;
+            }
+        }
+        let allowance = match exempt {
+            true => None,
+            false => self.duty.allowance(now),
+        };
+        if allowance.is_some_and(|allowance| allowance.is_zero()) {
+            log::warn!("Refusing to unlock the door, its strike reached the door.safety limits");
+            return;
+        }
+        match wanted {
+            Some(wanted) => {
+                let granted = allowance.map_or(wanted, |allowance| allowance.min(wanted));
+                if granted < wanted {
+                    log::warn!(
+                        "Cutting the unlock to {} ms, door.safety limits the strike",
+                        granted.as_millis()
+                    );
+                }
+                self.until = self.until.max(Some(now + granted));
+            }
+            None => {
+                self.held = true;
+                self.hold_until = allowance.map(|allowance| now + allowance);
+            }
+        }
+        self.energize(true, now);
+    }
+
+    /// Drives the line, or just logs what it would do in a dry run.
+    fn energize(&mut self, energized: bool, now: Instant) {
+        // An exempt hold doesn't count towards the duty cycle.
+        let exempt = self.held && self.hold_until.is_none() && self.duty.config.fail_secure_capable;
+        self.duty.count(energized && !exempt, now);
+        if self.energized == energized {
+            return;
+        }
+        self.energized = energized;
+        if !energized {
+            self.duty.off_since = Some(now);
+        }
+        match (&self.output, energized) {
+            (Output::Line(line), true) => {
+                if let Err(e) = line.set_value(true) {
+                    log::error!("Failed to energize door line: {e:?}");
+                }
+                log::debug!("Door unlocked");
+            }
+            (Output::Line(line), false) => {
+                if let Err(e) = line.set_value(false) {
+                    log::error!("Failed to release door line: {e:?}");
+                }
+                log::debug!("Door locked");
+            }
+            (Output::DryRun, true) => log::info!("[dry-run] Not unlocking the door"),
+            (Output::DryRun, false) => log::info!("[dry-run] Not locking the door"),
+            (Output::Unconnected, _) => {}
+        }
+    }
+}
+
+/// How long the strike has been energized, for the limits of [`config::DoorSafety`].
+#[derive(Default)]
+struct Duty {
+    config: config::DoorSafety,
+    /// Counted spells that have ended, oldest first, back to at most one `duty_window`.
+    spells: VecDeque<(Instant, Instant)>,
+    /// When the counted spell running now began.
+    on_since: Option<Instant>,
+    /// When the strike was last de-energized.
+    off_since: Option<Instant>,
+}
+
+impl Duty {
+    fn new(config: config::DoorSafety) -> Duty {
+        Duty {
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// Starts or ends a counted spell.
+    fn count(&mut self, counted: bool, now: Instant) {
+        match (self.on_since, counted) {
+            (None, true) => self.on_since = Some(now),
+            (Some(since), false) => {
+                self.on_since = None;
+                self.spells.push_back((since, now));
+            }
+            _ => {}
+        }
+    }
+
+    /// Counted time energized within the `duty_window` up to `now`.
+    fn used(&mut self, now: Instant) -> Duration {
+        let start = now.checked_sub(self.config.duty_window);
+        // Spells older than the window no longer count.
+        while let Some((_, end)) = self.spells.front() {
+            if start.is_some_and(|start| *end <= start) {
+                self.spells.pop_front();
+            } else {
+                break;
+            }
+        }
+        let running = self.on_since.map(|since| (since, now));
+        self.spells
+            .iter()
+            .chain(&running)
+            .map(|(since, end)| {
+                end.saturating_duration_since(start.map_or(*since, |start| start.max(*since)))
+            })
+            .sum()
+    }
+
+    /// When the strike has rested for `min_off` since it was de-energized last.
+    fn rested_at(&self) -> Option<Instant> {
+        Some(self.off_since? + self.config.min_off?)
+    }
+
+    /// How much longer the strike may be energized from `now`, `None` if unlimited.
+    fn allowance(&mut self, now: Instant) -> Option<Duration> {
+        let running = self
+            .on_since
+            .map_or(Duration::ZERO, |since| now.saturating_duration_since(since));
+        let on = self
+            .config
+            .max_on
+            .map(|max_on| max_on.saturating_sub(running));
+        let used = self.used(now);
+        let duty = self
+            .config
+            .duty_limit
+            .map(|limit| limit.saturating_sub(used));
+        match (on, duty) {
+            (Some(on), Some(duty)) => Some(on.min(duty)),
+            (on, duty) => on.or(duty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Door, Duty};
+    use crate::config;
+
+    #[test]
+    fn relock_test() {
+        let door = Door::unconnected();
+        door.set_unlock(Duration::from_secs(1));
+        door.unlock();
+        let until = door.unlocked_until().unwrap();
+        assert!(until > Instant::now() + Duration::from_millis(900));
+
+        door.relock(until - Duration::from_millis(1));
+        assert!(door.is_unlocked());
+        door.relock(until);
+        assert!(!door.is_unlocked());
+
+        // A hold outlasts the unlock, and releasing it afterwards locks at once.
+        door.unlock();
+        door.hold(true);
+        door.relock(Instant::now() + Duration::from_secs(2));
+        assert!(!door.is_unlocked());
+        assert!(door.state.lock().unwrap().energized);
+        door.hold(false);
+        assert!(!door.state.lock().unwrap().energized);
+    }
+
+    fn safety(max_on: u64, min_off: u64, duty_limit: u64) -> config::DoorSafety {
+        config::DoorSafety {
+            max_on: Some(Duration::from_secs(max_on)),
+            min_off: Some(Duration::from_secs(min_off)),
+            duty_limit: Some(Duration::from_secs(duty_limit)),
+            duty_window: Duration::from_secs(60),
+            fail_secure_capable: false,
+        }
+    }
+
+    fn limited(safety: config::DoorSafety) -> Door {
+        let door = Door::unconnected();
+        door.state.lock().unwrap().duty = Duty::new(safety);
+        door
+    }
+
+    #[test]
+    fn duty_window_test() {
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+        let mut duty = Duty::new(safety(10, 0, 30));
+        for start in [0, 20, 40] {
+            duty.count(true, at(start));
+            assert_eq!(duty.used(at(start + 5)), Duration::from_secs(start / 2 + 5));
+            duty.count(false, at(start + 10));
+        }
+        assert_eq!(duty.allowance(at(50)), Some(Duration::ZERO));
+
+        // The first spell slides out of the window bit by bit, then is forgotten.
+        assert_eq!(duty.allowance(at(65)), Some(Duration::from_secs(5)));
+        assert_eq!(duty.used(at(75)), Duration::from_secs(20));
+        assert_eq!(duty.spells.len(), 2);
+        assert_eq!(duty.used(at(200)), Duration::ZERO);
+        assert!(duty.spells.is_empty());
+
+        // A running spell counts against both limits.
+        duty.count(true, at(200));
+        assert_eq!(duty.allowance(at(204)), Some(Duration::from_secs(6)));
+        assert_eq!(Duty::default().allowance(at(0)), None);
+    }
+
+    #[test]
+    fn safety_test() {
+        let t0 = Instant::now();
+        let at = |millis| t0 + Duration::from_millis(millis);
+        let door = limited(safety(2, 1, 3));
+        let energized = || door.state.lock().unwrap().energized;
+
+        door.unlock_at(at(0), Duration::from_secs(3));
+        assert_eq!(door.unlocked_until(), Some(at(2000)));
+        door.relock(at(2000));
+        assert!(!energized());
+
+        // During min_off the unlock waits, then gets what is left of duty_limit.
+        door.unlock_at(at(2500), Duration::from_secs(3));
+        assert!(!energized());
+        assert_eq!(door.deadline(), Some(at(3000)));
+        door.relock(at(3000));
+        assert!(energized());
+        assert_eq!(door.unlocked_until(), Some(at(4000)));
+        door.relock(at(4000));
+
+        door.unlock_at(at(5000), Duration::from_secs(3));
+        assert!(!energized() && door.deadline().is_none());
+
+        // A hold is cut short unless the strike is fail-secure-capable, when it doesn't count.
+        let door = limited(safety(2, 1, 3));
+        door.hold_at(at(0), true);
+        assert_eq!(door.deadline(), Some(at(2000)));
+        door.relock(at(2000));
+        assert!(!door.is_held());
+        let door = limited(config::DoorSafety {
+            fail_secure_capable: true,
+            ..safety(2, 1, 3)
+        });
+        door.hold_at(at(0), true);
+        door.relock(at(100_000));
+        assert!(door.is_held() && door.deadline().is_none());
+        assert_eq!(
+            door.state.lock().unwrap().duty.used(at(100_000)),
+            Duration::ZERO
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{persistence, privacy, wakeup::Wakeup, Credential};
+
+/// How an enrollment ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Enrolled(Credential),
+    Failed(String),
+    TimedOut,
+}
+
+/// Captures the first unknown key presented within a window, so new members' keys can be added
+/// without reading kernel logs.
+///
+/// Enrolled keys are appended to the pending-enrollment file as `<id>,<name>` lines, the format
+/// of the MOS key list. With `thing.enroll_url` the refresh thread pushes them to MOS and removes
+/// them from the file once accepted.
+#[derive(Default)]
+pub struct Enroller {
+    path: PathBuf,
+    state: Mutex<State>,
+    /// Held while the pending file is read or written.
+    file: Mutex<()>,
+    /// Woken after each enrollment so the key is pushed right away.
+    upstream: Option<Arc<Wakeup>>,
+}
+
+#[derive(Default)]
+struct State {
+    window: Option<Window>,
+    outcome: Option<Outcome>,
+}
+
+struct Window {
+    deadline: Instant,
+    name: Option<String>,
+}
+
+impl Enroller {
+    pub fn new(path: PathBuf, upstream: Option<Arc<Wakeup>>) -> Enroller {
+        Enroller {
+            path,
+            state: Mutex::default(),
+            file: Mutex::default(),
+            upstream,
+        }
+    }
+
+    /// Starts waiting for a key; only one enrollment runs at a time.
+    pub fn start(&self, window: Duration, name: Option<String>, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.window.is_some() || state.outcome.is_some() {
+            return false;
+        }
+        log::info!("Enrollment started for {window:?}");
+        state.window = Some(Window {
+            deadline: now + window,
+            name,
+        });
+        true
+    }
+
+    pub fn is_active(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        state.window.as_ref().is_some_and(|w| now < w.deadline)
+    }
+
+    /// Enrolls the unknown key `id` if an enrollment is active, ending it.
+    pub fn offer(&self, id: &Credential, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(window) = state.window.take_if(|w| now < w.deadline) else {
+            return false;
+        };
+        let name = window.name.unwrap_or_default();
+        state.outcome = Some(match self.append(id, &name) {
+            Ok(()) => {
+                log::info!("Enrolled key {} as {name:?}", privacy::id(id));
+                if let Some(wakeup) = &self.upstream {
+                    wakeup.request_refresh();
+                }
+                Outcome::Enrolled(*id)
+            }
+            Err(e) => {
+                log::error!("Failed to record enrolled key {}: {e:?}", privacy::id(id));
+                Outcome::Failed(format!("{e:#}"))
+            }
+        });
+        true
+    }
+
+    fn append(&self, id: &Credential, name: &str) -> anyhow::Result<()> {
+        let _file = self.file.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context(format!("Failed to open {:?}", self.path))?;
+        writeln!(file, "{},{name}", id)?;
+        Ok(())
+    }
+
+    /// The keys in the pending-enrollment file with their names.
+    pub fn pending(&self) -> anyhow::Result<Vec<(Credential, String)>> {
+        let _file = self.file.lock().unwrap();
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let contents = std::fs::read_to_string(&self.path)
+            .context(format!("Failed to read {:?}", self.path))?;
+        Ok(contents
+            .lines()
+            .filter_map(|line| {
+                let (id, name) = line.split_once(',').unwrap_or((line, ""));
+                Some((Credential::parse(id.trim()).ok()?, name.trim().to_owned()))
+            })
+            .collect())
+    }
+
+    /// Drops the lines of `ids` from the pending-enrollment file; lines that don't parse stay.
+    pub fn remove_pending(&self, ids: &HashSet<Credential>) -> anyhow::Result<()> {
+        let _file = self.file.lock().unwrap();
+        let contents = std::fs::read_to_string(&self.path)
+            .context(format!("Failed to read {:?}", self.path))?;
+        let kept: String = contents
+            .lines()
+            .filter(|line| {
+                let id = line.split_once(',').map_or(*line, |(id, _)| id);
+                Credential::parse(id.trim()).map_or(true, |id| !ids.contains(&id))
+            })
+            .map(|line| format!("{line}\n"))
+            .collect();
+        persistence::write_atomically(&self.path, |file| Ok(file.write_all(kept.as_bytes())?))
+    }
+
+    /// Takes the outcome of a finished enrollment, including one that ran out of time.
+    pub fn finish(&self, now: Instant) -> Option<Outcome> {
+        let mut state = self.state.lock().unwrap();
+        if state.window.take_if(|w| now >= w.deadline).is_some() {
+            log::info!("Enrollment timed out without a key");
+            return Some(Outcome::TimedOut);
+        }
+        state.outcome.take()
+    }
+
+    /// Time until an active enrollment runs out, for use as the event loop's timeout.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        if state.outcome.is_some() {
+            return Some(Duration::ZERO);
+        }
+        let window = state.window.as_ref()?;
+        Some(window.deadline.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    };
+
+    use super::{Enroller, Outcome};
+    use crate::Credential;
+
+    #[test]
+    fn enrollment_captures_one_key_test() {
+        let dir = crate::testutil::test_dir("enroll");
+        let enroller = Enroller::new(dir.join("pending.csv"), None);
+        let key = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(!enroller.offer(&key, at(0)));
+        assert!(enroller.start(Duration::from_secs(30), Some("Alice".to_owned()), at(0)));
+        assert!(!enroller.start(Duration::from_secs(30), None, at(1)));
+        assert_eq!(enroller.finish(at(1)), None);
+        assert!(enroller.offer(&key, at(2)));
+        assert!(!enroller.is_active(at(2)));
+        assert!(!enroller.offer(&Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]), at(3)));
+        assert_eq!(enroller.finish(at(3)), Some(Outcome::Enrolled(key)));
+        assert_eq!(
+            std::fs::read_to_string(dir.join("pending.csv")).unwrap(),
+            "33-00000392c6ea,Alice\n"
+        );
+
+        assert!(enroller.start(Duration::from_secs(30), None, at(10)));
+        assert_eq!(enroller.timeout(at(20)), Some(Duration::from_secs(20)));
+        assert!(!enroller.offer(&key, at(40)));
+        assert_eq!(enroller.finish(at(40)), Some(Outcome::TimedOut));
+        assert_eq!(enroller.finish(at(41)), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pushed_keys_leave_the_pending_file_test() {
+        let dir = crate::testutil::test_dir("enroll-pending");
+        let path = dir.join("pending.csv");
+        std::fs::write(
+            &path,
+            "33-00000392c6ea,Alice\n# by hand\n01-000000000042,Bob\n",
+        )
+        .unwrap();
+        let enroller = Enroller::new(path.clone(), None);
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        assert_eq!(
+            enroller.pending().unwrap(),
+            [(alice, "Alice".to_owned()), (bob, "Bob".to_owned())]
+        );
+
+        enroller.remove_pending(&HashSet::from([alice])).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "# by hand\n01-000000000042,Bob\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
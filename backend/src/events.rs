@@ -0,0 +1,323 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+
+use crate::{audit, broadcast::Broadcast, config, privacy, Credential};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventType {
+    Access,
+    Refresh,
+    /// The door sensor changed.
+    Door,
+    /// A key was taken off a reader, with `reader.udev.events` including `remove`.
+    Departed,
+}
+
+/// One line of the event stream. Fields that don't apply to an event type are left out.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// Counts up from 1 since the start, see [`Broadcast`].
+    #[serde(default)]
+    pub seq: u64,
+    /// RFC3339 with milliseconds.
+    pub timestamp: String,
+    pub event: EventType,
+    /// Hex key id, or its hash with `hash_key_ids`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_name: Option<String>,
+    /// Only set when several readers are configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reader: Option<String>,
+    /// As in the audit log, e.g. `granted` or `denied`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decision: Option<String>,
+    /// Access list size after a refresh.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub list_size: Option<usize>,
+    /// Why a refresh failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Door events: whether it is open, and whether it has been open for too long.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alarm: Option<bool>,
+}
+
+/// Machine-readable stream of access, refresh and door events, one JSON object per line, in
+/// addition to the human-readable log. The same lines go to `stream` for the API's
+/// `GET /events`, and the last `history_size` events are kept for `HISTORY`.
+pub struct EventLog {
+    sink: Option<Mutex<File>>,
+    hash_key_ids: bool,
+    pub stream: Broadcast,
+    /// Newest last.
+    history: Mutex<VecDeque<Event>>,
+    history_size: usize,
+}
+
+impl EventLog {
+    pub fn new(config: Option<&config::Events>, history_size: usize) -> anyhow::Result<EventLog> {
+        let sink = config
+            .map(|config| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.path)
+                    .context(format!("Failed to open event log {:?}", config.path))
+            })
+            .transpose()?;
+        Ok(EventLog {
+            sink: sink.map(Mutex::new),
+            hash_key_ids: config.is_some_and(|config| config.hash_key_ids),
+            stream: Broadcast::default(),
+            history: Mutex::new(VecDeque::with_capacity(history_size)),
+            history_size,
+        })
+    }
+
+    /// An access attempt; `id` is `None` for devices whose id couldn't be parsed.
+    pub fn access(
+        &self,
+        id: Option<&Credential>,
+        name: &str,
+        reader: Option<&str>,
+        decision: audit::Decision,
+    ) {
+        let key_id = id.map(|id| self.key_id(id));
+        self.emit(Event {
+            key_id,
+            key_name: Some(name.to_owned()).filter(|name| !name.is_empty()),
+            reader: reader.map(str::to_owned),
+            decision: Some(decision.to_string()),
+            ..Event::new(EventType::Access)
+        });
+    }
+
+    pub fn refreshed(&self, list_size: usize) {
+        self.emit(Event {
+            list_size: Some(list_size),
+            ..Event::new(EventType::Refresh)
+        });
+    }
+
+    pub fn refresh_failed(&self, error: &anyhow::Error) {
+        self.emit(Event {
+            error: Some(format!("{error:#}")),
+            ..Event::new(EventType::Refresh)
+        });
+    }
+
+    pub fn departed(&self, id: &Credential, reader: Option<&str>) {
+        self.emit(Event {
+            key_id: Some(self.key_id(id)),
+            reader: reader.map(str::to_owned),
+            ..Event::new(EventType::Departed)
+        });
+    }
+
+    pub fn door(&self, open: bool, alarm: bool) {
+        self.emit(Event {
+            open: Some(open),
+            alarm: Some(alarm),
+            ..Event::new(EventType::Door)
+        });
+    }
+
+    fn key_id(&self, id: &Credential) -> String {
+        if self.hash_key_ids {
+            privacy::hash(id)
+        } else {
+            privacy::audit_id(id)
+        }
+    }
+
+    fn emit(&self, event: Event) {
+        let message = self.stream.publish(|seq| {
+            let event = Event { seq, ..event };
+            let line = serde_json::to_string(&event)
+                .map_err(|e| log::error!("Failed to serialize event {event:?}: {e:?}"))
+                .ok()?;
+            if self.history_size > 0 {
+                let mut history = self.history.lock().unwrap();
+                if history.len() >= self.history_size {
+                    history.pop_front();
+                }
+                history.push_back(event);
+            }
+            Some(line)
+        });
+        let (Some(sink), Some(message)) = (&self.sink, message) else {
+            return;
+        };
+        let line = format!("{}\n", message.data);
+        let mut file = sink.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::error!("Failed to write event {line:?}: {e:?}");
+        }
+    }
+}
+
+impl EventLog {
+    /// The `limit` most recent events, newest first.
+    pub fn history(&self, limit: usize) -> Vec<Event> {
+        let history = self.history.lock().unwrap();
+        history.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        EventLog::new(None, config::DEFAULT_HISTORY_SIZE).unwrap()
+    }
+}
+
+/// `<timestamp> <event> <key id or -> <outcome>`, as `HISTORY` lists events.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let event = match self.event {
+            EventType::Access => "access",
+            EventType::Refresh => "refresh",
+            EventType::Door => "door",
+            EventType::Departed => "departed",
+        };
+        let key_id = self.key_id.as_deref().unwrap_or("-");
+        write!(f, "{} {event} {key_id} ", self.timestamp)?;
+        match self {
+            Event {
+                decision: Some(decision),
+                ..
+            } => f.write_str(decision),
+            Event {
+                error: Some(error), ..
+            } => write!(f, "failed: {error}"),
+            Event {
+                list_size: Some(size),
+                ..
+            } => write!(f, "{size} keys"),
+            Event {
+                open: Some(open), ..
+            } => {
+                f.write_str(if *open { "open" } else { "closed" })?;
+                if self.alarm == Some(true) {
+                    f.write_str(" alarm")?;
+                }
+                Ok(())
+            }
+            _ => f.write_str("-"),
+        }
+    }
+}
+
+impl Event {
+    fn new(event: EventType) -> Event {
+        Event {
+            seq: 0,
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event,
+            key_id: None,
+            key_name: None,
+            reader: None,
+            decision: None,
+            list_size: None,
+            error: None,
+            open: None,
+            alarm: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, EventLog, EventType};
+    use crate::{audit::Decision, config, testutil::test_dir, Credential};
+
+    #[test]
+    fn events_deserialize_test() {
+        let dir = test_dir("events");
+        let path = dir.join("events.jsonl");
+        let key = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        for hash_key_ids in [false, true] {
+            let events = EventLog::new(
+                Some(&config::Events {
+                    path: path.clone(),
+                    hash_key_ids,
+                }),
+                0,
+            )
+            .unwrap();
+            events.access(Some(&key), "Alice", Some("inner"), Decision::Granted);
+        }
+        EventLog::new(
+            Some(&config::Events {
+                path: path.clone(),
+                hash_key_ids: false,
+            }),
+            0,
+        )
+        .unwrap()
+        .refreshed(42);
+
+        let events: Vec<Event> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        humantime::parse_rfc3339(&events[0].timestamp).unwrap();
+        assert_eq!(events[0].event, EventType::Access);
+        assert_eq!(events[0].key_id.as_deref(), Some("3300000392c6ea"));
+        assert_eq!(events[0].key_name.as_deref(), Some("Alice"));
+        assert_eq!(events[0].reader.as_deref(), Some("inner"));
+        assert_eq!(events[0].decision.as_deref(), Some("granted"));
+        assert_eq!(events[0].list_size, None);
+
+        let hashed = events[1].key_id.as_deref().unwrap();
+        assert_eq!(hashed.len(), 8);
+        assert_ne!(hashed, "3300000392c6ea");
+
+        assert_eq!(events[2].event, EventType::Refresh);
+        assert_eq!((events[0].seq, events[2].seq), (1, 1));
+        assert_eq!(events[2].list_size, Some(42));
+        assert_eq!(events[2].key_id, None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn history_keeps_newest_events_test() {
+        let events = EventLog::new(None, 3).unwrap();
+        let key = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        events.access(Some(&key), "Alice", None, Decision::Granted);
+        events.access(None, "", None, Decision::ParseError);
+        events.refreshed(42);
+        events.refresh_failed(&anyhow::anyhow!("HTTP 500"));
+
+        let history = events.history(10);
+        let seqs: Vec<_> = history.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, [4, 3, 2]);
+        let lines: Vec<_> = history
+            .iter()
+            .map(|event| event.to_string().split_once(' ').unwrap().1.to_owned())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "refresh - failed: HTTP 500",
+                "refresh - 42 keys",
+                "access - parse_error"
+            ]
+        );
+        assert_eq!(events.history(1).len(), 1);
+    }
+}
@@ -0,0 +1,88 @@
+use std::os::fd::AsRawFd;
+
+use anyhow::Context;
+use mio::{unix::SourceFd, Interest, Registry, Token};
+
+use crate::{config, gpio};
+
+/// Watches the exit button's GPIO line through edge events.
+pub struct ExitButton {
+    line: gpio::Line,
+    pressed: bool,
+}
+
+impl ExitButton {
+    /// Requests the button line and registers it under `token`.
+    pub fn open(
+        config: &config::ExitButton,
+        registry: &Registry,
+        token: Token,
+    ) -> anyhow::Result<ExitButton> {
+        let mut flags = 0;
+        if config.active_level == config::ActiveLevel::Low {
+            flags |= gpio::GPIO_V2_LINE_FLAG_ACTIVE_LOW;
+        }
+        let line = gpio::Line::request_edges(
+            &config.chip,
+            config.line,
+            flags,
+            config.debounce,
+            "cellardoor-exit",
+        )
+        .context(format!(
+            "Failed to request exit button line {} on {:?}",
+            config.line, config.chip
+        ))?;
+        registry.register(&mut SourceFd(&line.as_raw_fd()), token, Interest::READABLE)?;
+        let pressed = line.value()?;
+        log::info!("Watching exit button on line {}", config.line);
+        Ok(ExitButton { line, pressed })
+    }
+
+    /// Reads the line after edge events and returns whether the button was pressed since.
+    pub fn ready(&mut self) -> bool {
+        let edges = self.line.drain_events().unwrap_or_else(|e| {
+            log::error!("Failed to read exit button events: {e:?}");
+            0
+        });
+        match self.line.value() {
+            Ok(pressed) => {
+                let press = is_press(self.pressed, edges, pressed);
+                self.pressed = pressed;
+                press
+            }
+            Err(e) => {
+                log::error!("Failed to read exit button: {e:?}");
+                false
+            }
+        }
+    }
+}
+
+/// Whether `edges` edges from `was_pressed` to `pressed` contain a press. A button held down
+/// yields no further edges, so it only counts once.
+fn is_press(was_pressed: bool, edges: usize, pressed: bool) -> bool {
+    // Edges alternate, so a press and release between two reads shows as two edges.
+    let presses = if was_pressed {
+        edges / 2
+    } else {
+        edges.div_ceil(2)
+    };
+    presses > 0 || (!was_pressed && pressed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::is_press;
+
+    #[test]
+    fn held_button_counts_once_test() {
+        assert!(is_press(false, 1, true));
+        // Still held: bounces the kernel missed don't count as another press.
+        assert!(!is_press(true, 0, true));
+        assert!(!is_press(true, 1, false));
+        // Pressed and released again between two reads.
+        assert!(is_press(false, 2, false));
+        assert!(is_press(true, 2, true));
+    }
+}
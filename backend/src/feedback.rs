@@ -0,0 +1,268 @@
+use std::{sync::Mutex, time::Instant};
+
+use anyhow::Context;
+
+use crate::{audit::Decision, config, gpio};
+
+const LINES: [&str; 3] = ["green", "red", "buzzer"];
+
+/// What a [`Feedback`] pattern is played for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Grant,
+    Deny,
+    Lockdown,
+    AntiPassback,
+}
+
+impl Event {
+    /// What a presentation concluded as `decision` shows, if anything; a key waiting for its
+    /// PIN shows nothing until the PIN decides.
+    pub fn of(decision: Decision) -> Option<Event> {
+        match decision {
+            decision if decision.is_granted() => Some(Event::Grant),
+            Decision::PinRequired => None,
+            Decision::Lockdown => Some(Event::Lockdown),
+            Decision::AntiPassback => Some(Event::AntiPassback),
+            _ => Some(Event::Deny),
+        }
+    }
+
+    fn pattern(self, patterns: &config::Patterns) -> &[config::Step] {
+        match self {
+            Event::Grant => &patterns.grant,
+            Event::Deny => &patterns.deny,
+            Event::Lockdown => &patterns.lockdown,
+            Event::AntiPassback => &patterns.anti_passback,
+        }
+    }
+}
+
+/// LEDs and a buzzer playing the `feedback.patterns` of presentations.
+///
+/// Playing a pattern only sets the lines for its first step; the event loop moves on to the
+/// next once [`Feedback::deadline`] has passed, see [`Feedback::advance`], so patterns never
+/// block it. A pattern played while another one is still going replaces it.
+#[derive(Default)]
+pub struct Feedback {
+    /// `None` without a `feedback` config, when nothing is ever played.
+    patterns: Option<config::Patterns>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    output: Output,
+    playing: Option<Playing>,
+    /// Which of [`LINES`] are on.
+    lit: [bool; 3],
+}
+
+struct Playing {
+    event: Event,
+    /// Index of the current step.
+    step: usize,
+    /// When the current step ends.
+    until: Instant,
+}
+
+#[derive(Default)]
+enum Output {
+    /// By [`LINES`], `None` for those not configured.
+    Lines([Option<gpio::Line>; 3]),
+    /// Only logs what it would do.
+    DryRun,
+    #[default]
+    Unconnected,
+}
+
+impl Feedback {
+    /// In a dry run the GPIO lines are left alone and patterns are only logged.
+    pub fn new(config: Option<&config::Feedback>, dry_run: bool) -> anyhow::Result<Feedback> {
+        let Some(config) = config else {
+            return Ok(Feedback::default());
+        };
+        let output = if dry_run {
+            Output::DryRun
+        } else {
+            let mut flags = gpio::GPIO_V2_LINE_FLAG_OUTPUT;
+            if config.active_level == config::ActiveLevel::Low {
+                flags |= gpio::GPIO_V2_LINE_FLAG_ACTIVE_LOW;
+            }
+            let mut lines = [None, None, None];
+            for ((line, offset), name) in lines
+                .iter_mut()
+                .zip([config.green, config.red, config.buzzer])
+                .zip(LINES)
+            {
+                let Some(offset) = offset else {
+                    continue;
+                };
+                let requested = gpio::Line::request(&config.chip, offset, flags, "cellardoor")
+                    .context(format!(
+                        "Failed to request {name} feedback GPIO line {offset} on {:?}",
+                        config.chip
+                    ))?;
+                requested.set_value(false)?;
+                *line = Some(requested);
+            }
+            Output::Lines(lines)
+        };
+        Ok(Feedback {
+            patterns: Some(config.patterns.clone()),
+            state: Mutex::new(State {
+                output,
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Feedback with the default patterns and no GPIO lines, which only records what it plays.
+    #[cfg(test)]
+    pub fn unconnected() -> Feedback {
+        Feedback {
+            patterns: Some(config::Patterns::default()),
+            state: Default::default(),
+        }
+    }
+
+    /// Starts the pattern of `event` at `now`, cutting short the one playing.
+    pub fn play(&self, event: Event, now: Instant) {
+        let Some(patterns) = &self.patterns else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        state.playing = Some(Playing {
+            event,
+            step: 0,
+            until: now,
+        });
+        state.step(event.pattern(patterns), now);
+    }
+
+    /// When the step playing ends, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        Some(self.state.lock().unwrap().playing.as_ref()?.until)
+    }
+
+    /// Moves on to the next step if the current one has ended at `now`, turning everything off
+    /// after the last.
+    pub fn advance(&self, now: Instant) {
+        let Some(patterns) = &self.patterns else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        let Some(playing) = &mut state.playing else {
+            return;
+        };
+        if playing.until > now {
+            return;
+        }
+        playing.step += 1;
+        let event = playing.event;
+        state.step(event.pattern(patterns), now);
+    }
+
+    /// The pattern playing, if any.
+    #[cfg(test)]
+    pub fn playing(&self) -> Option<Event> {
+        Some(self.state.lock().unwrap().playing.as_ref()?.event)
+    }
+
+    /// Which of green, red and buzzer are on.
+    #[cfg(test)]
+    pub fn lit(&self) -> [bool; 3] {
+        self.state.lock().unwrap().lit
+    }
+}
+
+impl State {
+    /// Sets the lines for the current step of `pattern`, beginning at `now`.
+    fn step(&mut self, pattern: &[config::Step], now: Instant) {
+        let Some(playing) = &mut self.playing else {
+            return;
+        };
+        let lit = match pattern.get(playing.step) {
+            Some(step) => {
+                playing.until = now + step.duration;
+                [step.green, step.red, step.buzzer]
+            }
+            None => {
+                self.playing = None;
+                [false; 3]
+            }
+        };
+        for (index, name) in LINES.into_iter().enumerate() {
+            if self.lit[index] == lit[index] {
+                continue;
+            }
+            match &self.output {
+                Output::Lines(lines) => {
+                    if let Some(line) = &lines[index] {
+                        if let Err(e) = line.set_value(lit[index]) {
+                            log::error!("Failed to switch {name} feedback line: {e:?}");
+                        }
+                    }
+                }
+                Output::DryRun => {
+                    let state = if lit[index] { "on" } else { "off" };
+                    log::debug!("[dry-run] Not switching {name} feedback {state}");
+                }
+                Output::Unconnected => {}
+            }
+        }
+        self.lit = lit;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Event, Feedback};
+    use crate::audit::Decision;
+
+    #[test]
+    fn pattern_test() {
+        let feedback = Feedback::unconnected();
+        let t0 = Instant::now();
+        let at = |millis| t0 + Duration::from_millis(millis);
+        feedback.play(Event::Grant, t0);
+        assert_eq!(feedback.lit(), [true, false, true]);
+        assert_eq!(feedback.deadline(), Some(at(100)));
+        feedback.advance(at(99));
+        assert_eq!(feedback.lit(), [true, false, true]);
+        feedback.advance(at(100));
+        assert_eq!(feedback.lit(), [true, false, false]);
+        assert_eq!(feedback.deadline(), Some(at(2000)));
+
+        // A second badge replaces the grant instead of interleaving with it.
+        feedback.play(Event::Deny, at(500));
+        assert_eq!(feedback.playing(), Some(Event::Deny));
+        assert_eq!(feedback.lit(), [false, true, false]);
+        let mut blinks = 0;
+        let mut now = at(500);
+        while let Some(deadline) = feedback.deadline() {
+            now = deadline;
+            feedback.advance(now);
+            blinks += feedback.lit()[1] as u32;
+        }
+        assert_eq!((blinks, now), (2, at(1400)));
+        assert_eq!(feedback.lit(), [false; 3]);
+        assert_eq!(feedback.playing(), None);
+
+        // Without a config nothing plays.
+        let feedback = Feedback::default();
+        feedback.play(Event::Grant, t0);
+        assert_eq!(feedback.deadline(), None);
+    }
+
+    #[test]
+    fn event_test() {
+        assert_eq!(Event::of(Decision::GrantedMaster), Some(Event::Grant));
+        assert_eq!(Event::of(Decision::Lockdown), Some(Event::Lockdown));
+        assert_eq!(Event::of(Decision::AntiPassback), Some(Event::AntiPassback));
+        assert_eq!(Event::of(Decision::WrongPin), Some(Event::Deny));
+        assert_eq!(Event::of(Decision::PinRequired), None);
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    time::Duration,
+};
+
+// Subset of the GPIO character device uAPI v2 from <linux/gpio.h>.
+const GPIO_V2_LINES_MAX: usize = 64;
+const GPIO_MAX_NAME_SIZE: usize = 32;
+const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+
+pub const GPIO_V2_LINE_FLAG_ACTIVE_LOW: u64 = 1 << 1;
+pub const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+pub const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+pub const GPIO_V2_LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+pub const GPIO_V2_LINE_FLAG_EDGE_FALLING: u64 = 1 << 5;
+
+const GPIO_V2_LINE_ATTR_ID_DEBOUNCE: u32 = 3;
+/// Size of a gpio_v2_line_event.
+const LINE_EVENT_SIZE: usize = 48;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct LineAttribute {
+    id: u32,
+    padding: u32,
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct LineConfigAttribute {
+    attr: LineAttribute,
+    mask: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [LineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+#[repr(C)]
+struct LineRequestRaw {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+const _: () = assert!(std::mem::size_of::<LineRequestRaw>() == 592);
+
+const fn iowr<T>(nr: u64) -> u64 {
+    (3 << 30) | ((std::mem::size_of::<T>() as u64) << 16) | (0xB4 << 8) | nr
+}
+
+const GPIO_V2_GET_LINE_IOCTL: u64 = iowr::<LineRequestRaw>(0x07);
+const GPIO_V2_LINE_GET_VALUES_IOCTL: u64 = iowr::<LineValues>(0x0E);
+const GPIO_V2_LINE_SET_VALUES_IOCTL: u64 = iowr::<LineValues>(0x0F);
+
+/// A single requested GPIO line, released when dropped.
+pub struct Line {
+    fd: OwnedFd,
+}
+
+/// An edge reported on a line from [`Line::request_falling_edges`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub offset: u32,
+    /// On the kernel's monotonic clock.
+    pub timestamp: Duration,
+}
+
+impl Line {
+    pub fn request(chip: &Path, offset: u32, flags: u64, consumer: &str) -> io::Result<Line> {
+        let config = LineConfig {
+            flags,
+            ..Default::default()
+        };
+        Line::request_config(chip, &[offset], config, consumer)
+    }
+
+    /// Requests input lines reporting their falling edges on one non-blocking descriptor, which
+    /// keeps the edges of all of them in order. Values and [`Line::set_value`] only concern
+    /// the first line.
+    pub fn request_falling_edges(chip: &Path, offsets: &[u32], consumer: &str) -> io::Result<Line> {
+        let config = LineConfig {
+            flags: GPIO_V2_LINE_FLAG_INPUT | GPIO_V2_LINE_FLAG_EDGE_FALLING,
+            ..Default::default()
+        };
+        let line = Line::request_config(chip, offsets, config, consumer)?;
+        line.set_nonblocking()?;
+        Ok(line)
+    }
+
+    /// Requests an input line reporting edges, which the kernel debounces by `debounce`. The
+    /// line's descriptor is non-blocking, for polling it.
+    pub fn request_edges(
+        chip: &Path,
+        offset: u32,
+        flags: u64,
+        debounce: Duration,
+        consumer: &str,
+    ) -> io::Result<Line> {
+        let debounce_us = u32::try_from(debounce.as_micros())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "debounce too long"))?;
+        let mut config = LineConfig {
+            flags: flags
+                | GPIO_V2_LINE_FLAG_INPUT
+                | GPIO_V2_LINE_FLAG_EDGE_RISING
+                | GPIO_V2_LINE_FLAG_EDGE_FALLING,
+            ..Default::default()
+        };
+        if debounce_us > 0 {
+            config.num_attrs = 1;
+            config.attrs[0] = LineConfigAttribute {
+                attr: LineAttribute {
+                    id: GPIO_V2_LINE_ATTR_ID_DEBOUNCE,
+                    padding: 0,
+                    value: debounce_us.into(),
+                },
+                mask: 1,
+            };
+        }
+        let line = Line::request_config(chip, &[offset], config, consumer)?;
+        line.set_nonblocking()?;
+        Ok(line)
+    }
+
+    fn set_nonblocking(&self) -> io::Result<()> {
+        // SAFETY: `fd` is a valid descriptor owned by `self`.
+        unsafe {
+            let fd = self.fd.as_raw_fd();
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn request_config(
+        chip: &Path,
+        offsets: &[u32],
+        config: LineConfig,
+        consumer: &str,
+    ) -> io::Result<Line> {
+        if offsets.is_empty() || offsets.len() > GPIO_V2_LINES_MAX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "wrong number of lines",
+            ));
+        }
+        let chip = File::open(chip)?;
+
+        let mut request = LineRequestRaw {
+            offsets: [0; GPIO_V2_LINES_MAX],
+            consumer: [0; GPIO_MAX_NAME_SIZE],
+            config,
+            num_lines: offsets.len() as u32,
+            event_buffer_size: 0,
+            padding: [0; 5],
+            fd: -1,
+        };
+        request.offsets[..offsets.len()].copy_from_slice(offsets);
+        let len = consumer.len().min(GPIO_MAX_NAME_SIZE - 1);
+        request.consumer[..len].copy_from_slice(&consumer.as_bytes()[..len]);
+
+        // SAFETY: `request` is a correctly laid out gpio_v2_line_request that outlives the call.
+        let ret = unsafe {
+            libc::ioctl(
+                chip.as_raw_fd(),
+                GPIO_V2_GET_LINE_IOCTL as _,
+                &mut request as *mut LineRequestRaw,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: on success the kernel hands us ownership of a fresh line fd.
+        let fd = unsafe { OwnedFd::from_raw_fd(request.fd) };
+        Ok(Line { fd })
+    }
+
+    /// Sets the logical value of the line (`true` = active, honouring active-low).
+    pub fn set_value(&self, active: bool) -> io::Result<()> {
+        let mut values = LineValues {
+            bits: active as u64,
+            mask: 1,
+        };
+        // SAFETY: `values` is a correctly laid out gpio_v2_line_values that outlives the call.
+        let ret = unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                GPIO_V2_LINE_SET_VALUES_IOCTL as _,
+                &mut values as *mut LineValues,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The logical value of the line (`true` = active, honouring active-low).
+    pub fn value(&self) -> io::Result<bool> {
+        let mut values = LineValues { bits: 0, mask: 1 };
+        // SAFETY: `values` is a correctly laid out gpio_v2_line_values that outlives the call.
+        let ret = unsafe {
+            libc::ioctl(
+                self.fd.as_raw_fd(),
+                GPIO_V2_LINE_GET_VALUES_IOCTL as _,
+                &mut values as *mut LineValues,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(values.bits & 1 != 0)
+    }
+
+    /// Discards the queued edge events of a line from [`Line::request_edges`], returning how
+    /// many there were.
+    pub fn drain_events(&self) -> io::Result<usize> {
+        let mut file = File::from(self.fd.try_clone()?);
+        let mut buf = [0; LINE_EVENT_SIZE * 16];
+        let mut events = 0;
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => return Ok(events),
+                Ok(len) => events += len / LINE_EVENT_SIZE,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(events),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads the queued edge events of a line from [`Line::request_falling_edges`], oldest
+    /// first.
+    pub fn read_edges(&self) -> io::Result<Vec<Edge>> {
+        let mut file = File::from(self.fd.try_clone()?);
+        let mut buf = [0; LINE_EVENT_SIZE * 16];
+        let mut edges = Vec::new();
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => return Ok(edges),
+                Ok(len) => edges.extend(buf[..len].chunks_exact(LINE_EVENT_SIZE).map(parse_edge)),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(edges),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Decodes a gpio_v2_line_event: the timestamp in nanoseconds, the event id and the offset,
+/// followed by sequence numbers and padding.
+fn parse_edge(event: &[u8]) -> Edge {
+    let timestamp = u64::from_ne_bytes(event[..8].try_into().unwrap());
+    Edge {
+        offset: u32::from_ne_bytes(event[12..16].try_into().unwrap()),
+        timestamp: Duration::from_nanos(timestamp),
+    }
+}
+
+impl AsRawFd for Line {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
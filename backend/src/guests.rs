@@ -0,0 +1,219 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+
+use crate::{persistence, privacy, Credential};
+
+/// Guests are for workshops and visits, not a second access list.
+pub const MAX_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// A key lent out until `expires`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guest {
+    pub id: Credential,
+    pub name: String,
+    pub expires: SystemTime,
+}
+
+/// Keys added at runtime with `GUEST ADD`, honoured like listed keys until they expire. They
+/// live apart from the access list, so refreshes from MOS never touch them, and are kept in
+/// `persistence.path` with the extension `guests` as `<hex id>,<expires>,<name>` lines.
+#[derive(Default)]
+pub struct Guests {
+    /// `None` for guests that are forgotten on restart.
+    path: Option<PathBuf>,
+    /// The file is read but never written.
+    dry_run: bool,
+    /// By expiry, soonest first.
+    entries: Mutex<Vec<Guest>>,
+}
+
+impl Guests {
+    /// Loads the guests still valid at `now` from `path`.
+    pub fn load(path: PathBuf, dry_run: bool, now: SystemTime) -> Guests {
+        let mut entries = match path.exists() {
+            true => read(&path).unwrap_or_else(|e| {
+                log::error!("Failed to read guest keys, starting without: {e:?}");
+                Vec::new()
+            }),
+            false => Vec::new(),
+        };
+        entries.retain(|guest| guest.expires > now);
+        entries.sort_by_key(|guest| guest.expires);
+        Guests {
+            path: Some(path),
+            dry_run,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Lets `id` in for `ttl` from `now`, replacing an earlier guest entry for it.
+    pub fn add(
+        &self,
+        id: &Credential,
+        ttl: Duration,
+        name: &str,
+        now: SystemTime,
+    ) -> anyhow::Result<Guest> {
+        anyhow::ensure!(!ttl.is_zero(), "ttl must not be zero");
+        anyhow::ensure!(
+            ttl <= MAX_TTL,
+            "ttl must be at most {}",
+            humantime::format_duration(MAX_TTL)
+        );
+        anyhow::ensure!(!name.contains(['\n', '\r']), "name must fit on one line");
+        let guest = Guest {
+            id: *id,
+            name: name.to_owned(),
+            expires: now + ttl,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|other| other.id != *id);
+        let index = entries.partition_point(|other| other.expires <= guest.expires);
+        entries.insert(index, guest.clone());
+        self.save(&entries)?;
+        Ok(guest)
+    }
+
+    /// Withdraws `id`, returning whether it was a guest.
+    pub fn revoke(&self, id: &Credential) -> anyhow::Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|guest| guest.id != *id);
+        if entries.len() == len {
+            return Ok(false);
+        }
+        self.save(&entries)?;
+        Ok(true)
+    }
+
+    /// The guest `id` if it is still valid at `now`.
+    pub fn get(&self, id: &Credential, now: SystemTime) -> Option<Guest> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .find(|guest| guest.id == *id && guest.expires > now)
+            .cloned()
+    }
+
+    pub fn entries(&self) -> Vec<Guest> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Forgets the guests expired at `now`.
+    pub fn purge(&self, now: SystemTime) {
+        let mut entries = self.entries.lock().unwrap();
+        let expired = entries.partition_point(|guest| guest.expires <= now);
+        if expired == 0 {
+            return;
+        }
+        for guest in entries.drain(..expired) {
+            log::info!(
+                "Guest key {} ({:?}) expired",
+                privacy::id(&guest.id),
+                guest.name
+            );
+        }
+        if let Err(e) = self.save(&entries) {
+            log::error!("Failed to save guest keys: {e:?}");
+        }
+    }
+
+    fn save(&self, entries: &[Guest]) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.dry_run {
+            log::info!("[dry-run] Not saving {} guest keys", entries.len());
+            return Ok(());
+        }
+        write(path, entries)
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<Guest>> {
+    let contents = std::fs::read_to_string(path).context(format!("Failed to read {path:?}"))?;
+    let mut entries = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let mut fields = line.splitn(3, ',');
+        let guest = (|| {
+            Some(Guest {
+                id: Credential::parse(fields.next()?).ok()?,
+                expires: humantime::parse_rfc3339(fields.next()?.trim()).ok()?,
+                name: fields.next()?.to_owned(),
+            })
+        })();
+        match guest {
+            Some(guest) => entries.push(guest),
+            None => log::warn!("Skipping malformed line {} of {path:?}", idx + 1),
+        }
+    }
+    Ok(entries)
+}
+
+fn write(path: &Path, entries: &[Guest]) -> anyhow::Result<()> {
+    let contents: String = entries
+        .iter()
+        .map(|guest| {
+            format!(
+                "{},{},{}\n",
+                guest.id.hex(),
+                humantime::format_rfc3339_seconds(guest.expires),
+                guest.name
+            )
+        })
+        .collect();
+    persistence::write_atomically(path, |file| Ok(file.write_all(contents.as_bytes())?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::Guests;
+    use crate::Credential;
+
+    #[test]
+    fn guests_expire_and_survive_restarts_test() {
+        let dir = crate::testutil::test_dir("guests");
+        let path = dir.join("keys.guests");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_750_000_000);
+        let at = |secs| start + Duration::from_secs(secs);
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+
+        let guests = Guests::load(path.clone(), false, at(0));
+        let hour = Duration::from_secs(3600);
+        guests
+            .add(&alice, 2 * hour, "Alice, workshop", at(0))
+            .unwrap();
+        guests.add(&bob, hour, "Bob", at(0)).unwrap();
+        assert!(guests.add(&bob, 31 * 24 * hour, "Bob", at(0)).is_err());
+        assert_eq!(guests.entries()[0].name, "Bob");
+        assert!(guests.get(&bob, at(3599)).is_some());
+        assert!(guests.get(&bob, at(3600)).is_none());
+
+        let reloaded = Guests::load(path.clone(), false, at(10));
+        assert_eq!(reloaded.entries(), guests.entries());
+        assert_eq!(reloaded.entries()[1].name, "Alice, workshop");
+
+        guests.purge(at(3600));
+        assert_eq!(guests.entries().len(), 1);
+        assert!(guests.revoke(&alice).unwrap());
+        assert!(!guests.revoke(&alice).unwrap());
+        assert!(Guests::load(path.clone(), false, at(0))
+            .entries()
+            .is_empty());
+
+        // A dry run reads the guests but leaves the file alone.
+        guests.add(&bob, hour, "Bob", at(0)).unwrap();
+        let dry = Guests::load(path.clone(), true, at(0));
+        dry.revoke(&bob).unwrap();
+        assert_eq!(Guests::load(path, false, at(0)).entries().len(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
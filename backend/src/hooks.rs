@@ -0,0 +1,274 @@
+use std::{
+    collections::HashSet,
+    process::{Command, Stdio},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    audit,
+    config::{self, WebhookEvent},
+    privacy,
+    refresh::FailureKind,
+    webhooks::Webhooks,
+    Credential,
+};
+
+/// Exit status `sh` reports when the command itself can't be found.
+const SH_NOT_FOUND: i32 = 127;
+
+struct Job {
+    command: String,
+    env: Vec<(&'static str, String)>,
+}
+
+/// Runs the configured hook commands on a worker thread, so a slow or hung script never holds
+/// up the event loop, and passes the events on to the [`Webhooks`].
+#[derive(Default)]
+pub struct Hooks {
+    config: Option<config::Hooks>,
+    /// `None` in a dry run, which only logs the commands.
+    jobs: Option<mpsc::Sender<Job>>,
+    webhooks: Webhooks,
+}
+
+impl Hooks {
+    pub fn new(config: Option<config::Hooks>, webhooks: Webhooks, dry_run: bool) -> Hooks {
+        let jobs = config.as_ref().filter(|_| !dry_run).map(|config| {
+            let (jobs, queue) = mpsc::channel();
+            spawn_worker(queue, config.timeout);
+            jobs
+        });
+        Hooks {
+            config,
+            jobs,
+            webhooks,
+        }
+    }
+
+    /// A key presentation at `reader` was decided; granted ones run `on_granted`, all others
+    /// `on_denied`.
+    pub fn access(&self, id: &Credential, name: &str, reader: &str, decision: audit::Decision) {
+        let event = if decision.is_granted() {
+            WebhookEvent::Granted
+        } else {
+            WebhookEvent::Denied
+        };
+        self.webhooks.send(
+            event,
+            vec![
+                ("key_id", privacy::id(id)),
+                ("key_name", name.to_owned()),
+                ("decision", decision.to_string()),
+                ("reader", reader.to_owned()),
+            ],
+        );
+        let Some(config) = &self.config else {
+            return;
+        };
+        let (event, command) = if decision.is_granted() {
+            ("granted", &config.on_granted)
+        } else {
+            ("denied", &config.on_denied)
+        };
+        self.run(
+            command,
+            vec![
+                ("CD_EVENT", event.to_owned()),
+                ("CD_KEY_ID", id.hex()),
+                ("CD_KEY_NAME", name.to_owned()),
+                ("CD_DECISION", decision.to_string()),
+            ],
+        );
+    }
+
+    /// The access list was updated from MOS.
+    pub fn refreshed(&self, list_size: usize) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        self.run(
+            &config.on_refresh,
+            vec![
+                ("CD_EVENT", "refresh".to_owned()),
+                ("CD_KEY_COUNT", list_size.to_string()),
+            ],
+        );
+    }
+
+    /// The door was left open for `open_for` and the alarm raised, or closed after that.
+    pub fn door_alarm(&self, raised: bool, open_for: Duration) {
+        if raised {
+            self.webhooks.send(
+                WebhookEvent::DoorLeftOpen,
+                vec![("open_secs", open_for.as_secs().to_string())],
+            );
+        }
+        let Some(config) = &self.config else {
+            return;
+        };
+        let event = if raised { "door_alarm" } else { "door_closed" };
+        self.run(
+            &config.on_door_alarm,
+            vec![
+                ("CD_EVENT", event.to_owned()),
+                ("CD_OPEN_SECS", open_for.as_secs().to_string()),
+            ],
+        );
+    }
+
+    /// The reader has seen no w1 events for `silent_for` and the alert is raised, or an event
+    /// came after that.
+    pub fn reader_silent(&self, raised: bool, silent_for: Duration) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        let event = if raised {
+            "reader_silent"
+        } else {
+            "reader_active"
+        };
+        self.run(
+            &config.on_reader_silent,
+            vec![
+                ("CD_EVENT", event.to_owned()),
+                ("CD_SILENT_SECS", silent_for.as_secs().to_string()),
+            ],
+        );
+    }
+
+    /// `failures` fetches in a row failed, the last one for `kind`.
+    pub fn refresh_failing(&self, failures: u32, kind: FailureKind) {
+        self.webhooks.send(
+            WebhookEvent::RefreshFailed,
+            vec![
+                ("failures", failures.to_string()),
+                ("failure_kind", kind.to_string()),
+            ],
+        );
+        let Some(config) = &self.config else {
+            return;
+        };
+        self.run(
+            &config.on_refresh_failure,
+            vec![
+                ("CD_EVENT", "refresh_failure".to_owned()),
+                ("CD_FAILURES", failures.to_string()),
+                ("CD_FAILURE_KIND", kind.to_string()),
+            ],
+        );
+    }
+
+    /// A fetch succeeded after an outage reported by [`Hooks::refresh_failing`].
+    pub fn refresh_recovered(&self, outage: Duration) {
+        let Some(config) = &self.config else {
+            return;
+        };
+        self.run(
+            &config.on_refresh_failure,
+            vec![
+                ("CD_EVENT", "refresh_recovered".to_owned()),
+                ("CD_OUTAGE_SECS", outage.as_secs().to_string()),
+            ],
+        );
+    }
+
+    fn run(&self, command: &Option<String>, env: Vec<(&'static str, String)>) {
+        let Some(command) = command else {
+            return;
+        };
+        let Some(jobs) = &self.jobs else {
+            log::info!("[dry-run] Not running hook {command:?}");
+            return;
+        };
+        let job = Job {
+            command: command.clone(),
+            env,
+        };
+        if jobs.send(job).is_err() {
+            log::error!("Hook worker is gone, not running {command:?}");
+        }
+    }
+}
+
+fn spawn_worker(queue: mpsc::Receiver<Job>, timeout: Duration) {
+    std::thread::spawn(move || {
+        // Commands we already complained about as missing, so a broken hook logs only once.
+        let mut missing = HashSet::new();
+        for job in queue {
+            match execute(&job, timeout) {
+                Ok(Some(status)) if status.code() == Some(SH_NOT_FOUND) => {
+                    if missing.insert(job.command.clone()) {
+                        log::error!("Hook command {:?} not found", job.command);
+                    }
+                }
+                Ok(Some(status)) if status.success() => {
+                    log::debug!("Hook {:?} finished", job.command);
+                }
+                Ok(Some(status)) => log::warn!("Hook {:?} failed: {status}", job.command),
+                Ok(None) => log::warn!("Hook {:?} killed after {timeout:?}", job.command),
+                Err(e) => {
+                    if missing.insert(job.command.clone()) {
+                        log::error!("Failed to run hook {:?}: {e:?}", job.command);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Runs a hook to completion, killing it once `timeout` has passed; `None` means it was killed.
+fn execute(job: &Job, timeout: Duration) -> std::io::Result<Option<std::process::ExitStatus>> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(&job.command)
+        .envs(job.env.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::null())
+        .spawn()?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{execute, Job};
+
+    #[test]
+    fn hook_environment_and_timeout_test() {
+        let dir = crate::testutil::test_dir("hooks");
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out");
+        let job = Job {
+            command: format!("echo \"$CD_EVENT $CD_KEY_ID\" > {}", out.display()),
+            env: vec![
+                ("CD_EVENT", "granted".to_owned()),
+                ("CD_KEY_ID", "3300000392c6ea".to_owned()),
+            ],
+        };
+        let status = execute(&job, Duration::from_secs(5)).unwrap().unwrap();
+        assert!(status.success());
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            "granted 3300000392c6ea\n"
+        );
+
+        let job = Job {
+            command: "sleep 5".to_owned(),
+            env: Vec::new(),
+        };
+        assert!(execute(&job, Duration::from_millis(100)).unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
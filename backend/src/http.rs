@@ -0,0 +1,163 @@
+use std::io::{BufRead, BufReader, Read};
+
+/// Longest request or header line accepted, like the 8 KiB most servers allow.
+const MAX_LINE: usize = 8192;
+/// How many header lines a request may have.
+const MAX_HEADERS: usize = 64;
+
+/// An HTTP/1.1 request as the API and metrics endpoints need it.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Everything after `?`, if anything.
+    pub query: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of the first header called `name`, in any case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Why a request couldn't be read, with the status to answer it with.
+#[derive(Debug)]
+pub struct BadRequest {
+    pub status: u16,
+    pub error: anyhow::Error,
+}
+
+impl BadRequest {
+    fn new(status: u16, error: impl Into<anyhow::Error>) -> BadRequest {
+        BadRequest {
+            status,
+            error: error.into(),
+        }
+    }
+}
+
+/// Reads a request from `stream`, with a body of at most `max_body` bytes. No line is read
+/// past `MAX_LINE`, so a client can't make it buffer without end.
+pub fn read_request(stream: impl Read, max_body: usize) -> Result<Request, BadRequest> {
+    let mut reader = BufReader::new(stream);
+    let request_line = read_line(&mut reader, 414)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(BadRequest::new(
+            400,
+            anyhow::anyhow!("malformed request line"),
+        ));
+    };
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(&mut reader, 431)?;
+        if line.trim_end().is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(BadRequest::new(431, anyhow::anyhow!("too many headers")));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.to_owned(), value.trim().to_owned()));
+        }
+    }
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let mut request = Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        headers,
+        body: Vec::new(),
+    };
+    let content_length = match request.header("content-length") {
+        Some(value) => value
+            .parse()
+            .map_err(|e| BadRequest::new(400, anyhow::Error::new(e).context("content-length")))?,
+        None => 0,
+    };
+    if content_length > max_body {
+        return Err(BadRequest::new(
+            413,
+            anyhow::anyhow!("request body too large"),
+        ));
+    }
+    request.body = vec![0; content_length];
+    reader
+        .read_exact(&mut request.body)
+        .map_err(|e| BadRequest::new(400, e))?;
+    Ok(request)
+}
+
+/// Reads a line of at most `MAX_LINE` bytes, answering `status` to a longer one.
+fn read_line(reader: &mut impl BufRead, status: u16) -> Result<String, BadRequest> {
+    let mut line = String::new();
+    let read = reader
+        .take(MAX_LINE as u64)
+        .read_line(&mut line)
+        .map_err(|e| BadRequest::new(400, e))?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        return Err(BadRequest::new(status, anyhow::anyhow!("line too long")));
+    }
+    Ok(line)
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::read_request;
+
+    #[test]
+    fn read_request_test() {
+        let request = read_request(
+            &b"POST /open?door=1 HTTP/1.1\r\nAuthorization: Bearer x\r\nContent-Length: 2\r\n\r\n{}"[..],
+            16,
+        )
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/open");
+        assert_eq!(request.query, "door=1");
+        assert_eq!(request.header("authorization"), Some("Bearer x"));
+        assert_eq!(request.body, b"{}");
+
+        let status = |request: String| read_request(request.as_bytes(), 16).unwrap_err().status;
+        let long = "a".repeat(super::MAX_LINE);
+        assert_eq!(status(format!("GET /{long} HTTP/1.1\r\n\r\n")), 414);
+        assert_eq!(status(format!("GET / HTTP/1.1\r\nX: {long}\r\n\r\n")), 431);
+        assert_eq!(
+            status(format!("GET / HTTP/1.1\r\n{}\r\n", "X: y\r\n".repeat(65))),
+            431
+        );
+        // Just below the limit is fine.
+        let line = format!("X: {}\r\n", "a".repeat(super::MAX_LINE - 5));
+        assert!(read_request(format!("GET / HTTP/1.1\r\n{line}\r\n").as_bytes(), 16).is_ok());
+        assert_eq!(status("GET\r\n\r\n".to_owned()), 400);
+        assert_eq!(
+            status("POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n".to_owned()),
+            413
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+
+use crate::{
+    config,
+    persistence::{self, Inspection, Protection},
+    store, Credential, Key,
+};
+
+/// Prints the access list persisted as `config` says, for `cellardoor inspect`. The file backend
+/// is taken apart by [`file`]; an SQLite database is only listed through the store.
+pub fn configured(config: &config::Persistence) -> anyhow::Result<()> {
+    if config.backend == config::Backend::File {
+        return file(
+            &config.path,
+            &config.protection()?,
+            &config.last_seen_path(),
+        );
+    }
+    let list = store::open(config)?
+        .load()
+        .context("Failed to load the key list")?;
+    let mut out = io::stdout().lock();
+    writeln!(
+        out,
+        "SQLite database {:?} with {} keys",
+        config.path,
+        list.len()
+    )?;
+    let mut keys: Vec<_> = list.into_iter().collect();
+    keys.sort_by_key(|(id, _)| *id);
+    let last_seen = load_last_seen(&mut out, &config.last_seen_path())?;
+    print_keys(&mut out, &keys, &last_seen)
+}
+
+/// Prints what the key list at `path` holds, its format, checksum and signature, and where
+/// decoding stopped if it did. Fails after printing if the daemon wouldn't load the file.
+pub fn file(path: &Path, protection: &Protection, last_seen: &Path) -> anyhow::Result<()> {
+    let inspection = persistence::inspect_1w_devices(path, protection)?;
+    let modified = std::fs::metadata(path)?.modified()?;
+    let mut out = io::stdout().lock();
+    writeln!(out, "File {path:?}")?;
+    writeln!(
+        out,
+        "{} bytes, modified {}",
+        inspection.len,
+        humantime::format_rfc3339_seconds(modified)
+    )?;
+    let format = match inspection.version {
+        Some(version) => format!("version {version}"),
+        None => "legacy, bare ids".to_owned(),
+    };
+    let encrypted = if inspection.encrypted {
+        ", encrypted"
+    } else {
+        ""
+    };
+    writeln!(out, "Format {format}{encrypted}")?;
+    let checksum = match inspection.checksum {
+        Some(true) => "valid",
+        Some(false) => "MISMATCH",
+        None => "none",
+    };
+    writeln!(out, "Checksum {checksum}")?;
+    match inspection.signed {
+        Some(true) => writeln!(out, "Signature valid")?,
+        Some(false) => writeln!(out, "Signature MISMATCH")?,
+        None => {}
+    }
+    match inspection.announced {
+        Some(announced) => writeln!(
+            out,
+            "{} keys decoded, {announced} announced",
+            inspection.keys.len()
+        )?,
+        None => writeln!(out, "{} keys decoded", inspection.keys.len())?,
+    }
+    let last_seen = load_last_seen(&mut out, last_seen)?;
+    print_keys(&mut out, &inspection.keys, &last_seen)?;
+    if let Some((offset, error)) = &inspection.error {
+        writeln!(out, "Decoding stopped at byte {offset}: {error}")?;
+    }
+    match problem(&inspection) {
+        Some(problem) => anyhow::bail!("Key list {path:?} doesn't load: {problem}"),
+        None => Ok(()),
+    }
+}
+
+/// Why [`persistence::deserialize_1w_devices_with_backups`] would refuse the file, if it would.
+fn problem(inspection: &Inspection) -> Option<String> {
+    if let Some((offset, _)) = &inspection.error {
+        return Some(format!("decoding stopped at byte {offset}"));
+    }
+    if inspection.signed == Some(false) {
+        return Some("signature mismatch".to_owned());
+    }
+    if inspection.checksum == Some(false) {
+        return Some("checksum mismatch".to_owned());
+    }
+    let announced = inspection.announced?;
+    (announced != inspection.keys.len()).then(|| {
+        format!(
+            "{announced} keys announced, {} decoded",
+            inspection.keys.len()
+        )
+    })
+}
+
+/// The last-seen times at `path`, empty if there are none or they don't load.
+fn load_last_seen(
+    out: &mut impl Write,
+    path: &Path,
+) -> anyhow::Result<DashMap<Credential, SystemTime>> {
+    if !path.exists() {
+        return Ok(DashMap::new());
+    }
+    match persistence::deserialize_last_seen(path) {
+        Ok(last_seen) => Ok(last_seen),
+        Err(e) => {
+            writeln!(out, "Last-seen times {path:?} don't load: {e:#}")?;
+            Ok(DashMap::new())
+        }
+    }
+}
+
+/// One line per key, with those fields the format keeps; the PIN hash is never shown.
+fn print_keys(
+    out: &mut impl Write,
+    keys: &[(Credential, Key)],
+    last_seen: &DashMap<Credential, SystemTime>,
+) -> anyhow::Result<()> {
+    for (id, key) in keys {
+        let mut line = id.to_string();
+        if !key.name.is_empty() {
+            line.push_str(&format!(" name={:?}", key.name));
+        }
+        if let Some(expiry) = key.expiry {
+            let expiry = humantime::format_rfc3339_seconds(expiry);
+            line.push_str(&format!(" expires={expiry}"));
+        }
+        for (field, value) in [("schedule", &key.schedule), ("group", &key.group)] {
+            if let Some(value) = value {
+                line.push_str(&format!(" {field}={value:?}"));
+            }
+        }
+        if key.pin.is_some() {
+            line.push_str(" pin=yes");
+        }
+        if let Some(seen) = last_seen.get(id) {
+            let seen = humantime::format_rfc3339_seconds(*seen);
+            line.push_str(&format!(" last_seen={seen}"));
+        }
+        writeln!(out, "{line}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use dashmap::DashMap;
+
+    use crate::{
+        persistence::{self, Protection},
+        testutil::test_dir,
+        Credential, Key,
+    };
+
+    #[test]
+    fn damaged_file_test() {
+        let dir = test_dir("inspect");
+        let path = dir.join("keys.bin");
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x33, 0, 0, 0, 0, 0, 0x42]);
+        let list = DashMap::from_iter([(alice, Key::named("Alice")), (bob, Key::named("Bob"))]);
+        persistence::serialize_1w_devices(&list, &path).unwrap();
+        let seen = SystemTime::UNIX_EPOCH + Duration::from_secs(1_750_000_000);
+        let last_seen = dir.join("keys.seen");
+        persistence::serialize_last_seen(&DashMap::from_iter([(alice, seen)]), &last_seen).unwrap();
+        super::file(&path, &Protection::default(), &last_seen).unwrap();
+
+        // Cut into the second record: the first still decodes, and the file is refused.
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 10]).unwrap();
+        let inspection = persistence::inspect_1w_devices(&path, &Protection::default()).unwrap();
+        assert_eq!(inspection.version, Some(7));
+        assert_eq!(inspection.checksum, Some(false));
+        assert_eq!(inspection.announced, Some(2));
+        assert_eq!(inspection.keys.len(), 1);
+        assert_eq!(inspection.keys[0].1.name, "Bob");
+        let (offset, error) = inspection.error.unwrap();
+        assert_eq!(
+            (offset, error.as_str()),
+            (36, "record 2: File is truncated")
+        );
+        let err = super::file(&path, &Protection::default(), &last_seen).unwrap_err();
+        assert!(
+            err.to_string().ends_with("decoding stopped at byte 36"),
+            "{err}"
+        );
+
+        // A legacy list of bare ids with a stray byte.
+        std::fs::write(
+            &path,
+            [&alice.bytes()[..], &bob.bytes()[..], &[0x33]].concat(),
+        )
+        .unwrap();
+        let inspection = persistence::inspect_1w_devices(&path, &Protection::default()).unwrap();
+        assert_eq!((inspection.version, inspection.checksum), (None, None));
+        assert_eq!(inspection.keys.len(), 2);
+        assert_eq!(
+            inspection.error,
+            Some((14, "1 bytes left over, not a whole id".to_owned()))
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+};
+
+use anyhow::Context;
+use mio::{unix::SourceFd, Interest, Registry, Token};
+
+use crate::config;
+
+/// `EVIOCGRAB`, `_IOW('E', 0x90, int)`: keeps the keys from also reaching a console.
+const EVIOCGRAB: u64 = 0x4004_4590;
+const EV_KEY: u16 = 0x01;
+/// Key event value of a press, as opposed to a release (0) or autorepeat (2).
+const PRESSED: i32 = 1;
+
+/// A key on the keypad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Press {
+    Digit(char),
+    /// `#` or Enter, ending a PIN.
+    Enter,
+    /// `*`, Esc or Backspace, discarding the digits so far.
+    Clear,
+}
+
+/// The keypad's evdev device, read whenever it has events.
+pub struct Keypad {
+    file: File,
+}
+
+impl Keypad {
+    /// Opens and grabs the device at `config.path` and registers it under `token`.
+    pub fn open(
+        config: &config::Keypad,
+        registry: &Registry,
+        token: Token,
+    ) -> anyhow::Result<Keypad> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&config.path)
+            .context(format!("Failed to open keypad {:?}", config.path))?;
+        // SAFETY: EVIOCGRAB takes an int by value and `file` stays open.
+        if unsafe { libc::ioctl(file.as_raw_fd(), EVIOCGRAB as _, 1 as libc::c_int) } < 0 {
+            log::warn!(
+                "Failed to grab keypad {:?}, PINs may also reach a console: {}",
+                config.path,
+                io::Error::last_os_error()
+            );
+        }
+        registry.register(&mut SourceFd(&file.as_raw_fd()), token, Interest::READABLE)?;
+        log::info!("Watching keypad {:?}", config.path);
+        Ok(Keypad { file })
+    }
+
+    /// The keys pressed since the last call; an error means the keypad is gone.
+    pub fn ready(&mut self) -> io::Result<Vec<Press>> {
+        let size = std::mem::size_of::<libc::input_event>();
+        let mut presses = Vec::new();
+        let mut buf = vec![0; size * 64];
+        loop {
+            match self.file.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(len) => presses.extend(buf[..len].chunks_exact(size).filter_map(parse_event)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(presses),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// The key pressed in an `input_event`: a `timeval`, then type, code and value.
+fn parse_event(event: &[u8]) -> Option<Press> {
+    let tail = &event[event.len() - 8..];
+    let kind = u16::from_ne_bytes(tail[0..2].try_into().unwrap());
+    let code = u16::from_ne_bytes(tail[2..4].try_into().unwrap());
+    let value = i32::from_ne_bytes(tail[4..8].try_into().unwrap());
+    (kind == EV_KEY && value == PRESSED)
+        .then(|| press(code))
+        .flatten()
+}
+
+/// What the key `code` from `linux/input-event-codes.h` means on a keypad: the number row, the
+/// numeric keypad and the `KEY_NUMERIC_*` codes matrix keypads are usually mapped to.
+fn press(code: u16) -> Option<Press> {
+    let digit = |digit: u8| Some(Press::Digit(char::from(b'0' + digit)));
+    match code {
+        // KEY_1 to KEY_9, then KEY_0.
+        2..=10 => digit(code as u8 - 1),
+        11 => digit(0),
+        // KEY_KP7 to KEY_KP9, KEY_KP4 to KEY_KP6, KEY_KP1 to KEY_KP3, KEY_KP0.
+        71..=73 => digit(code as u8 - 64),
+        75..=77 => digit(code as u8 - 71),
+        79..=81 => digit(code as u8 - 78),
+        82 => digit(0),
+        // KEY_NUMERIC_0 to KEY_NUMERIC_9.
+        0x200..=0x209 => digit((code - 0x200) as u8),
+        // KEY_ENTER, KEY_KPENTER, KEY_NUMERIC_POUND.
+        28 | 96 | 0x20b => Some(Press::Enter),
+        // KEY_ESC, KEY_BACKSPACE, KEY_KPASTERISK, KEY_NUMERIC_STAR.
+        1 | 14 | 55 | 0x20a => Some(Press::Clear),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_event, press, Press};
+
+    #[test]
+    fn keys_test() {
+        let digits: String = [2, 10, 11, 71, 75, 79, 82, 0x200, 0x209]
+            .into_iter()
+            .filter_map(press)
+            .map(|press| match press {
+                Press::Digit(digit) => digit,
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(digits, "190741009");
+        assert_eq!(press(96), Some(Press::Enter));
+        assert_eq!(press(0x20b), Some(Press::Enter));
+        assert_eq!(press(0x20a), Some(Press::Clear));
+        // KEY_A
+        assert_eq!(press(30), None);
+
+        let event = |kind: u16, code: u16, value: i32| {
+            let mut event = vec![0; std::mem::size_of::<libc::input_event>() - 8];
+            event.extend_from_slice(&kind.to_ne_bytes());
+            event.extend_from_slice(&code.to_ne_bytes());
+            event.extend_from_slice(&value.to_ne_bytes());
+            event
+        };
+        assert_eq!(parse_event(&event(1, 80, 1)), Some(Press::Digit('2')));
+        // Releases, autorepeat and other event types don't count.
+        assert_eq!(parse_event(&event(1, 80, 0)), None);
+        assert_eq!(parse_event(&event(1, 80, 2)), None);
+        assert_eq!(parse_event(&event(4, 80, 1)), None);
+    }
+}
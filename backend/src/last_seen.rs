@@ -0,0 +1,103 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use dashmap::DashMap;
+
+use crate::{persistence, Credential, Key};
+
+/// When each key last opened the door, for member engagement stats.
+#[derive(Default)]
+pub struct LastSeen {
+    seen: DashMap<Credential, SystemTime>,
+    /// Set on every change so saves only touch the disk when there is something new.
+    dirty: AtomicBool,
+}
+
+impl LastSeen {
+    pub fn load(path: &Path) -> LastSeen {
+        let seen = if path.exists() {
+            persistence::deserialize_last_seen(path).unwrap_or_else(|e| {
+                log::error!("Failed to load last-seen timestamps, starting empty: {e:?}");
+                DashMap::new()
+            })
+        } else {
+            DashMap::new()
+        };
+        LastSeen {
+            seen,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    pub fn touch(&self, id: &Credential) {
+        self.seen.insert(*id, SystemTime::now());
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// All entries, most recently seen first.
+    pub fn entries(&self) -> Vec<(Credential, SystemTime)> {
+        let mut entries: Vec<_> = self.seen.iter().map(|e| (*e.key(), *e.value())).collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.1));
+        entries
+    }
+
+    /// Forgets keys that are no longer on the access list and weren't seen within `retention`.
+    pub fn expire(&self, access_list: &DashMap<Credential, Key>, retention: Duration) {
+        let cutoff = SystemTime::now()
+            .checked_sub(retention)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let before = self.seen.len();
+        self.seen
+            .retain(|id, seen| access_list.contains_key(id) || *seen >= cutoff);
+        if self.seen.len() != before {
+            log::debug!(
+                "Dropped {} last-seen entries of removed keys",
+                before - self.seen.len()
+            );
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes the timestamps to `path` if they changed since the last save.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if self.dirty.swap(false, Ordering::Relaxed) {
+            if let Err(e) = persistence::serialize_last_seen(&self.seen, path) {
+                self.dirty.store(true, Ordering::Relaxed);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use dashmap::DashMap;
+
+    use super::LastSeen;
+    use crate::{Credential, Key};
+
+    #[test]
+    fn expire_keeps_listed_and_recent_keys_test() {
+        let listed = Credential::OneWire([1, 0, 0, 0, 0, 0, 1]);
+        let recent = Credential::OneWire([1, 0, 0, 0, 0, 0, 2]);
+        let stale = Credential::OneWire([1, 0, 0, 0, 0, 0, 3]);
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        let last_seen = LastSeen::default();
+        last_seen.seen.insert(listed, old);
+        last_seen.seen.insert(stale, old);
+        last_seen.touch(&recent);
+
+        let access_list = DashMap::from_iter([(listed, Key::default())]);
+        last_seen.expire(&access_list, Duration::from_secs(60));
+
+        let mut ids: Vec<_> = last_seen.entries().into_iter().map(|e| e.0).collect();
+        ids.sort();
+        assert_eq!(ids, [listed, recent]);
+    }
+}
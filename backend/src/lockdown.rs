@@ -0,0 +1,85 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Context;
+
+/// Refuses every key until cleared. With `lockdown_file` the flag is the file's existence, so
+/// it survives a restart.
+#[derive(Default)]
+pub struct Lockdown {
+    active: AtomicBool,
+    path: Option<PathBuf>,
+    /// Master keys still open the door.
+    pub exempt_master: bool,
+    /// The flag changes, but the file is left alone.
+    dry_run: bool,
+}
+
+impl Lockdown {
+    pub fn load(path: Option<PathBuf>, exempt_master: bool, dry_run: bool) -> Lockdown {
+        let active = path.as_ref().is_some_and(|path| path.exists());
+        if active {
+            log::warn!("Lockdown still active from {path:?}, refusing all keys");
+        }
+        Lockdown {
+            active: AtomicBool::new(active),
+            path,
+            exempt_master,
+            dry_run,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Enables or lifts the lockdown. The flag changes even if the file can't be, which is
+    /// reported as an error as it won't survive a restart.
+    pub fn set(&self, active: bool) -> anyhow::Result<()> {
+        self.active.store(active, Ordering::Relaxed);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.dry_run {
+            log::info!("[dry-run] Not updating {path:?}");
+            return Ok(());
+        }
+        if active {
+            std::fs::write(path, "").context(format!("Failed to create {path:?}"))
+        } else {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(e).context(format!("Failed to remove {path:?}"))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Lockdown;
+
+    #[test]
+    fn lockdown_survives_restart_test() {
+        let dir = crate::testutil::test_dir("lockdown");
+        let path = dir.join("lockdown");
+
+        let lockdown = Lockdown::load(Some(path.clone()), false, false);
+        assert!(!lockdown.is_active());
+        lockdown.set(true).unwrap();
+        assert!(Lockdown::load(Some(path.clone()), false, false).is_active());
+
+        lockdown.set(false).unwrap();
+        assert!(!path.exists());
+        assert!(!Lockdown::load(Some(path.clone()), false, false).is_active());
+
+        let dry = Lockdown::load(Some(path.clone()), false, true);
+        dry.set(true).unwrap();
+        assert!(dry.is_active() && !path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
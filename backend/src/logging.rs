@@ -0,0 +1,304 @@
+//! Sets up log4rs from the `logging` config and the command line's overrides, adding the
+//! `journald` appender. It sends records to the systemd journal over its native protocol;
+//! key-values attached to a record become fields prefixed with `CD_`, e.g.
+//! `log::info!(event = "access"; ...)` gives `CD_EVENT=access`.
+
+use std::{
+    io::Write,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+};
+
+use log::{kv, Level, LevelFilter, Record};
+use log4rs::{
+    append::{
+        console::{ConsoleAppender, Target},
+        Append,
+    },
+    config::{Appender, Deserialize, Deserializers, Logger, Root},
+    encode::pattern::PatternEncoder,
+};
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends each record as one datagram, or writes it to stderr if there is no journal.
+#[derive(Debug)]
+pub struct JournaldAppender {
+    /// `None` without a journal.
+    socket: Option<UnixDatagram>,
+    path: PathBuf,
+    identifier: String,
+}
+
+impl JournaldAppender {
+    /// Falls back to stderr, saying so once, if there is no journal listening at `path`.
+    pub fn new(path: &Path, identifier: String) -> JournaldAppender {
+        let socket = match std::fs::exists(path) {
+            Ok(true) => UnixDatagram::unbound()
+                .map_err(|e| eprintln!("Failed to create a socket for the journal: {e}"))
+                .ok(),
+            _ => {
+                eprintln!("No journal at {path:?}, logging to stderr instead");
+                None
+            }
+        };
+        JournaldAppender {
+            socket,
+            path: path.to_owned(),
+            identifier,
+        }
+    }
+}
+
+impl Append for JournaldAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let mut fields = Fields::default();
+        // Errors only come from the visitor, which has none.
+        let _ = record.key_values().visit(&mut fields);
+        let Some(socket) = &self.socket else {
+            let mut line = format!("{} {} {}", record.level(), record.target(), record.args());
+            for (name, value) in &fields.0 {
+                line.push_str(&format!(" {}={value}", name.to_ascii_lowercase()));
+            }
+            writeln!(std::io::stderr(), "{line}")?;
+            return Ok(());
+        };
+        let mut datagram = Vec::new();
+        push_field(&mut datagram, "PRIORITY", priority(record.level()));
+        push_field(&mut datagram, "SYSLOG_IDENTIFIER", &self.identifier);
+        push_field(&mut datagram, "MESSAGE", &record.args().to_string());
+        push_field(&mut datagram, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            push_field(&mut datagram, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            push_field(&mut datagram, "CODE_LINE", &line.to_string());
+        }
+        for (name, value) in &fields.0 {
+            push_field(&mut datagram, &format!("CD_{name}"), value);
+        }
+        socket.send_to(&datagram, &self.path)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+/// The key-values of a record as journal field names without the prefix, and values.
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> kv::VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        // Field names are upper case letters, digits and underscores.
+        let name = key
+            .as_str()
+            .chars()
+            .map(|c| match c.to_ascii_uppercase() {
+                c @ ('A'..='Z' | '0'..='9') => c,
+                _ => '_',
+            })
+            .collect();
+        self.0.push((name, value.to_string()));
+        Ok(())
+    }
+}
+
+/// The syslog priority of `level`.
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
+
+/// Appends `name=value`, or the length-prefixed form for values with line breaks.
+fn push_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JournaldAppenderConfig {
+    /// `cellardoor` by default.
+    syslog_identifier: Option<String>,
+}
+
+pub struct JournaldAppenderDeserializer;
+
+impl Deserialize for JournaldAppenderDeserializer {
+    type Trait = dyn Append;
+    type Config = JournaldAppenderConfig;
+
+    fn deserialize(
+        &self,
+        config: JournaldAppenderConfig,
+        _: &Deserializers,
+    ) -> anyhow::Result<Box<dyn Append>> {
+        let identifier = config
+            .syslog_identifier
+            .unwrap_or_else(|| "cellardoor".to_owned());
+        Ok(Box::new(JournaldAppender::new(
+            Path::new(SOCKET),
+            identifier,
+        )))
+    }
+}
+
+/// What the command line overrides of the `logging` config.
+#[derive(Debug, Default)]
+pub struct Overrides {
+    /// Replaces the level of the root logger.
+    pub level: Option<LevelFilter>,
+    /// Replaces every appender with one writing to stderr.
+    pub foreground: bool,
+}
+
+/// Sets up log4rs from `config`, knowing the `journald` appender besides its own, with the
+/// command line's `overrides` taking precedence.
+pub fn init(config: log4rs::config::RawConfig, overrides: &Overrides) -> anyhow::Result<()> {
+    log4rs::init_config(build(config, overrides)?)?;
+    Ok(())
+}
+
+fn build(
+    config: log4rs::config::RawConfig,
+    overrides: &Overrides,
+) -> anyhow::Result<log4rs::Config> {
+    let mut root = config.root();
+    if let Some(level) = overrides.level {
+        root.set_level(level);
+    }
+    if overrides.foreground {
+        // Coloured by level when stderr is a terminal.
+        let encoder = PatternEncoder::new("{d(%H:%M:%S%.3f)} {h({l:5})} {t} {m}{n}");
+        let stderr = ConsoleAppender::builder()
+            .target(Target::Stderr)
+            .encoder(Box::new(encoder))
+            .build();
+        // The configured loggers keep their levels, but lose appenders that are gone.
+        let loggers = config.loggers().into_iter().map(|logger| {
+            Logger::builder()
+                .additive(logger.additive())
+                .build(logger.name(), logger.level())
+        });
+        let config = log4rs::Config::builder()
+            .appender(Appender::builder().build("stderr", Box::new(stderr)))
+            .loggers(loggers)
+            .build(Root::builder().appender("stderr").build(root.level()))?;
+        return Ok(config);
+    }
+    let mut deserializers = Deserializers::default();
+    deserializers.insert("journald", JournaldAppenderDeserializer);
+    let (appenders, errors) = config.appenders_lossy(&deserializers);
+    if !errors.is_empty() {
+        return Err(log4rs::config::InitError::Deserializing(errors).into());
+    }
+    Ok(log4rs::Config::builder()
+        .appenders(appenders)
+        .loggers(config.loggers())
+        .build(root)?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::net::UnixDatagram;
+
+    use log::{Level, LevelFilter, Record};
+    use log4rs::append::Append;
+
+    use super::{build, JournaldAppender, Overrides};
+    use crate::testutil::test_dir;
+
+    #[test]
+    fn overrides_test() {
+        let raw: log4rs::config::RawConfig = serde_yaml_ng::from_str(
+            "
+appenders:
+  stdout:
+    kind: console
+root:
+  level: warn
+  appenders: [stdout]
+loggers:
+  cellardoor::mqtt:
+    level: debug
+    appenders: [stdout]
+    additive: false
+",
+        )
+        .unwrap();
+        let config = build(raw.clone(), &Overrides::default()).unwrap();
+        assert_eq!(config.root().level(), LevelFilter::Warn);
+        assert_eq!(config.loggers()[0].appenders(), ["stdout"]);
+        let config = build(
+            raw,
+            &Overrides {
+                level: Some(LevelFilter::Trace),
+                foreground: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(config.root().level(), LevelFilter::Trace);
+        assert_eq!(config.root().appenders(), ["stderr"]);
+        let appenders: Vec<_> = config.appenders().iter().map(|a| a.name()).collect();
+        assert_eq!(appenders, ["stderr"]);
+        let logger = &config.loggers()[0];
+        assert_eq!(
+            (logger.level(), logger.additive()),
+            (LevelFilter::Debug, false)
+        );
+        assert!(logger.appenders().is_empty());
+    }
+
+    #[test]
+    fn native_protocol_test() {
+        let dir = test_dir("logging");
+        let path = dir.join("socket");
+        let journal = UnixDatagram::bind(&path).unwrap();
+        let appender = JournaldAppender::new(&path, "cellardoor".to_owned());
+        let fields: &[(&str, log::kv::Value)] = &[
+            ("event", "access".into()),
+            ("key_id", "33-00000392c6ea".into()),
+            ("keys", 42.into()),
+        ];
+        appender
+            .append(
+                &Record::builder()
+                    .level(Level::Warn)
+                    .target("cellardoor::access")
+                    .args(format_args!("Two\nlines"))
+                    .key_values(&fields)
+                    .build(),
+            )
+            .unwrap();
+
+        let mut buf = [0; 1024];
+        let len = journal.recv(&mut buf).unwrap();
+        let datagram = &buf[..len];
+        let text = String::from_utf8_lossy(datagram);
+        assert!(text.starts_with("PRIORITY=4\nSYSLOG_IDENTIFIER=cellardoor\n"));
+        let mut message = b"MESSAGE\n".to_vec();
+        message.extend_from_slice(&9u64.to_le_bytes());
+        message.extend_from_slice(b"Two\nlines\n");
+        assert!(datagram
+            .windows(message.len())
+            .any(|window| window == message));
+        assert!(text.ends_with("CD_EVENT=access\nCD_KEY_ID=33-00000392c6ea\nCD_KEYS=42\n"));
+
+        // Without a journal the record goes to stderr instead.
+        let missing = JournaldAppender::new(&dir.join("missing"), "cellardoor".to_owned());
+        assert!(missing.socket.is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,203 +1,958 @@
 use std::{
     collections::HashSet,
-    fs::File,
-    io::{Read, Write},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use clap::Parser;
-use dashmap::DashSet;
+use cellardoor_core::{
+    config, crc8, keylist, parse_1w_id, persistence, pin, privacy, schedule, Credential, Key,
+    OneWireId,
+};
+use clap::{Parser, Subcommand};
+use dashmap::DashMap;
 use mio::{Events, Interest, Token};
 use udev::MonitorBuilder;
 
-mod config;
+mod access;
+mod api;
+mod audit;
+mod auto_unlock;
+mod backoff;
+mod broadcast;
+mod clock;
+mod control;
+mod debounce;
+mod denials;
+mod door;
+mod enroll;
+mod events;
+mod exit_button;
+mod feedback;
+mod gpio;
+mod guests;
+mod hooks;
+mod http;
+mod inspect;
+mod keypad;
+mod last_seen;
+mod lockdown;
+mod logging;
+mod metrics;
+mod mqtt;
+mod passback;
+mod pins;
+mod pn532;
+mod presence;
+mod ratelimit;
+mod reader_health;
+mod refresh;
+mod sdnotify;
+mod sensor;
+mod sha_auth;
+mod sightings;
+mod signals;
+mod simulate;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod staleness;
+mod store;
+#[cfg(test)]
+mod testutil;
+mod timers;
+mod transfer;
+mod usage;
+mod w1poll;
+mod wakeup;
+mod webhooks;
+mod wiegand;
 
 const W1_TOKEN: Token = Token(0);
+const SIGNAL_TOKEN: Token = Token(1);
+const CONTROL_TOKEN: Token = Token(2);
+/// MQTT commands, NFC tags and unlocks from other threads.
+const WAKE_TOKEN: Token = Token(3);
+const SIMULATE_TOKEN: Token = Token(4);
+const SENSOR_TOKEN: Token = Token(5);
+const EXIT_BUTTON_TOKEN: Token = Token(6);
+const KEYPAD_TOKEN: Token = Token(7);
+/// Wiegand readers are registered from here on, by their index in `readers`.
+const WIEGAND_TOKEN_BASE: usize = 16;
+const W1_DEVICES: &str = "/sys/bus/w1/devices";
+
+/// How often a failed udev monitor is recreated before giving up.
+const MONITOR_RETRIES: u32 = 5;
+const MONITOR_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long shutdown waits for the refresh thread to finish.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the event loop at least wakes for housekeeping: pruning the debounce state and
+/// checking the refresh thread is still running.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
 
-type OneWireId = [u8; 7];
+/// Work the event loop does at a deadline, see [`timers::Timers`].
+enum Timer {
+    Housekeeping,
+    /// Relocks the door of the reader at this index, if its unlock has ended.
+    Relock(usize),
+    /// Moves the feedback pattern on to its next step.
+    Feedback,
+}
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[clap(short = 'c', long, default_value = "config.yaml", env)]
+    #[clap(short = 'c', long, default_value = "config.yaml", env, global = true)]
     config: PathBuf,
+    /// Only parse and validate the configuration, then exit.
+    #[clap(long)]
+    check_config: bool,
+    /// Also take key presentations from lines written to this FIFO, created if missing, for
+    /// testing without iButton hardware.
+    #[clap(long)]
+    simulate: Option<PathBuf>,
+    /// Decide and log every presentation, but never unlock the door, run hooks or persist
+    /// anything.
+    #[clap(long)]
+    dry_run: bool,
+    /// Read a PIN from stdin and print its salted hash for `pin_required` or the key list, then
+    /// exit.
+    #[clap(long)]
+    hash_pin: bool,
+    /// Overrides the root level of the `logging` config, e.g. `trace`.
+    #[clap(long)]
+    log_level: Option<log::LevelFilter>,
+    /// Logs to stderr only, in colour on a terminal, instead of the appenders of the `logging`
+    /// config. Both this and `--log-level` take precedence over the config file.
+    #[clap(long)]
+    foreground: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write the persisted access list, with when each key was last seen, as CSV. Refuses to
+    /// run while the daemon is.
+    Export {
+        /// Where to write the CSV instead of stdout.
+        #[clap(long)]
+        out: Option<PathBuf>,
+    },
+    /// Replace the persisted access list and last-seen times with those from CSV as `export`
+    /// writes it. Refuses to run while the daemon is.
+    Import {
+        file: PathBuf,
+        /// Leave out invalid records instead of importing nothing.
+        #[clap(long)]
+        skip_invalid: bool,
+    },
+    /// Print the persisted access list with last-seen times, and for the file backend its
+    /// format, checksum and how far a damaged file decodes.
+    Inspect {
+        /// Inspect this key list file instead of the configured one, without reading the config.
+        #[clap(long)]
+        file: Option<PathBuf>,
+    },
+    /// Fetch, apply and persist the key list once, e.g. from a systemd timer, printing what
+    /// changed. Exits with 1 if the fetch failed and 2 if the safety limits refused the list;
+    /// while the daemon runs it is asked to refresh over the control socket instead.
+    Refresh {
+        /// Apply the list even if it removes more keys than the safety limits allow.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print unique keys and grants per period, as counted with `persistence.usage_stats`.
+    Stats {
+        /// The first day counted, e.g. 2024-01-01; all that is kept by default.
+        #[clap(long)]
+        since: Option<chrono::NaiveDate>,
+        #[clap(long, value_enum, default_value = "month")]
+        group_by: usage::GroupBy,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    if args.hash_pin {
+        let mut pin = String::new();
+        std::io::stdin().read_line(&mut pin)?;
+        let pin = pin.trim();
+        anyhow::ensure!(
+            !pin.is_empty() && pin.chars().all(|c| c.is_ascii_digit()),
+            "A PIN consists of digits"
+        );
+        println!("{}", pin::hash(pin)?);
+        return Ok(());
+    }
+    if let Some(Command::Inspect { file: Some(file) }) = &args.command {
+        let last_seen = file.with_extension("seen");
+        return inspect::file(file, &persistence::Protection::default(), &last_seen);
+    }
     let config = config::Config::parse(&args.config)
-        .context(format!("Failed to read file {:?}", args.config))?;
-    log4rs::init_raw_config(config.logging)?;
-
-    let access_list = Arc::new(
-        deserialize_1w_devices(&config.persistence.path).unwrap_or_else(|e| {
-            log::error!("Failed to deserialize persisted key list, using empty list: {e:?}");
-            DashSet::new()
-        }),
-    );
-    let inner_access_list = access_list.clone();
-
-    std::thread::spawn(move || loop {
-        match mos_refresh(&config.thing, &config.persistence, &inner_access_list) {
+        .context(format!("Failed to read file {:?}", args.config));
+    let config = config.and_then(|config| config.validate().map(|_| config));
+    if args.check_config {
+        match config {
             Ok(_) => {
-                log::error!("MOS refresh thread terminated");
+                println!("Configuration {:?} is valid", args.config);
+                std::process::exit(0);
             }
             Err(e) => {
-                log::error!("MOS refresh thread error: {:?}", e);
+                eprintln!("{e:#}");
+                std::process::exit(1);
             }
         }
+    }
+    let config = config?;
+    if let Some(command) = &args.command {
+        privacy::init(&config.privacy)?;
+        return match command {
+            Command::Export { out } => transfer::export(&config, out.as_deref()),
+            Command::Import { file, skip_invalid } => {
+                transfer::import(&config, file, *skip_invalid)
+            }
+            Command::Inspect { file: _ } => inspect::configured(&config.persistence),
+            Command::Refresh { force } => {
+                // Whatever the config says, the summary goes to stdout and the log to stderr.
+                let overrides = logging::Overrides {
+                    level: args.log_level,
+                    foreground: true,
+                };
+                logging::init(config.logging.clone(), &overrides)?;
+                let status = refresh::once(&config, *force, args.dry_run || config.dry_run)?;
+                std::process::exit(status)
+            }
+            Command::Stats { since, group_by } => usage::print(&config, *since, *group_by),
+        };
+    }
+    let dry_run = args.dry_run || config.dry_run;
+    // Before the list is loaded or the door touched, so a second instance, or `import`, can't
+    // fight this one over either. A dry run does neither for real.
+    let _lock = (!dry_run)
+        .then(|| persistence::Lock::acquire(config.persistence.lock_path()))
+        .transpose()?;
+    if let Some(timezone) = &config.timezone {
+        // Before any thread starts; chrono's local time follows TZ.
+        std::env::set_var("TZ", timezone);
+    }
+    // What a reload on SIGHUP is compared against.
+    let mut running = config.clone();
+    let overrides = logging::Overrides {
+        level: args.log_level,
+        foreground: args.foreground,
+    };
+    logging::init(config.logging, &overrides)?;
+    privacy::init(&config.privacy)?;
+    if dry_run {
+        log::warn!("Dry run: the door stays locked and nothing is persisted");
+    }
+
+    let readers = access::readers(&config.readers, &config.door, dry_run)?;
+    let clock = Arc::new(clock::Clock::new(
+        config.clock.clone(),
+        std::time::SystemTime::now(),
+    ));
+    let audit = Arc::new(audit::AuditLog::new(
+        config.audit.as_ref(),
+        dry_run,
+        clock.clone(),
+    )?);
+    let event_log = Arc::new(events::EventLog::new(
+        config.events.as_ref(),
+        config.history_size,
+    )?);
+
+    let mut store = store::open(&config.persistence)?;
+    if dry_run {
+        store = Arc::new(store::DryRun(store));
+    }
+    let store: Arc<dyn store::KeyStore> = Arc::new(store::Background::spawn(store));
+    let access_list = Arc::new(store.load().unwrap_or_else(|e| {
+        log::error!("Failed to deserialize persisted key list, using empty list: {e:?}");
+        DashMap::new()
+    }));
+
+    // Keys blocked or families disallowed since the list was saved must not linger in it.
+    access_list.retain(|id, _| {
+        !config.deny_keys.contains(id) && access::family_allowed(&config.allowed_family_codes, id)
     });
 
-    let monitor = MonitorBuilder::new()?.match_subsystem("w1")?;
-    let mut socket = monitor.listen()?;
+    let metrics = Arc::new(metrics::Metrics::default());
+    metrics.access_list_size.set(access_list.len() as u64);
+    match persistence::load_fetch_time(config.persistence.fetched_path()) {
+        Ok(Some(fetched)) => metrics.last_refresh.set(
+            fetched
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        ),
+        Ok(None) => {}
+        Err(e) => log::error!("Failed to load the time of the last fetch: {e:?}"),
+    }
+    if let Some(metrics_config) = &config.metrics {
+        let listener = std::net::TcpListener::bind(metrics_config.listen).context(format!(
+            "Failed to bind metrics endpoint to {}",
+            metrics_config.listen
+        ))?;
+        metrics::serve(listener, metrics.clone());
+    }
+
+    let last_seen = Arc::new(last_seen::LastSeen::load(
+        &config.persistence.last_seen_path(),
+    ));
+    let sightings = sightings::Sightings::load(
+        config.persistence.pending_path.clone(),
+        config.persistence.pending_max,
+    );
+
+    let wakeup = Arc::new(wakeup::Wakeup::default());
+    let last_seen_path = config.persistence.last_seen_path();
+    let guests_path = config.persistence.guests_path();
+    let usage = match config.persistence.usage_stats {
+        true => usage::Usage::new(
+            store.clone(),
+            config.privacy.mode == config::PrivacyMode::Full,
+        ),
+        false => usage::Usage::default(),
+    };
+    let staleness = staleness::Staleness::new(&config.thing);
+    let enroller = Arc::new(enroll::Enroller::new(
+        config.persistence.enrollment_path(),
+        config.thing.enroll_url.is_some().then(|| wakeup.clone()),
+    ));
 
+    let notifier = Arc::new(sdnotify::Notifier::from_env());
+    let liveness = Arc::new(sdnotify::Liveness::default());
+    let stall_limit = config.systemd.refresh_stall;
+
+    let webhooks = webhooks::Webhooks::new(config.webhooks, metrics.clone(), dry_run)?;
+    let hooks = Arc::new(hooks::Hooks::new(config.hooks, webhooks, dry_run));
     let mut poll = mio::Poll::new()?;
+    let waker = Arc::new(mio::Waker::new(poll.registry(), WAKE_TOKEN)?);
+    let discovery = mqtt::Discovery {
+        record: config.persistence.discovery_path(),
+        door_sensor: config.sensor.is_some(),
+        dry_run,
+    };
+    let mqtt = Arc::new(mqtt::Mqtt::new(config.mqtt, discovery, waker.clone())?);
+
+    let reload = Arc::new(Mutex::new(None));
+    let refresh_thread = refresh::Refresher {
+        thing: config.thing,
+        persistence: config.persistence,
+        store: store.clone(),
+        filter: refresh::KeyFilter {
+            deny_keys: config.deny_keys.clone(),
+            allowed_family_codes: config.allowed_family_codes.clone(),
+            known_groups: running.known_groups(),
+        },
+        access_list: access_list.clone(),
+        last_seen: last_seen.clone(),
+        metrics: metrics.clone(),
+        wakeup: wakeup.clone(),
+        hooks: hooks.clone(),
+        mqtt: mqtt.clone(),
+        audit: audit.clone(),
+        enroller: enroller.clone(),
+        staleness,
+        clock: clock.clone(),
+        events: event_log.clone(),
+        notifier: notifier.clone(),
+        liveness: liveness.clone(),
+        dry_run,
+        reload: reload.clone(),
+    }
+    .spawn();
+
+    let access = Arc::new(access::Access {
+        access_list,
+        master_keys: RwLock::new(config.master_keys),
+        deny_keys: RwLock::new(config.deny_keys),
+        allowed_family_codes: RwLock::new(config.allowed_family_codes),
+        readers,
+        metrics,
+        audit,
+        last_seen,
+        debounce: Mutex::new(debounce::Debounce::new(config.reader.debounce)),
+        denials: Mutex::new(denials::Denials::new(&config.denials)),
+        passback: Mutex::new(passback::Passback::new(config.anti_passback)),
+        hooks,
+        mqtt,
+        lockdown: lockdown::Lockdown::load(
+            config.lockdown_file.clone(),
+            config.lockdown_exempt_master,
+            dry_run,
+        ),
+        auto_unlock: auto_unlock::AutoUnlock::new(config.auto_unlock),
+        enroller,
+        pins: pins::Pins::new(config.keypad.clone(), config.pin_required),
+        guests: guests::Guests::load(guests_path, dry_run, std::time::SystemTime::now()),
+        usage,
+        presence: presence::Presence::new(config.presence),
+        reader_health: reader_health::ReaderHealth::new(
+            config.reader.watchdog.clone(),
+            dry_run,
+            Instant::now(),
+        ),
+        open_limit: ratelimit::RateLimit::new(config.open_rate_limit),
+        sightings,
+        staleness: RwLock::new(staleness),
+        clock,
+        feedback: feedback::Feedback::new(config.feedback.as_ref(), dry_run)?,
+        schedules: RwLock::new(config.schedules),
+        events: event_log,
+        w1_devices: PathBuf::from(W1_DEVICES),
+        authenticator: sha_auth::Authenticator::new(&config.auth, PathBuf::from(W1_DEVICES))?,
+        store,
+        dry_run,
+    });
+
+    for reader in &access.readers {
+        reader.door.set_waker(waker.clone());
+    }
+    access
+        .metrics
+        .lockdown
+        .set(access.lockdown.is_active() as u64);
+    // A window may have begun or ended while we were down.
+    access.check_auto_unlock();
+    if access.reader_health.is_configured() {
+        access
+            .metrics
+            .reader_watchdog
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        // Replaces an alert retained from before the restart.
+        access.mqtt.reader_silent(false, Duration::ZERO);
+    }
+
+    if let Some(api_config) = &config.api {
+        let listener = std::net::TcpListener::bind(api_config.listen)
+            .context(format!("Failed to bind API to {}", api_config.listen))?;
+        let auth = api::Auth {
+            token: api_config.token()?,
+            public_reads: api_config.public_reads,
+        };
+        api::serve(listener, access.clone(), wakeup.clone(), auth);
+    }
+
+    let mut signals = signals::Signals::new(&[
+        signals::SIGTERM,
+        signals::SIGINT,
+        signals::SIGUSR1,
+        signals::SIGHUP,
+    ])?;
+
     let mut events = Events::with_capacity(1024);
+
+    // What presence polls for the keys on the bus; sysfs without any.
+    let slave_lists = match config.reader.mode {
+        config::ReaderMode::Poll => poll_paths(&config.readers, &config.reader),
+        config::ReaderMode::Udev => Vec::new(),
+    };
+    let (mut socket, mut pollers) = match config.reader.mode {
+        config::ReaderMode::Udev => {
+            let mut socket = w1_monitor(&config.reader.udev)?;
+            poll.registry()
+                .register(&mut socket, W1_TOKEN, Interest::READABLE)?;
+            (Some(socket), Vec::new())
+        }
+        config::ReaderMode::Poll => {
+            let pollers = slave_lists
+                .iter()
+                .cloned()
+                .map(|path| {
+                    log::info!("Polling {path:?} for keys");
+                    w1poll::Poller::new(&config.reader, path)
+                })
+                .collect();
+            (None, pollers)
+        }
+    };
     poll.registry()
-        .register(&mut socket, W1_TOKEN, Interest::READABLE)?;
+        .register(&mut signals, SIGNAL_TOKEN, Interest::READABLE)?;
+    let mut control = control::Control::bind(&config.control.path, poll.registry(), CONTROL_TOKEN)
+        .map_err(|e| log::error!("Control socket unavailable: {e:?}"))
+        .ok();
+    let mut simulator = args
+        .simulate
+        .map(|path| simulate::Simulator::open(&path, poll.registry(), SIMULATE_TOKEN))
+        .transpose()?;
 
-    loop {
-        poll.poll(&mut events, None)?;
+    let mut sensor = config
+        .sensor
+        .as_ref()
+        .map(|sensor| {
+            let door = access.readers[0].door.clone();
+            sensor::DoorSensor::open(sensor, door, poll.registry(), SENSOR_TOKEN, &access)
+        })
+        .transpose()?;
+    let mut exit_button = config
+        .exit_button
+        .as_ref()
+        .map(|button| exit_button::ExitButton::open(button, poll.registry(), EXIT_BUTTON_TOKEN))
+        .transpose()?;
 
-        for event in &events {
-            if event.token() == W1_TOKEN {
-                socket
-                    .iter()
-                    .filter(|event| event.event_type() == udev::EventType::Add)
-                    .for_each(|event| {
-                        log::debug!("device recognized: {:?}", event.sysname());
-                        match parse_1w_id(event.sysname().to_str().unwrap()) {
-                            Ok(id) => {
-                                if access_list.contains(&id) {
-                                    log::info!("Valid user detected!");
-                                } else {
-                                    log::debug!("Invalid user detected!");
-                                }
-                            }
-                            Err(e) => {
-                                log::warn!("Failed to parse device id: {e:?}");
-                            }
-                        }
-                    });
+    let mut keypad = config
+        .keypad
+        .as_ref()
+        .map(|keypad| keypad::Keypad::open(keypad, poll.registry(), KEYPAD_TOKEN))
+        .transpose()?;
+
+    let mut wiegands = config
+        .readers
+        .iter()
+        .enumerate()
+        .filter_map(|(index, reader)| Some((index, reader.wiegand.as_ref()?)))
+        .map(|(index, wiegand)| {
+            let token = Token(WIEGAND_TOKEN_BASE + index);
+            let reader = wiegand::WiegandReader::open(wiegand, poll.registry(), token)?;
+            anyhow::Ok((index, reader))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (tag_sender, tags) = std::sync::mpsc::channel();
+    for (index, reader) in config.readers.iter().enumerate() {
+        if let Some(nfc) = &reader.nfc {
+            pn532::spawn(index, nfc.clone(), tag_sender.clone(), waker.clone());
+        }
+    }
+    drop(tag_sender);
+
+    // Scan only after the monitor is listening so no key slips through in between.
+    if !pollers.is_empty() {
+        // The first poll reports everything on the bus, which is the startup scan.
+        if !config.reader.startup_scan {
+            for poller in &mut pollers {
+                poller.poll();
             }
         }
+    } else if config.reader.startup_scan {
+        scan_w1_devices(&access);
     }
-}
+    notifier.ready(&format!("{} keys", access.access_list.len()));
 
-fn mos_refresh(
-    config: &config::Thing,
-    persistence: &config::Persistence,
-    access_list: &Arc<DashSet<OneWireId>>,
-) -> anyhow::Result<()> {
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert("X-TOKEN", config.token.parse().unwrap());
-    let client = reqwest::blocking::Client::builder()
-        .default_headers(headers)
-        .build()?;
-    loop {
-        match client.get(&config.url).send() {
-            Ok(resp) => {
-                if resp.status().is_success() {
-                    let mut ids = HashSet::new();
-                    for line in resp.text().unwrap().lines() {
-                        let line = line.trim();
-                        if line.is_empty() || line.starts_with('#') {
-                            continue;
+    let mut timers = timers::Timers::default();
+    timers.schedule(Instant::now(), Timer::Housekeeping);
+    // The re-lock deadline scheduled for each reader's door.
+    let mut relocks = vec![None; access.readers.len()];
+    // The end of the feedback step scheduled.
+    let mut feedback_step = None;
+    // Last published for the Home Assistant lock.
+    let mut door_unlocked = None;
+    'main: loop {
+        let now = Instant::now();
+        for (at, timer) in timers.expired(now) {
+            match timer {
+                Timer::Housekeeping => {
+                    access.debounce.lock().unwrap().prune(now);
+                    access.passback.lock().unwrap().prune(now);
+                    access.pins.prune(now);
+                    if access.presence.config().is_some() {
+                        access.check_presence(&listed_w1_keys(&slave_lists));
+                    }
+                    access.check_reader_health(now);
+                    if access.clock.check(std::time::SystemTime::now()) {
+                        access.clock_trusted();
+                    }
+                    if access.clock.is_trusted() {
+                        access.guests.purge(std::time::SystemTime::now());
+                    }
+                    if refresh_thread.is_finished()
+                        && access.metrics.refresh_thread_exited.get() == 0
+                    {
+                        log::error!(
+                            "MOS refresh thread exited, the key list is no longer refreshed"
+                        );
+                        access.metrics.refresh_thread_exited.set(1);
+                    }
+                    timers.schedule(now + HOUSEKEEPING_INTERVAL, Timer::Housekeeping);
+                }
+                // A deadline since pushed out is rescheduled below.
+                Timer::Relock(index) if relocks[index] == Some(at) => {
+                    relocks[index] = None;
+                    access.readers[index].door.relock(now);
+                }
+                Timer::Relock(_) => {}
+                // Likewise for a pattern since replaced.
+                Timer::Feedback if feedback_step == Some(at) => {
+                    feedback_step = None;
+                    access.feedback.advance(now);
+                }
+                Timer::Feedback => {}
+            }
+        }
+        notifier.watchdog(now, &liveness, stall_limit);
+        access.denials.lock().unwrap().summarize(Instant::now());
+        if let Some(sensor) = &mut sensor {
+            sensor.check(&access);
+        }
+        // After the sensor, which thus sees the door still held when a window ends.
+        access.check_auto_unlock();
+        for poller in &mut pollers {
+            for sysname in poller.poll() {
+                access.handle_device(&sysname, &w1_ancestors(&sysname));
+            }
+        }
+        for (index, wiegand) in &mut wiegands {
+            if let Some(frame) = wiegand.check(Instant::now()) {
+                access.handle_card(&frame, &access.readers[*index]);
+            }
+        }
+
+        for (index, reader) in access.readers.iter().enumerate() {
+            if let Some(until) = reader.door.deadline() {
+                if relocks[index] != Some(until) {
+                    timers.schedule(until, Timer::Relock(index));
+                    relocks[index] = Some(until);
+                }
+            }
+        }
+        let unlocked = access.readers[0].door.is_energized();
+        if door_unlocked != Some(unlocked) {
+            access.mqtt.door_unlocked(unlocked);
+            door_unlocked = Some(unlocked);
+        }
+        if let Some(until) = access.feedback.deadline() {
+            if feedback_step != Some(until) {
+                timers.schedule(until, Timer::Feedback);
+                feedback_step = Some(until);
+            }
+        }
+
+        let timeout = pollers
+            .iter()
+            .map(w1poll::Poller::timeout)
+            .chain(timers.timeout(Instant::now()))
+            .chain(
+                wiegands
+                    .iter()
+                    .filter_map(|(_, wiegand)| wiegand.timeout(Instant::now())),
+            )
+            .chain(access.enroller.timeout(Instant::now()))
+            .chain(notifier.timeout(Instant::now()))
+            .chain(access.denials.lock().unwrap().timeout(Instant::now()))
+            .chain(sensor.as_ref().and_then(|s| s.timeout(Instant::now())))
+            .chain(
+                access
+                    .auto_unlock
+                    .timeout(chrono::Local::now().naive_local()),
+            )
+            .min();
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        for event in &events {
+            if event.token() == SIGNAL_TOKEN {
+                for signal in signals.pending() {
+                    match signal {
+                        signals::SIGTERM | signals::SIGINT => {
+                            log::info!("Received signal {signal}, shutting down");
+                            break 'main;
                         }
-                        if let Some((id, _name)) = line.split_once(',') {
-                            match parse_1w_id(id) {
-                                Ok(id) => {
-                                    ids.insert(id);
-                                }
-                                Err(e) => {
-                                    log::error!("Failed to parse ID {id:?}: {e:?}");
-                                }
-                            }
+                        signals::SIGUSR1 if !wakeup.request_refresh() => {
+                            log::debug!("Refresh already running, ignoring SIGUSR1");
                         }
+                        signals::SIGHUP => {
+                            reload_config(&args.config, &mut running, &access, &reload, &wakeup);
+                        }
+                        _ => {}
                     }
-                    let len = ids.len();
-                    let old_len = access_list.len();
-                    access_list.retain(|button| ids.remove(button));
-                    log::debug!(
-                        "List of IDs refreshed, we have {len} buttons now ({} new, {} removed)",
-                        ids.len(),
-                        old_len - access_list.len(),
-                    );
-                    let updated = !ids.is_empty() || old_len - access_list.len() > 0;
-                    for id in ids {
-                        access_list.insert(id);
+                }
+            } else if let Some(socket) = socket.as_mut().filter(|_| event.token() == W1_TOKEN) {
+                if event.is_error() || event.is_read_closed() {
+                    log::error!("udev monitor socket failed, recreating it");
+                    reopen_w1_monitor(poll.registry(), socket, &config.reader.udev)?;
+                    continue;
+                }
+                for event in socket.iter() {
+                    access.reader_activity(Instant::now());
+                    let Some(kind) = handled_event(&config.reader.udev, &event) else {
+                        continue;
+                    };
+                    let Some(sysname) = event.sysname().to_str() else {
+                        log::warn!("Ignoring non-UTF8 w1 device {:?}", event.sysname());
+                        continue;
+                    };
+                    let ancestors = ancestors(&event.device());
+                    match kind {
+                        config::UdevEvent::Remove => access.key_departed(sysname, &ancestors),
+                        _ => access.handle_device(sysname, &ancestors),
                     }
-
-                    if updated {
-                        if let Err(err) = serialize_1w_devices(access_list, &persistence.path) {
-                            log::error!("Failed to persist key list: {err:?}");
+                }
+            } else if let Some(simulator) = simulator
+                .as_mut()
+                .filter(|_| event.token() == SIMULATE_TOKEN)
+            {
+                for input in simulator.inputs() {
+                    match input {
+                        simulate::Input::Presentation(presentation) => {
+                            access.handle_device(&presentation.sysname, &presentation.ancestors)
                         }
+                        simulate::Input::ExitButton => access.exit_button(),
                     }
                 }
-            }
-            Err(e) => {
-                log::error!("Failed fetching key list: {e:?}");
+            } else if let Some(button) = exit_button
+                .as_mut()
+                .filter(|_| event.token() == EXIT_BUTTON_TOKEN)
+            {
+                if button.ready() {
+                    access.exit_button();
+                }
+            } else if let Some(device) = keypad.as_mut().filter(|_| event.token() == KEYPAD_TOKEN) {
+                match device.ready() {
+                    Ok(presses) => {
+                        for press in presses {
+                            access.handle_keypress(press);
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("Keypad failed, PINs can't be entered until a restart: {e}");
+                        keypad = None;
+                    }
+                }
+            } else if let Some((index, wiegand)) = wiegands
+                .iter_mut()
+                .find(|(index, _)| event.token() == Token(WIEGAND_TOKEN_BASE + *index))
+            {
+                for frame in wiegand.ready() {
+                    access.handle_card(&frame, &access.readers[*index]);
+                }
+            } else if let Some(sensor) = sensor.as_mut().filter(|_| event.token() == SENSOR_TOKEN) {
+                sensor.ready(&access);
+            } else if let Some(control) = control.as_mut().filter(|c| c.handles(event.token())) {
+                control.ready(poll.registry(), event.token(), &access, &wakeup);
+            } else if event.token() == WAKE_TOKEN {
+                for (index, uid) in tags.try_iter() {
+                    access.handle_tag(&uid, &access.readers[index]);
+                }
+                for command in access.mqtt.commands() {
+                    let result = control::execute(command.line, &access, &wakeup, "MQTT");
+                    access.mqtt.ack(&command, &result);
+                }
             }
         }
-
-        std::thread::sleep(std::time::Duration::from_secs(config.refresh_secs));
+        if let Some(control) = &mut control {
+            control.finish_enrollment(poll.registry(), &access, &wakeup);
+        }
     }
-}
 
-fn parse_1w_id(id: &str) -> anyhow::Result<[u8; 7]> {
-    let (devtype, id) = id.split_once('-').context("Wrong id format")?;
+    notifier.stopping();
+    wakeup.shutdown();
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while !refresh_thread.is_finished() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    if refresh_thread.is_finished() {
+        if refresh_thread.join().is_err() {
+            log::error!("MOS refresh thread panicked");
+        }
+    } else {
+        log::warn!("MOS refresh thread did not stop within {SHUTDOWN_TIMEOUT:?}");
+    }
 
-    let mut result = [0u8; 7];
-    result[0] = u8::from_str_radix(devtype, 16)?;
-    for idx in 0..6 {
-        result[idx + 1] = u8::from_str_radix(&id[(idx * 2)..(idx * 2 + 2)], 16)?;
+    if let Err(err) = access.store.save(&access.access_list) {
+        log::error!("Failed to persist key list on shutdown: {err:?}");
+    }
+    if dry_run {
+        log::info!("[dry-run] Not persisting last-seen timestamps on shutdown");
+    } else if let Err(err) = access.last_seen.save(&last_seen_path) {
+        log::error!("Failed to persist last-seen timestamps on shutdown: {err:?}");
     }
+    access.mqtt.shutdown(SHUTDOWN_TIMEOUT);
+    log::info!("Shutdown complete");
 
-    Ok(result)
+    Ok(())
 }
 
-fn serialize_1w_devices(
-    list: &DashSet<OneWireId>,
-    destination: impl AsRef<Path>,
-) -> anyhow::Result<()> {
-    let mut file = File::create(destination)?;
-    for id in list.iter() {
-        file.write_all(&*id)?;
+/// Re-reads the configuration at `path` and applies what can change while running. An invalid
+/// configuration is logged and the running one kept.
+fn reload_config(
+    path: &Path,
+    running: &mut config::Config,
+    access: &access::Access,
+    refresh: &Mutex<Option<refresh::Reload>>,
+    wakeup: &wakeup::Wakeup,
+) {
+    log::info!("Received SIGHUP, reloading {path:?}");
+    let reloaded = config::Config::parse(path)
+        .context(format!("Failed to read file {path:?}"))
+        .and_then(|config| config.validate().map(|_| config))
+        .and_then(|config| Ok((running.changes(&config)?, config)));
+    let (changes, config) = match reloaded {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            log::error!("Keeping the running configuration: {e:#}");
+            return;
+        }
+    };
+    if changes.is_empty() {
+        log::info!("Configuration unchanged");
+        return;
+    }
+    for change in &changes {
+        log::info!("Reloaded {change}");
     }
-    file.flush()?;
+    access.reload(&config);
+    *refresh.lock().unwrap() = Some(refresh::Reload {
+        thing: config.thing.clone(),
+        filter: refresh::KeyFilter {
+            deny_keys: config.deny_keys.clone(),
+            allowed_family_codes: config.allowed_family_codes.clone(),
+            // Readers only change with a restart.
+            known_groups: running.known_groups(),
+        },
+    });
+    wakeup.request_refresh();
+    *running = config;
+}
 
-    Ok(())
+fn w1_monitor(config: &config::Udev) -> std::io::Result<udev::MonitorSocket> {
+    let mut builder = MonitorBuilder::new()?;
+    for subsystem in &config.subsystems {
+        builder = builder.match_subsystem(subsystem)?;
+    }
+    builder.listen()
 }
 
-fn deserialize_1w_devices(destination: impl AsRef<Path>) -> anyhow::Result<DashSet<OneWireId>> {
-    let mut file = File::open(destination)?;
+/// What `event` is to the event loop, if `reader.udev` wants it handled at all.
+fn handled_event(config: &config::Udev, event: &udev::Event) -> Option<config::UdevEvent> {
+    let kind = match event.event_type() {
+        udev::EventType::Add => config::UdevEvent::Add,
+        udev::EventType::Change => config::UdevEvent::Change,
+        udev::EventType::Remove => config::UdevEvent::Remove,
+        _ => return None,
+    };
+    let text = |value: Option<&std::ffi::OsStr>| Some(value?.to_string_lossy().into_owned());
+    let device = event.device();
+    let matches = config.events.contains(&kind)
+        && config.properties_match(|name| text(device.property_value(name)))
+        && (kind == config::UdevEvent::Remove
+            || config.attributes_match(|name| text(device.attribute_value(name))));
+    matches.then_some(kind)
+}
 
-    let set = DashSet::new();
+/// Replaces a broken udev monitor with a freshly registered one, retrying a few times.
+fn reopen_w1_monitor(
+    registry: &mio::Registry,
+    socket: &mut udev::MonitorSocket,
+    config: &config::Udev,
+) -> anyhow::Result<()> {
+    if let Err(e) = registry.deregister(socket) {
+        log::warn!("Failed to deregister udev monitor: {e:?}");
+    }
 
-    let mut id = OneWireId::default();
+    let mut attempt = 1;
     loop {
-        match file.read_exact(&mut id) {
-            Ok(_) => {
-                set.insert(id);
+        let result = w1_monitor(config).and_then(|mut socket| {
+            registry.register(&mut socket, W1_TOKEN, Interest::READABLE)?;
+            Ok(socket)
+        });
+        match result {
+            Ok(new_socket) => {
+                log::info!("udev monitor recreated");
+                *socket = new_socket;
+                return Ok(());
             }
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                break;
+            Err(e) if attempt < MONITOR_RETRIES => {
+                log::error!("Failed to recreate udev monitor (attempt {attempt}): {e:?}");
+                attempt += 1;
+                std::thread::sleep(MONITOR_RETRY_DELAY);
             }
             Err(e) => {
-                return Err(e.into());
+                return Err(e).context(format!(
+                    "Failed to recreate udev monitor after {MONITOR_RETRIES} attempts"
+                ))
             }
         }
     }
+}
+
+/// Processes keys that were already present on the bus before the udev monitor was set up.
+fn scan_w1_devices(access: &access::Access) {
+    let entries = match std::fs::read_dir(W1_DEVICES) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::info!("Skipping startup scan, cannot read {W1_DEVICES}: {e}");
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let sysname = entry.file_name();
+        match sysname.to_str() {
+            Some(sysname) if sysname.starts_with("w1_bus_master") => {}
+            Some(sysname) => access.handle_device(sysname, &w1_ancestors(sysname)),
+            None => log::warn!("Ignoring non-UTF8 w1 device {sysname:?}"),
+        }
+    }
+}
 
-    Ok(set)
+/// The w1 keys on the bus now, from the `slave_lists` if any or the devices in sysfs. Lists
+/// that can't be read count as empty, so their keys depart.
+fn listed_w1_keys(slave_lists: &[PathBuf]) -> HashSet<Credential> {
+    let names: Vec<String> = if slave_lists.is_empty() {
+        std::fs::read_dir(W1_DEVICES)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect()
+    } else {
+        slave_lists
+            .iter()
+            .filter_map(|path| std::fs::read_to_string(path).ok())
+            .flat_map(|list| list.lines().map(str::to_owned).collect::<Vec<_>>())
+            .collect()
+    };
+    names
+        .iter()
+        .filter_map(|name| parse_1w_id(name).ok())
+        .map(Credential::OneWire)
+        .collect()
 }
 
-mod test {
-    #[test]
-    fn parse_1w_id_test() {
-        let id = "33-00000392c6ea";
-        let id_bytes = super::parse_1w_id(id).unwrap();
-        assert_eq!(id_bytes, [0x33, 0x00, 0x00, 0x03, 0x92, 0xc6, 0xea]);
+/// The slave lists to poll: each w1 reader's bus master if all name one, otherwise `poll_path`.
+fn poll_paths(readers: &[config::NamedReader], config: &config::Reader) -> Vec<PathBuf> {
+    let bus_masters: Option<Vec<&String>> = readers
+        .iter()
+        .filter(|reader| reader.wiegand.is_none() && reader.nfc.is_none())
+        .map(|reader| reader.bus_master.as_ref())
+        .collect();
+    match bus_masters {
+        Some(mut bus_masters) if !bus_masters.is_empty() => {
+            bus_masters.sort();
+            bus_masters.dedup();
+            bus_masters
+                .into_iter()
+                .map(|bus_master| {
+                    Path::new(W1_DEVICES)
+                        .join(bus_master)
+                        .join("w1_master_slaves")
+                })
+                .collect()
+        }
+        _ => vec![config.poll_path.clone()],
+    }
+}
+
+/// Sysnames of the device's parents, nearest first, which attribute it to a reader.
+fn ancestors(device: &udev::Device) -> Vec<String> {
+    std::iter::successors(device.parent(), udev::Device::parent)
+        .map(|parent| parent.sysname().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Like `ancestors`, for a w1 device known only by name.
+fn w1_ancestors(sysname: &str) -> Vec<String> {
+    match udev::Device::from_subsystem_sysname("w1".to_owned(), sysname.to_owned()) {
+        Ok(device) => ancestors(&device),
+        Err(e) => {
+            log::debug!("Cannot look up the parents of {sysname}: {e}");
+            Vec::new()
+        }
     }
 }
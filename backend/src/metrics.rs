@@ -0,0 +1,492 @@
+use std::{
+    fmt::Write as _,
+    io::Write,
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    http::{self, BadRequest},
+    refresh::FailureKind,
+};
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Upper bounds of the [`Histogram`] buckets, in seconds: from a key decision, well under a
+/// millisecond, to a fetch over a struggling uplink.
+const BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Durations counted into [`BUCKETS`], like a Prometheus histogram.
+#[derive(Default)]
+pub struct Histogram {
+    /// Observations up to each bound, not cumulative; rendering sums them up.
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count();
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}\n{name}_count {count}");
+    }
+}
+
+/// Counters and gauges shared between the event loop and the refresh thread.
+#[derive(Default)]
+pub struct Metrics {
+    pub granted: Counter,
+    pub denied: Counter,
+    pub unparsable: Counter,
+    pub bad_crc: Counter,
+    pub wiegand_bad_frames: Counter,
+    pub exit_button: Counter,
+    pub fetch_success: Counter,
+    pub fetch_failure: Counter,
+    /// Indexed by [`FailureKind`].
+    pub fetch_failure_kinds: [Counter; FailureKind::ALL.len()],
+    /// Failed fetches since the last successful one.
+    pub consecutive_fetch_failures: Gauge,
+    /// Refresh cycles restarted after a panic.
+    pub refresh_panics: Counter,
+    /// Set once the refresh thread ended before shutdown.
+    pub refresh_thread_exited: Gauge,
+    /// Webhook notifications dropped because the queue was full.
+    pub webhooks_dropped: Counter,
+    /// Webhook deliveries given up after their retries.
+    pub webhook_failures: Counter,
+    /// Remote opens refused by `open_rate_limit`.
+    pub opens_rate_limited: Counter,
+    pub access_list_size: Gauge,
+    pub last_refresh: Gauge,
+    /// When the next fetch is due, in seconds since the epoch.
+    pub next_refresh: Gauge,
+    /// Index into `thing.url` and URL of the source the list was last fetched from.
+    pub list_source: Mutex<Option<(usize, String)>>,
+    pub lockdown: Gauge,
+    pub auto_unlock: Gauge,
+    /// Only reported with a door sensor.
+    pub door_sensor: AtomicBool,
+    pub door_open: Gauge,
+    pub door_alarm: Gauge,
+    /// Only reported with a `reader.watchdog`.
+    pub reader_watchdog: AtomicBool,
+    pub reader_silent: Gauge,
+    /// When the udev monitor last saw an event, in seconds since the epoch.
+    pub reader_last_event: Gauge,
+    /// From sending a fetch until the response headers arrived, retries included.
+    pub fetch_response_seconds: Histogram,
+    /// Whole fetches, body included, whether they succeeded or not.
+    pub fetch_seconds: Histogram,
+    pub parse_seconds: Histogram,
+    /// Applying a fetched list to the access list and persisting it.
+    pub apply_seconds: Histogram,
+    /// From a key being presented until it was decided, authentication included.
+    pub decision_seconds: Histogram,
+}
+
+impl Metrics {
+    pub fn refreshed(&self, list_size: usize) {
+        self.fetch_success.inc();
+        self.consecutive_fetch_failures.set(0);
+        self.access_list_size.set(list_size as u64);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_refresh.set(now.as_secs());
+    }
+
+    /// Moves a last refresh stamped before `before`, by a clock that wasn't set yet, to now.
+    pub fn restamp_refresh(&self, before: SystemTime) {
+        let before = before
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        if (1..before.as_secs()).contains(&self.last_refresh.get()) {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            self.last_refresh.set(now.as_secs());
+        }
+    }
+
+    pub fn fetched_from(&self, index: usize, url: String) {
+        *self.list_source.lock().unwrap() = Some((index, url));
+    }
+
+    pub fn fetch_failed(&self, kind: FailureKind) {
+        self.fetch_failure.inc();
+        self.fetch_failure_kinds[kind as usize].inc();
+    }
+
+    /// Time since the last successful refresh, `None` if there never was one.
+    pub fn list_age(&self, now: SystemTime) -> Option<Duration> {
+        match self.last_refresh.get() {
+            0 => None,
+            secs => Some(
+                now.duration_since(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        };
+        metric(
+            "cellardoor_access_granted_total",
+            "counter",
+            "Key presentations that opened the door.",
+            self.granted.get(),
+        );
+        metric(
+            "cellardoor_access_denied_total",
+            "counter",
+            "Key presentations that were denied.",
+            self.denied.get(),
+        );
+        metric(
+            "cellardoor_unparsable_devices_total",
+            "counter",
+            "w1 devices whose sysname could not be parsed.",
+            self.unparsable.get(),
+        );
+        metric(
+            "cellardoor_bad_crc_total",
+            "counter",
+            "Keys whose ROM code failed its CRC.",
+            self.bad_crc.get(),
+        );
+        metric(
+            "cellardoor_wiegand_bad_frames_total",
+            "counter",
+            "Wiegand frames dropped for their length or parity.",
+            self.wiegand_bad_frames.get(),
+        );
+        metric(
+            "cellardoor_exit_button_total",
+            "counter",
+            "Presses of the exit button.",
+            self.exit_button.get(),
+        );
+        metric(
+            "cellardoor_fetch_success_total",
+            "counter",
+            "Successful key list fetches.",
+            self.fetch_success.get(),
+        );
+        metric(
+            "cellardoor_fetch_failure_total",
+            "counter",
+            "Failed key list fetches.",
+            self.fetch_failure.get(),
+        );
+        metric(
+            "cellardoor_consecutive_fetch_failures",
+            "gauge",
+            "Failed key list fetches since the last successful one.",
+            self.consecutive_fetch_failures.get(),
+        );
+        metric(
+            "cellardoor_refresh_panics_total",
+            "counter",
+            "Key list refresh cycles restarted after a panic.",
+            self.refresh_panics.get(),
+        );
+        metric(
+            "cellardoor_refresh_thread_exited",
+            "gauge",
+            "Whether the key list refresh thread ended unexpectedly.",
+            self.refresh_thread_exited.get(),
+        );
+        metric(
+            "cellardoor_webhooks_dropped_total",
+            "counter",
+            "Webhook notifications dropped because too many were waiting.",
+            self.webhooks_dropped.get(),
+        );
+        metric(
+            "cellardoor_webhook_failures_total",
+            "counter",
+            "Webhook deliveries that failed on every attempt.",
+            self.webhook_failures.get(),
+        );
+        metric(
+            "cellardoor_opens_rate_limited_total",
+            "counter",
+            "Remote door opens refused because open_rate_limit was used up.",
+            self.opens_rate_limited.get(),
+        );
+        metric(
+            "cellardoor_access_list_size",
+            "gauge",
+            "Number of keys in the access list.",
+            self.access_list_size.get(),
+        );
+        metric(
+            "cellardoor_last_refresh_timestamp_seconds",
+            "gauge",
+            "Unix time of the last successful key list refresh.",
+            self.last_refresh.get(),
+        );
+        metric(
+            "cellardoor_lockdown",
+            "gauge",
+            "Whether lockdown refuses every key.",
+            self.lockdown.get(),
+        );
+        metric(
+            "cellardoor_auto_unlock",
+            "gauge",
+            "Whether an auto_unlock window holds the door unlocked.",
+            self.auto_unlock.get(),
+        );
+        if self.door_sensor.load(Ordering::Relaxed) {
+            metric(
+                "cellardoor_door_open",
+                "gauge",
+                "Whether the door sensor reports the door open.",
+                self.door_open.get(),
+            );
+            metric(
+                "cellardoor_door_alarm",
+                "gauge",
+                "Whether the door has been open for longer than allowed.",
+                self.door_alarm.get(),
+            );
+        }
+        if self.reader_watchdog.load(Ordering::Relaxed) {
+            metric(
+                "cellardoor_reader_silent",
+                "gauge",
+                "Whether the reader has seen no w1 events for longer than reader.watchdog.silence.",
+                self.reader_silent.get(),
+            );
+        }
+        if self.reader_last_event.get() != 0 {
+            metric(
+                "cellardoor_reader_last_event_timestamp_seconds",
+                "gauge",
+                "When the udev monitor last saw a w1 event.",
+                self.reader_last_event.get(),
+            );
+        }
+        if self.next_refresh.get() != 0 {
+            metric(
+                "cellardoor_next_refresh_timestamp_seconds",
+                "gauge",
+                "When the next key list fetch is due.",
+                self.next_refresh.get(),
+            );
+        }
+        if let Some((index, _)) = *self.list_source.lock().unwrap() {
+            metric(
+                "cellardoor_list_source",
+                "gauge",
+                "Position in thing.url of the source the list was last fetched from.",
+                index as u64,
+            );
+        }
+        if let Some(age) = self.list_age(SystemTime::now()) {
+            metric(
+                "cellardoor_access_list_age_seconds",
+                "gauge",
+                "Seconds since the last successful key list refresh.",
+                age.as_secs(),
+            );
+        }
+        out.push_str(
+            "# HELP cellardoor_fetch_failure_kind_total Failed key list fetches by cause.\n\
+             # TYPE cellardoor_fetch_failure_kind_total counter\n",
+        );
+        for kind in FailureKind::ALL {
+            let _ = writeln!(
+                out,
+                "cellardoor_fetch_failure_kind_total{{kind=\"{kind}\"}} {}",
+                self.fetch_failure_kinds[kind as usize].get()
+            );
+        }
+        for (histogram, name, help) in [
+            (
+                &self.fetch_response_seconds,
+                "cellardoor_fetch_response_seconds",
+                "Time until MOS answered a key list fetch with its headers.",
+            ),
+            (
+                &self.fetch_seconds,
+                "cellardoor_fetch_seconds",
+                "Time key list fetches took, body included.",
+            ),
+            (
+                &self.parse_seconds,
+                "cellardoor_parse_seconds",
+                "Time parsing fetched key lists took.",
+            ),
+            (
+                &self.apply_seconds,
+                "cellardoor_apply_seconds",
+                "Time applying and persisting fetched key lists took.",
+            ),
+            (
+                &self.decision_seconds,
+                "cellardoor_decision_seconds",
+                "Time from presenting a key to deciding on it.",
+            ),
+        ] {
+            histogram.render(&mut out, name, help);
+        }
+        out
+    }
+}
+
+/// Serves `GET /metrics` on `listener` from a dedicated thread.
+pub fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_request(stream, &metrics) {
+                        log::debug!("Metrics request failed: {e:?}");
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Failed to accept metrics connection: {e:?}");
+                }
+            }
+        }
+    });
+}
+
+fn handle_request(mut stream: TcpStream, metrics: &Metrics) -> anyhow::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let (status, body) = match http::read_request(&stream, 0) {
+        Ok(request) if request.method == "GET" && request.path == "/metrics" => {
+            (200, metrics.render())
+        }
+        Ok(_) => (404, "Not Found\n".to_owned()),
+        Err(BadRequest { status, error }) => (status, format!("{error:#}\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status} {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        http::reason(status),
+        body.len()
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use super::Metrics;
+
+    #[test]
+    fn endpoint_smoke_test() {
+        let metrics = Arc::new(Metrics::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        super::serve(listener, metrics.clone());
+
+        metrics.granted.inc();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\ncellardoor_access_granted_total 1\n"));
+        assert!(response.contains("\ncellardoor_access_denied_total 0\n"));
+    }
+
+    #[test]
+    fn histogram_test() {
+        let metrics = Metrics::default();
+        for millis in [3, 40, 40, 20_000] {
+            metrics.fetch_seconds.observe(Duration::from_millis(millis));
+        }
+        let out = metrics.render();
+        for line in [
+            "# TYPE cellardoor_fetch_seconds histogram",
+            "cellardoor_fetch_seconds_bucket{le=\"0.0025\"} 0",
+            "cellardoor_fetch_seconds_bucket{le=\"0.005\"} 1",
+            "cellardoor_fetch_seconds_bucket{le=\"0.05\"} 3",
+            "cellardoor_fetch_seconds_bucket{le=\"10\"} 3",
+            "cellardoor_fetch_seconds_bucket{le=\"+Inf\"} 4",
+            "cellardoor_fetch_seconds_sum 20.083",
+            "cellardoor_fetch_seconds_count 4",
+            "cellardoor_decision_seconds_count 0",
+        ] {
+            assert!(
+                out.contains(&format!("\n{line}\n")),
+                "{line} missing from {out}"
+            );
+        }
+    }
+}
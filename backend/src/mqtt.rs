@@ -0,0 +1,1301 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError, TrySendError},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use ring::hmac;
+
+use crate::{
+    audit, backoff, config, keylist, persistence, privacy, refresh::FailureKind, Credential,
+};
+
+/// Events waiting for the MQTT thread; further ones are dropped rather than blocking the caller.
+const QUEUE_LEN: usize = 256;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the MQTT thread waits for incoming packets before looking at the queue again.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+enum Event {
+    Publish {
+        topic: &'static str,
+        payload: serde_json::Value,
+        retain: bool,
+    },
+    Shutdown,
+}
+
+/// An authenticated command from `<prefix>/cmd`, waiting for the main loop.
+pub struct Command {
+    pub action: String,
+    /// `None` for a command with the Home Assistant code.
+    pub nonce: Option<String>,
+    /// The equivalent control socket command.
+    pub line: &'static str,
+}
+
+/// Publishes access and refresh events to an MQTT broker from a thread of its own, and, with a
+/// `command_secret`, accepts commands on `<prefix>/cmd`.
+#[derive(Default)]
+pub struct Mqtt {
+    events: Option<SyncSender<Event>>,
+    commands: Option<Mutex<Receiver<Command>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// What Home Assistant discovery needs besides `mqtt.homeassistant`.
+pub struct Discovery {
+    /// Where the announced topics are recorded, see [`config::Persistence::discovery_path`].
+    pub record: PathBuf,
+    /// Whether there is a door sensor to announce.
+    pub door_sensor: bool,
+    /// Leaves the record alone.
+    pub dry_run: bool,
+}
+
+impl Mqtt {
+    /// Starts the client; received commands wake the event loop through `waker`.
+    pub fn new(
+        config: Option<config::Mqtt>,
+        discovery: Discovery,
+        waker: Arc<mio::Waker>,
+    ) -> anyhow::Result<Mqtt> {
+        let Some(config) = config else {
+            return Ok(Mqtt::default());
+        };
+        let (host, port) = config.broker_addr().context("Invalid MQTT broker")?;
+        let announcer = Announcer::new(&config, discovery);
+
+        let (events, queue) = mpsc::sync_channel(QUEUE_LEN);
+        let (inbox, commands) = match &config.command_secret {
+            Some(secret) => {
+                let (sender, commands) = mpsc::channel();
+                let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+                let code = config.homeassistant.as_ref().and_then(|homeassistant| {
+                    let code = homeassistant.code.as_ref()?;
+                    Some(hmac::sign(&key, code.as_bytes()))
+                });
+                let inbox = Inbox {
+                    key,
+                    code,
+                    challenge: None,
+                    nonces: Nonces::new(config.command_max_age),
+                    commands: sender,
+                    waker,
+                };
+                (Some(inbox), Some(Mutex::new(commands)))
+            }
+            None => (None, None),
+        };
+        let thread =
+            std::thread::spawn(move || run(&config, &host, port, &queue, inbox, announcer));
+        Ok(Mqtt {
+            events: Some(events),
+            commands,
+            thread: Mutex::new(Some(thread)),
+        })
+    }
+
+    /// Commands received since the last call.
+    pub fn commands(&self) -> Vec<Command> {
+        match &self.commands {
+            Some(commands) => commands.lock().unwrap().try_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Reports the outcome of a command on `<prefix>/cmd/ack`; `result` ends in `OK` or `ERR`.
+    pub fn ack(&self, command: &Command, result: &str) {
+        let result = result.lines().last().unwrap_or_default();
+        self.send(
+            "cmd/ack",
+            ack(&command.action, command.nonce.as_deref(), result),
+            false,
+        );
+    }
+
+    pub fn access(&self, id: &Credential, name: &str, decision: audit::Decision) {
+        self.send(
+            "access",
+            serde_json::json!({
+                "key": privacy::hash(id),
+                "name": name,
+                "decision": decision.to_string(),
+                "time": now(),
+            }),
+            true,
+        );
+    }
+
+    pub fn key_count(&self, count: usize) {
+        self.send(
+            "keys/count",
+            serde_json::json!({ "count": count, "time": now() }),
+            true,
+        );
+    }
+
+    /// Keys a refresh added and removed, at most `max` of each named.
+    pub fn key_diff(&self, diff: &keylist::Diff, max: usize) {
+        let keys = |keys: &[(Credential, String)]| {
+            keys.iter()
+                .take(max)
+                .map(|(id, name)| serde_json::json!({ "key": privacy::hash(id), "name": name }))
+                .collect::<Vec<_>>()
+        };
+        self.send(
+            "keys/diff",
+            serde_json::json!({
+                "added": keys(&diff.added),
+                "removed": keys(&diff.removed),
+                "added_count": diff.added.len(),
+                "removed_count": diff.removed.len(),
+                "changed_count": diff.changed,
+                "time": now(),
+            }),
+            false,
+        );
+    }
+
+    /// The state of the door sensor, and whether the door has been open for too long.
+    pub fn door(&self, open: bool, alarm: bool) {
+        self.send(
+            "door",
+            serde_json::json!({ "open": open, "alarm": alarm, "time": now() }),
+            true,
+        );
+    }
+
+    /// Whether the strike of the first reader's door is energized, for the Home Assistant lock.
+    pub fn door_unlocked(&self, unlocked: bool) {
+        self.send(
+            "door/lock",
+            serde_json::json!({ "unlocked": unlocked, "time": now() }),
+            true,
+        );
+    }
+
+    /// Whether an `auto_unlock` window holds the door unlocked.
+    pub fn auto_unlock(&self, active: bool) {
+        self.send(
+            "door/auto_unlock",
+            serde_json::json!({ "active": active, "time": now() }),
+            true,
+        );
+    }
+
+    /// Whether the reader has seen no w1 events for longer than `reader.watchdog.silence`,
+    /// and for how long it had when this changed.
+    pub fn reader_silent(&self, silent: bool, silent_for: Duration) {
+        self.send(
+            "reader/health",
+            serde_json::json!({
+                "silent": silent,
+                "silent_secs": silent_for.as_secs(),
+                "time": now(),
+            }),
+            true,
+        );
+    }
+
+    /// `failures` fetches of the key list in a row failed, the last one for `kind`.
+    pub fn refresh_failing(&self, failures: u32, kind: FailureKind) {
+        self.send(
+            "refresh/failure",
+            serde_json::json!({
+                "failing": true,
+                "failures": failures,
+                "kind": kind.to_string(),
+                "time": now(),
+            }),
+            true,
+        );
+    }
+
+    /// A fetch succeeded after an outage reported by [`Mqtt::refresh_failing`].
+    pub fn refresh_recovered(&self, failures: u32, outage: Duration) {
+        self.send(
+            "refresh/failure",
+            serde_json::json!({
+                "failing": false,
+                "failures": failures,
+                "outage_secs": outage.as_secs(),
+                "time": now(),
+            }),
+            true,
+        );
+    }
+
+    fn send(&self, topic: &'static str, payload: serde_json::Value, retain: bool) {
+        let Some(events) = &self.events else {
+            return;
+        };
+        match events.try_send(Event::Publish {
+            topic,
+            payload,
+            retain,
+        }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => log::warn!("MQTT queue full, dropping {topic} event"),
+            Err(TrySendError::Disconnected(_)) => log::error!("MQTT thread is gone"),
+        }
+    }
+
+    /// Publishes the offline status and disconnects, waiting at most `timeout` for it.
+    pub fn shutdown(&self, timeout: Duration) {
+        let (Some(events), Some(thread)) = (&self.events, self.thread.lock().unwrap().take())
+        else {
+            return;
+        };
+        let _ = events.send(Event::Shutdown);
+        let deadline = Instant::now() + timeout;
+        while !thread.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        if !thread.is_finished() {
+            log::warn!("MQTT thread did not stop within {timeout:?}");
+        }
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+fn ack(action: &str, nonce: Option<&str>, result: &str) -> serde_json::Value {
+    serde_json::json!({
+        "action": action,
+        "nonce": nonce,
+        "ok": result == "OK",
+        "result": result,
+        "time": now(),
+    })
+}
+
+/// Receives commands on the MQTT thread and hands the authentic ones to the main loop.
+struct Inbox {
+    key: hmac::Key,
+    /// HMAC of `mqtt.homeassistant.code` under `key`, so codes are compared in constant time.
+    code: Option<hmac::Tag>,
+    /// What the next coded command must carry, published on `<prefix>/cmd/challenge`, and when
+    /// it was issued.
+    challenge: Option<(String, SystemTime)>,
+    nonces: Nonces,
+    commands: Sender<Command>,
+    waker: Arc<mio::Waker>,
+}
+
+/// The outer message on `<prefix>/cmd`; `hmac` is HMAC-SHA256 over the exact `payload` string.
+#[derive(serde::Deserialize)]
+struct SignedCommand {
+    payload: String,
+    hmac: String,
+}
+
+/// What the Home Assistant lock sends, `action` being `lock` or `unlock`. Home Assistant can't
+/// sign commands, so instead of a nonce an unlock carries the challenge last published on
+/// `<prefix>/cmd/challenge`, which is good for one unlock within `command_max_age`; a
+/// captured command can't be replayed.
+#[derive(serde::Deserialize)]
+struct CodedCommand {
+    action: String,
+    code: String,
+    #[serde(default)]
+    challenge: String,
+}
+
+#[derive(serde::Deserialize)]
+struct CommandPayload {
+    action: String,
+    enabled: Option<bool>,
+    /// Unique per command, so a captured message can't be replayed.
+    nonce: String,
+    /// Unix seconds; commands older than `command_max_age` are refused.
+    time: u64,
+}
+
+/// Nonces seen within the accepted age, which bounds how many need to be kept.
+struct Nonces {
+    max_age: Duration,
+    seen: HashMap<String, SystemTime>,
+}
+
+impl Nonces {
+    fn new(max_age: Duration) -> Nonces {
+        Nonces {
+            max_age,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Whether a command issued at `time` with `nonce` may run; each nonce is accepted once.
+    fn accept(&mut self, nonce: &str, time: SystemTime, now: SystemTime) -> Result<(), String> {
+        let max_age = self.max_age;
+        let age = |time: SystemTime| now.duration_since(time).unwrap_or_else(|e| e.duration());
+        if age(time) > max_age {
+            return Err("ERR command too old".to_owned());
+        }
+        self.seen.retain(|_, seen| age(*seen) <= max_age);
+        if self.seen.insert(nonce.to_owned(), time).is_some() {
+            return Err("ERR nonce already used".to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Inbox {
+    /// A new challenge for coded commands to publish, if they are taken and the current one was
+    /// used or has expired at `now`.
+    fn renew_challenge(&mut self, now: SystemTime) -> Option<String> {
+        self.code.as_ref()?;
+        let max_age = self.nonces.max_age;
+        let valid =
+            |issued: &SystemTime| now.duration_since(*issued).is_ok_and(|age| age < max_age);
+        if self
+            .challenge
+            .as_ref()
+            .is_some_and(|(_, issued)| valid(issued))
+        {
+            return None;
+        }
+        let mut bytes = [0u8; 16];
+        ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut bytes).ok()?;
+        let challenge: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        self.challenge = Some((challenge.clone(), now));
+        Some(challenge)
+    }
+
+    /// Authenticates a message from `<prefix>/cmd`, returning the ack payload for rejected ones.
+    fn verify(&mut self, message: &[u8], now: SystemTime) -> Result<Command, serde_json::Value> {
+        let reject = |action: &str, nonce: Option<&str>, reason: &str| {
+            log::warn!("Rejected MQTT command: {reason}");
+            ack(action, nonce, reason)
+        };
+        if let Some(code) = &self.code {
+            if let Ok(coded) = serde_json::from_slice::<CodedCommand>(message) {
+                let action = coded.action.as_str();
+                if hmac::verify(&self.key, coded.code.as_bytes(), code.as_ref()).is_err() {
+                    return Err(reject(action, None, "ERR bad code"));
+                }
+                let line = match action {
+                    "unlock" => {
+                        let max_age = self.nonces.max_age;
+                        let current = self.challenge.take_if(|(challenge, issued)| {
+                            *challenge == coded.challenge
+                                && now.duration_since(*issued).is_ok_and(|age| age < max_age)
+                        });
+                        if current.is_none() {
+                            return Err(reject(action, None, "ERR stale challenge"));
+                        }
+                        "OPEN"
+                    }
+                    "lock" => return Err(reject(action, None, "ERR the door locks by itself")),
+                    _ => {
+                        return Err(reject(
+                            action,
+                            None,
+                            &format!("ERR unknown action {action:?}"),
+                        ))
+                    }
+                };
+                return Ok(Command {
+                    action: coded.action.clone(),
+                    nonce: None,
+                    line,
+                });
+            }
+        }
+        let signed: SignedCommand = serde_json::from_slice(message)
+            .map_err(|e| reject("", None, &format!("ERR malformed command: {e}")))?;
+        let tag = decode_hex(&signed.hmac).unwrap_or_default();
+        if hmac::verify(&self.key, signed.payload.as_bytes(), &tag).is_err() {
+            return Err(reject("", None, "ERR bad hmac"));
+        }
+        let payload: CommandPayload = serde_json::from_str(&signed.payload)
+            .map_err(|e| reject("", None, &format!("ERR malformed command: {e}")))?;
+        let (action, nonce) = (payload.action.as_str(), payload.nonce.as_str());
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(payload.time);
+        self.nonces
+            .accept(nonce, time, now)
+            .map_err(|reason| reject(action, Some(nonce), &reason))?;
+        let line = match (action, payload.enabled) {
+            ("open", _) => "OPEN",
+            ("refresh", _) => "REFRESH",
+            ("lockdown", Some(true)) => "LOCKDOWN ON",
+            ("lockdown", Some(false)) => "LOCKDOWN OFF",
+            ("lockdown", None) => return Err(reject(action, Some(nonce), "ERR enabled missing")),
+            _ => {
+                return Err(reject(
+                    action,
+                    Some(nonce),
+                    &format!("ERR unknown action {action:?}"),
+                ))
+            }
+        };
+        Ok(Command {
+            action: payload.action.clone(),
+            nonce: Some(payload.nonce.clone()),
+            line,
+        })
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn run(
+    config: &config::Mqtt,
+    host: &str,
+    port: u16,
+    queue: &Receiver<Event>,
+    mut inbox: Option<Inbox>,
+    mut announcer: Announcer,
+) {
+    let mut backoff = backoff::Backoff::new(&config::Backoff::default());
+    let mut errors = backoff::ErrorThrottle::default();
+    loop {
+        let session = session(
+            config,
+            (host, port),
+            queue,
+            &mut backoff,
+            inbox.as_mut(),
+            &mut announcer,
+        );
+        match session {
+            Ok(()) => return,
+            Err(e) => errors.error(format!("MQTT connection failed: {e:?}")),
+        }
+        // Keep draining while disconnected so events don't pile up into a stale burst later.
+        let deadline = Instant::now() + backoff.next_delay();
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match queue.recv_timeout(timeout) {
+                Ok(Event::Publish { .. }) => {}
+                Ok(Event::Shutdown) | Err(RecvTimeoutError::Disconnected) => return,
+                Err(RecvTimeoutError::Timeout) => break,
+            }
+        }
+    }
+}
+
+/// Runs one broker connection until shutdown (`Ok`) or a connection error.
+fn session(
+    config: &config::Mqtt,
+    (host, port): (&str, u16),
+    queue: &Receiver<Event>,
+    backoff: &mut backoff::Backoff,
+    mut inbox: Option<&mut Inbox>,
+    announcer: &mut Announcer,
+) -> anyhow::Result<()> {
+    let addr: SocketAddr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .context(format!("{host} does not resolve"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+
+    let status_topic = topic(config, "status");
+    let will = status(false, false);
+    stream.write_all(&connect_packet(config, &status_topic, &will))?;
+
+    let mut packets = PacketReader::default();
+    let (header, body) = loop {
+        if let Some(packet) = packets.next()? {
+            break packet;
+        }
+        packets.fill(&mut stream)?;
+    };
+    anyhow::ensure!(
+        header & 0xf0 == CONNACK,
+        "expected CONNACK, got {header:#04x}"
+    );
+    match body.get(1) {
+        Some(0) => {}
+        Some(code) => anyhow::bail!("broker refused the connection with code {code}"),
+        None => anyhow::bail!("truncated CONNACK"),
+    }
+    log::info!("Connected to MQTT broker {host}:{port}");
+    backoff.reset();
+
+    let mut next_id = 0u16;
+    let mut packet_id = || {
+        next_id = next_id.wrapping_add(1).max(1);
+        next_id
+    };
+    let cmd_topic = topic(config, "cmd");
+    let ack_topic = topic(config, "cmd/ack");
+    if inbox.is_some() {
+        stream.write_all(&subscribe_packet(&cmd_topic, config.qos, packet_id()))?;
+    }
+    let mut publish = |stream: &mut TcpStream, topic: &str, payload: &[u8], retain: bool| {
+        stream.write_all(&publish_packet(
+            topic,
+            payload,
+            config.qos,
+            retain,
+            packet_id(),
+        ))
+    };
+    publish(&mut stream, &status_topic, &status(true, true), true)?;
+    // Retained like the will, but sent again on every connection in case the broker forgot.
+    announcer.announce(|topic, payload| publish(&mut stream, topic, payload, true))?;
+    let challenge_topic = topic(config, "cmd/challenge");
+    if let Some(inbox) = inbox.as_deref_mut() {
+        inbox.challenge = None;
+    }
+
+    let keepalive = config.keepalive;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut last_sent = Instant::now();
+    let mut last_received = Instant::now();
+    loop {
+        let challenge = inbox
+            .as_deref_mut()
+            .and_then(|inbox| inbox.renew_challenge(SystemTime::now()));
+        if let Some(challenge) = challenge {
+            let payload = serde_json::json!({ "challenge": challenge, "time": now() });
+            publish(
+                &mut stream,
+                &challenge_topic,
+                payload.to_string().as_bytes(),
+                true,
+            )?;
+        }
+        loop {
+            match queue.try_recv() {
+                Ok(Event::Publish {
+                    topic: suffix,
+                    payload,
+                    retain,
+                }) => {
+                    let topic = topic(config, suffix);
+                    publish(&mut stream, &topic, payload.to_string().as_bytes(), retain)?;
+                    last_sent = Instant::now();
+                }
+                Ok(Event::Shutdown) | Err(TryRecvError::Disconnected) => {
+                    publish(&mut stream, &status_topic, &status(false, true), true)?;
+                    stream.write_all(&[DISCONNECT, 0])?;
+                    return Ok(());
+                }
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        match packets.fill(&mut stream) {
+            Ok(()) => last_received = Instant::now(),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e.into()),
+        }
+        // Apart from commands, incoming packets (PUBACK, PINGRESP) only prove the connection is
+        // alive.
+        while let Some((header, body)) = packets.next()? {
+            match header & 0xf0 {
+                SUBACK if body.get(2) == Some(&0x80) => {
+                    anyhow::bail!("broker refused the subscription to {cmd_topic}")
+                }
+                PUBLISH => {
+                    let (received, packet_id, message) = split_publish(header, &body)?;
+                    if let Some(packet_id) = packet_id {
+                        stream.write_all(&frame(PUBACK, &packet_id.to_be_bytes()))?;
+                    }
+                    let Some(inbox) = inbox.as_deref_mut().filter(|_| received == cmd_topic) else {
+                        continue;
+                    };
+                    match inbox.verify(message, SystemTime::now()) {
+                        Ok(command) => {
+                            log::info!("Received MQTT command {:?}", command.action);
+                            inbox.commands.send(command)?;
+                            inbox.waker.wake()?;
+                        }
+                        Err(ack) => {
+                            publish(&mut stream, &ack_topic, ack.to_string().as_bytes(), false)?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if keepalive.is_zero() {
+            continue;
+        }
+        if last_sent.elapsed() >= keepalive / 2 {
+            stream.write_all(&[PINGREQ, 0])?;
+            last_sent = Instant::now();
+        }
+        anyhow::ensure!(
+            last_received.elapsed() < keepalive * 3 / 2,
+            "broker stopped responding"
+        );
+    }
+}
+
+/// The retained Home Assistant discovery messages, and the removal of entities announced
+/// before and since dropped from the config.
+struct Announcer {
+    messages: Vec<(String, Vec<u8>)>,
+    /// Topics of the last announcement, as recorded.
+    recorded: Vec<String>,
+    discovery: Discovery,
+}
+
+impl Announcer {
+    fn new(config: &config::Mqtt, discovery: Discovery) -> Announcer {
+        let messages = match &config.homeassistant {
+            Some(homeassistant) => entities(config, homeassistant, discovery.door_sensor)
+                .into_iter()
+                .map(|(topic, payload)| (topic, payload.to_string().into_bytes()))
+                .collect(),
+            None => Vec::new(),
+        };
+        let recorded = match std::fs::read_to_string(&discovery.record) {
+            Ok(record) => record.lines().map(str::to_owned).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                log::error!(
+                    "Failed to read the Home Assistant discovery record {:?}: {e:?}",
+                    discovery.record
+                );
+                Vec::new()
+            }
+        };
+        Announcer {
+            messages,
+            recorded,
+            discovery,
+        }
+    }
+
+    /// Publishes the discovery messages and empty ones for stale entities, then records what
+    /// was announced once that changed.
+    fn announce(
+        &mut self,
+        mut publish: impl FnMut(&str, &[u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let topics: Vec<String> = self
+            .messages
+            .iter()
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        let stale: Vec<&String> = self
+            .recorded
+            .iter()
+            .filter(|topic| !topics.contains(topic))
+            .collect();
+        for topic in &stale {
+            publish(topic, b"")?;
+        }
+        for (topic, payload) in &self.messages {
+            publish(topic, payload)?;
+        }
+        if self.recorded == topics {
+            return Ok(());
+        }
+        if !stale.is_empty() {
+            log::info!("Removed {} Home Assistant entities", stale.len());
+        }
+        if self.discovery.dry_run {
+            log::info!("[dry-run] Not recording the Home Assistant discovery topics");
+        } else if let Err(e) = persistence::write_atomically(&self.discovery.record, |file| {
+            Ok(file.write_all(topics.join("\n").as_bytes())?)
+        }) {
+            log::error!("Failed to record the Home Assistant discovery topics: {e:?}");
+            return Ok(());
+        }
+        self.recorded = topics;
+        Ok(())
+    }
+}
+
+/// The discovery topics and configs of the Home Assistant entities: the lock, operated
+/// through `<prefix>/cmd` with the challenge sensor it reads for unlocking, the door sensor if there is one, the key count and the time of the
+/// last refresh, all available while the status is online.
+fn entities(
+    config: &config::Mqtt,
+    homeassistant: &config::HomeAssistant,
+    door_sensor: bool,
+) -> Vec<(String, serde_json::Value)> {
+    let id = &homeassistant.id;
+    let device = serde_json::json!({
+        "identifiers": [format!("cellardoor_{id}")],
+        "name": homeassistant.name.as_deref().unwrap_or(id),
+        "manufacturer": "Metalab",
+        "model": "cellardoor",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let entity = |component: &str, object: &str, state: &str, extra: serde_json::Value| {
+        let mut entity = serde_json::json!({
+            "name": serde_json::Value::Null,
+            "unique_id": format!("cellardoor_{id}_{object}"),
+            "object_id": format!("{id}_{object}"),
+            "device": device,
+            "state_topic": topic(config, state),
+            "availability_topic": topic(config, "status"),
+            "availability_template": "{{ value_json.status }}",
+        });
+        if let (Some(entity), serde_json::Value::Object(extra)) = (entity.as_object_mut(), extra) {
+            entity.extend(extra);
+        }
+        let topic = format!(
+            "{}/{component}/{id}/{object}/config",
+            homeassistant.discovery_prefix.trim_end_matches('/')
+        );
+        (topic, entity)
+    };
+    let mut lock = serde_json::json!({
+        "value_template": "{{ 'UNLOCKED' if value_json.unlocked else 'LOCKED' }}",
+        "command_topic": topic(config, "cmd"),
+        "payload_lock": "lock",
+        "payload_unlock": "unlock",
+    });
+    if homeassistant.code.is_some() {
+        lock["code_format"] = "^.+$".into();
+        lock["command_template"] = format!(
+            r#"{{"action": "{{{{ value }}}}", "code": "{{{{ code }}}}", "challenge": "{{{{ states('sensor.{id}_challenge') }}}}"}}"#
+        )
+        .into();
+    }
+    let mut entities = vec![entity("lock", "lock", "door/lock", lock)];
+    if homeassistant.code.is_some() {
+        entities.push(entity(
+            "sensor",
+            "challenge",
+            "cmd/challenge",
+            serde_json::json!({
+                "name": "Unlock challenge",
+                "entity_category": "diagnostic",
+                "icon": "mdi:dice-multiple",
+                "value_template": "{{ value_json.challenge }}",
+            }),
+        ));
+    }
+    if door_sensor {
+        entities.push(entity(
+            "binary_sensor",
+            "door",
+            "door",
+            serde_json::json!({
+                "name": "Door",
+                "device_class": "door",
+                "value_template": "{{ 'ON' if value_json.open else 'OFF' }}",
+            }),
+        ));
+    }
+    entities.push(entity(
+        "sensor",
+        "keys",
+        "keys/count",
+        serde_json::json!({
+            "name": "Keys",
+            "state_class": "measurement",
+            "icon": "mdi:key",
+            "value_template": "{{ value_json.count }}",
+        }),
+    ));
+    entities.push(entity(
+        "sensor",
+        "last_refresh",
+        "keys/count",
+        serde_json::json!({
+            "name": "Last refresh",
+            "device_class": "timestamp",
+            "value_template": "{{ value_json.time }}",
+        }),
+    ));
+    entities
+}
+
+fn topic(config: &config::Mqtt, suffix: &str) -> String {
+    format!("{}/{suffix}", config.topic_prefix.trim_end_matches('/'))
+}
+
+fn status(online: bool, clean: bool) -> Vec<u8> {
+    let payload = if online {
+        serde_json::json!({ "status": "online", "time": now() })
+    } else {
+        serde_json::json!({ "status": "offline", "clean": clean })
+    };
+    payload.to_string().into_bytes()
+}
+
+fn push_str(packet: &mut Vec<u8>, value: &[u8]) {
+    packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    packet.extend_from_slice(value);
+}
+
+/// Prefixes `body` with the fixed header: packet type/flags and the varint remaining length.
+fn frame(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn connect_packet(config: &config::Mqtt, will_topic: &str, will: &[u8]) -> Vec<u8> {
+    // Clean session, with a retained will.
+    let mut flags = 0x02 | 0x04 | (config.qos << 3) | 0x20;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    let mut body = Vec::new();
+    push_str(&mut body, b"MQTT");
+    body.push(4);
+    body.push(flags);
+    let keepalive = u16::try_from(config.keepalive.as_secs()).unwrap_or(u16::MAX);
+    body.extend_from_slice(&keepalive.to_be_bytes());
+    push_str(&mut body, config.client_id.as_bytes());
+    push_str(&mut body, will_topic.as_bytes());
+    push_str(&mut body, will);
+    if let Some(username) = &config.username {
+        push_str(&mut body, username.as_bytes());
+    }
+    if let Some(password) = &config.password {
+        push_str(&mut body, password.as_bytes());
+    }
+    frame(CONNECT, &body)
+}
+
+/// `packet_id` is only sent for QoS 1.
+fn publish_packet(topic: &str, payload: &[u8], qos: u8, retain: bool, packet_id: u16) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_str(&mut body, topic.as_bytes());
+    if qos > 0 {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+    body.extend_from_slice(payload);
+    frame(PUBLISH | (qos << 1) | u8::from(retain), &body)
+}
+
+fn subscribe_packet(topic: &str, qos: u8, packet_id: u16) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    push_str(&mut body, topic.as_bytes());
+    body.push(qos);
+    frame(SUBSCRIBE, &body)
+}
+
+/// Splits an incoming PUBLISH into topic, packet id (QoS 1 and 2 only) and payload.
+fn split_publish(header: u8, body: &[u8]) -> anyhow::Result<(&str, Option<u16>, &[u8])> {
+    let truncated = || anyhow::anyhow!("truncated PUBLISH");
+    let len = u16::from_be_bytes([
+        *body.first().ok_or_else(truncated)?,
+        *body.get(1).ok_or_else(truncated)?,
+    ]) as usize;
+    let topic = std::str::from_utf8(body.get(2..2 + len).ok_or_else(truncated)?)?;
+    let mut rest = &body[2 + len..];
+    let packet_id = if header & 0x06 != 0 {
+        let id = rest.get(..2).ok_or_else(truncated)?;
+        let id = u16::from_be_bytes([id[0], id[1]]);
+        rest = &rest[2..];
+        Some(id)
+    } else {
+        None
+    };
+    Ok((topic, packet_id, rest))
+}
+
+/// Splits the incoming byte stream into packets.
+#[derive(Default)]
+struct PacketReader {
+    buf: Vec<u8>,
+}
+
+impl PacketReader {
+    /// Reads whatever is available; a timeout surfaces as `WouldBlock`/`TimedOut`.
+    fn fill(&mut self, stream: &mut impl Read) -> io::Result<()> {
+        let mut chunk = [0u8; 1024];
+        let len = stream.read(&mut chunk)?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.buf.extend_from_slice(&chunk[..len]);
+        Ok(())
+    }
+
+    /// Takes the next complete packet off the buffer as `(fixed header byte, body)`.
+    fn next(&mut self) -> anyhow::Result<Option<(u8, Vec<u8>)>> {
+        let mut len = 0usize;
+        let mut idx = 1;
+        loop {
+            let Some(&byte) = self.buf.get(idx) else {
+                return Ok(None);
+            };
+            len |= ((byte & 0x7f) as usize) << (7 * (idx - 1));
+            idx += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            anyhow::ensure!(idx <= 4, "malformed MQTT remaining length");
+        }
+        if self.buf.len() < idx + len {
+            return Ok(None);
+        }
+        let header = self.buf[0];
+        let body = self.buf[idx..idx + len].to_vec();
+        self.buf.drain(..idx + len);
+        Ok(Some((header, body)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        net::TcpListener,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use ring::hmac;
+
+    use super::{Discovery, Inbox, Mqtt, Nonces, PacketReader};
+    use crate::{audit::Decision, config, testutil::test_dir, Credential};
+
+    fn discovery(dir: &std::path::Path) -> Discovery {
+        Discovery {
+            record: dir.join("keys.discovery"),
+            door_sensor: true,
+            dry_run: false,
+        }
+    }
+
+    /// Connects a client for `config` to a listener standing in for the broker, returning the
+    /// client and the broker's end once the connection has been accepted.
+    fn connect(
+        config: config::Mqtt,
+        discovery: Discovery,
+    ) -> (Mqtt, std::net::TcpStream, PacketReader) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let poll = mio::Poll::new().unwrap();
+        let mqtt = Mqtt::new(
+            Some(config::Mqtt {
+                broker: format!("mqtt://127.0.0.1:{port}"),
+                ..config
+            }),
+            discovery,
+            Arc::new(mio::Waker::new(poll.registry(), mio::Token(0)).unwrap()),
+        )
+        .unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = PacketReader::default();
+        assert_eq!(read_packet(&mut reader, &mut stream).0, super::CONNECT);
+        stream.write_all(&[super::CONNACK, 2, 0, 0]).unwrap();
+        (mqtt, stream, reader)
+    }
+
+    fn read_packet(reader: &mut PacketReader, stream: &mut std::net::TcpStream) -> (u8, Vec<u8>) {
+        loop {
+            if let Some(packet) = reader.next().unwrap() {
+                return packet;
+            }
+            reader.fill(stream).unwrap();
+        }
+    }
+
+    /// Splits a PUBLISH body without packet id into topic and payload.
+    fn split_publish(body: &[u8]) -> (String, serde_json::Value) {
+        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+        (topic, serde_json::from_slice(&body[2 + len..]).unwrap())
+    }
+
+    #[test]
+    fn publishes_status_and_access_test() {
+        let dir = test_dir("mqtt-status");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let poll = mio::Poll::new().unwrap();
+        let mqtt = Mqtt::new(
+            Some(config::Mqtt {
+                broker: format!("mqtt://127.0.0.1:{port}"),
+                username: Some("door".to_owned()),
+                password: Some("secret".to_owned()),
+                client_id: "cellardoor-test".to_owned(),
+                topic_prefix: "test/door/".to_owned(),
+                qos: 0,
+                keepalive: Duration::from_secs(30),
+                command_secret: None,
+                command_max_age: Duration::from_secs(300),
+                homeassistant: None,
+            }),
+            discovery(&dir),
+            Arc::new(mio::Waker::new(poll.registry(), mio::Token(0)).unwrap()),
+        )
+        .unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = PacketReader::default();
+        let (header, connect) = read_packet(&mut reader, &mut stream);
+        assert_eq!(header, super::CONNECT);
+        // Username, password, retained will and clean session.
+        assert_eq!(connect[7], 0xc0 | 0x20 | 0x04 | 0x02);
+        stream.write_all(&[super::CONNACK, 2, 0, 0]).unwrap();
+
+        let (header, online) = read_packet(&mut reader, &mut stream);
+        assert_eq!(header, super::PUBLISH | 0x01);
+        let (topic, payload) = split_publish(&online);
+        assert_eq!(topic, "test/door/status");
+        assert_eq!(payload["status"], "online");
+
+        mqtt.access(
+            &Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]),
+            "Alice",
+            Decision::Granted,
+        );
+        let (topic, payload) = split_publish(&read_packet(&mut reader, &mut stream).1);
+        assert_eq!(topic, "test/door/access");
+        assert_eq!(payload["name"], "Alice");
+        assert_eq!(payload["decision"], "granted");
+        assert_eq!(payload["key"].as_str().unwrap().len(), 8);
+        assert!(!payload.to_string().contains("3300000392c6ea"));
+
+        mqtt.key_count(42);
+        let (topic, payload) = split_publish(&read_packet(&mut reader, &mut stream).1);
+        assert_eq!(topic, "test/door/keys/count");
+        assert_eq!(payload["count"], 42);
+
+        mqtt.shutdown(Duration::from_secs(5));
+        let (topic, payload) = split_publish(&read_packet(&mut reader, &mut stream).1);
+        assert_eq!(topic, "test/door/status");
+        assert_eq!(payload["status"], "offline");
+        assert_eq!(payload["clean"], true);
+        assert_eq!(read_packet(&mut reader, &mut stream).0, super::DISCONNECT);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn homeassistant_discovery_test() {
+        let dir = test_dir("mqtt-discovery");
+        let config = config::Mqtt {
+            broker: String::new(),
+            username: None,
+            password: None,
+            client_id: "cellardoor-test".to_owned(),
+            topic_prefix: "site/door".to_owned(),
+            qos: 0,
+            keepalive: Duration::from_secs(30),
+            command_secret: Some("secret".to_owned()),
+            command_max_age: Duration::from_secs(300),
+            homeassistant: Some(config::HomeAssistant {
+                id: "metalab_front".to_owned(),
+                name: Some("Front door".to_owned()),
+                discovery_prefix: "homeassistant".to_owned(),
+                code: Some("1234".to_owned()),
+            }),
+        };
+        // The retained messages of one connection besides the status, up to the disconnect.
+        let session = |config: &config::Mqtt| {
+            let (mqtt, mut stream, mut reader) = connect(config.clone(), discovery(&dir));
+            // Announcing comes first, so the queued shutdown doesn't cut it short.
+            mqtt.shutdown(Duration::from_secs(5));
+            let mut retained = Vec::new();
+            loop {
+                match read_packet(&mut reader, &mut stream) {
+                    (super::DISCONNECT, _) => return retained,
+                    (header, body) if header == super::PUBLISH | 0x01 => {
+                        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+                        if topic != "site/door/status" && topic != "site/door/cmd/challenge" {
+                            retained.push((topic, body[2 + len..].to_vec()));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        };
+        let topics = [
+            "homeassistant/lock/metalab_front/lock/config",
+            "homeassistant/sensor/metalab_front/challenge/config",
+            "homeassistant/binary_sensor/metalab_front/door/config",
+            "homeassistant/sensor/metalab_front/keys/config",
+            "homeassistant/sensor/metalab_front/last_refresh/config",
+        ];
+        // Announced again on every connection.
+        for _ in 0..2 {
+            let retained = session(&config);
+            let announced: Vec<_> = retained.iter().map(|(topic, _)| topic.as_str()).collect();
+            assert_eq!(announced, topics);
+            let lock: serde_json::Value = serde_json::from_slice(&retained[0].1).unwrap();
+            assert_eq!(lock["unique_id"], "cellardoor_metalab_front_lock");
+            assert_eq!(lock["command_topic"], "site/door/cmd");
+            assert_eq!(lock["state_topic"], "site/door/door/lock");
+            assert_eq!(lock["availability_topic"], "site/door/status");
+            assert_eq!(lock["device"]["name"], "Front door");
+            let template = lock["command_template"].as_str().unwrap();
+            assert!(template.contains("{{ code }}"), "{template}");
+            assert!(
+                template.contains("{{ states('sensor.metalab_front_challenge') }}"),
+                "{template}"
+            );
+        }
+        let record = std::fs::read_to_string(dir.join("keys.discovery")).unwrap();
+        assert_eq!(record.lines().collect::<Vec<_>>(), topics);
+
+        // Without the block the entities are removed with empty messages, once.
+        let config = config::Mqtt {
+            homeassistant: None,
+            ..config
+        };
+        let retained = session(&config);
+        assert_eq!(retained.len(), 5);
+        assert!(retained.iter().all(|(_, payload)| payload.is_empty()));
+        assert!(session(&config).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn coded_commands_test() {
+        let poll = mio::Poll::new().unwrap();
+        let (commands, _queue) = std::sync::mpsc::channel();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let mut inbox = Inbox {
+            code: Some(hmac::sign(&key, b"1234")),
+            key,
+            challenge: None,
+            nonces: Nonces::new(Duration::from_secs(300)),
+            commands,
+            waker: Arc::new(mio::Waker::new(poll.registry(), mio::Token(0)).unwrap()),
+        };
+        let now = SystemTime::now();
+        let rejected = |inbox: &mut Inbox, message: &[u8], now| {
+            inbox.verify(message, now).err().unwrap()["result"].clone()
+        };
+        let challenge = inbox.renew_challenge(now).unwrap();
+        assert_eq!(inbox.renew_challenge(now), None);
+        let coded = |code: &str, challenge: &str| {
+            let message =
+                serde_json::json!({ "action": "unlock", "code": code, "challenge": challenge });
+            message.to_string().into_bytes()
+        };
+        let unlock = coded("1234", &challenge);
+        let command = inbox.verify(&unlock, now).unwrap();
+        assert_eq!((command.line, command.nonce), ("OPEN", None));
+        // A challenge opens once, so a captured unlock can't be replayed.
+        assert_eq!(rejected(&mut inbox, &unlock, now), "ERR stale challenge");
+        let challenge = inbox.renew_challenge(now).unwrap();
+        let missing = br#"{"action": "unlock", "code": "1234"}"#;
+        assert_eq!(rejected(&mut inbox, missing, now), "ERR stale challenge");
+        let late = now + Duration::from_secs(300);
+        let unlock = coded("1234", &challenge);
+        assert_eq!(rejected(&mut inbox, &unlock, late), "ERR stale challenge");
+        let challenge = inbox.renew_challenge(late).unwrap();
+        assert!(inbox.verify(&coded("1234", &challenge), late).is_ok());
+
+        let rejected = |inbox: &mut Inbox, message: &[u8]| rejected(inbox, message, late);
+        let guess = &coded("0000", &challenge);
+        assert_eq!(rejected(&mut inbox, guess), "ERR bad code");
+        let lock = br#"{"action": "lock", "code": "1234"}"#;
+        assert_eq!(rejected(&mut inbox, lock), "ERR the door locks by itself");
+
+        inbox.code = None;
+        assert_eq!(inbox.renew_challenge(late), None);
+        assert!(rejected(&mut inbox, &unlock)
+            .as_str()
+            .unwrap()
+            .starts_with("ERR malformed command"));
+    }
+
+    #[test]
+    fn signed_commands_test() {
+        let poll = mio::Poll::new().unwrap();
+        let (commands, _queue) = std::sync::mpsc::channel();
+        let mut inbox = Inbox {
+            key: hmac::Key::new(hmac::HMAC_SHA256, b"secret"),
+            code: None,
+            challenge: None,
+            nonces: Nonces::new(Duration::from_secs(300)),
+            commands,
+            waker: Arc::new(mio::Waker::new(poll.registry(), mio::Token(0)).unwrap()),
+        };
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let sign = |key: &[u8], payload: &str| {
+            let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), payload.as_bytes());
+            let hex: String = tag.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+            serde_json::json!({ "payload": payload, "hmac": hex }).to_string()
+        };
+
+        let lockdown = r#"{"action":"lockdown","enabled":true,"nonce":"a","time":1700000000}"#;
+        let command = inbox
+            .verify(sign(b"secret", lockdown).as_bytes(), now)
+            .unwrap();
+        assert_eq!(command.line, "LOCKDOWN ON");
+        assert_eq!(command.nonce.as_deref(), Some("a"));
+
+        let rejected = |inbox: &mut Inbox, message: String| {
+            inbox.verify(message.as_bytes(), now).err().unwrap()["result"].clone()
+        };
+        assert_eq!(
+            rejected(&mut inbox, sign(b"secret", lockdown)),
+            "ERR nonce already used"
+        );
+        let open = r#"{"action":"open","nonce":"b","time":1700000000}"#;
+        assert_eq!(rejected(&mut inbox, sign(b"guess", open)), "ERR bad hmac");
+        let stale = r#"{"action":"open","nonce":"c","time":1699999000}"#;
+        assert_eq!(
+            rejected(&mut inbox, sign(b"secret", stale)),
+            "ERR command too old"
+        );
+        let unknown = r#"{"action":"explode","nonce":"d","time":1700000000}"#;
+        assert_eq!(
+            rejected(&mut inbox, sign(b"secret", unknown)),
+            "ERR unknown action \"explode\""
+        );
+        assert_eq!(
+            inbox
+                .verify(sign(b"secret", open).as_bytes(), now)
+                .unwrap()
+                .line,
+            "OPEN"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::Credential;
+
+/// Beyond this many keys the one granted longest ago is forgotten, early.
+const MAX_TRACKED: usize = 4096;
+
+/// Refuses a key for `anti_passback` after each grant, so one member can't badge in a queue
+/// of strangers. Unlike [`crate::debounce::Debounce`], which drops repeated sightings
+/// silently, this decides presentations, and is kept in memory only.
+#[derive(Default)]
+pub struct Passback {
+    /// `None` when off.
+    interval: Option<Duration>,
+    /// When each key was last granted; entries older than `interval` are removed by
+    /// [`Passback::prune`].
+    granted: HashMap<Credential, Instant>,
+}
+
+impl Passback {
+    pub fn new(interval: Option<Duration>) -> Passback {
+        Passback {
+            interval,
+            granted: HashMap::new(),
+        }
+    }
+
+    /// How much longer `id` is refused at `now`, if at all.
+    pub fn refused_for(&self, id: &Credential, now: Instant) -> Option<Duration> {
+        let interval = self.interval?;
+        let since = now.saturating_duration_since(*self.granted.get(id)?);
+        interval.checked_sub(since).filter(|left| !left.is_zero())
+    }
+
+    /// Starts the interval of `id`, granted at `now`.
+    pub fn granted(&mut self, id: &Credential, now: Instant) {
+        if self.interval.is_none() {
+            return;
+        }
+        if self.granted.len() >= MAX_TRACKED && !self.granted.contains_key(id) {
+            self.prune(now);
+            let oldest = self.granted.iter().min_by_key(|(_, granted)| **granted);
+            if let Some((&oldest, _)) = oldest.filter(|_| self.granted.len() >= MAX_TRACKED) {
+                self.granted.remove(&oldest);
+            }
+        }
+        self.granted.insert(*id, now);
+    }
+
+    /// Lets `id` in again right away, returning whether it was refused.
+    pub fn clear(&mut self, id: &Credential, now: Instant) -> bool {
+        let refused = self.refused_for(id, now).is_some();
+        self.granted.remove(id);
+        refused
+    }
+
+    /// Forgets keys whose interval has ended at `now`.
+    pub fn prune(&mut self, now: Instant) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        self.granted
+            .retain(|_, granted| now.saturating_duration_since(*granted) < interval);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Passback, MAX_TRACKED};
+    use crate::Credential;
+
+    #[test]
+    fn interval_test() {
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let mut passback = Passback::new(Some(Duration::from_secs(60)));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(passback.refused_for(&alice, at(0)), None);
+        passback.granted(&alice, at(0));
+        assert_eq!(
+            passback.refused_for(&alice, at(20)),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(passback.refused_for(&bob, at(20)), None);
+        assert_eq!(passback.refused_for(&alice, at(60)), None);
+
+        passback.granted(&alice, at(100));
+        assert!(passback.clear(&alice, at(101)));
+        assert_eq!(passback.refused_for(&alice, at(101)), None);
+        assert!(!passback.clear(&alice, at(101)));
+
+        // Bounded: a full table first drops expired entries, then the oldest one.
+        passback.granted(&alice, at(200));
+        let id = |n: u64| {
+            let mut id = [0x01; 7];
+            id[1..].copy_from_slice(&n.to_le_bytes()[..6]);
+            Credential::OneWire(id)
+        };
+        for n in 0..MAX_TRACKED as u64 {
+            passback.granted(&id(n), at(300) + Duration::from_millis(n));
+        }
+        assert_eq!(passback.refused_for(&alice, at(300)), None);
+        passback.granted(&bob, at(310));
+        assert_eq!(passback.granted.len(), MAX_TRACKED);
+        assert_eq!(passback.refused_for(&id(0), at(310)), None);
+        assert!(passback.refused_for(&id(1), at(310)).is_some());
+        assert!(passback.refused_for(&bob, at(310)).is_some());
+        passback.prune(at(400));
+        assert!(passback.granted.is_empty());
+
+        let mut off = Passback::default();
+        off.granted(&alice, at(0));
+        assert_eq!(off.refused_for(&alice, at(0)), None);
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    audit::{Crc, Decision},
+    config,
+    keypad::Press,
+    pin::PinHash,
+    privacy, Credential,
+};
+
+/// Digits beyond this are dropped; no PIN is that long.
+const MAX_DIGITS: usize = 16;
+
+/// The PIN second factor of `pin_required` keys: presenting such a key starts a challenge that
+/// the right PIN entered on the keypad within the window completes.
+///
+/// There is one keypad, for the challenge of the key presented last, at whichever reader.
+pub struct Pins {
+    /// `None` without a keypad, when challenges can't be completed.
+    keypad: Option<config::Keypad>,
+    /// `pin_required` from the config; these take precedence over hashes in the key list.
+    required: RwLock<HashMap<Credential, String>>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    challenge: Option<Challenge>,
+    digits: String,
+    failures: HashMap<Credential, Failures>,
+}
+
+#[derive(Default)]
+struct Failures {
+    /// Wrong PINs in a row.
+    wrong: u32,
+    cooldown_until: Option<Instant>,
+}
+
+/// A key granted but for its PIN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Challenge {
+    pub id: Credential,
+    /// Index of the reader the key was presented at.
+    pub reader: usize,
+    pub decision: Decision,
+    pub crc: Crc,
+    hash: String,
+    deadline: Instant,
+}
+
+/// What presenting a key needs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Gate {
+    /// The key has no PIN.
+    Open,
+    /// The door opens once the PIN follows.
+    Challenged,
+    /// Too many wrong PINs; the key is refused for now.
+    CoolingDown,
+}
+
+/// A PIN completed with `#`.
+#[derive(Debug, PartialEq)]
+pub enum Entry {
+    /// No key is waiting for a PIN, or its window has passed.
+    Unexpected,
+    Verified(Challenge),
+    /// `cooldown` if this was one wrong PIN too many.
+    Wrong {
+        challenge: Challenge,
+        cooldown: bool,
+    },
+}
+
+impl Default for Pins {
+    fn default() -> Pins {
+        Pins::new(None, HashMap::new())
+    }
+}
+
+impl Pins {
+    pub fn new(keypad: Option<config::Keypad>, required: HashMap<Credential, String>) -> Pins {
+        Pins {
+            keypad,
+            required: RwLock::new(required),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Applies a reloaded `pin_required`.
+    pub fn set_required(&self, required: HashMap<Credential, String>) {
+        *self.required.write().unwrap() = required;
+    }
+
+    /// Starts the challenge of a key that would be `decision`, if it needs a PIN: one from the
+    /// config, else `listed` from the key list.
+    pub fn challenge(
+        &self,
+        id: &Credential,
+        listed: Option<&str>,
+        reader: usize,
+        decision: Decision,
+        crc: Crc,
+        now: Instant,
+    ) -> Gate {
+        let hash = self.required.read().unwrap().get(id).cloned();
+        let Some(hash) = hash.or(listed.map(str::to_owned)) else {
+            return Gate::Open;
+        };
+        let mut state = self.state.lock().unwrap();
+        if state
+            .failures
+            .get(id)
+            .and_then(|failures| failures.cooldown_until)
+            .is_some_and(|until| now < until)
+        {
+            return Gate::CoolingDown;
+        }
+        let Some(keypad) = &self.keypad else {
+            log::warn!(
+                "Key {} needs a PIN, but there is no keypad to enter it on",
+                privacy::id(id)
+            );
+            return Gate::Challenged;
+        };
+        // Digits typed before the key don't count.
+        state.digits.clear();
+        state.challenge = Some(Challenge {
+            id: *id,
+            reader,
+            decision,
+            crc,
+            hash,
+            deadline: now + keypad.window,
+        });
+        Gate::Challenged
+    }
+
+    /// Takes a keypress, returning the outcome once `#` completes a PIN.
+    pub fn press(&self, press: Press, now: Instant) -> Option<Entry> {
+        let mut state = self.state.lock().unwrap();
+        match press {
+            Press::Digit(digit) => {
+                if state.digits.len() < MAX_DIGITS {
+                    state.digits.push(digit);
+                }
+                return None;
+            }
+            Press::Clear => {
+                state.digits.clear();
+                return None;
+            }
+            Press::Enter => {}
+        }
+        let pin = std::mem::take(&mut state.digits);
+        let Some(challenge) = state
+            .challenge
+            .take()
+            .filter(|challenge| now < challenge.deadline)
+        else {
+            return Some(Entry::Unexpected);
+        };
+        // The hash was checked when it was configured or listed.
+        let verified = PinHash::parse(&challenge.hash).is_ok_and(|hash| hash.verify(&pin));
+        if verified {
+            state.failures.remove(&challenge.id);
+            return Some(Entry::Verified(challenge));
+        }
+        let max_attempts = self.keypad.as_ref().map_or(1, |keypad| keypad.max_attempts);
+        let failures = state.failures.entry(challenge.id).or_default();
+        failures.wrong += 1;
+        let cooling = failures.wrong >= max_attempts;
+        if cooling {
+            failures.wrong = 0;
+            failures.cooldown_until = Some(now + self.cooldown());
+        }
+        Some(Entry::Wrong {
+            challenge,
+            cooldown: cooling,
+        })
+    }
+
+    /// How long a key is refused after too many wrong PINs.
+    pub fn cooldown(&self) -> Duration {
+        self.keypad
+            .as_ref()
+            .map_or(Duration::ZERO, |keypad| keypad.cooldown)
+    }
+
+    /// Forgets cooldowns that have ended at `now`, so the state stays bounded.
+    pub fn prune(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.failures.retain(|_, failures| {
+            failures.wrong > 0 || failures.cooldown_until.is_some_and(|until| now < until)
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    use super::{Entry, Gate, Pins};
+    use crate::{
+        audit::{Crc, Decision},
+        config,
+        keypad::Press,
+        Credential,
+    };
+
+    const KEY: Credential = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+    /// Of the PIN `0000`.
+    const HASH: &str = "pbkdf2-sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk";
+
+    fn pins() -> Pins {
+        let keypad = config::Keypad {
+            path: PathBuf::new(),
+            window: Duration::from_secs(10),
+            max_attempts: 3,
+            cooldown: Duration::from_secs(300),
+        };
+        Pins::new(Some(keypad), HashMap::from([(KEY, HASH.to_owned())]))
+    }
+
+    fn enter(pins: &Pins, pin: &str, now: Instant) -> Option<Entry> {
+        for digit in pin.chars() {
+            assert_eq!(pins.press(Press::Digit(digit), now), None);
+        }
+        pins.press(Press::Enter, now)
+    }
+
+    fn challenge(pins: &Pins, now: Instant) -> Gate {
+        pins.challenge(&KEY, None, 0, Decision::Granted, Crc::Valid, now)
+    }
+
+    #[test]
+    fn pin_follows_key_test() {
+        let pins = pins();
+        let now = Instant::now();
+        let other = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        assert_eq!(
+            pins.challenge(&other, None, 0, Decision::Granted, Crc::Valid, now),
+            Gate::Open
+        );
+        // Without a key waiting, even the right PIN does nothing.
+        assert_eq!(enter(&pins, "0000", now), Some(Entry::Unexpected));
+
+        // Digits typed before the key and cleared ones are dropped.
+        pins.press(Press::Digit('9'), now);
+        assert_eq!(challenge(&pins, now), Gate::Challenged);
+        pins.press(Press::Digit('1'), now);
+        pins.press(Press::Clear, now);
+        let Some(Entry::Verified(verified)) = enter(&pins, "0000", now) else {
+            panic!("PIN not verified");
+        };
+        assert_eq!((verified.id, verified.decision), (KEY, Decision::Granted));
+        // A challenge is completed once.
+        assert_eq!(enter(&pins, "0000", now), Some(Entry::Unexpected));
+
+        // The list's hash applies to keys the config doesn't name.
+        assert_eq!(
+            pins.challenge(&other, Some(HASH), 1, Decision::Granted, Crc::Valid, now),
+            Gate::Challenged
+        );
+        assert!(matches!(
+            enter(&pins, "0000", now),
+            Some(Entry::Verified(challenge)) if challenge.reader == 1
+        ));
+
+        challenge(&pins, now);
+        let late = now + Duration::from_secs(10);
+        assert_eq!(enter(&pins, "0000", late), Some(Entry::Unexpected));
+    }
+
+    #[test]
+    fn wrong_pins_cool_the_key_down_test() {
+        let pins = pins();
+        let now = Instant::now();
+        for attempt in 1..=3 {
+            assert_eq!(challenge(&pins, now), Gate::Challenged);
+            let Some(Entry::Wrong { cooldown, .. }) = enter(&pins, "1234", now) else {
+                panic!("wrong PIN accepted");
+            };
+            assert_eq!(cooldown, attempt == 3);
+        }
+        assert_eq!(challenge(&pins, now), Gate::CoolingDown);
+        assert_eq!(
+            challenge(&pins, now + Duration::from_secs(299)),
+            Gate::CoolingDown
+        );
+
+        let later = now + Duration::from_secs(300);
+        pins.prune(later);
+        assert_eq!(challenge(&pins, later), Gate::Challenged);
+        assert!(matches!(
+            enter(&pins, "0000", later),
+            Some(Entry::Verified(_))
+        ));
+
+        // The right PIN resets the count of wrong ones.
+        for _ in 0..2 {
+            challenge(&pins, later);
+            enter(&pins, "1234", later);
+        }
+        challenge(&pins, later);
+        enter(&pins, "0000", later);
+        challenge(&pins, later);
+        assert!(matches!(
+            enter(&pins, "1234", later),
+            Some(Entry::Wrong {
+                cooldown: false,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,301 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+    sync::{mpsc::Sender, Arc},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{backoff, config};
+
+/// A tag's UID as read by the reader at an index into `readers`.
+pub type Tag = (usize, Vec<u8>);
+
+/// Wakes the PN532 from power down in HSU mode; it needs a while of bytes on the line.
+const WAKEUP: [u8; 16] = [0x55, 0x55, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// Normal mode, no virtual card, with the IRQ line used.
+const SAM_CONFIGURATION: [u8; 4] = [0x14, 0x01, 0x14, 0x01];
+/// MaxRetries: after one attempt at activating a target, InListPassiveTarget answers with none
+/// rather than waiting for one forever.
+const MAX_RETRIES: [u8; 5] = [0x32, 0x05, 0xff, 0x01, 0x01];
+/// InListPassiveTarget for one target at 106 kbps type A, i.e. ISO 14443A.
+const LIST_TARGET: [u8; 3] = [0x4a, 0x01, 0x00];
+
+/// Host to PN532 and back frame identifiers.
+const TFI_HOST: u8 = 0xd4;
+const TFI_PN532: u8 = 0xd5;
+/// Frame identifier of the error frame sent for a syntax error in a command.
+const TFI_ERROR: u8 = 0x7f;
+
+const ACK_TIMEOUT: Duration = Duration::from_millis(100);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Starts polling the PN532 on `config.path` for tags, sending each detection to `tags` and
+/// waking the event loop through `waker`. The port is reopened with backoff whenever it fails,
+/// e.g. when the reader is unplugged; the thread ends once `tags` is disconnected.
+///
+/// A tag held on the reader is reported on every poll. The access debounce drops the repeats.
+pub fn spawn(
+    index: usize,
+    config: config::Nfc,
+    tags: Sender<Tag>,
+    waker: Arc<mio::Waker>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut backoff = backoff::Backoff::new(&config::Backoff::default());
+        let mut errors = backoff::ErrorThrottle::default();
+        let mut report = |uid: Vec<u8>| {
+            tags.send((index, uid)).is_ok() && waker.wake().map_err(|e| log::error!("{e}")).is_ok()
+        };
+        loop {
+            match session(&config, &mut backoff, &mut errors, &mut report) {
+                Ok(()) => return,
+                Err(e) => errors.error(format!(
+                    "NFC reader on {} failed: {e:?}",
+                    config.path.display()
+                )),
+            }
+            std::thread::sleep(backoff.next_delay());
+        }
+    })
+}
+
+/// Polls the open port until it fails, or until `report` declines a tag (`Ok`).
+fn session(
+    config: &config::Nfc,
+    backoff: &mut backoff::Backoff,
+    errors: &mut backoff::ErrorThrottle,
+    report: &mut impl FnMut(Vec<u8>) -> bool,
+) -> anyhow::Result<()> {
+    let mut port = Port::open(&config.path, config.baud)?;
+    port.file.write_all(&WAKEUP)?;
+    port.command(&SAM_CONFIGURATION)?;
+    port.command(&MAX_RETRIES)?;
+    log::info!("Polling NFC reader on {}", config.path.display());
+    backoff.reset();
+    errors.reset();
+    loop {
+        if let Some(uid) = target_uid(&port.command(&LIST_TARGET)?)? {
+            if !report(uid) {
+                return Ok(());
+            }
+        }
+        std::thread::sleep(config.poll_interval);
+    }
+}
+
+/// A PN532 on a serial port in raw mode.
+struct Port {
+    file: File,
+    /// Bytes read but not yet parsed into a frame.
+    received: Vec<u8>,
+}
+
+impl Port {
+    fn open(path: &Path, baud: u32) -> anyhow::Result<Port> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)
+            .context(format!("Failed to open {}", path.display()))?;
+        let speed = speed(baud)?;
+        let fd = file.as_raw_fd();
+        // SAFETY: `termios` is plain data filled in by tcgetattr, and `fd` stays open.
+        unsafe {
+            let mut termios: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(fd, &mut termios) < 0 {
+                return Err(io::Error::last_os_error()).context("Not a serial port");
+            }
+            libc::cfmakeraw(&mut termios);
+            termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+            // Reads return after 100 ms without data, so deadlines are checked.
+            termios.c_cc[libc::VMIN] = 0;
+            termios.c_cc[libc::VTIME] = 1;
+            if libc::cfsetspeed(&mut termios, speed) < 0
+                || libc::tcsetattr(fd, libc::TCSANOW, &termios) < 0
+                || libc::tcflush(fd, libc::TCIOFLUSH) < 0
+            {
+                return Err(io::Error::last_os_error()).context("Failed to configure the port");
+            }
+        }
+        Ok(Port {
+            file,
+            received: Vec::new(),
+        })
+    }
+
+    /// Sends the command `data` and returns the data of its response, starting with the
+    /// response code.
+    fn command(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.received.clear();
+        self.file.write_all(&encode(data))?;
+        anyhow::ensure!(
+            self.receive(ACK_TIMEOUT)? == Frame::Ack,
+            "PN532 didn't acknowledge command {:#04x}",
+            data[0]
+        );
+        match self.receive(RESPONSE_TIMEOUT)? {
+            Frame::Data(response) if response.first() == Some(&(data[0] + 1)) => Ok(response),
+            frame => anyhow::bail!("Unexpected answer to command {:#04x}: {frame:?}", data[0]),
+        }
+    }
+
+    fn receive(&mut self, timeout: Duration) -> anyhow::Result<Frame> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(frame) = next_frame(&mut self.received)? {
+                return Ok(frame);
+            }
+            anyhow::ensure!(Instant::now() < deadline, "No answer from the PN532");
+            let mut buf = [0; 64];
+            let len = self.file.read(&mut buf)?;
+            self.received.extend_from_slice(&buf[..len]);
+        }
+    }
+}
+
+fn speed(baud: u32) -> anyhow::Result<libc::speed_t> {
+    Ok(match baud {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        460800 => libc::B460800,
+        921600 => libc::B921600,
+        _ => anyhow::bail!("Unsupported baud rate {baud}"),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Ack,
+    Nack,
+    /// A response's data after the frame identifier.
+    Data(Vec<u8>),
+}
+
+/// The normal information frame carrying the command `data` to the PN532.
+fn encode(data: &[u8]) -> Vec<u8> {
+    let len = data.len() as u8 + 1;
+    let mut frame = vec![0x00, 0x00, 0xff, len, len.wrapping_neg(), TFI_HOST];
+    frame.extend_from_slice(data);
+    let sum = data
+        .iter()
+        .fold(TFI_HOST, |sum, &byte| sum.wrapping_add(byte));
+    frame.extend_from_slice(&[sum.wrapping_neg(), 0x00]);
+    frame
+}
+
+/// Takes the first complete frame out of `received`, skipping anything before it.
+fn next_frame(received: &mut Vec<u8>) -> anyhow::Result<Option<Frame>> {
+    let Some(start) = received.windows(2).position(|pair| pair == [0x00, 0xff]) else {
+        // A trailing zero may begin the next start code.
+        let keep = usize::from(received.last() == Some(&0x00));
+        received.drain(..received.len() - keep);
+        return Ok(None);
+    };
+    received.drain(..start);
+    // Start code, length and its checksum.
+    let &[_, _, len, lcs, ..] = received.as_slice() else {
+        return Ok(None);
+    };
+    match (len, lcs) {
+        (0x00, 0xff) => {
+            received.drain(..4);
+            return Ok(Some(Frame::Ack));
+        }
+        (0xff, 0x00) => {
+            received.drain(..4);
+            return Ok(Some(Frame::Nack));
+        }
+        _ => {}
+    }
+    if len.wrapping_add(lcs) != 0 {
+        received.drain(..4);
+        anyhow::bail!("Bad length checksum in PN532 frame");
+    }
+    let end = 4 + usize::from(len) + 1;
+    if received.len() < end {
+        return Ok(None);
+    }
+    let frame: Vec<u8> = received.drain(..end).skip(4).collect();
+    let (body, dcs) = frame.split_at(frame.len() - 1);
+    let sum = body
+        .iter()
+        .fold(dcs[0], |sum, &byte| sum.wrapping_add(byte));
+    anyhow::ensure!(sum == 0, "Bad data checksum in PN532 frame");
+    match body.split_first() {
+        Some((&TFI_PN532, data)) => Ok(Some(Frame::Data(data.to_vec()))),
+        Some((&TFI_ERROR, _)) => anyhow::bail!("PN532 reported a syntax error"),
+        _ => anyhow::bail!("PN532 frame with unknown identifier"),
+    }
+}
+
+/// The UID in an InListPassiveTarget response, if a target was found.
+fn target_uid(response: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    // Response code, number of targets, target number, SENS_RES, SEL_RES, UID length.
+    match response {
+        [_, 0, ..] => Ok(None),
+        [_, _, _, _, _, _, len, uid @ ..] if uid.len() >= usize::from(*len) => {
+            Ok(Some(uid[..usize::from(*len)].to_vec()))
+        }
+        _ => anyhow::bail!("Truncated target in PN532 response"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, next_frame, target_uid, Frame, SAM_CONFIGURATION};
+
+    #[test]
+    fn frame_test() {
+        assert_eq!(
+            encode(&SAM_CONFIGURATION),
+            [0x00, 0x00, 0xff, 0x05, 0xfb, 0xd4, 0x14, 0x01, 0x14, 0x01, 0x02, 0x00]
+        );
+
+        // Noise, then an ACK and the SAMConfiguration response, the latter arriving in parts.
+        let mut received = vec![0x55, 0x00, 0x00, 0xff, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff];
+        assert_eq!(next_frame(&mut received).unwrap(), Some(Frame::Ack));
+        assert_eq!(next_frame(&mut received).unwrap(), None);
+        received.extend_from_slice(&[0x02, 0xfe, 0xd5]);
+        assert_eq!(next_frame(&mut received).unwrap(), None);
+        received.extend_from_slice(&[0x15, 0x16, 0x00]);
+        assert_eq!(
+            next_frame(&mut received).unwrap(),
+            Some(Frame::Data(vec![0x15]))
+        );
+
+        let mut corrupted = vec![0x00, 0x00, 0xff, 0x02, 0xfe, 0xd5, 0x15, 0x17, 0x00];
+        assert!(next_frame(&mut corrupted).is_err());
+        let mut error = vec![0x00, 0x00, 0xff, 0x01, 0xff, 0x7f, 0x81, 0x00];
+        assert!(next_frame(&mut error).is_err());
+    }
+
+    #[test]
+    fn target_uid_test() {
+        assert_eq!(target_uid(&[0x4b, 0x00]).unwrap(), None);
+        let seven = [
+            0x4b, 0x01, 0x01, 0x00, 0x44, 0x00, 0x07, 0x04, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x80,
+        ];
+        assert_eq!(
+            target_uid(&seven).unwrap(),
+            Some(vec![0x04, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x80])
+        );
+        let four = [
+            0x4b, 0x01, 0x01, 0x00, 0x04, 0x08, 0x04, 0xde, 0xad, 0xbe, 0xef,
+        ];
+        assert_eq!(
+            target_uid(&four).unwrap(),
+            Some(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+        assert!(target_uid(&seven[..10]).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::{config, Credential};
+
+/// Which keys allowed to hold a door by `presence` are on its reader, and which doors they
+/// hold. Departures are taken from polls of the bus rather than removal events, which get lost.
+#[derive(Default)]
+pub struct Presence {
+    /// `None` without a `presence` config, when no door is ever held.
+    config: Option<config::Presence>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Keys on the bus, with the index of their reader and how many polls in a row missed them.
+    present: HashMap<Credential, (usize, u32)>,
+    /// Held doors by reader index, with when the last key allowed to hold it stopped doing so.
+    held: HashMap<usize, Option<Instant>>,
+}
+
+impl Presence {
+    pub fn new(config: Option<config::Presence>) -> Presence {
+        Presence {
+            config,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> Option<&config::Presence> {
+        self.config.as_ref()
+    }
+
+    /// Starts tracking `id` on the reader `index`, returning whether it wasn't already there.
+    pub fn arrived(&self, id: &Credential, index: usize) -> bool {
+        if self.config.is_none() {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.present.insert(*id, (index, 0)).is_none()
+    }
+
+    /// Counts a poll of the bus finding the `listed` keys, returning those that departed.
+    pub fn poll(&self, listed: &HashSet<Credential>) -> Vec<(Credential, usize)> {
+        let Some(config) = &self.config else {
+            return Vec::new();
+        };
+        let mut state = self.state.lock().unwrap();
+        let mut departed = Vec::new();
+        state.present.retain(|id, (index, missed)| {
+            *missed = match listed.contains(id) {
+                true => 0,
+                false => *missed + 1,
+            };
+            if *missed >= config.missed_polls {
+                departed.push((*id, *index));
+            }
+            *missed < config.missed_polls
+        });
+        departed
+    }
+
+    /// Holds the doors that a present key is `allowed` to hold at `now` and releases those
+    /// whose last such key was gone for `grace`, returning the changes as reader index and
+    /// whether held.
+    pub fn update(
+        &self,
+        allowed: impl Fn(&Credential, usize) -> bool,
+        now: Instant,
+    ) -> Vec<(usize, bool)> {
+        let Some(config) = &self.config else {
+            return Vec::new();
+        };
+        let mut state = self.state.lock().unwrap();
+        let State { present, held } = &mut *state;
+        let wanted: HashSet<usize> = present
+            .iter()
+            .filter(|(id, (index, _))| allowed(id, *index))
+            .map(|(_, (index, _))| *index)
+            .collect();
+        let mut changes = Vec::new();
+        for index in &wanted {
+            if held.insert(*index, None).is_none() {
+                changes.push((*index, true));
+            }
+        }
+        held.retain(|index, since| {
+            if wanted.contains(index) {
+                return true;
+            }
+            let since = *since.get_or_insert(now);
+            let keep = now.saturating_duration_since(since) < config.grace;
+            if !keep {
+                changes.push((*index, false));
+            }
+            keep
+        });
+        changes.sort();
+        changes
+    }
+
+    /// Whether the door of reader `index` is held.
+    pub fn holds(&self, index: usize) -> bool {
+        self.state.lock().unwrap().held.contains_key(&index)
+    }
+
+    /// Releases every door at once, for a lockdown, returning the reader indexes.
+    pub fn release(&self) -> Vec<usize> {
+        let mut state = self.state.lock().unwrap();
+        let mut released: Vec<usize> = state.held.drain().map(|(index, _)| index).collect();
+        released.sort();
+        released
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    };
+
+    use super::Presence;
+    use crate::Credential;
+
+    #[test]
+    fn presence_test() {
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let presence = Presence::new(Some(
+            serde_yaml_ng::from_str("groups: [late]\ngrace: 10s\nmissed_polls: 2").unwrap(),
+        ));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let only_alice = |id: &Credential, _| *id == alice;
+
+        assert!(presence.arrived(&alice, 0));
+        assert!(!presence.arrived(&alice, 0));
+        assert!(presence.arrived(&bob, 1));
+        assert_eq!(presence.update(only_alice, at(0)), [(0, true)]);
+        assert!(presence.holds(0) && !presence.holds(1));
+
+        // One missed poll is a flaky read, the second a departure.
+        let listed = HashSet::from([bob]);
+        assert!(presence.poll(&listed).is_empty());
+        assert!(presence.poll(&HashSet::from([alice, bob])).is_empty());
+        assert!(presence.poll(&listed).is_empty());
+        assert_eq!(presence.poll(&listed), [(alice, 0)]);
+
+        // The door stays held for the grace period.
+        assert!(presence.update(only_alice, at(1)).is_empty());
+        assert!(presence.update(only_alice, at(10)).is_empty());
+        assert_eq!(presence.update(only_alice, at(11)), [(0, false)]);
+        assert!(!presence.holds(0));
+
+        // Coming back within the grace keeps the hold going.
+        presence.arrived(&alice, 0);
+        presence.update(only_alice, at(20));
+        presence.poll(&listed);
+        presence.poll(&listed);
+        presence.update(only_alice, at(21));
+        presence.arrived(&alice, 0);
+        assert!(presence.update(only_alice, at(40)).is_empty());
+        assert_eq!(presence.release(), [0]);
+
+        let off = Presence::default();
+        assert!(!off.arrived(&alice, 0));
+        assert!(off.update(|_, _| true, at(0)).is_empty());
+    }
+}
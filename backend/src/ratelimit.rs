@@ -0,0 +1,95 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config;
+
+/// The token bucket of `open_rate_limit`, shared by the remote ways to open a door, so a leaked
+/// token or a looping automation can't pulse the strike. It refills by `Instant`, which a jump
+/// of the wall clock doesn't move.
+#[derive(Default)]
+pub struct RateLimit {
+    /// `None` without `open_rate_limit`, when every open passes.
+    config: Option<config::OpenRateLimit>,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Default)]
+struct Bucket {
+    tokens: f64,
+    /// When `tokens` was last brought up to date; `None` for a full bucket never taken from.
+    updated: Option<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(config: Option<config::OpenRateLimit>) -> RateLimit {
+        RateLimit {
+            config,
+            bucket: Mutex::default(),
+        }
+    }
+
+    /// Takes a token at `now`, or tells how long until the next one is refilled.
+    pub fn take(&self, now: Instant) -> Result<(), Duration> {
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+        let mut bucket = self.bucket.lock().unwrap();
+        let tokens = bucket.refill(config, now);
+        if tokens >= 1.0 {
+            bucket.tokens = tokens - 1.0;
+            return Ok(());
+        }
+        Err(config.refill.mul_f64(1.0 - tokens))
+    }
+
+    /// The tokens left at `now` and the `burst` they refill to, if limited at all.
+    pub fn level(&self, now: Instant) -> Option<(f64, u32)> {
+        let config = self.config.as_ref()?;
+        let tokens = self.bucket.lock().unwrap().refill(config, now);
+        Some((tokens, config.burst))
+    }
+}
+
+impl Bucket {
+    /// Brings `tokens` up to `now`, returning it.
+    fn refill(&mut self, config: &config::OpenRateLimit, now: Instant) -> f64 {
+        let burst = f64::from(config.burst);
+        self.tokens = match self.updated {
+            None => burst,
+            Some(updated) => {
+                let elapsed = now.saturating_duration_since(updated);
+                (self.tokens + elapsed.as_secs_f64() / config.refill.as_secs_f64()).min(burst)
+            }
+        };
+        self.updated = Some(now);
+        self.tokens
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimit;
+
+    #[test]
+    fn token_bucket_test() {
+        let limit = RateLimit::new(Some(
+            serde_yaml_ng::from_str("burst: 2\nrefill: 10s").unwrap(),
+        ));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(limit.level(at(0)), Some((2.0, 2)));
+        assert_eq!(limit.take(at(0)), Ok(()));
+        assert_eq!(limit.take(at(0)), Ok(()));
+        assert_eq!(limit.take(at(4)), Err(Duration::from_secs(6)));
+        assert_eq!(limit.take(at(10)), Ok(()));
+        // Refilling stops at the burst.
+        assert_eq!(limit.level(at(100)), Some((2.0, 2)));
+
+        assert_eq!(RateLimit::default().take(at(0)), Ok(()));
+        assert_eq!(RateLimit::default().level(at(0)), None);
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config;
+
+/// Whether the udev monitor still hears from the bus, for `reader.watchdog`. A dead reader
+/// sends no remove events, so silence is the only sign of it.
+///
+/// Where a quiet bus is normal, probes make up the activity: the kernel counts each search of
+/// a bus master in its `w1_master_attempts`, whether it found a device or not, and writing to
+/// its `w1_master_search` wakes it up to search right away. A master that is gone or stuck
+/// stops counting.
+#[derive(Default)]
+pub struct ReaderHealth {
+    /// `None` without a `reader.watchdog` config, when the bus is never found silent.
+    config: Option<config::Watchdog>,
+    /// Probes read the search counters but don't trigger searches.
+    dry_run: bool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// When the last event arrived, or the watchdog started.
+    last_event: Option<Instant>,
+    silent: bool,
+    last_probe: Option<Instant>,
+    /// `w1_master_attempts` of each bus master at the last probe.
+    attempts: HashMap<PathBuf, i64>,
+}
+
+impl ReaderHealth {
+    /// Silence is counted from `now`, when the monitor starts.
+    pub fn new(config: Option<config::Watchdog>, dry_run: bool, now: Instant) -> ReaderHealth {
+        ReaderHealth {
+            config,
+            dry_run,
+            state: Mutex::new(State {
+                last_event: Some(now),
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.config.is_some()
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.state.lock().unwrap().silent
+    }
+
+    /// Counts an event at `now`, returning for how long the bus was silent if it was found so.
+    pub fn activity(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let last_event = state.last_event.replace(now);
+        if !std::mem::take(&mut state.silent) {
+            return None;
+        }
+        Some(now.saturating_duration_since(last_event.unwrap_or(now)))
+    }
+
+    /// Finds the bus silent once there was no event for `silence` at `now`, returning for how
+    /// long the first time.
+    pub fn check(&self, now: Instant) -> Option<Duration> {
+        let config = self.config.as_ref()?;
+        let mut state = self.state.lock().unwrap();
+        let silent_for = now.saturating_duration_since(*state.last_event.get_or_insert(now));
+        if state.silent || silent_for < config.silence {
+            return None;
+        }
+        state.silent = true;
+        Some(silent_for)
+    }
+
+    /// Probes the bus masters in `w1_devices` if `probe` has passed at `now`, returning
+    /// whether one searched its bus since the last probe.
+    pub fn probe(&self, w1_devices: &Path, now: Instant) -> bool {
+        let Some(interval) = self.config.as_ref().and_then(|config| config.probe) else {
+            return false;
+        };
+        let mut state = self.state.lock().unwrap();
+        if state
+            .last_probe
+            .is_some_and(|last| now.saturating_duration_since(last) < interval)
+        {
+            return false;
+        }
+        state.last_probe = Some(now);
+        let masters = match bus_masters(w1_devices) {
+            Ok(masters) => masters,
+            Err(e) => {
+                log::warn!("Failed to list the bus masters in {w1_devices:?}: {e}");
+                return false;
+            }
+        };
+        let mut searched = false;
+        let mut attempts = HashMap::new();
+        for master in masters {
+            match read_number(&master.join("w1_master_attempts")) {
+                Ok(count) => {
+                    searched |= state
+                        .attempts
+                        .get(&master)
+                        .is_some_and(|last| count > *last);
+                    attempts.insert(master.clone(), count);
+                }
+                Err(e) => log::warn!("Failed to read the search attempts of {master:?}: {e}"),
+            }
+            if !self.dry_run {
+                if let Err(e) = trigger_search(&master) {
+                    log::warn!("Failed to trigger a search on {master:?}: {e}");
+                }
+            }
+        }
+        state.attempts = attempts;
+        searched
+    }
+}
+
+fn bus_masters(w1_devices: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut masters = Vec::new();
+    for entry in fs::read_dir(w1_devices)? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with("w1_bus_master")
+        {
+            masters.push(entry.path());
+        }
+    }
+    masters.sort();
+    Ok(masters)
+}
+
+fn read_number(path: &Path) -> std::io::Result<i64> {
+    let contents = fs::read_to_string(path)?;
+    contents
+        .trim()
+        .parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Wakes up the search of `master`. Writing sets how many searches are left, -1 for searching
+/// continuously, so the count it has is written back, unless it stopped searching.
+fn trigger_search(master: &Path) -> std::io::Result<()> {
+    let path = master.join("w1_master_search");
+    let count = match read_number(&path)? {
+        0 => 1,
+        count => count,
+    };
+    fs::write(path, count.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::ReaderHealth;
+
+    #[test]
+    fn silence_and_probe_test() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let health = ReaderHealth::new(
+            Some(serde_yaml_ng::from_str("silence: 10m\nprobe: 1m").unwrap()),
+            false,
+            start,
+        );
+        assert_eq!(health.check(at(599)), None);
+        assert_eq!(health.activity(at(500)), None);
+        assert_eq!(health.check(at(1099)), None);
+        assert_eq!(health.check(at(1100)), Some(Duration::from_secs(600)));
+        assert_eq!(health.check(at(1200)), None);
+        assert!(health.is_silent());
+        assert_eq!(health.activity(at(1300)), Some(Duration::from_secs(800)));
+        assert!(!health.is_silent());
+
+        // A master that keeps searching is alive; the first probe only learns its count.
+        let dir = crate::testutil::test_dir("reader_health");
+        let master = dir.join("w1_bus_master1");
+        std::fs::create_dir_all(&master).unwrap();
+        std::fs::create_dir_all(dir.join("33-00000392c6ea")).unwrap();
+        let attempts = |count: u64| {
+            std::fs::write(master.join("w1_master_attempts"), format!("{count}\n")).unwrap()
+        };
+        attempts(7);
+        std::fs::write(master.join("w1_master_search"), "0\n").unwrap();
+        assert!(!health.probe(&dir, at(0)));
+        let search = std::fs::read_to_string(master.join("w1_master_search")).unwrap();
+        assert_eq!(search, "1");
+        attempts(8);
+        assert!(!health.probe(&dir, at(59)));
+        assert!(health.probe(&dir, at(60)));
+        assert!(!health.probe(&dir, at(120)));
+
+        // Continuous searching is left as it is.
+        std::fs::write(master.join("w1_master_search"), "-1\n").unwrap();
+        health.probe(&dir, at(180));
+        let search = std::fs::read_to_string(master.join("w1_master_search")).unwrap();
+        assert_eq!(search, "-1");
+
+        let off = ReaderHealth::default();
+        assert_eq!(off.check(at(100_000)), None);
+        assert!(!off.probe(&dir, at(0)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,2210 @@
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    hash::{DefaultHasher, Hasher},
+    io::{self, BufReader, Read},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+use reqwest::{header, StatusCode};
+
+use crate::{
+    access::family_allowed,
+    audit::AuditLog,
+    backoff,
+    clock::Clock,
+    config, control,
+    enroll::Enroller,
+    events::EventLog,
+    hooks::Hooks,
+    keylist,
+    last_seen::LastSeen,
+    metrics,
+    mqtt::Mqtt,
+    persistence, privacy,
+    sdnotify::{Liveness, Notifier},
+    staleness::{Level, Staleness},
+    store::{self, KeyStore},
+    usage, wakeup, Credential, Key,
+};
+
+/// Local rules for which keys from MOS make it into the access list.
+pub struct KeyFilter {
+    pub deny_keys: HashSet<Credential>,
+    pub allowed_family_codes: HashSet<u8>,
+    /// The groups readers admit, see [`config::Config::known_groups`]; keys in others are
+    /// kept but reported.
+    pub known_groups: HashSet<String>,
+}
+
+/// MOS settings and filter of a reloaded configuration, handed to the refresh thread.
+pub struct Reload {
+    pub thing: config::Thing,
+    pub filter: KeyFilter,
+}
+
+/// What we know about the last list we applied, to skip work when it didn't change.
+#[derive(Default)]
+struct Validators {
+    etag: Option<header::HeaderValue>,
+    last_modified: Option<header::HeaderValue>,
+    body_hash: Option<u64>,
+    /// Modification time of a list read from a file.
+    mtime: Option<SystemTime>,
+}
+
+/// A list that was fetched or read, before it is parsed.
+struct Fetched {
+    body: Body,
+    json: bool,
+    validators: Validators,
+    /// The list's `X-List-Generation`, if its source sends one.
+    generation: Option<u64>,
+    /// The list's `X-Signature`, if its source sends one.
+    signature: Option<Vec<u8>>,
+}
+
+/// The body of a [`Fetched`] list.
+enum Body {
+    /// Read whole, for JSON and to check a signature.
+    Text(String),
+    /// Left to be parsed while it is read, limited to one byte past `thing.max_response_bytes`,
+    /// so a large list is never held in memory.
+    Stream(Box<dyn Read>),
+}
+
+/// Hashes and counts what is read through it, so a streamed list can be told apart from the
+/// last one like a list read whole.
+struct Hashing<R> {
+    inner: R,
+    hasher: DefaultHasher,
+    len: u64,
+}
+
+impl<R> Hashing<R> {
+    fn new(inner: R) -> Hashing<R> {
+        Hashing {
+            inner,
+            hasher: DefaultHasher::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.write(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+}
+
+/// A parsed list as far as it differs from the access list, so a refresh holds on to the ids
+/// and to the new and changed keys, not to a second copy of every key.
+#[derive(Default)]
+struct Delta {
+    /// Keys the list had, including those [`KeyFilter`] drops.
+    valid: usize,
+    /// Keys that stay in or join the access list.
+    listed: HashSet<Credential>,
+    /// Those of `listed` that are new or changed.
+    changes: HashMap<Credential, Key>,
+    /// Groups of listed keys no reader admits, with how many keys each has. Without any known
+    /// groups no reader restricts them, so none is counted.
+    unknown_groups: BTreeMap<String, usize>,
+}
+
+impl Delta {
+    /// Counts `listed` in unless `filter` drops it, copying it only if `access_list` doesn't
+    /// have it as it is.
+    fn add(
+        &mut self,
+        listed: &keylist::Listed,
+        access_list: &DashMap<Credential, Key>,
+        filter: &KeyFilter,
+    ) {
+        self.valid += 1;
+        if !admitted(&listed.id, &listed.name, filter) {
+            return;
+        }
+        if let Some(group) = &listed.group {
+            let known = &filter.known_groups;
+            if !known.is_empty() && !known.contains(group.as_ref()) {
+                *self.unknown_groups.entry(group.to_string()).or_default() += 1;
+            }
+        }
+        self.listed.insert(listed.id);
+        if access_list
+            .get(&listed.id)
+            .is_some_and(|key| listed.matches(&key))
+        {
+            return;
+        }
+        self.changes.insert(listed.id, listed.to_key());
+    }
+}
+
+/// How long the stages of one fetch took. Stages not reached stay `None`.
+#[derive(Default)]
+struct Timing {
+    /// Of the response; a file source has none.
+    status: Option<StatusCode>,
+    /// Until the response headers arrived.
+    response: Option<Duration>,
+    /// Until the body was read as well.
+    fetch: Option<Duration>,
+    /// Of a streamed body, reading it counts as parsing.
+    parse: Option<Duration>,
+    /// Applying the list and persisting it.
+    apply: Option<Duration>,
+}
+
+/// Response header numbering the lists a source serves, so a lagging mirror can be told apart.
+const GENERATION_HEADER: &str = "X-List-Generation";
+
+/// Response header with the list's ed25519 signature; without it, `<url>.sig` is fetched.
+const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// A source's client and what we know about the last list it served.
+struct Mirror {
+    token_source: config::TokenSource,
+    token_modified: Option<SystemTime>,
+    client: reqwest::blocking::Client,
+    validators: Validators,
+}
+
+/// Result of a successful fetch cycle.
+enum Outcome {
+    NotModified,
+    Identical,
+    /// `unsaved` is why the applied list couldn't be persisted, if it couldn't.
+    Updated {
+        added: usize,
+        removed: usize,
+        unsaved: Option<anyhow::Error>,
+    },
+}
+
+/// Why a fetch failed, as the remedies differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// MOS couldn't be reached, or the connection broke.
+    Network,
+    /// MOS answered with an error status.
+    Http,
+    /// The response had no valid key in it.
+    NoValidKeys,
+    /// Anything else, such as a list the safety limits rejected.
+    Other,
+}
+
+impl FailureKind {
+    pub const ALL: [FailureKind; 4] = [
+        FailureKind::Network,
+        FailureKind::Http,
+        FailureKind::NoValidKeys,
+        FailureKind::Other,
+    ];
+
+    fn of(error: &anyhow::Error) -> FailureKind {
+        if error.downcast_ref::<HttpStatus>().is_some() {
+            FailureKind::Http
+        } else if error.downcast_ref::<NoValidKeys>().is_some() {
+            FailureKind::NoValidKeys
+        } else if error
+            .chain()
+            .any(|cause| cause.is::<reqwest::Error>() || cause.is::<std::io::Error>())
+        {
+            FailureKind::Network
+        } else {
+            FailureKind::Other
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::Network => "network",
+            FailureKind::Http => "http",
+            FailureKind::NoValidKeys => "no_valid_keys",
+            FailureKind::Other => "other",
+        })
+    }
+}
+
+/// An error status MOS answered with.
+#[derive(Debug)]
+struct HttpStatus(StatusCode);
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP {}", self.0)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+#[derive(Debug)]
+struct NoValidKeys;
+
+impl fmt::Display for NoValidKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key list has no valid keys")
+    }
+}
+
+impl std::error::Error for NoValidKeys {}
+
+/// A list the safety limits refused, see [`check_shrink`].
+#[derive(Debug)]
+struct SafetyLimit(String);
+
+impl fmt::Display for SafetyLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for SafetyLimit {}
+
+/// Fetches that failed in a row.
+struct Outage {
+    failures: u32,
+    since: SystemTime,
+    /// Whether the outage was reported, so its end is too.
+    reported: bool,
+}
+
+/// What one refresh cycle hands to the next.
+struct Cycle {
+    /// One per `thing.url` source, in the same order.
+    mirrors: Vec<Mirror>,
+    backoff: backoff::Backoff,
+    errors: backoff::ErrorThrottle,
+    /// Lift the safety limits for the next fetch.
+    force: bool,
+    push_failures: HashMap<Credential, u32>,
+    level: Level,
+    outage: Option<Outage>,
+    /// Generation of the list applied last, which fallback sources must not go back from.
+    generation: Option<u64>,
+    /// Lists have to be signed with this key, from `thing.pubkey_file`.
+    pubkey: Option<[u8; 32]>,
+    /// How long the source of the last list asked us to wait before fetching again.
+    server_interval: Option<Duration>,
+}
+
+/// Keeps `access_list` in sync with MOS until shutdown.
+pub struct Refresher {
+    pub thing: config::Thing,
+    pub persistence: config::Persistence,
+    pub store: Arc<dyn KeyStore>,
+    pub filter: KeyFilter,
+    pub access_list: Arc<DashMap<Credential, Key>>,
+    pub last_seen: Arc<LastSeen>,
+    pub metrics: Arc<metrics::Metrics>,
+    pub wakeup: Arc<wakeup::Wakeup>,
+    pub hooks: Arc<Hooks>,
+    pub mqtt: Arc<Mqtt>,
+    pub audit: Arc<AuditLog>,
+    pub enroller: Arc<Enroller>,
+    pub staleness: Staleness,
+    /// Staleness isn't tracked before it is trusted.
+    pub clock: Arc<Clock>,
+    pub events: Arc<EventLog>,
+    pub notifier: Arc<Notifier>,
+    pub liveness: Arc<Liveness>,
+    /// Fetch and apply the list, but persist nothing and push no enrollments.
+    pub dry_run: bool,
+    /// Taken before the next cycle.
+    pub reload: Arc<Mutex<Option<Reload>>>,
+}
+
+impl Refresher {
+    /// Starts the refresh thread. It supervises the cycles, retrying until they can start and
+    /// restarting them after a panic with the validators, outage and backoff they built up.
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut backoff = backoff::Backoff::new(&self.thing.backoff);
+            let mut errors = backoff::ErrorThrottle::default();
+            let mut cycle = None;
+            loop {
+                self.liveness.busy();
+                let mut current = match cycle.take() {
+                    Some(current) => current,
+                    None => match self.start() {
+                        Ok(started) => started,
+                        Err(e) => {
+                            errors.error(format!("MOS refresh thread error: {e:?}"));
+                            self.liveness.idle();
+                            if self.wakeup.wait(backoff.next_delay()) == wakeup::Wake::Shutdown {
+                                break;
+                            }
+                            continue;
+                        }
+                    },
+                };
+                match panic::catch_unwind(AssertUnwindSafe(|| self.run(&mut current))) {
+                    Ok(()) => {
+                        log::info!("MOS refresh thread stopped");
+                        break;
+                    }
+                    Err(payload) => {
+                        let delay = backoff.next_delay();
+                        log::error!(
+                            "MOS refresh cycle panicked: {}, restarting it in {}",
+                            panic_message(payload.as_ref()),
+                            humantime::format_duration(Duration::from_secs(delay.as_secs()))
+                        );
+                        self.metrics.refresh_panics.inc();
+                        cycle = Some(current);
+                        self.liveness.idle();
+                        if self.wakeup.wait(delay) == wakeup::Wake::Shutdown {
+                            break;
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Runs refresh cycles until shutdown.
+    fn run(&mut self, cycle: &mut Cycle) {
+        loop {
+            let reload = self.reload.lock().unwrap().take();
+            if let Some(reload) = reload {
+                self.reload(reload, cycle);
+            }
+            let delay = self.run_cycle(cycle);
+            self.liveness.idle();
+            match self.wakeup.wait(delay) {
+                wakeup::Wake::Shutdown => return,
+                wakeup::Wake::Refresh => log::info!("Key list refresh triggered by signal"),
+                wakeup::Wake::ForcedRefresh => {
+                    log::warn!("Forced key list refresh, safety limits are lifted for this fetch");
+                    cycle.force = true;
+                }
+                wakeup::Wake::Timeout => log::info!("Key list refresh triggered by timer"),
+            }
+        }
+    }
+
+    /// Switches to reloaded settings. The cycle starts afresh as its client and validators belong
+    /// to the old ones; if the new token can't be read, the old MOS settings stay.
+    fn reload(&mut self, reload: Reload, cycle: &mut Cycle) {
+        self.filter = reload.filter;
+        self.staleness = Staleness::new(&reload.thing);
+        let thing = std::mem::replace(&mut self.thing, reload.thing);
+        match self.start() {
+            Ok(started) => {
+                *cycle = Cycle {
+                    push_failures: std::mem::take(&mut cycle.push_failures),
+                    level: cycle.level,
+                    outage: cycle.outage.take(),
+                    generation: cycle.generation,
+                    ..started
+                };
+            }
+            Err(e) => {
+                log::error!("Keeping the previous MOS settings: {e:?}");
+                self.thing = thing;
+            }
+        }
+    }
+
+    fn start(&self) -> anyhow::Result<Cycle> {
+        let config = &self.thing;
+        let mirrors = config
+            .url
+            .iter()
+            .map(|source| {
+                let token_source = config.token_source(source)?;
+                Ok(Mirror {
+                    token_modified: token_source.modified(),
+                    client: build_client(config, &token_source.resolve()?)?,
+                    token_source,
+                    validators: Validators::default(),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Cycle {
+            mirrors,
+            backoff: backoff::Backoff::new(&config.backoff),
+            errors: backoff::ErrorThrottle::default(),
+            force: false,
+            push_failures: HashMap::new(),
+            level: Level::Fresh,
+            outage: None,
+            generation: None,
+            pubkey: config.pubkey()?,
+            server_interval: None,
+        })
+    }
+
+    /// Fetches, applies and persists the key list once, returning how long to wait until the
+    /// next cycle.
+    fn run_cycle(&self, cycle: &mut Cycle) -> Duration {
+        let config = &self.thing;
+        let persistence = &self.persistence;
+        self.liveness.busy();
+        for mirror in &mut cycle.mirrors {
+            let modified = mirror.token_source.modified();
+            if modified != mirror.token_modified {
+                match mirror
+                    .token_source
+                    .resolve()
+                    .and_then(|token| build_client(config, &token))
+                {
+                    Ok(new_client) => {
+                        log::info!("Token file changed, using the new token");
+                        mirror.client = new_client;
+                        mirror.token_modified = modified;
+                    }
+                    Err(e) => {
+                        cycle.errors.error(format!("Failed to reload token: {e:?}"));
+                    }
+                }
+            }
+        }
+
+        if let Some(url) = &config.enroll_url {
+            if self.dry_run {
+                log::info!("[dry-run] Not pushing pending enrollments to MOS");
+            } else {
+                let client = &cycle.mirrors[0].client;
+                self.push_enrollments(client, url, &mut cycle.push_failures);
+            }
+        }
+
+        let force = std::mem::take(&mut cycle.force);
+        let delay = match self.fetch_any(cycle, force) {
+            Ok(outcome) => {
+                match outcome {
+                    Outcome::NotModified => {
+                        log::debug!("Key list not modified, keeping current list")
+                    }
+                    Outcome::Identical => {
+                        log::debug!("Key list identical to the last one, keeping current list")
+                    }
+                    Outcome::Updated { .. } => {
+                        log::info!(
+                            event = "refresh",
+                            keys = self.access_list.len();
+                            "Key list updated"
+                        );
+                        self.hooks.refreshed(self.access_list.len());
+                        self.events.refreshed(self.access_list.len());
+                    }
+                }
+                self.metrics.refreshed(self.access_list.len());
+                self.mqtt.key_count(self.access_list.len());
+                self.notifier.status(&format!(
+                    "{} keys, last refresh {}",
+                    self.access_list.len(),
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S")
+                ));
+                if self.dry_run {
+                    log::info!("[dry-run] Not persisting the time of the last fetch");
+                } else if let Err(e) =
+                    persistence::save_fetch_time(SystemTime::now(), persistence.fetched_path())
+                {
+                    log::error!("Failed to persist the time of the last fetch: {e:?}");
+                }
+                cycle.backoff.reset();
+                cycle.errors.reset();
+                if let Some(outage) = cycle.outage.take() {
+                    self.recovered(&outage);
+                }
+                // MOS may stretch the interval, though not beyond max_server_interval.
+                let interval = match cycle.server_interval.take() {
+                    Some(wanted) => config.refresh.max(wanted.min(config.max_server_interval)),
+                    None => config.refresh,
+                };
+                backoff::jitter(interval, config.refresh_jitter)
+            }
+            Err(e) => {
+                let kind = FailureKind::of(&e);
+                self.metrics.fetch_failed(kind);
+                self.events.refresh_failed(&e);
+                cycle.errors.error(format!("{e:?}"));
+                let outage = cycle.outage.get_or_insert_with(|| Outage {
+                    failures: 0,
+                    since: SystemTime::now(),
+                    reported: false,
+                });
+                outage.failures += 1;
+                self.metrics
+                    .consecutive_fetch_failures
+                    .set(outage.failures.into());
+                if !outage.reported && outage.failures >= config.alert_after_failures {
+                    outage.reported = true;
+                    log::error!(
+                        "Key list fetch failed {} times in a row ({kind}): {e:#}",
+                        outage.failures
+                    );
+                    self.hooks.refresh_failing(outage.failures, kind);
+                    self.mqtt.refresh_failing(outage.failures, kind);
+                }
+                cycle.backoff.next_delay()
+            }
+        };
+        self.metrics.next_refresh.set(
+            (SystemTime::now() + delay)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+
+        let age = self.metrics.list_age(SystemTime::now());
+        // Ages measured by an unset clock mean nothing; the level stays as it was.
+        let current = match self.clock.is_trusted() {
+            true => self.staleness.level(age),
+            false => cycle.level,
+        };
+        if current != cycle.level {
+            let age = humantime::format_duration(age.unwrap_or_default());
+            match current {
+                Level::Fresh => log::info!("Key list is up to date again"),
+                Level::Stale => log::warn!("Key list was last fetched {age} ago"),
+                Level::Restricted => log::error!(
+                    "Key list was last fetched {age} ago, only master keys are honoured"
+                ),
+            }
+            cycle.level = current;
+        }
+
+        self.last_seen
+            .expire(&self.access_list, persistence.last_seen_retention);
+        if self.dry_run {
+            log::info!("[dry-run] Not persisting last-seen timestamps");
+        } else if let Err(e) = self.last_seen.save(&persistence.last_seen_path()) {
+            log::error!("Failed to persist last-seen timestamps: {e:?}");
+        }
+        if persistence.usage_stats {
+            let retention =
+                chrono::Days::new(persistence.usage_retention.as_secs() / (24 * 60 * 60));
+            let before = chrono::Local::now().date_naive() - retention;
+            if let Err(e) = self.store.prune_usage(&usage::day(before)) {
+                log::error!("Failed to prune usage counts: {e:?}");
+            }
+        }
+        delay
+    }
+
+    fn recovered(&self, outage: &Outage) {
+        if !outage.reported {
+            return;
+        }
+        let lasted = outage.since.elapsed().unwrap_or_default();
+        log::warn!(
+            "Key list fetched again after {} failures in {}",
+            outage.failures,
+            humantime::format_duration(Duration::from_secs(lasted.as_secs()))
+        );
+        self.hooks.refresh_recovered(lasted);
+        self.mqtt.refresh_recovered(outage.failures, lasted);
+    }
+
+    /// Pushes pending enrollments to MOS, keeping failed ones for the next cycle until they
+    /// exceed `enroll_max_retries`.
+    fn push_enrollments(
+        &self,
+        client: &reqwest::blocking::Client,
+        url: &str,
+        failures: &mut HashMap<Credential, u32>,
+    ) {
+        let pending = match self.enroller.pending() {
+            Ok(pending) => pending,
+            Err(e) => {
+                log::error!("Failed to read pending enrollments: {e:?}");
+                return;
+            }
+        };
+        let max_retries = self.thing.enroll_max_retries;
+        let mut pushed = HashSet::new();
+        for (id, name) in pending {
+            if failures
+                .get(&id)
+                .is_some_and(|&failed| failed >= max_retries)
+            {
+                continue;
+            }
+            let body = serde_json::json!({ "id": id.to_string(), "name": name });
+            let result = client
+                .post(url)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+                .send();
+            match result {
+                Ok(resp) if resp.status().is_success() || resp.status() == StatusCode::CONFLICT => {
+                    log::info!("Pushed enrolled key {} to MOS", privacy::id(&id));
+                    // Usable right away; the next fetch confirms or drops it.
+                    self.access_list.insert(id, Key::named(name));
+                    failures.remove(&id);
+                    pushed.insert(id);
+                }
+                result => {
+                    let reason = match result {
+                        Ok(resp) => format!("HTTP {}", resp.status()),
+                        Err(e) => format!("{e:?}"),
+                    };
+                    let failed = failures.entry(id).or_default();
+                    *failed += 1;
+                    if *failed >= max_retries {
+                        log::error!(
+                            "Giving up pushing enrolled key {} after {failed} attempts: {reason}",
+                            privacy::id(&id)
+                        );
+                    } else {
+                        log::warn!(
+                            "Failed to push enrolled key {}, retrying: {reason}",
+                            privacy::id(&id)
+                        );
+                    }
+                }
+            }
+        }
+        if !pushed.is_empty() {
+            if let Err(e) = self.enroller.remove_pending(&pushed) {
+                log::error!("Failed to update pending enrollments: {e:?}");
+            }
+        }
+    }
+
+    /// Tries the `thing.url` sources in order until one yields a list, and records which did.
+    fn fetch_any(&self, cycle: &mut Cycle, force: bool) -> anyhow::Result<Outcome> {
+        let sources = &self.thing.url;
+        cycle.server_interval = None;
+        for (index, source) in sources.iter().enumerate() {
+            match self.fetch(cycle, index, force) {
+                Ok(outcome) => {
+                    self.metrics.fetched_from(index, source.display());
+                    return Ok(outcome);
+                }
+                Err(e) if index + 1 < sources.len() => log::debug!(
+                    "Key list source {} failed, trying the next one: {e:#}",
+                    source.display()
+                ),
+                Err(e) if index > 0 => {
+                    return Err(e.context(format!("All {} key list sources failed", sources.len())))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        anyhow::bail!("No key list source configured")
+    }
+
+    /// Fetches the key list once from the `index`th source and applies it to `access_list` if it
+    /// changed, recording how long that took.
+    fn fetch(&self, cycle: &mut Cycle, index: usize, force: bool) -> anyhow::Result<Outcome> {
+        let started = Instant::now();
+        let mut timing = Timing::default();
+        let result = self.fetch_timed(cycle, index, force, &mut timing);
+        // A fetch that failed took until now.
+        let fetch = *timing.fetch.get_or_insert_with(|| started.elapsed());
+        let metrics = &self.metrics;
+        metrics.fetch_seconds.observe(fetch);
+        let stages = [
+            (timing.response, &metrics.fetch_response_seconds, "response"),
+            (timing.parse, &metrics.parse_seconds, "parsed"),
+            (timing.apply, &metrics.apply_seconds, "applied"),
+        ];
+        let mut summary = format!("fetched in {} ms", fetch.as_millis());
+        for (duration, histogram, stage) in stages {
+            if let Some(duration) = duration {
+                histogram.observe(duration);
+                summary.push_str(&format!(", {stage} in {} ms", duration.as_millis()));
+            }
+        }
+        if let Some(status) = timing.status {
+            summary.push_str(&format!(", HTTP {}", status.as_u16()));
+        }
+        let level = if fetch > self.thing.slow_fetch {
+            log::Level::Warn
+        } else {
+            log::Level::Debug
+        };
+        log::log!(
+            level,
+            "Key list from {} {summary}",
+            self.thing.url[index].display()
+        );
+        result
+    }
+
+    fn fetch_timed(
+        &self,
+        cycle: &mut Cycle,
+        index: usize,
+        force: bool,
+        timing: &mut Timing,
+    ) -> anyhow::Result<Outcome> {
+        let started = Instant::now();
+        let config = &self.thing;
+        let store = &*self.store;
+        let access_list = &*self.access_list;
+        let source = &config.url[index];
+        let mirror = &mut cycle.mirrors[index];
+        let validators = &mut mirror.validators;
+        // A signature covers the whole body, so signed lists are read before they're parsed.
+        let whole = cycle.pubkey.is_some();
+        let (fetched, server_interval) = match source.file_source() {
+            Some(path) => (read_file(&path, validators, config, whole)?, None),
+            None => self.get(source, &mirror.client, validators, whole, timing)?,
+        };
+        timing.fetch = Some(started.elapsed());
+        cycle.server_interval = server_interval;
+        let Some(Fetched {
+            body,
+            json,
+            validators: mut fetched,
+            generation,
+            signature,
+        }) = fetched
+        else {
+            purge_expired(access_list, store);
+            return Ok(Outcome::NotModified);
+        };
+        // Only the first source may go back to an older list, e.g. after restoring MOS from a
+        // backup; a mirror that lags behind it is skipped.
+        if let (true, Some(served), Some(applied)) = (index > 0, generation, cycle.generation) {
+            anyhow::ensure!(
+                served >= applied,
+                "Key list source {} serves generation {served}, older than the applied \
+                 {applied}, ignoring it",
+                source.display()
+            );
+        }
+
+        // Expired entries are left out here, which drops them from the access list below.
+        let now = SystemTime::now();
+        let mut delta = Delta::default();
+        let text = match body {
+            Body::Text(text) => {
+                if let Some(pubkey) = &cycle.pubkey {
+                    let signature = match signature {
+                        Some(signature) => signature,
+                        None => self.sibling_signature(source, &mirror.client)?,
+                    };
+                    check_signature(text.as_bytes(), &signature, pubkey)?;
+                }
+                fetched.body_hash = Some(hash(text.as_bytes()));
+                Some(text)
+            }
+            Body::Stream(stream) => {
+                anyhow::ensure!(!whole, "Signed key list wasn't read whole");
+                let parsing = Instant::now();
+                let mut reader = BufReader::new(Hashing::new(stream));
+                let issues = keylist::read_key_list(
+                    &mut reader,
+                    &config.csv,
+                    config.invalid_utf8,
+                    now,
+                    |listed| delta.add(&listed, access_list, &self.filter),
+                )?;
+                let hashing = reader.into_inner();
+                anyhow::ensure!(
+                    hashing.len <= config.max_response_bytes,
+                    "Key list response exceeds {} bytes, ignoring it",
+                    config.max_response_bytes
+                );
+                timing.parse = Some(parsing.elapsed());
+                for issue in issues {
+                    log::error!("Skipping key list {issue}");
+                }
+                fetched.body_hash = Some(hashing.hasher.finish());
+                None
+            }
+        };
+        if fetched.etag.is_none()
+            && fetched.last_modified.is_none()
+            && validators.body_hash == fetched.body_hash
+        {
+            *validators = fetched;
+            cycle.generation = generation.or(cycle.generation);
+            purge_expired(access_list, store);
+            return Ok(Outcome::Identical);
+        }
+        if let Some(text) = text {
+            let parsing = Instant::now();
+            if json {
+                let ids = keylist::parse_json(&text, now).context(NoValidKeys)?;
+                for (id, key) in &ids {
+                    delta.add(&keylist::Listed::of(*id, key), access_list, &self.filter);
+                }
+            } else {
+                let issues = keylist::read_key_list(
+                    text.as_bytes(),
+                    &config.csv,
+                    config.invalid_utf8,
+                    now,
+                    |listed| delta.add(&listed, access_list, &self.filter),
+                )?;
+                for issue in issues {
+                    log::error!("Skipping key list {issue}");
+                }
+            }
+            timing.parse = Some(parsing.elapsed());
+        }
+        if delta.valid == 0 && !force {
+            return Err(NoValidKeys.into());
+        }
+        if !delta.unknown_groups.is_empty() {
+            let unknown: Vec<_> = delta
+                .unknown_groups
+                .iter()
+                .map(|(group, count)| format!("{group:?} ({count} keys)"))
+                .collect();
+            log::warn!(
+                "Key list names groups no reader admits, those keys open no door restricted \
+                 to groups: {}",
+                unknown.join(", ")
+            );
+        }
+        if !force {
+            let removed = access_list
+                .iter()
+                .filter(|entry| !delta.listed.contains(entry.key()))
+                .count();
+            check_shrink(access_list.len(), delta.listed.len(), removed, config)?;
+        }
+        let len = delta.listed.len();
+        let applying = Instant::now();
+        let diff = keylist::apply_changes(access_list, &delta.listed, delta.changes);
+        log::debug!(
+            "List of IDs refreshed, we have {len} buttons now ({} new, {} removed, {} changed)",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed,
+        );
+        self.report(&diff);
+        let updated = !diff.is_empty();
+
+        let mut unsaved = None;
+        if updated {
+            if let Err(err) = store.save(access_list) {
+                log::error!("Failed to persist key list: {err:?}");
+                unsaved = Some(err);
+            }
+        }
+        timing.apply = Some(applying.elapsed());
+
+        *validators = fetched;
+        cycle.generation = generation.or(cycle.generation);
+        Ok(if updated {
+            Outcome::Updated {
+                added: diff.added.len(),
+                removed: diff.removed.len(),
+                unsaved,
+            }
+        } else {
+            Outcome::Identical
+        })
+    }
+
+    /// GETs the key list from `source`, or `None` if it answered 304 Not Modified, along with how
+    /// long it asks us to wait before the next fetch. The body is left to be streamed unless it is
+    /// JSON or needed `whole`.
+    fn get(
+        &self,
+        source: &config::ListSource,
+        client: &reqwest::blocking::Client,
+        validators: &Validators,
+        whole: bool,
+        timing: &mut Timing,
+    ) -> anyhow::Result<(Option<Fetched>, Option<Duration>)> {
+        let config = &self.thing;
+        let mut request = client.get(&source.url);
+        if let Some(etag) = &validators.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let sent = Instant::now();
+        let resp = self.send(request)?;
+        timing.response = Some(sent.elapsed());
+        timing.status = Some(resp.status());
+        let interval = server_interval(resp.headers(), SystemTime::now());
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok((None, interval));
+        }
+        if resp.status() == StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+            return Err(anyhow::Error::new(HttpStatus(resp.status())).context(PROXY_AUTH_FAILED));
+        }
+        if matches!(
+            resp.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) {
+            return Err(anyhow::Error::new(HttpStatus(resp.status()))
+                .context("MOS token rejected, check the configured token"));
+        }
+        if !resp.status().is_success() {
+            return Err(
+                anyhow::Error::new(HttpStatus(resp.status())).context("Failed fetching key list")
+            );
+        }
+
+        let json = match config.format {
+            config::ListFormat::Auto => resp
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with("application/json")),
+            config::ListFormat::Csv => false,
+            config::ListFormat::Json => true,
+        };
+        let etag = resp.headers().get(header::ETAG).cloned();
+        let last_modified = resp.headers().get(header::LAST_MODIFIED).cloned();
+        let generation = resp
+            .headers()
+            .get(GENERATION_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok());
+        let signature = resp
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .map(|value| value.as_bytes().to_vec());
+        let fetched = Fetched {
+            body: read_body(resp, config, json || whole)?,
+            json,
+            validators: Validators {
+                etag,
+                last_modified,
+                ..Default::default()
+            },
+            generation,
+            signature,
+        };
+        Ok((Some(fetched), interval))
+    }
+
+    /// Fetches the signature published next to the list at `source`, `<url>.sig`.
+    fn sibling_signature(
+        &self,
+        source: &config::ListSource,
+        client: &reqwest::blocking::Client,
+    ) -> anyhow::Result<Vec<u8>> {
+        if let Some(path) = source.file_source() {
+            let mut path = path.into_os_string();
+            path.push(".sig");
+            return std::fs::read(&path)
+                .context(format!("Failed to read key list signature {path:?}"));
+        }
+        let mut url = reqwest::Url::parse(&source.url)?;
+        url.set_path(&format!("{}.sig", url.path()));
+        let resp = self.send(client.get(url))?;
+        if !resp.status().is_success() {
+            return Err(anyhow::Error::new(HttpStatus(resp.status()))
+                .context("Failed fetching key list signature"));
+        }
+        let mut signature = Vec::new();
+        resp.take(MAX_SIGNATURE_BYTES)
+            .read_to_end(&mut signature)
+            .context("Failed reading key list signature")?;
+        Ok(signature)
+    }
+
+    /// Names the keys a refresh added and removed in the log, the audit log and on MQTT.
+    fn report(&self, diff: &keylist::Diff) {
+        if diff.added.is_empty() && diff.removed.is_empty() {
+            return;
+        }
+        let max = self.thing.diff_max_keys;
+        let names = |keys: &[(Credential, String)]| {
+            let mut names: Vec<_> = keys
+                .iter()
+                .take(max)
+                .map(|(id, name)| match name.as_str() {
+                    "" => privacy::id(id),
+                    name => name.to_owned(),
+                })
+                .collect();
+            if keys.len() > max {
+                names.push(format!("{} more", keys.len() - max));
+            }
+            names.join(", ")
+        };
+        if !diff.added.is_empty() {
+            log::info!(
+                event = "refresh",
+                added = diff.added.len();
+                "Keys added: {}",
+                names(&diff.added)
+            );
+        }
+        if !diff.removed.is_empty() {
+            log::info!(
+                event = "refresh",
+                removed = diff.removed.len();
+                "Keys removed: {}",
+                names(&diff.removed)
+            );
+        }
+        self.audit.refresh(diff);
+        self.mqtt.key_diff(diff, max);
+    }
+
+    /// Sends `request`, retrying server errors, connection failures and 429s as configured in
+    /// `thing.retry`. Other responses, including errors, are returned as they are.
+    fn send(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> anyhow::Result<reqwest::blocking::Response> {
+        let retry = &self.thing.retry;
+        let mut delay = retry.initial;
+        let mut attempt = 0;
+        loop {
+            let result = request
+                .try_clone()
+                .context("Request can't be retried")?
+                .send();
+            let (reason, retry_after) = match &result {
+                Ok(resp) if resp.status() == StatusCode::TOO_MANY_REQUESTS => (
+                    format!("HTTP {}", resp.status()),
+                    retry_after(resp.headers(), SystemTime::now()),
+                ),
+                Ok(resp) if resp.status().is_server_error() => {
+                    (format!("HTTP {}", resp.status()), None)
+                }
+                Ok(_) => return Ok(result?),
+                Err(e) if proxy_auth_failed(e) => return result.context(PROXY_AUTH_FAILED),
+                Err(e) if e.is_connect() || e.is_timeout() => (format!("{e}"), None),
+                Err(_) => return result.context("Failed fetching key list"),
+            };
+            let wait = retry_after.unwrap_or(delay);
+            attempt += 1;
+            if attempt > retry.attempts || wait > retry.max_retry_after {
+                return result.context("Failed fetching key list");
+            }
+            log::debug!(
+                "Fetching key list failed ({reason}), retry {attempt}/{} in {wait:?}",
+                retry.attempts
+            );
+            if !self.wakeup.sleep(wait) {
+                return result.context("Failed fetching key list");
+            }
+            delay = (delay * 2).min(retry.max);
+        }
+    }
+}
+
+const PROXY_AUTH_FAILED: &str = "Proxy refused to forward the request to MOS (407), check the \
+                                 credentials in thing.proxy";
+
+/// Runs a single refresh cycle for `cellardoor refresh`, for deployments that schedule fetches
+/// with a timer, and prints what it did. Returns the exit status: 0 once the list was applied or
+/// found unchanged, 1 if it couldn't be fetched and 2 if the safety limits refused it; a list
+/// that couldn't be persisted is an error. While the daemon runs, it is asked over the control
+/// socket to refresh instead, and 0 only means it agreed to; otherwise its lock is taken like
+/// `export` and `import` do. A dry run never asks the daemon, and needs no lock as it writes
+/// nothing.
+pub fn once(config: &config::Config, force: bool, dry_run: bool) -> anyhow::Result<i32> {
+    let command = if force { "REFRESH FORCE" } else { "REFRESH" };
+    let asked = match dry_run {
+        true => Err(io::ErrorKind::NotFound.into()),
+        false => control::request(&config.control.path, command),
+    };
+    match asked {
+        Ok(Ok(_)) => {
+            println!("Asked the running cellardoor to refresh the key list");
+            return Ok(0);
+        }
+        Ok(Err(reason)) => {
+            println!("The running cellardoor refused to refresh: {reason}");
+            return Ok(1);
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) => {}
+        Err(e) => {
+            return Err(e).context(format!("Failed to talk to {:?}", config.control.path));
+        }
+    }
+    let _lock = (!dry_run)
+        .then(|| persistence::Lock::acquire(config.persistence.lock_path()))
+        .transpose()?;
+    let mut store = store::open(&config.persistence)?;
+    if dry_run {
+        store = Arc::new(store::DryRun(store));
+    }
+    let access_list = store.load().unwrap_or_else(|e| {
+        log::error!("Failed to deserialize persisted key list, using empty list: {e:?}");
+        DashMap::new()
+    });
+    let clock = Arc::new(Clock::new(config.clock.clone(), SystemTime::now()));
+    // Hooks, webhooks and MQTT are left to the daemon; a timer has the exit status.
+    let refresher = Refresher {
+        thing: config.thing.clone(),
+        persistence: config.persistence.clone(),
+        store,
+        filter: KeyFilter {
+            deny_keys: config.deny_keys.clone(),
+            allowed_family_codes: config.allowed_family_codes.clone(),
+            known_groups: config.known_groups(),
+        },
+        access_list: Arc::new(access_list),
+        last_seen: Default::default(),
+        metrics: Default::default(),
+        wakeup: Default::default(),
+        hooks: Default::default(),
+        mqtt: Default::default(),
+        audit: Arc::new(AuditLog::new(
+            config.audit.as_ref(),
+            dry_run,
+            clock.clone(),
+        )?),
+        enroller: Arc::new(Enroller::new(config.persistence.enrollment_path(), None)),
+        staleness: Staleness::new(&config.thing),
+        clock,
+        events: Default::default(),
+        notifier: Default::default(),
+        liveness: Default::default(),
+        dry_run,
+        reload: Default::default(),
+    };
+    let mut cycle = refresher.start()?;
+    if let Some(url) = &config.thing.enroll_url {
+        if !dry_run {
+            let client = &cycle.mirrors[0].client;
+            refresher.push_enrollments(client, url, &mut cycle.push_failures);
+        }
+    }
+    let (added, removed) = match refresher.fetch_any(&mut cycle, force) {
+        Ok(Outcome::Updated {
+            unsaved: Some(e), ..
+        }) => return Err(e.context("Failed to persist the refreshed key list")),
+        Ok(Outcome::Updated { added, removed, .. }) => (added, removed),
+        Ok(Outcome::NotModified | Outcome::Identical) => (0, 0),
+        Err(e) => {
+            println!("Key list refresh failed: {e:#}");
+            return Ok(match e.downcast_ref::<SafetyLimit>() {
+                Some(_) => 2,
+                None => 1,
+            });
+        }
+    };
+    if !dry_run {
+        persistence::save_fetch_time(SystemTime::now(), config.persistence.fetched_path())
+            .context("Failed to persist the time of the last fetch")?;
+    }
+    println!(
+        "Key list refreshed: {added} added, {removed} removed, {} keys",
+        refresher.access_list.len()
+    );
+    Ok(0)
+}
+
+/// Whether a proxy refused our credentials when asked to tunnel to an HTTPS URL; plain HTTP
+/// requests get a 407 response instead.
+fn proxy_auth_failed(error: &reqwest::Error) -> bool {
+    std::iter::successors(Some(error as &dyn std::error::Error), |cause| {
+        cause.source()
+    })
+    .any(|cause| cause.to_string() == "proxy authentication required")
+}
+
+/// The message a panic was raised with, if it was a string.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("(no message)", String::as_str),
+    }
+}
+
+/// How long a response asks us to wait before fetching again, by `Cache-Control: max-age` or
+/// `Retry-After`, whichever is longer.
+fn server_interval(headers: &header::HeaderMap, now: SystemTime) -> Option<Duration> {
+    let max_age = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|directive| {
+            let (name, value) = directive.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("max-age")
+                .then(|| value.trim().trim_matches('"').parse().ok())?
+        })
+        .map(Duration::from_secs);
+    max_age.max(retry_after(headers, now))
+}
+
+/// The delay a `Retry-After` header asks for, given in seconds or as an HTTP date.
+fn retry_after(headers: &header::HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        SystemTime::from(date)
+            .duration_since(now)
+            .unwrap_or_default(),
+    )
+}
+
+/// Drops keys that expired since the list was fetched, for when the list itself didn't change.
+fn purge_expired(access_list: &DashMap<Credential, Key>, store: &dyn KeyStore) {
+    let now = SystemTime::now();
+    let old_len = access_list.len();
+    access_list.retain(|_, key| !key.is_expired(now));
+    let removed = old_len - access_list.len();
+    if removed > 0 {
+        log::debug!(
+            "Removed {removed} expired keys, we have {} buttons now",
+            access_list.len()
+        );
+        if let Err(err) = store.save(access_list) {
+            log::error!("Failed to persist key list: {err:?}");
+        }
+    }
+}
+
+/// Whether `id` makes it into the access list, that is it isn't blocked locally nor of a
+/// foreign device family, so neither ends up in the access list or on disk.
+fn admitted(id: &Credential, name: &str, filter: &KeyFilter) -> bool {
+    if filter.deny_keys.contains(id) {
+        log::info!(
+            "Ignoring blocked key {name:?} ({}) from MOS",
+            privacy::id(id)
+        );
+        false
+    } else if !family_allowed(&filter.allowed_family_codes, id) {
+        log::warn!(
+            "Ignoring key {name:?} ({}) from MOS, its family code is not allowed",
+            privacy::id(id)
+        );
+        false
+    } else {
+        true
+    }
+}
+
+/// Rejects lists that would wipe out a suspicious share of the access list in one go, e.g. an
+/// empty response from a misdeployed MOS.
+fn check_shrink(
+    old_len: usize,
+    new_len: usize,
+    removed: usize,
+    config: &config::Thing,
+) -> anyhow::Result<()> {
+    if new_len < config.min_keys && new_len < old_len {
+        return Err(SafetyLimit(format!(
+            "Refusing key list with only {new_len} keys (min_keys is {}), keeping the current \
+             {old_len}; apply it with `cellardoorctl refresh --force` if this is intended",
+            config.min_keys
+        ))
+        .into());
+    }
+    if old_len > 0 && removed as f64 / old_len as f64 > config.max_removal_fraction {
+        return Err(SafetyLimit(format!(
+            "Refusing key list that removes {removed} of {old_len} keys (max_removal_fraction is \
+             {}), keeping the current list; apply it with `cellardoorctl refresh --force` if \
+             this is intended",
+            config.max_removal_fraction
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Reads the key list from `path`, or returns `None` if its modification time is the one of the
+/// last list read, e.g. because rsync didn't bring a new one. Like a response, the file is
+/// streamed unless it is JSON or needed `whole`.
+fn read_file(
+    path: &Path,
+    validators: &Validators,
+    config: &config::Thing,
+    whole: bool,
+) -> anyhow::Result<Option<Fetched>> {
+    let metadata =
+        std::fs::metadata(path).context(format!("Failed to read key list file {path:?}"))?;
+    let mtime = metadata.modified().ok();
+    if mtime.is_some() && mtime == validators.mtime {
+        return Ok(None);
+    }
+    let max_bytes = config.max_response_bytes;
+    anyhow::ensure!(
+        metadata.len() <= max_bytes,
+        "Key list file {path:?} of {} bytes exceeds {max_bytes} bytes, ignoring it",
+        metadata.len()
+    );
+    let file =
+        std::fs::File::open(path).context(format!("Failed to read key list file {path:?}"))?;
+    let json = match config.format {
+        config::ListFormat::Auto => path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json")),
+        config::ListFormat::Csv => false,
+        config::ListFormat::Json => true,
+    };
+    // The file may have grown since its size was checked.
+    let file = file.take(max_bytes + 1);
+    let body = match json || whole {
+        true => Body::Text(read_whole(file, config)?),
+        false => Body::Stream(Box::new(file)),
+    };
+    Ok(Some(Fetched {
+        body,
+        json,
+        validators: Validators {
+            mtime,
+            ..Default::default()
+        },
+        generation: None,
+        signature: None,
+    }))
+}
+
+/// More than a hex signature with some whitespace is not read.
+const MAX_SIGNATURE_BYTES: u64 = 1024;
+
+/// Checks `body` against its ed25519 `signature`, given as 64 bytes or 128 hex digits.
+fn check_signature(body: &[u8], signature: &[u8], pubkey: &[u8; 32]) -> anyhow::Result<()> {
+    let signature = match signature.len() {
+        64 => Some(signature.to_vec()),
+        _ => {
+            let hex = std::str::from_utf8(signature).unwrap_or_default().trim();
+            (hex.len() == 128 && hex.bytes().all(|b| b.is_ascii_hexdigit())).then(|| {
+                (0..64)
+                    .map(|idx| u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).unwrap())
+                    .collect()
+            })
+        }
+    };
+    let key = ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, pubkey);
+    if signature.is_none_or(|signature| key.verify(body, &signature).is_err()) {
+        let digest = ring::digest::digest(&ring::digest::SHA256, body);
+        let digest: String = digest.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+        anyhow::bail!(
+            "Key list signature doesn't verify against thing.pubkey_file (sha256 {digest}), \
+             keeping the current list"
+        );
+    }
+    Ok(())
+}
+
+fn hash(body: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(body);
+    hasher.finish()
+}
+
+/// The response body, read `whole` or left to be streamed; either gives up once it exceeds
+/// `thing.max_response_bytes`.
+fn read_body(
+    resp: reqwest::blocking::Response,
+    config: &config::Thing,
+    whole: bool,
+) -> anyhow::Result<Body> {
+    let max_bytes = config.max_response_bytes;
+    if let Some(len) = resp.content_length() {
+        anyhow::ensure!(
+            len <= max_bytes,
+            "Key list response of {len} bytes exceeds {max_bytes} bytes, ignoring it"
+        );
+    }
+    let body = resp.take(max_bytes + 1);
+    Ok(match whole {
+        true => Body::Text(read_whole(body, config)?),
+        false => Body::Stream(Box::new(body)),
+    })
+}
+
+/// Reads `body`, limited to one byte more than `thing.max_response_bytes`, into text.
+fn read_whole(mut body: impl Read, config: &config::Thing) -> anyhow::Result<String> {
+    let max_bytes = config.max_response_bytes;
+    let mut bytes = Vec::new();
+    body.read_to_end(&mut bytes)
+        .context("Failed reading key list body")?;
+    anyhow::ensure!(
+        bytes.len() as u64 <= max_bytes,
+        "Key list response exceeds {max_bytes} bytes, ignoring it"
+    );
+    decode_body(bytes, config.invalid_utf8)
+}
+
+/// Turns the response body into text as `thing.invalid_utf8` says.
+fn decode_body(body: Vec<u8>, invalid_utf8: config::InvalidUtf8) -> anyhow::Result<String> {
+    match String::from_utf8(body) {
+        Ok(body) => Ok(body),
+        Err(e) if invalid_utf8 == config::InvalidUtf8::Lossy => {
+            log::warn!(
+                "Key list response is not valid UTF-8 ({}), decoding it lossily",
+                e.utf8_error()
+            );
+            Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
+        }
+        Err(e) => Err(e).context("Key list response is not valid UTF-8"),
+    }
+}
+
+fn build_client(config: &config::Thing, token: &str) -> anyhow::Result<reqwest::blocking::Client> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if !token.is_empty() {
+        let (name, value) = config.auth.header(token)?;
+        headers.insert(name, value);
+    }
+    if config.danger_accept_invalid_certs {
+        log::warn!("thing.danger_accept_invalid_certs is set, MOS's certificate is not checked");
+    }
+    let mut builder = reqwest::blocking::Client::builder()
+        .default_headers(headers)
+        .connect_timeout(config.connect_timeout)
+        .timeout(config.request_timeout);
+    if config.no_proxy {
+        builder = builder.no_proxy();
+    }
+    if let Some(proxy) = config.http_proxy().context("thing.proxy")? {
+        builder = builder.proxy(proxy);
+    }
+    Ok(config.tls()?.apply(builder).build()?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        io::{Read, Write},
+        net::TcpListener,
+        path::Path,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use dashmap::DashMap;
+
+    use super::{check_signature, decode_body, Delta, FailureKind, KeyFilter, Outcome, Refresher};
+    use crate::{
+        audit::AuditLog, config, keylist, store::FileStore, testutil::test_dir, Credential, Key,
+    };
+
+    const ALICE: Credential = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+    const BOB: Credential = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+    const CAROL: Credential = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]);
+    const DAVE: Credential = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x44]);
+
+    /// A refresher fetching as the `thing` config says into a list persisted at `path`, with
+    /// everything else left at its default.
+    fn refresher(thing: &str, path: &Path) -> Refresher {
+        Refresher {
+            thing: serde_yaml_ng::from_str(thing).unwrap(),
+            persistence: serde_yaml_ng::from_str(&format!("path: {path:?}")).unwrap(),
+            store: Arc::new(FileStore {
+                path: path.to_owned(),
+                backups: 0,
+                ..Default::default()
+            }),
+            filter: KeyFilter {
+                deny_keys: HashSet::new(),
+                allowed_family_codes: HashSet::new(),
+                known_groups: HashSet::new(),
+            },
+            access_list: Default::default(),
+            last_seen: Default::default(),
+            metrics: Default::default(),
+            wakeup: Default::default(),
+            hooks: Default::default(),
+            mqtt: Default::default(),
+            audit: Arc::new(AuditLog::new(None, false, Default::default()).unwrap()),
+            enroller: Default::default(),
+            staleness: Default::default(),
+            clock: Default::default(),
+            events: Default::default(),
+            notifier: Default::default(),
+            liveness: Default::default(),
+            dry_run: false,
+            reload: Default::default(),
+        }
+    }
+
+    /// Stands in for MOS, answering one connection per response in turn, and returns its URL.
+    fn serve(responses: Vec<(u16, &'static str)>) -> String {
+        serve_with_headers(
+            responses
+                .into_iter()
+                .map(|(status, body)| (status, "", body))
+                .collect(),
+        )
+    }
+
+    /// Like [`serve`], with extra header lines for each response.
+    fn serve_with_headers(responses: Vec<(u16, &'static str, &'static str)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/keys", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (status, headers, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let len = stream.read(&mut buf).unwrap();
+                    assert!(len > 0, "Request ended early");
+                    request.extend_from_slice(&buf[..len]);
+                }
+                assert!(String::from_utf8_lossy(&request).contains("x-token: test\r\n"));
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Mock\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n\
+                     {body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    /// The expected key list file: `records` sorted by id, none expiring, scheduled, grouped or
+    /// with a PIN, then `crc`.
+    fn key_file(records: &[(Credential, &str)], crc: u32) -> Vec<u8> {
+        let mut data = b"CDKL\x07".to_vec();
+        data.extend_from_slice(&(records.len() as u32).to_le_bytes());
+        for (id, name) in records {
+            let id = id.bytes();
+            data.push(id.len() as u8);
+            data.extend_from_slice(&id);
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&[0; 14]);
+        }
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    fn names(list: &DashMap<Credential, Key>) -> Vec<(Credential, String)> {
+        let mut names: Vec<_> = list
+            .iter()
+            .map(|entry| (*entry.key(), entry.name.clone()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn refresh_cycles_test() {
+        let dir = test_dir("refresh");
+        let path = dir.join("keys.bin");
+        let url = serve(vec![
+            (200, "33-00000392c6ea,Alice\n01-000000000042,Bob\n"),
+            (
+                200,
+                "33-00000392c6ea,Alice\n01-000000000042,Bob\n01-000000000043,Carol\n",
+            ),
+            (200, "33-00000392c6ea,Alice\n01-000000000043,Carol\n"),
+            (500, "Internal Server Error"),
+            (
+                200,
+                "33-00000392c6ea,Alice\nnot-an-id,Mallory\n01-000000000044,Dave\n",
+            ),
+        ]);
+        let refresher = refresher(
+            &format!(
+                "url: {url}\ntoken: test\nrefresh_secs: 60\nmin_keys: 0\n\
+                 max_removal_fraction: 1.0\nretry:\n  attempts: 0\n"
+            ),
+            &path,
+        );
+        let mut cycle = refresher.start().unwrap();
+        let mut run = || {
+            refresher.run_cycle(&mut cycle);
+            (names(&refresher.access_list), std::fs::read(&path).unwrap())
+        };
+        let keys = |ids: &[(Credential, &str)]| {
+            ids.iter()
+                .map(|(id, name)| (*id, name.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let initial = [(BOB, "Bob"), (ALICE, "Alice")];
+        assert_eq!(run(), (keys(&initial), key_file(&initial, 0x0576_78d7)));
+
+        let added = [(BOB, "Bob"), (CAROL, "Carol"), (ALICE, "Alice")];
+        assert_eq!(run(), (keys(&added), key_file(&added, 0xcc51_35df)));
+
+        let removed = [(CAROL, "Carol"), (ALICE, "Alice")];
+        let removed_file = key_file(&removed, 0x672a_d2cc);
+        assert_eq!(run(), (keys(&removed), removed_file.clone()));
+
+        // A server error leaves the list and the file alone.
+        assert_eq!(run(), (keys(&removed), removed_file));
+        assert_eq!(refresher.metrics.fetch_failure.get(), 1);
+        // The failed fetch is timed too, but never got to parsing.
+        assert_eq!(refresher.metrics.fetch_seconds.count(), 4);
+        assert_eq!(refresher.metrics.parse_seconds.count(), 3);
+
+        // Broken lines are skipped, the rest still applies.
+        let partial = [(DAVE, "Dave"), (ALICE, "Alice")];
+        assert_eq!(run(), (keys(&partial), key_file(&partial, 0x32ad_8beb)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_source_test() {
+        let dir = test_dir("refresh_file");
+        let list = dir.join("keys.csv");
+        let path = dir.join("keys.bin");
+        let write = |contents: &str, mtime: SystemTime| {
+            std::fs::write(&list, contents).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&list)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        };
+        let refresher = refresher(
+            &format!(
+                "url: file://{}\nrefresh_secs: 60\nmin_keys: 0\nmax_removal_fraction: 1.0\n",
+                list.display()
+            ),
+            &path,
+        );
+        let mut cycle = refresher.start().unwrap();
+        let mut fetch = || refresher.fetch(&mut cycle, 0, false);
+        let synced = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        write("33-00000392c6ea,Alice\n", synced);
+        assert!(matches!(fetch(), Ok(Outcome::Updated { .. })));
+        assert_eq!(names(&refresher.access_list), [(ALICE, "Alice".to_owned())]);
+
+        // An unchanged mtime skips the file, even if its contents changed.
+        write("01-000000000042,Bob\n", synced);
+        assert!(matches!(fetch(), Ok(Outcome::NotModified)));
+        assert_eq!(names(&refresher.access_list), [(ALICE, "Alice".to_owned())]);
+
+        // A new mtime with the same contents is read but changes nothing.
+        write("33-00000392c6ea,Alice\n", synced + Duration::from_secs(60));
+        assert!(matches!(fetch(), Ok(Outcome::Identical)));
+        assert!(matches!(fetch(), Ok(Outcome::NotModified)));
+
+        write("01-000000000042,Bob\n", synced + Duration::from_secs(120));
+        assert!(matches!(fetch(), Ok(Outcome::Updated { .. })));
+        assert_eq!(names(&refresher.access_list), [(BOB, "Bob".to_owned())]);
+
+        // A missing file fails the fetch like an unreachable MOS, keeping the list.
+        std::fs::remove_file(&list).unwrap();
+        let error = fetch().err().unwrap();
+        assert_eq!(FailureKind::of(&error), FailureKind::Network);
+        assert_eq!(names(&refresher.access_list), [(BOB, "Bob".to_owned())]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mirror_fallback_test() {
+        let dir = test_dir("refresh_mirror");
+        let path = dir.join("keys.bin");
+        let primary = serve_with_headers(vec![
+            (200, "X-List-Generation: 5\r\n", "33-00000392c6ea,Alice\n"),
+            (500, "", "Internal Server Error"),
+            (500, "", "Internal Server Error"),
+        ]);
+        let mirror = serve_with_headers(vec![
+            (200, "X-List-Generation: 4\r\n", "01-000000000042,Bob\n"),
+            (200, "X-List-Generation: 6\r\n", "01-000000000043,Carol\n"),
+        ]);
+        let refresher = refresher(
+            &format!(
+                "url:\n  - {primary}\n  - url: {mirror}\n    token: test\ntoken: test\n\
+                 refresh_secs: 60\nmin_keys: 0\nmax_removal_fraction: 1.0\nretry:\n  attempts: 0\n"
+            ),
+            &path,
+        );
+        let mut cycle = refresher.start().unwrap();
+        let source = || refresher.metrics.list_source.lock().unwrap().clone();
+
+        assert!(matches!(
+            refresher.fetch_any(&mut cycle, false),
+            Ok(Outcome::Updated { .. })
+        ));
+        assert_eq!(names(&refresher.access_list), [(ALICE, "Alice".to_owned())]);
+        assert_eq!(source(), Some((0, primary)));
+
+        // The mirror lags behind the list applied from the primary, so it is skipped.
+        let error = refresher.fetch_any(&mut cycle, false).err().unwrap();
+        assert!(format!("{error:#}").contains("serves generation 4, older than the applied 5"));
+        assert_eq!(names(&refresher.access_list), [(ALICE, "Alice".to_owned())]);
+
+        assert!(matches!(
+            refresher.fetch_any(&mut cycle, false),
+            Ok(Outcome::Updated { .. })
+        ));
+        assert_eq!(names(&refresher.access_list), [(CAROL, "Carol".to_owned())]);
+        assert_eq!(source(), Some((1, mirror)));
+        assert_eq!(cycle.generation, Some(6));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Public key of the ed25519 seed 00 01 .. 1f.
+    const PUBKEY: &str = "03a107bff3ce10be1d70dd18e74bc09967e4d6309ba50d5f1ddc8664125531b8";
+    const SIGNED_LIST: &str = "33-00000392c6ea,Alice\n01-000000000042,Bob\n";
+    /// Signature of `SIGNED_LIST` under `PUBKEY`.
+    const SIGNATURE: &str = "2887e3bcabd2dd7c456288c4d4011b5df65a5d1f0c77beb581b7a8eadafd5371\
+                             f8e152fd5e6a01dc68b56da081df0be1aeb4d048ed3df2ca89f03fb8fcee7306";
+    /// `SIGNATURE` with its first byte changed.
+    const BAD_SIGNATURE: &str = "2987e3bcabd2dd7c456288c4d4011b5df65a5d1f0c77beb581b7a8eadafd5371\
+                                 f8e152fd5e6a01dc68b56da081df0be1aeb4d048ed3df2ca89f03fb8fcee7306";
+
+    #[test]
+    fn check_signature_test() {
+        let mut pubkey = [0; 32];
+        for (idx, byte) in pubkey.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&PUBKEY[idx * 2..idx * 2 + 2], 16).unwrap();
+        }
+        let body = SIGNED_LIST.as_bytes();
+        check_signature(body, SIGNATURE.as_bytes(), &pubkey).unwrap();
+        check_signature(body, format!("{SIGNATURE}\n").as_bytes(), &pubkey).unwrap();
+        let error = check_signature(body, BAD_SIGNATURE.as_bytes(), &pubkey).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("(sha256 8ef4d43da443a2b04afac1389f5fc17650639d94c374892d67943855386fd565)"));
+        assert!(
+            check_signature(b"01-000000000043,Mallory\n", SIGNATURE.as_bytes(), &pubkey).is_err()
+        );
+        assert!(check_signature(body, b"not a signature", &pubkey).is_err());
+    }
+
+    #[test]
+    fn signed_list_test() {
+        let dir = test_dir("refresh_signed");
+        let list = dir.join("keys.csv");
+        let path = dir.join("keys.bin");
+        std::fs::write(dir.join("pubkey"), format!("{PUBKEY}\n")).unwrap();
+        std::fs::write(&list, SIGNED_LIST).unwrap();
+        std::fs::write(dir.join("keys.csv.sig"), SIGNATURE).unwrap();
+        let url = serve_with_headers(vec![(
+            200,
+            format!("X-Signature: {BAD_SIGNATURE}\r\n").leak(),
+            "01-000000000043,Mallory\n",
+        )]);
+        let refresher = refresher(
+            &format!(
+                "url: file://{}\nrefresh_secs: 60\npubkey_file: {}\n",
+                list.display(),
+                dir.join("pubkey").display()
+            ),
+            &path,
+        );
+        let signed = [(BOB, "Bob".to_owned()), (ALICE, "Alice".to_owned())];
+        let mut cycle = refresher.start().unwrap();
+        assert!(matches!(
+            refresher.fetch(&mut cycle, 0, false),
+            Ok(Outcome::Updated { .. })
+        ));
+        assert_eq!(names(&refresher.access_list), signed);
+        let persisted = std::fs::read(&path).unwrap();
+
+        // A list changed after signing leaves the access list and the file alone.
+        std::fs::write(&list, "01-000000000043,Mallory\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&list)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let error = refresher.fetch(&mut cycle, 0, false).err().unwrap();
+        assert!(error.to_string().contains("doesn't verify"));
+        assert_eq!(names(&refresher.access_list), signed);
+        assert_eq!(std::fs::read(&path).unwrap(), persisted);
+
+        // So does one whose X-Signature doesn't match.
+        let refresher = Refresher {
+            thing: serde_yaml_ng::from_str(&format!(
+                "url: {url}\ntoken: test\nrefresh_secs: 60\npubkey_file: {}\n\
+                 retry:\n  attempts: 0\n",
+                dir.join("pubkey").display()
+            ))
+            .unwrap(),
+            ..refresher
+        };
+        let mut cycle = refresher.start().unwrap();
+        assert!(refresher.fetch(&mut cycle, 0, false).is_err());
+        assert_eq!(names(&refresher.access_list), signed);
+        assert_eq!(std::fs::read(&path).unwrap(), persisted);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn proxy_test() {
+        let dir = test_dir("refresh_proxy");
+        let path = dir.join("keys.bin");
+        let proxy = serve(vec![
+            (200, "33-00000392c6ea,Alice\n"),
+            (407, "Proxy Authentication Required"),
+        ]);
+        let refresher = refresher(
+            &format!(
+                "url: http://mos.invalid/keys\ntoken: test\nrefresh_secs: 60\n\
+                 proxy:\n  url: {}\n  username: door\n  password: s3cret\n\
+                 retry:\n  attempts: 0\n",
+                proxy.trim_end_matches("/keys")
+            ),
+            &path,
+        );
+        let mut cycle = refresher.start().unwrap();
+        assert!(matches!(
+            refresher.fetch(&mut cycle, 0, false),
+            Ok(Outcome::Updated { .. })
+        ));
+        assert_eq!(names(&refresher.access_list), [(ALICE, "Alice".to_owned())]);
+
+        // A proxy refusing us is told apart from MOS doing so.
+        let error = refresher.fetch(&mut cycle, 0, false).err().unwrap();
+        assert!(error
+            .to_string()
+            .contains("check the credentials in thing.proxy"));
+        assert_eq!(FailureKind::of(&error), FailureKind::Http);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn outage_is_reported_once_test() {
+        let dir = test_dir("outage");
+        let path = dir.join("keys.bin");
+        let url = serve(vec![
+            (500, "Internal Server Error"),
+            (200, "not-an-id,Mallory\n"),
+            (503, "Service Unavailable"),
+            (200, "33-00000392c6ea,Alice\n"),
+        ]);
+        let refresher = refresher(
+            &format!(
+                "url: {url}\ntoken: test\nrefresh_secs: 60\nmin_keys: 0\n\
+                 alert_after_failures: 2\nretry:\n  attempts: 0\n"
+            ),
+            &path,
+        );
+        let metrics = &refresher.metrics;
+        let kind = |kind: FailureKind| metrics.fetch_failure_kinds[kind as usize].get();
+        let mut cycle = refresher.start().unwrap();
+
+        refresher.run_cycle(&mut cycle);
+        assert!(!cycle.outage.as_ref().unwrap().reported);
+        refresher.run_cycle(&mut cycle);
+        assert!(cycle.outage.as_ref().unwrap().reported);
+        refresher.run_cycle(&mut cycle);
+        assert_eq!(metrics.consecutive_fetch_failures.get(), 3);
+        assert_eq!(kind(FailureKind::Http), 2);
+        assert_eq!(kind(FailureKind::NoValidKeys), 1);
+
+        refresher.run_cycle(&mut cycle);
+        assert!(cycle.outage.is_none());
+        assert_eq!(metrics.consecutive_fetch_failures.get(), 0);
+
+        // MOS is gone now.
+        refresher.run_cycle(&mut cycle);
+        assert_eq!(kind(FailureKind::Network), 1);
+        assert_eq!(metrics.fetch_failure.get(), 4);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_utf8_body_test() {
+        let body = b"33-00000392c6ea,Alice\n01-000000000042,B\xffb\xfe\n".to_vec();
+        assert!(decode_body(body.clone(), config::InvalidUtf8::Reject).is_err());
+
+        let body = decode_body(body, config::InvalidUtf8::Lossy).unwrap();
+        let (ids, issues) = keylist::parse_key_list(&body, &Default::default(), SystemTime::now());
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids[&ALICE].name, "Alice");
+        assert_eq!(ids[&BOB].name, "B\u{fffd}b\u{fffd}");
+        assert!(issues.is_empty());
+    }
+
+    /// A refresh of 100k keys where a few changed keeps only those keys besides the listed ids,
+    /// where collecting the whole list first holds every one of them.
+    #[test]
+    fn large_list_delta_test() {
+        const KEYS: u64 = 100_000;
+        let id = |n: u64| {
+            let mut id = [0x01; 7];
+            id[1..].copy_from_slice(&n.to_be_bytes()[2..]);
+            Credential::OneWire(id)
+        };
+        let mut body = String::from("id,name\n");
+        for n in 0..KEYS {
+            body.push_str(&format!("{},Member {n},,,member\n", id(n)));
+        }
+        // Ten renamed, three new and five gone.
+        let access_list = DashMap::new();
+        for n in (0..KEYS - 3).chain(KEYS..KEYS + 5) {
+            let name = if n < 10 {
+                "Old name".to_owned()
+            } else {
+                format!("Member {n}")
+            };
+            let key = Key {
+                group: Some("member".to_owned()),
+                ..Key::named(&name)
+            };
+            access_list.insert(id(n), key);
+        }
+        let filter = KeyFilter {
+            deny_keys: HashSet::new(),
+            allowed_family_codes: HashSet::new(),
+            known_groups: HashSet::new(),
+        };
+        let csv = config::Csv::default();
+        let now = SystemTime::now();
+
+        let whole_list = access_list.clone();
+        let (ids, issues) = keylist::parse_key_list(&body, &csv, now);
+        assert!(issues.is_empty());
+        assert_eq!(ids.len(), KEYS as usize);
+        let whole_diff = keylist::apply_key_list(&whole_list, ids);
+
+        let mut delta = Delta::default();
+        let mut peak = 0;
+        let issues = keylist::read_key_list(
+            std::io::BufReader::new(body.as_bytes()),
+            &csv,
+            config::InvalidUtf8::Reject,
+            now,
+            |listed| {
+                delta.add(&listed, &access_list, &filter);
+                peak = peak.max(delta.changes.len());
+            },
+        )
+        .unwrap();
+        assert!(issues.is_empty());
+        assert_eq!(delta.listed.len(), KEYS as usize);
+        // Never more keys held than changed.
+        assert_eq!(peak, 13);
+        let diff = keylist::apply_changes(&access_list, &delta.listed, delta.changes);
+
+        assert_eq!(diff, whole_diff);
+        assert_eq!(
+            (diff.added.len(), diff.removed.len(), diff.changed),
+            (3, 5, 10)
+        );
+        assert_eq!(access_list.len(), KEYS as usize);
+        assert_eq!(access_list.get(&id(0)).unwrap().name, "Member 0");
+    }
+
+    /// The delta of `ids` against an empty access list.
+    fn delta(ids: &HashMap<Credential, Key>, filter: &KeyFilter) -> Delta {
+        let access_list = DashMap::new();
+        let mut delta = Delta::default();
+        for (id, key) in ids {
+            delta.add(&keylist::Listed::of(*id, key), &access_list, filter);
+        }
+        delta
+    }
+
+    #[test]
+    fn strip_denied_test() {
+        let blocked = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let allowed = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xeb]);
+        let ids = HashMap::from([
+            (blocked, Key::named("Mallory")),
+            (allowed, Key::named("Alice")),
+        ]);
+
+        let delta = delta(
+            &ids,
+            &super::KeyFilter {
+                deny_keys: HashSet::from([blocked]),
+                allowed_family_codes: HashSet::new(),
+                known_groups: HashSet::new(),
+            },
+        );
+
+        assert_eq!(
+            delta.changes,
+            HashMap::from([(allowed, Key::named("Alice"))])
+        );
+        assert_eq!(delta.valid, 2);
+    }
+
+    #[test]
+    fn shrink_limits_test() {
+        let thing: crate::config::Thing = serde_yaml_ng::from_str(
+            "url: http://localhost\nrefresh_secs: 60\nmin_keys: 5\nmax_removal_fraction: 0.5",
+        )
+        .unwrap();
+
+        // Removing exactly half is still fine, one more is not.
+        assert!(super::check_shrink(10, 5, 5, &thing).is_ok());
+        assert!(super::check_shrink(10, 6, 6, &thing).is_err());
+        // Just above and just below the floor.
+        assert!(super::check_shrink(6, 5, 1, &thing).is_ok());
+        assert!(super::check_shrink(6, 4, 2, &thing).is_err());
+        // A small list that only grows isn't held back by the floor.
+        assert!(super::check_shrink(2, 3, 0, &thing).is_ok());
+        assert!(super::check_shrink(0, 0, 0, &thing).is_ok());
+    }
+
+    #[test]
+    fn strip_foreign_families_test() {
+        let key = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let sensor = Credential::OneWire([0x28, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let ids = HashMap::from([(key, Key::named("Alice")), (sensor, Key::named("DS18B20"))]);
+
+        let delta = delta(
+            &ids,
+            &super::KeyFilter {
+                deny_keys: HashSet::new(),
+                allowed_family_codes: HashSet::from([0x01, 0x33]),
+                known_groups: HashSet::new(),
+            },
+        );
+
+        assert_eq!(delta.changes, HashMap::from([(key, Key::named("Alice"))]));
+        assert_eq!(delta.listed, HashSet::from([key]));
+    }
+
+    #[test]
+    fn unknown_groups_test() {
+        let in_group = |name, group: &str| Key {
+            group: Some(group.to_owned()),
+            ..Key::named(name)
+        };
+        let ids = HashMap::from([
+            (
+                Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x41]),
+                in_group("Alice", "keyholder"),
+            ),
+            (
+                Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]),
+                in_group("Bob", "member"),
+            ),
+            (
+                Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]),
+                in_group("Carol", "member"),
+            ),
+            (
+                Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x44]),
+                Key::named("Dave"),
+            ),
+        ]);
+
+        let unknown_groups = |known: &[&str]| {
+            let filter = super::KeyFilter {
+                deny_keys: HashSet::new(),
+                allowed_family_codes: HashSet::new(),
+                known_groups: known.iter().map(|group| group.to_string()).collect(),
+            };
+            delta(&ids, &filter).unknown_groups
+        };
+        assert_eq!(
+            unknown_groups(&["keyholder", "board"])
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [("member".to_owned(), 2)]
+        );
+        // No reader restricts groups, so none is unknown.
+        assert!(unknown_groups(&[]).is_empty());
+    }
+
+    #[test]
+    fn retry_after_test() {
+        use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+        use std::time::{Duration, SystemTime};
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1445412480);
+        let mut headers = HeaderMap::new();
+        assert_eq!(super::retry_after(&headers, now), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(
+            super::retry_after(&headers, now),
+            Some(Duration::from_secs(120))
+        );
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:29:00 GMT"),
+        );
+        assert_eq!(
+            super::retry_after(&headers, now),
+            Some(Duration::from_secs(60))
+        );
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(super::retry_after(&headers, now), None);
+    }
+
+    #[test]
+    fn panic_message_test() {
+        let payload = std::panic::catch_unwind(|| panic!("index out of bounds")).unwrap_err();
+        assert_eq!(
+            super::panic_message(payload.as_ref()),
+            "index out of bounds"
+        );
+        let id = 7;
+        let payload = std::panic::catch_unwind(|| panic!("bad key {id}")).unwrap_err();
+        assert_eq!(super::panic_message(payload.as_ref()), "bad key 7");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(42)).unwrap_err();
+        assert_eq!(super::panic_message(payload.as_ref()), "(no message)");
+    }
+
+    #[test]
+    fn server_interval_test() {
+        use reqwest::header::{HeaderMap, HeaderValue, CACHE_CONTROL, RETRY_AFTER};
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1445412480);
+        let mut headers = HeaderMap::new();
+        assert_eq!(super::server_interval(&headers, now), None);
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("private, Max-Age=300"),
+        );
+        assert_eq!(
+            super::server_interval(&headers, now),
+            Some(Duration::from_secs(300))
+        );
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("600"));
+        assert_eq!(
+            super::server_interval(&headers, now),
+            Some(Duration::from_secs(600))
+        );
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        headers.remove(RETRY_AFTER);
+        assert_eq!(super::server_interval(&headers, now), None);
+    }
+
+    #[test]
+    fn server_interval_is_capped_test() {
+        let dir = test_dir("refresh_interval");
+        let path = dir.join("keys.bin");
+        let url = serve_with_headers(vec![
+            (200, "", "33-00000392c6ea,Alice\n"),
+            (
+                200,
+                "Cache-Control: max-age=120\r\n",
+                "33-00000392c6ea,Alice\n",
+            ),
+            (
+                200,
+                "Cache-Control: max-age=86400\r\n",
+                "33-00000392c6ea,Alice\n",
+            ),
+        ]);
+        let refresher = refresher(
+            &format!(
+                "url: {url}\ntoken: test\nrefresh: 60\nrefresh_jitter: 0\n\
+                 max_server_interval: 5m\n"
+            ),
+            &path,
+        );
+        let mut cycle = refresher.start().unwrap();
+        let delays: Vec<_> = (0..3).map(|_| refresher.run_cycle(&mut cycle)).collect();
+        assert_eq!(delays, [60, 120, 300].map(Duration::from_secs));
+        let next =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(refresher.metrics.next_refresh.get());
+        assert!(next > SystemTime::now() + Duration::from_secs(290));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    os::unix::net::{SocketAddr, UnixDatagram},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Reports readiness, liveness and status to systemd for `Type=notify` units.
+///
+/// Everything is a no-op unless systemd passed `NOTIFY_SOCKET`; the watchdog is only petted when
+/// it also passed `WATCHDOG_USEC`.
+#[derive(Default)]
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// Half of `WatchdogSec`, as systemd recommends.
+    interval: Option<Duration>,
+    /// When the watchdog is next due; `None` until the first pet.
+    due: Mutex<Option<Instant>>,
+}
+
+/// Since when the refresh thread has been working on a cycle, so a wedged fetch stops petting
+/// the watchdog while sleeping between cycles doesn't.
+#[derive(Default)]
+pub struct Liveness(Mutex<Option<Instant>>);
+
+impl Liveness {
+    pub fn busy(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn idle(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    /// Whether the thread is idle or has been busy for no longer than `limit`.
+    fn alive(&self, now: Instant, limit: Duration) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .is_none_or(|busy| now.saturating_duration_since(busy) <= limit)
+    }
+}
+
+impl Notifier {
+    pub fn from_env() -> Notifier {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Notifier::default();
+        };
+        let watchdog_pid = std::env::var("WATCHDOG_PID").ok();
+        let interval = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| watchdog_pid.is_none_or(|pid| pid == std::process::id().to_string()))
+            .map(|usec| Duration::from_micros(usec) / 2);
+        match connect(path.as_encoded_bytes()) {
+            Ok(socket) => Notifier {
+                socket: Some(socket),
+                interval,
+                due: Mutex::default(),
+            },
+            Err(e) => {
+                log::error!("Failed to open NOTIFY_SOCKET {path:?}: {e:?}");
+                Notifier::default()
+            }
+        }
+    }
+
+    /// The service finished starting up.
+    pub fn ready(&self, status: &str) {
+        self.send(&format!("READY=1\nSTATUS={status}"));
+    }
+
+    /// The service is shutting down.
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// Replaces the one-line status shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={status}"));
+    }
+
+    /// Pets the watchdog if it is due, unless the refresh thread has been stuck for longer
+    /// than `stall_limit`.
+    pub fn watchdog(&self, now: Instant, refresh: &Liveness, stall_limit: Option<Duration>) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let mut due = self.due.lock().unwrap();
+        if due.is_some_and(|due| now < due) {
+            return;
+        }
+        *due = Some(now + interval);
+        if let Some(limit) = stall_limit {
+            if !refresh.alive(now, limit) {
+                log::error!("MOS refresh thread stalled for over {limit:?}, not petting watchdog");
+                return;
+            }
+        }
+        self.send("WATCHDOG=1");
+    }
+
+    /// Time until the watchdog is due next, for use as the event loop's timeout.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.interval?;
+        let due = self.due.lock().unwrap();
+        Some(due.map_or(Duration::ZERO, |due| due.saturating_duration_since(now)))
+    }
+
+    fn send(&self, message: &str) {
+        let Some((socket, addr)) = &self.socket else {
+            return;
+        };
+        if let Err(e) = socket.send_to_addr(message.as_bytes(), addr) {
+            log::warn!("Failed to notify systemd: {e:?}");
+        }
+    }
+}
+
+/// Opens an unbound datagram socket for `path`, which starts with `@` for abstract sockets.
+fn connect(path: &[u8]) -> std::io::Result<(UnixDatagram, SocketAddr)> {
+    use std::os::{linux::net::SocketAddrExt, unix::ffi::OsStrExt};
+
+    let addr = match path.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(std::ffi::OsStr::from_bytes(path))?,
+    };
+    Ok((UnixDatagram::unbound()?, addr))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        os::unix::net::UnixDatagram,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use super::{connect, Liveness, Notifier};
+
+    fn received(socket: &UnixDatagram) -> Vec<String> {
+        let mut messages = Vec::new();
+        let mut buf = [0; 256];
+        while let Ok(len) = socket.recv(&mut buf) {
+            messages.push(String::from_utf8_lossy(&buf[..len]).into_owned());
+        }
+        messages
+    }
+
+    #[test]
+    fn watchdog_stops_for_a_stalled_refresh_test() {
+        let dir = crate::testutil::test_dir("sdnotify");
+        let path = dir.join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_nonblocking(true).unwrap();
+        let notifier = Notifier {
+            socket: Some(connect(path.as_os_str().as_encoded_bytes()).unwrap()),
+            interval: Some(Duration::from_secs(10)),
+            due: Mutex::default(),
+        };
+        let refresh = Liveness::default();
+        let limit = Some(Duration::from_secs(60));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        notifier.ready("3 keys");
+        assert_eq!(notifier.timeout(at(0)), Some(Duration::ZERO));
+        notifier.watchdog(at(0), &refresh, limit);
+        notifier.watchdog(at(5), &refresh, limit);
+        assert_eq!(notifier.timeout(at(5)), Some(Duration::from_secs(5)));
+        assert_eq!(received(&systemd), ["READY=1\nSTATUS=3 keys", "WATCHDOG=1"]);
+
+        refresh.busy();
+        notifier.watchdog(at(10), &refresh, limit);
+        notifier.watchdog(at(100), &refresh, limit);
+        assert_eq!(notifier.timeout(at(100)), Some(Duration::from_secs(10)));
+        refresh.idle();
+        notifier.watchdog(at(110), &refresh, limit);
+        assert_eq!(received(&systemd), ["WATCHDOG=1", "WATCHDOG=1"]);
+
+        // Without NOTIFY_SOCKET nothing is sent and the event loop is not woken.
+        let off = Notifier::default();
+        off.ready("3 keys");
+        off.watchdog(at(0), &refresh, limit);
+        assert_eq!(off.timeout(at(0)), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
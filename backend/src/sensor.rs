@@ -0,0 +1,245 @@
+use std::{
+    os::fd::AsRawFd,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use mio::{unix::SourceFd, Interest, Registry, Token};
+
+use crate::{access::Access, config, door::Door, gpio};
+
+/// Whether the door is open and since when the alarm timer runs.
+#[derive(Debug)]
+struct Monitor {
+    max_open: Duration,
+    /// Set while the door is open: when it opened, and when the timer started counting.
+    open: Option<(Instant, Instant)>,
+    alarm: bool,
+}
+
+/// A change worth reporting.
+#[derive(Debug, PartialEq, Eq)]
+enum Change {
+    Opened,
+    Closed { open_for: Duration, alarm: bool },
+    Alarm { open_for: Duration },
+}
+
+impl Monitor {
+    /// Takes the current state of the door; an opening during an unlock only starts the timer
+    /// once the unlock ends.
+    fn update(
+        &mut self,
+        now: Instant,
+        open: bool,
+        unlocked_until: Option<Instant>,
+    ) -> Option<Change> {
+        match (self.open, open) {
+            (None, true) => {
+                let counting = unlocked_until.map_or(now, |until| until.max(now));
+                self.open = Some((now, counting));
+                Some(Change::Opened)
+            }
+            (Some((since, _)), false) => {
+                self.open = None;
+                Some(Change::Closed {
+                    open_for: now.saturating_duration_since(since),
+                    alarm: std::mem::take(&mut self.alarm),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Raises the alarm once the door has been open for too long. While `held` unlocked the
+    /// door may stay open, the timer starts over.
+    fn check(&mut self, now: Instant, held: bool) -> Option<Change> {
+        let (since, counting) = self.open.as_mut()?;
+        if held {
+            *counting = now;
+        }
+        let (since, counting) = (*since, *counting);
+        if self.alarm || now.saturating_duration_since(counting) < self.max_open {
+            return None;
+        }
+        self.alarm = true;
+        Some(Change::Alarm {
+            open_for: now.saturating_duration_since(since),
+        })
+    }
+
+    fn timeout(&self, now: Instant) -> Option<Duration> {
+        match self.open {
+            Some((_, counting)) if !self.alarm => {
+                Some((counting + self.max_open).saturating_duration_since(now))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Watches the reed switch of `door` through edge events on its GPIO line, logging openings
+/// and raising an alarm through the hooks and MQTT when the door is left open.
+pub struct DoorSensor {
+    line: gpio::Line,
+    door: Door,
+    monitor: Monitor,
+}
+
+impl DoorSensor {
+    /// Requests the sensor line and registers it under `token`.
+    pub fn open(
+        config: &config::Sensor,
+        door: Door,
+        registry: &Registry,
+        token: Token,
+        access: &Access,
+    ) -> anyhow::Result<DoorSensor> {
+        let mut flags = 0;
+        if config.active_level == config::ActiveLevel::Low {
+            flags |= gpio::GPIO_V2_LINE_FLAG_ACTIVE_LOW;
+        }
+        let line = gpio::Line::request_edges(
+            &config.chip,
+            config.line,
+            flags,
+            config.debounce,
+            "cellardoor-sensor",
+        )
+        .context(format!(
+            "Failed to request sensor line {} on {:?}",
+            config.line, config.chip
+        ))?;
+        registry.register(&mut SourceFd(&line.as_raw_fd()), token, Interest::READABLE)?;
+        access.metrics.door_sensor.store(true, Ordering::Relaxed);
+        let mut sensor = DoorSensor {
+            line,
+            door,
+            monitor: Monitor {
+                max_open: config.max_open,
+                open: None,
+                alarm: false,
+            },
+        };
+        sensor.ready(access);
+        if sensor.monitor.open.is_none() {
+            log::info!("Door is closed");
+        }
+        Ok(sensor)
+    }
+
+    /// Reads the line after edge events.
+    pub fn ready(&mut self, access: &Access) {
+        if let Err(e) = self.line.drain_events() {
+            log::error!("Failed to read door sensor events: {e:?}");
+        }
+        match self.line.value() {
+            Ok(open) => {
+                let change = self
+                    .monitor
+                    .update(Instant::now(), open, self.door.unlocked_until());
+                self.report(change, access);
+            }
+            Err(e) => log::error!("Failed to read door sensor: {e:?}"),
+        }
+    }
+
+    /// Raises the alarm if it is due.
+    pub fn check(&mut self, access: &Access) {
+        let change = self.monitor.check(Instant::now(), self.door.is_held());
+        self.report(change, access);
+    }
+
+    /// Time until the alarm is due, for use as the event loop's timeout.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.monitor.timeout(now)
+    }
+
+    fn report(&self, change: Option<Change>, access: &Access) {
+        let Some(change) = change else {
+            return;
+        };
+        match change {
+            Change::Opened => log::info!("Door opened"),
+            Change::Closed { open_for, alarm } => {
+                log::info!("Door closed after {}", rounded(open_for));
+                if alarm {
+                    log::warn!("Door alarm cleared");
+                    access.hooks.door_alarm(false, open_for);
+                }
+            }
+            Change::Alarm { open_for } => {
+                log::warn!(
+                    "Door left open for {}, raising the alarm",
+                    rounded(open_for)
+                );
+                access.hooks.door_alarm(true, open_for);
+            }
+        }
+        let open = self.monitor.open.is_some();
+        access.metrics.door_open.set(open as u64);
+        access.metrics.door_alarm.set(self.monitor.alarm as u64);
+        access.mqtt.door(open, self.monitor.alarm);
+        access.events.door(open, self.monitor.alarm);
+    }
+}
+
+/// `duration` in whole seconds, for log lines.
+fn rounded(duration: Duration) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_secs(duration.as_secs()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Change, Monitor};
+
+    #[test]
+    fn door_left_open_raises_alarm_test() {
+        let mut monitor = Monitor {
+            max_open: Duration::from_secs(60),
+            open: None,
+            alarm: false,
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(monitor.update(at(0), true, None), Some(Change::Opened));
+        assert_eq!(monitor.update(at(1), true, None), None);
+        assert_eq!(monitor.timeout(at(30)), Some(Duration::from_secs(30)));
+        assert_eq!(monitor.check(at(59), false), None);
+        assert_eq!(
+            monitor.check(at(60), false),
+            Some(Change::Alarm {
+                open_for: Duration::from_secs(60)
+            })
+        );
+        assert_eq!(monitor.check(at(61), false), None);
+        assert_eq!(monitor.timeout(at(61)), None);
+
+        // Closing clears the alarm.
+        assert_eq!(
+            monitor.update(at(90), false, None),
+            Some(Change::Closed {
+                open_for: Duration::from_secs(90),
+                alarm: true
+            })
+        );
+        assert_eq!(monitor.check(at(200), false), None);
+
+        // Opened during an unlock, the timer starts when the unlock ends.
+        monitor.update(at(100), true, Some(at(103)));
+        assert_eq!(monitor.check(at(162), false), None);
+        assert_eq!(monitor.timeout(at(162)), Some(Duration::from_secs(1)));
+        assert!(monitor.check(at(163), false).is_some());
+
+        // Held unlocked, the door may stay open until the hold ends.
+        monitor.update(at(200), false, None);
+        monitor.update(at(210), true, None);
+        assert_eq!(monitor.check(at(300), true), None);
+        assert_eq!(monitor.check(at(359), false), None);
+        assert!(monitor.check(at(360), false).is_some());
+    }
+}
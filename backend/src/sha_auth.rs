@@ -0,0 +1,271 @@
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::Context;
+use rand::Rng;
+
+use crate::{config, OneWireId};
+
+/// Family code of the DS1961S (and DS2432) SHA-1 iButton.
+pub const DS1961S_FAMILY: u8 = 0x33;
+
+const WRITE_SCRATCHPAD: u8 = 0x0f;
+const READ_AUTHENTICATED_PAGE: u8 = 0xa5;
+const PAGE_SIZE: usize = 32;
+/// The device needs up to 2 ms to compute the MAC before it can be read.
+const MAC_DELAY: Duration = Duration::from_millis(2);
+
+/// Outcome of a challenge.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Genuine,
+    /// The MAC didn't match, so the button doesn't know the secret.
+    Clone,
+}
+
+/// Proves DS1961S buttons know the site secret via the SHA-1 challenge-response of their
+/// Read Authenticated Page command, talking to them through the w1 core's raw `rw` file.
+pub struct Authenticator {
+    secret: [u8; 8],
+    page: u8,
+    w1_devices: PathBuf,
+}
+
+impl Authenticator {
+    /// `None` unless `auth.mode` is `challenge`.
+    pub fn new(
+        config: &config::Auth,
+        w1_devices: PathBuf,
+    ) -> anyhow::Result<Option<Authenticator>> {
+        if config.mode != config::AuthMode::Challenge {
+            return Ok(None);
+        }
+        Ok(Some(Authenticator {
+            secret: config.secret()?,
+            page: config.page,
+            w1_devices,
+        }))
+    }
+
+    /// Challenges the button `sysname` with a random value.
+    pub fn verify(&self, sysname: &str, id: &OneWireId) -> anyhow::Result<Verdict> {
+        let path = self.w1_devices.join(sysname).join("rw");
+        let mut rw = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .context(format!("Failed to open {path:?}"))?;
+        let scratchpad = rand::thread_rng().gen::<[u8; 8]>();
+        self.challenge(&mut rw, id, scratchpad)
+    }
+
+    fn challenge(
+        &self,
+        rw: &mut (impl Read + Write),
+        id: &OneWireId,
+        scratchpad: [u8; 8],
+    ) -> anyhow::Result<Verdict> {
+        // Every write to `rw` selects the device anew, reads continue the transaction.
+        let mut command = vec![WRITE_SCRATCHPAD, 0, 0];
+        command.extend_from_slice(&scratchpad);
+        rw.write_all(&command)?;
+        let mut crc = [0; 2];
+        rw.read_exact(&mut crc)?;
+        check_crc16(&command, crc).context("Scratchpad write failed")?;
+
+        let address = self.page * PAGE_SIZE as u8;
+        let mut command = vec![READ_AUTHENTICATED_PAGE, address, 0];
+        rw.write_all(&command)?;
+        let mut data = [0; PAGE_SIZE + 3];
+        rw.read_exact(&mut data)?;
+        command.extend_from_slice(&data[..PAGE_SIZE + 1]);
+        check_crc16(&command, [data[PAGE_SIZE + 1], data[PAGE_SIZE + 2]])
+            .context("Page read failed")?;
+        std::thread::sleep(MAC_DELAY);
+        let mut mac = [0; 22];
+        rw.read_exact(&mut mac)?;
+        check_crc16(&mac[..20], [mac[20], mac[21]]).context("MAC read failed")?;
+
+        let page: [u8; PAGE_SIZE] = data[..PAGE_SIZE].try_into().unwrap();
+        let challenge = [scratchpad[4], scratchpad[5], scratchpad[6]];
+        let expected = compute_mac(&self.secret, &page, self.page, id, challenge);
+        Ok(if mac[..20] == expected {
+            Verdict::Genuine
+        } else {
+            Verdict::Clone
+        })
+    }
+}
+
+/// The MAC a DS1961S returns for Read Authenticated Page: SHA-1 over the 55-byte message of the
+/// data sheet's "SHA-1 input data" table, with the result sent lowest byte of E first.
+fn compute_mac(
+    secret: &[u8; 8],
+    page_data: &[u8; PAGE_SIZE],
+    page: u8,
+    id: &OneWireId,
+    challenge: [u8; 3],
+) -> [u8; 20] {
+    let mut message = Vec::with_capacity(55);
+    message.extend_from_slice(&secret[..4]);
+    message.extend_from_slice(page_data);
+    message.extend_from_slice(&[0xff; 4]);
+    message.push(0x40 | (page & 0x07));
+    message.extend_from_slice(&rom_code(id));
+    message.extend_from_slice(&secret[4..]);
+    message.extend_from_slice(&challenge);
+    let digest = ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, &message);
+    let mut mac: [u8; 20] = digest.as_ref().try_into().unwrap();
+    mac.reverse();
+    mac
+}
+
+/// `id` in bus order, with the serial starting at its lowest byte, as the device hashes it.
+fn rom_code(id: &OneWireId) -> [u8; 7] {
+    let mut rom = [id[0], 0, 0, 0, 0, 0, 0];
+    for idx in 0..6 {
+        rom[idx + 1] = id[6 - idx];
+    }
+    rom
+}
+
+/// Checks the inverted CRC16 a device appends to its responses, lowest byte first.
+fn check_crc16(bytes: &[u8], received: [u8; 2]) -> anyhow::Result<()> {
+    let crc = !crc16(bytes);
+    anyhow::ensure!(
+        crc.to_le_bytes() == received,
+        "CRC16 mismatch, expected {crc:04x} but got {:04x}",
+        u16::from_le_bytes(received)
+    );
+    Ok(())
+}
+
+/// The 1-Wire CRC16 (polynomial x^16 + x^15 + x^2 + 1).
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ u16::from(byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use super::{compute_mac, crc16, Authenticator, Verdict, PAGE_SIZE};
+
+    const KEY: [u8; 7] = [0x33, 0, 0, 3, 0x92, 0xc6, 0xea];
+
+    /// A DS1961S behind the `rw` file, answering with the MAC for `secret`.
+    struct FakeButton {
+        secret: [u8; 8],
+        page: [u8; PAGE_SIZE],
+        scratchpad: [u8; 8],
+        response: Vec<u8>,
+    }
+
+    impl Write for FakeButton {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.response.clear();
+            match buf[0] {
+                super::WRITE_SCRATCHPAD => {
+                    self.scratchpad.copy_from_slice(&buf[3..11]);
+                    self.response = (!crc16(buf)).to_le_bytes().to_vec();
+                }
+                super::READ_AUTHENTICATED_PAGE => {
+                    let mut sent = buf.to_vec();
+                    sent.extend_from_slice(&self.page);
+                    sent.push(0xff);
+                    self.response = sent[3..].to_vec();
+                    self.response.extend((!crc16(&sent)).to_le_bytes());
+                    let challenge = [self.scratchpad[4], self.scratchpad[5], self.scratchpad[6]];
+                    let mac = compute_mac(&self.secret, &self.page, buf[1] / 32, &KEY, challenge);
+                    self.response.extend(mac);
+                    self.response.extend((!crc16(&mac)).to_le_bytes());
+                }
+                _ => {}
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for FakeButton {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.response.len());
+            buf[..len].copy_from_slice(&self.response[..len]);
+            self.response.drain(..len);
+            Ok(len)
+        }
+    }
+
+    fn button(secret: [u8; 8]) -> FakeButton {
+        FakeButton {
+            secret,
+            page: [0x5a; PAGE_SIZE],
+            scratchpad: [0; 8],
+            response: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn challenge_response_test() {
+        let secret = *b"cellar\x00\x01";
+        let auth = Authenticator {
+            secret,
+            page: 0,
+            w1_devices: Default::default(),
+        };
+        let scratchpad = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(
+            auth.challenge(&mut button(secret), &KEY, scratchpad)
+                .unwrap(),
+            Verdict::Genuine
+        );
+        assert_eq!(
+            auth.challenge(&mut button(*b"guessing"), &KEY, scratchpad)
+                .unwrap(),
+            Verdict::Clone
+        );
+
+        // A garbled response is an error rather than a suspected clone.
+        assert!(auth
+            .challenge(&mut Garble(button(secret)), &KEY, scratchpad)
+            .is_err());
+    }
+
+    /// Flips a bit of the page data read back.
+    struct Garble(FakeButton);
+
+    impl Write for Garble {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let len = self.0.write(buf)?;
+            if buf[0] == super::READ_AUTHENTICATED_PAGE {
+                self.0.response[0] ^= 1;
+            }
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Garble {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+
+use crate::{persistence, privacy, Credential};
+
+/// A key that was denied because nobody has entered it into MOS yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sighting {
+    pub id: Credential,
+    pub first_seen: SystemTime,
+    pub count: u64,
+}
+
+/// Keeps the unknown keys touched to a reader in `persistence.pending_path` as
+/// `<hex id>,<first seen>,<count>` lines, so new keys can be looked up when entering them into
+/// MOS. Only the `max` most recently first seen keys are kept.
+#[derive(Default)]
+pub struct Sightings {
+    path: Option<PathBuf>,
+    max: usize,
+    /// Oldest first.
+    entries: Mutex<Vec<Sighting>>,
+}
+
+impl Sightings {
+    /// Loads the file at `path`, if any; without a path nothing is recorded.
+    pub fn load(path: Option<PathBuf>, max: usize) -> Sightings {
+        let entries = match &path {
+            Some(path) if path.exists() => read(path).unwrap_or_else(|e| {
+                log::error!("Failed to read unknown key sightings, starting afresh: {e:?}");
+                Vec::new()
+            }),
+            _ => Vec::new(),
+        };
+        Sightings {
+            path,
+            max,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Counts a sighting of the unknown key `id`.
+    pub fn record(&self, id: &Credential, now: SystemTime) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        match entries.iter_mut().find(|sighting| sighting.id == *id) {
+            Some(sighting) => sighting.count += 1,
+            None => {
+                log::info!("Recording unknown key {} as pending", privacy::id(id));
+                entries.push(Sighting {
+                    id: *id,
+                    first_seen: now,
+                    count: 1,
+                });
+                let excess = entries.len().saturating_sub(self.max);
+                entries.drain(..excess);
+            }
+        }
+        if let Err(e) = write(path, &entries) {
+            log::error!("Failed to record unknown key sighting: {e:?}");
+        }
+    }
+
+    pub fn entries(&self) -> Vec<Sighting> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Forgets `id`, returning whether it was pending.
+    pub fn clear(&self, id: &Credential) -> anyhow::Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|sighting| sighting.id != *id);
+        if entries.len() == len {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            write(path, &entries)?;
+        }
+        Ok(true)
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<Sighting>> {
+    let contents = std::fs::read_to_string(path).context(format!("Failed to read {path:?}"))?;
+    let mut entries = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let mut fields = line.split(',').map(str::trim);
+        let sighting = (|| {
+            Some(Sighting {
+                id: Credential::parse(fields.next()?).ok()?,
+                first_seen: humantime::parse_rfc3339(fields.next()?).ok()?,
+                count: fields.next()?.parse().ok()?,
+            })
+        })();
+        match sighting {
+            Some(sighting) => entries.push(sighting),
+            None => log::warn!("Skipping malformed line {} of {path:?}", idx + 1),
+        }
+    }
+    Ok(entries)
+}
+
+fn write(path: &Path, entries: &[Sighting]) -> anyhow::Result<()> {
+    let contents: String = entries
+        .iter()
+        .map(|sighting| {
+            format!(
+                "{},{},{}\n",
+                sighting.id.hex(),
+                humantime::format_rfc3339_seconds(sighting.first_seen),
+                sighting.count
+            )
+        })
+        .collect();
+    persistence::write_atomically(path, |file| Ok(file.write_all(contents.as_bytes())?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::Sightings;
+    use crate::Credential;
+
+    #[test]
+    fn sightings_are_counted_and_capped_test() {
+        let dir = crate::testutil::test_dir("sightings");
+        let path = dir.join("pending.csv");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_750_000_000);
+        let at = |secs| start + Duration::from_secs(secs);
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let carol = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x43]);
+
+        let sightings = Sightings::load(Some(path.clone()), 2);
+        sightings.record(&alice, at(0));
+        sightings.record(&bob, at(10));
+        sightings.record(&alice, at(20));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "3300000392c6ea,2025-06-15T15:06:40Z,2\n\
+             01000000000042,2025-06-15T15:06:50Z,1\n"
+        );
+
+        // The oldest key makes room, and the file survives a restart.
+        sightings.record(&carol, at(30));
+        let sightings = Sightings::load(Some(path.clone()), 2);
+        let ids: Vec<_> = sightings.entries().iter().map(|s| s.id).collect();
+        assert_eq!(ids, [bob, carol]);
+
+        assert!(sightings.clear(&bob).unwrap());
+        assert!(!sightings.clear(&bob).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "01000000000043,2025-06-15T15:07:10Z,1\n"
+        );
+
+        // Without a path nothing is kept.
+        let off = Sightings::default();
+        off.record(&alice, at(0));
+        assert!(off.entries().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
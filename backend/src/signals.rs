@@ -0,0 +1,121 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::atomic::{AtomicI32, Ordering},
+};
+
+use mio::{event::Source, unix::SourceFd, Interest, Registry, Token};
+
+pub use libc::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
+
+static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = PIPE_WRITE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let byte = signal as u8;
+        // SAFETY: errno access and write(2) are async-signal-safe; errno is restored so the
+        // interrupted code doesn't observe our write.
+        unsafe {
+            let errno = *libc::__errno_location();
+            libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+/// Delivers POSIX signals through a self-pipe that can be registered with a mio [`Registry`].
+pub struct Signals {
+    read: OwnedFd,
+    _write: OwnedFd,
+}
+
+impl Signals {
+    pub fn new(signals: &[libc::c_int]) -> io::Result<Signals> {
+        let mut fds = [0 as RawFd; 2];
+        // SAFETY: `fds` has room for the two descriptors pipe2 writes.
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both descriptors are fresh and owned by us.
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        PIPE_WRITE.store(write.as_raw_fd(), Ordering::Relaxed);
+
+        for &signal in signals {
+            // SAFETY: the handler only performs async-signal-safe operations.
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(signal, &action, std::ptr::null_mut()) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+
+        Ok(Signals {
+            read,
+            _write: write,
+        })
+    }
+
+    /// Returns the signals received since the last call, in arrival order.
+    pub fn pending(&self) -> Vec<libc::c_int> {
+        let mut signals = Vec::new();
+        let mut buf = [0u8; 64];
+        loop {
+            // SAFETY: `buf` is valid for writes of its full length.
+            let len = unsafe {
+                libc::read(
+                    self.read.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if len <= 0 {
+                break;
+            }
+            signals.extend(buf[..len as usize].iter().map(|&s| s as libc::c_int));
+        }
+        signals
+    }
+}
+
+impl Source for Signals {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.read.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.read.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.read.as_raw_fd()).deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn raised_signal_is_pending_test() {
+        let signals = super::Signals::new(&[super::SIGTERM]).unwrap();
+        assert!(signals.pending().is_empty());
+
+        // SAFETY: raise(3) just delivers the signal to our own handler.
+        unsafe { libc::raise(super::SIGTERM) };
+
+        assert_eq!(signals.pending(), [super::SIGTERM]);
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    os::unix::{
+        fs::{FileTypeExt, OpenOptionsExt},
+        io::AsRawFd,
+    },
+    path::Path,
+};
+
+use anyhow::Context;
+use mio::{unix::SourceFd, Interest, Registry, Token};
+
+/// Longest line accepted; anything longer is dropped whole.
+const MAX_LINE: usize = 256;
+
+/// A line read from the FIFO.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    Presentation(Presentation),
+    /// The line `exit_button`.
+    ExitButton,
+}
+
+/// A key presentation read from the FIFO.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Presentation {
+    /// Taken as the w1 device's sysname, so it is parsed like one.
+    pub sysname: String,
+    /// Sysnames of the device's ancestors, nearest first, to pick a reader.
+    pub ancestors: Vec<String>,
+}
+
+/// Injects key presentations written to a named pipe, one `<id> [ancestor...]` per line, e.g.
+/// `echo 33-00000392c6ea w1_bus_master1 > /run/cellardoor/sim`, and presses of the exit button
+/// as `exit_button`.
+pub struct Simulator {
+    fifo: File,
+    input: Vec<u8>,
+    /// Skipping the rest of an over-long line.
+    discarding: bool,
+}
+
+impl Simulator {
+    /// Opens the FIFO at `path`, creating it if needed, and registers it under `token`.
+    pub fn open(path: &Path, registry: &Registry, token: Token) -> anyhow::Result<Simulator> {
+        match std::fs::metadata(path) {
+            Ok(metadata) if metadata.file_type().is_fifo() => {}
+            Ok(_) => anyhow::bail!("{path:?} exists and is not a FIFO"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let c_path = CString::new(path.as_os_str().as_encoded_bytes())?;
+                // SAFETY: `c_path` is NUL-terminated.
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o660) } < 0 {
+                    return Err(io::Error::last_os_error())
+                        .context(format!("Failed to create FIFO {path:?}"));
+                }
+            }
+            Err(e) => return Err(e).context(format!("Failed to stat {path:?}")),
+        }
+        // Also opened for writing, so the FIFO doesn't hang up whenever a writer closes it.
+        let fifo = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+            .context(format!("Failed to open FIFO {path:?}"))?;
+        registry.register(&mut SourceFd(&fifo.as_raw_fd()), token, Interest::READABLE)?;
+        log::info!("Simulating key presentations written to {path:?}");
+        Ok(Simulator {
+            fifo,
+            input: Vec::new(),
+            discarding: false,
+        })
+    }
+
+    /// Reads what is available and returns the complete lines, skipping blank ones.
+    pub fn inputs(&mut self) -> Vec<Input> {
+        let mut buf = [0; 1024];
+        let mut inputs = Vec::new();
+        loop {
+            match self.fifo.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    for &byte in &buf[..len] {
+                        if let Some(input) = self.push(byte) {
+                            inputs.push(input);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::error!("Failed to read simulated key presentations: {e:?}");
+                    break;
+                }
+            }
+        }
+        inputs
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Input> {
+        if byte != b'\n' {
+            if self.input.len() < MAX_LINE {
+                self.input.push(byte);
+            } else if !self.discarding {
+                log::warn!("Ignoring simulated line longer than {MAX_LINE} bytes");
+                self.discarding = true;
+            }
+            return None;
+        }
+        let line = std::mem::take(&mut self.input);
+        if std::mem::take(&mut self.discarding) {
+            return None;
+        }
+        let line = String::from_utf8_lossy(&line);
+        let mut fields = line.split_whitespace().map(str::to_owned);
+        let sysname = fields.next()?;
+        if sysname == "exit_button" {
+            return Some(Input::ExitButton);
+        }
+        Some(Input::Presentation(Presentation {
+            sysname,
+            ancestors: fields.collect(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, time::Duration};
+
+    use mio::{Events, Poll, Token};
+
+    use super::{Input, Presentation, Simulator};
+    use crate::{
+        access::Access,
+        feedback::{Event, Feedback},
+        testutil::{access, test_dir},
+        Credential,
+    };
+
+    #[test]
+    fn simulated_presentations_test() {
+        let dir = test_dir("simulate");
+        let path = dir.join("sim");
+        let mut poll = Poll::new().unwrap();
+        let mut simulator = Simulator::open(&path, poll.registry(), Token(0)).unwrap();
+        let access = Access {
+            feedback: Feedback::unconnected(),
+            ..access(
+                &[Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea])],
+                &[],
+                &[],
+            )
+        };
+
+        // Writers come and go like `echo` does; a line may span writes.
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        writer
+            .write_all(b"33-00000392c6ea w1_bus_master1\n\n01-0000")
+            .unwrap();
+        drop(writer);
+        let mut writer = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        writer
+            .write_all(b"00000042\nnot-an-id\nexit_button\n")
+            .unwrap();
+        drop(writer);
+
+        let mut events = Events::with_capacity(8);
+        poll.poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert!(!events.is_empty());
+        let inputs = simulator.inputs();
+        assert_eq!(
+            inputs[0],
+            Input::Presentation(Presentation {
+                sysname: "33-00000392c6ea".to_owned(),
+                ancestors: vec!["w1_bus_master1".to_owned()],
+            })
+        );
+        assert_eq!(inputs.len(), 4);
+        let mut played = Vec::new();
+        for input in inputs {
+            match input {
+                Input::Presentation(presentation) => {
+                    access.handle_device(&presentation.sysname, &presentation.ancestors)
+                }
+                Input::ExitButton => access.exit_button(),
+            }
+            played.push(access.feedback.playing());
+        }
+        // The unknown key's pattern replaced Alice's; neither junk nor the button plays one.
+        let deny = Some(Event::Deny);
+        assert_eq!(played, [Some(Event::Grant), deny, deny, deny]);
+        assert!(access.feedback.deadline().is_some());
+        assert_eq!(access.metrics.granted.get(), 1);
+        assert_eq!(access.metrics.denied.get(), 1);
+        assert_eq!(access.metrics.unparsable.get(), 1);
+        assert_eq!(access.metrics.exit_button.get(), 1);
+
+        // Once drained, the closed writers don't leave the FIFO readable.
+        poll.poll(&mut events, Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(events.is_empty());
+        assert!(simulator.inputs().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
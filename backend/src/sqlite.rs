@@ -0,0 +1,279 @@
+//! Just enough of the SQLite C API for [`crate::store::SqliteStore`], linked against the system
+//! `libsqlite3`.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    path::Path,
+    ptr,
+};
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_NULL: c_int = 5;
+const SQLITE_OPEN_READWRITE: c_int = 0x0000_0002;
+const SQLITE_OPEN_CREATE: c_int = 0x0000_0004;
+const SQLITE_OPEN_NOMUTEX: c_int = 0x0000_8000;
+/// Makes SQLite copy bound values, so they needn't outlive the statement.
+const SQLITE_TRANSIENT: isize = -1;
+const BUSY_TIMEOUT_MS: c_int = 5000;
+
+#[repr(C)]
+struct Sqlite3 {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct Sqlite3Stmt {
+    _private: [u8; 0],
+}
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut Sqlite3,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close_v2(db: *mut Sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut Sqlite3) -> *const c_char;
+    fn sqlite3_busy_timeout(db: *mut Sqlite3, ms: c_int) -> c_int;
+    fn sqlite3_exec(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        callback: *const c_void,
+        arg: *mut c_void,
+        errmsg: *mut *mut c_char,
+    ) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        len: c_int,
+        stmt: *mut *mut Sqlite3Stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_blob(
+        stmt: *mut Sqlite3Stmt,
+        idx: c_int,
+        value: *const c_void,
+        len: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_bind_text(
+        stmt: *mut Sqlite3Stmt,
+        idx: c_int,
+        value: *const c_char,
+        len: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_bind_int64(stmt: *mut Sqlite3Stmt, idx: c_int, value: i64) -> c_int;
+    fn sqlite3_bind_null(stmt: *mut Sqlite3Stmt, idx: c_int) -> c_int;
+    fn sqlite3_step(stmt: *mut Sqlite3Stmt) -> c_int;
+    fn sqlite3_column_type(stmt: *mut Sqlite3Stmt, col: c_int) -> c_int;
+    fn sqlite3_column_blob(stmt: *mut Sqlite3Stmt, col: c_int) -> *const c_void;
+    fn sqlite3_column_text(stmt: *mut Sqlite3Stmt, col: c_int) -> *const u8;
+    fn sqlite3_column_bytes(stmt: *mut Sqlite3Stmt, col: c_int) -> c_int;
+    fn sqlite3_column_int64(stmt: *mut Sqlite3Stmt, col: c_int) -> i64;
+    fn sqlite3_finalize(stmt: *mut Sqlite3Stmt) -> c_int;
+}
+
+/// A value bound to a `?` parameter.
+pub enum Value<'a> {
+    Null,
+    Integer(i64),
+    Text(&'a str),
+    Blob(&'a [u8]),
+}
+
+/// An open database. Not synchronized; callers serialize access, e.g. with a `Mutex`.
+pub struct Connection {
+    db: *mut Sqlite3,
+}
+
+// SAFETY: connections opened with SQLITE_OPEN_NOMUTEX may move between threads as long as only
+// one uses them at a time, which holds as `Connection` isn't `Sync`.
+unsafe impl Send for Connection {}
+
+impl Connection {
+    pub fn open(path: &Path) -> anyhow::Result<Connection> {
+        let c_path = CString::new(path.as_os_str().as_encoded_bytes())?;
+        let mut db = ptr::null_mut();
+        let flags = SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE | SQLITE_OPEN_NOMUTEX;
+        // SAFETY: `c_path` is NUL-terminated and `db` is a valid out pointer.
+        let rc = unsafe { sqlite3_open_v2(c_path.as_ptr(), &mut db, flags, ptr::null()) };
+        // Even a failed open allocates a handle carrying the error message.
+        let connection = Connection { db };
+        anyhow::ensure!(rc == SQLITE_OK, "{}", connection.error());
+        // SAFETY: `db` is an open connection.
+        unsafe { sqlite3_busy_timeout(db, BUSY_TIMEOUT_MS) };
+        Ok(connection)
+    }
+
+    /// Runs one or more statements without parameters.
+    pub fn execute_batch(&self, sql: &str) -> anyhow::Result<()> {
+        let c_sql = CString::new(sql)?;
+        // SAFETY: `c_sql` is NUL-terminated; no callback or error out pointer is passed.
+        let rc = unsafe {
+            sqlite3_exec(
+                self.db,
+                c_sql.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        self.check(rc)
+    }
+
+    pub fn execute(&self, sql: &str, params: &[Value]) -> anyhow::Result<()> {
+        self.query(sql, params, |_| Ok(()))?;
+        Ok(())
+    }
+
+    /// Runs `sql` and maps each resulting row with `map`.
+    pub fn query<T>(
+        &self,
+        sql: &str,
+        params: &[Value],
+        mut map: impl FnMut(&Row) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Vec<T>> {
+        let statement = self.prepare(sql)?;
+        for (idx, param) in params.iter().enumerate() {
+            statement.bind(idx as c_int + 1, param)?;
+        }
+        let mut rows = Vec::new();
+        loop {
+            // SAFETY: `statement` is a prepared statement of this connection.
+            match unsafe { sqlite3_step(statement.stmt) } {
+                SQLITE_ROW => rows.push(map(&Row(&statement))?),
+                SQLITE_DONE => return Ok(rows),
+                rc => self.check(rc)?,
+            }
+        }
+    }
+
+    fn prepare(&self, sql: &str) -> anyhow::Result<Statement<'_>> {
+        let c_sql = CString::new(sql)?;
+        let mut stmt = ptr::null_mut();
+        // SAFETY: `c_sql` is NUL-terminated and `stmt` is a valid out pointer.
+        let rc =
+            unsafe { sqlite3_prepare_v2(self.db, c_sql.as_ptr(), -1, &mut stmt, ptr::null_mut()) };
+        self.check(rc)?;
+        Ok(Statement {
+            connection: self,
+            stmt,
+        })
+    }
+
+    fn check(&self, rc: c_int) -> anyhow::Result<()> {
+        anyhow::ensure!(rc == SQLITE_OK, "SQLite error {rc}: {}", self.error());
+        Ok(())
+    }
+
+    fn error(&self) -> String {
+        // SAFETY: `sqlite3_errmsg` accepts any handle, even a failed one, and returns a
+        // NUL-terminated string valid until the next call on the connection.
+        unsafe { CStr::from_ptr(sqlite3_errmsg(self.db)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // SAFETY: `db` came from `sqlite3_open_v2` and all statements were finalized.
+        unsafe { sqlite3_close_v2(self.db) };
+    }
+}
+
+struct Statement<'a> {
+    connection: &'a Connection,
+    stmt: *mut Sqlite3Stmt,
+}
+
+impl Statement<'_> {
+    fn bind(&self, idx: c_int, value: &Value) -> anyhow::Result<()> {
+        // SAFETY: `stmt` is prepared; SQLITE_TRANSIENT makes SQLite copy the bound bytes.
+        let rc = unsafe {
+            match value {
+                Value::Null => sqlite3_bind_null(self.stmt, idx),
+                Value::Integer(value) => sqlite3_bind_int64(self.stmt, idx, *value),
+                Value::Text(value) => sqlite3_bind_text(
+                    self.stmt,
+                    idx,
+                    value.as_ptr().cast(),
+                    value.len().try_into()?,
+                    SQLITE_TRANSIENT,
+                ),
+                Value::Blob(value) => sqlite3_bind_blob(
+                    self.stmt,
+                    idx,
+                    value.as_ptr().cast(),
+                    value.len().try_into()?,
+                    SQLITE_TRANSIENT,
+                ),
+            }
+        };
+        self.connection.check(rc)
+    }
+}
+
+impl Drop for Statement<'_> {
+    fn drop(&mut self) {
+        // SAFETY: `stmt` came from `sqlite3_prepare_v2` and is finalized only here.
+        unsafe { sqlite3_finalize(self.stmt) };
+    }
+}
+
+/// The current row of a query.
+pub struct Row<'a>(&'a Statement<'a>);
+
+impl Row<'_> {
+    pub fn blob(&self, col: c_int) -> Vec<u8> {
+        let stmt = self.0.stmt;
+        // SAFETY: the statement is on a row; the pointer is valid for `column_bytes` bytes until
+        // the next step, and is only read before then.
+        unsafe {
+            let data = sqlite3_column_blob(stmt, col);
+            let len = sqlite3_column_bytes(stmt, col) as usize;
+            if data.is_null() {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(data.cast::<u8>(), len).to_vec()
+            }
+        }
+    }
+
+    pub fn text(&self, col: c_int) -> String {
+        let stmt = self.0.stmt;
+        // SAFETY: as for `blob`.
+        unsafe {
+            let data = sqlite3_column_text(stmt, col);
+            let len = sqlite3_column_bytes(stmt, col) as usize;
+            if data.is_null() {
+                String::new()
+            } else {
+                String::from_utf8_lossy(std::slice::from_raw_parts(data, len)).into_owned()
+            }
+        }
+    }
+
+    /// Like [`Row::text`], but `None` for NULL.
+    pub fn optional_text(&self, col: c_int) -> Option<String> {
+        // SAFETY: the statement is on a row.
+        let null = unsafe { sqlite3_column_type(self.0.stmt, col) } == SQLITE_NULL;
+        (!null).then(|| self.text(col))
+    }
+
+    pub fn integer(&self, col: c_int) -> Option<i64> {
+        // SAFETY: the statement is on a row.
+        unsafe {
+            if sqlite3_column_type(self.0.stmt, col) == SQLITE_NULL {
+                None
+            } else {
+                Some(sqlite3_column_int64(self.0.stmt, col))
+            }
+        }
+    }
+}
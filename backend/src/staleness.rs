@@ -0,0 +1,54 @@
+use std::{fmt, time::Duration};
+
+use crate::config;
+
+/// How far the key list has fallen behind MOS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Fresh,
+    /// Older than `warn_stale_after`.
+    Stale,
+    /// Older than `deny_after`: only master keys are honoured.
+    Restricted,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Fresh => "fresh",
+            Level::Stale => "stale",
+            Level::Restricted => "restricted",
+        })
+    }
+}
+
+/// What to do about a key list that couldn't be refreshed for a while.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Staleness {
+    pub warn_after: Option<Duration>,
+    pub deny_after: Option<Duration>,
+}
+
+impl Staleness {
+    pub fn new(config: &config::Thing) -> Staleness {
+        Staleness {
+            warn_after: config.warn_stale_after,
+            deny_after: config.deny_after,
+        }
+    }
+
+    /// The level for a list last fetched `age` ago; a list never fetched counts as fresh so an
+    /// upgrade doesn't lock everyone out before the first fetch.
+    pub fn level(&self, age: Option<Duration>) -> Level {
+        let Some(age) = age else {
+            return Level::Fresh;
+        };
+        if self.deny_after.is_some_and(|limit| age > limit) {
+            Level::Restricted
+        } else if self.warn_after.is_some_and(|limit| age > limit) {
+            Level::Stale
+        } else {
+            Level::Fresh
+        }
+    }
+}
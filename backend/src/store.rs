@@ -0,0 +1,833 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use dashmap::DashMap;
+
+use crate::{audit::Decision, config, persistence, usage::ended_before, Credential, Key};
+
+/// Grants counted for `period`, a day `2024-05-03`, a month `2024-05` or a year `2024`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsageRow {
+    pub period: String,
+    /// `None` for counts over all keys, the only ones kept unless privacy is full.
+    pub key: Option<Credential>,
+    pub grants: u64,
+    /// Distinct keys among the grants of counts over all keys, 0 for those of one key.
+    pub keys: u64,
+}
+
+/// Where the access list survives restarts, and optionally an audit trail of presentations.
+pub trait KeyStore: Send + Sync {
+    fn load(&self) -> anyhow::Result<DashMap<Credential, Key>>;
+
+    /// Replaces the stored list with `list`.
+    fn save(&self, list: &DashMap<Credential, Key>) -> anyhow::Result<()>;
+
+    /// Records a key presentation; stores without an events table ignore it.
+    fn append_event(
+        &self,
+        time: SystemTime,
+        id: Option<&Credential>,
+        decision: Decision,
+        reader: Option<&str>,
+    ) -> anyhow::Result<()>;
+
+    /// Adds the grants and keys of each of `rows` to those stored for its period and key, which
+    /// are those of one grant.
+    fn add_usage(&self, rows: &[UsageRow]) -> anyhow::Result<()>;
+
+    fn usage(&self) -> anyhow::Result<Vec<UsageRow>>;
+
+    /// Removes the usage counts of periods that ended before the day `before`.
+    fn prune_usage(&self, before: &str) -> anyhow::Result<()>;
+}
+
+/// Opens the backend selected by `persistence.backend`.
+pub fn open(config: &config::Persistence) -> anyhow::Result<Arc<dyn KeyStore>> {
+    Ok(match config.backend {
+        config::Backend::File => Arc::new(FileStore {
+            path: config.path.clone(),
+            backups: config.backups,
+            protection: config.protection()?,
+            usage_lock: Mutex::new(()),
+        }),
+        #[cfg(feature = "sqlite")]
+        config::Backend::Sqlite => Arc::new(SqliteStore::open(&config.path)?),
+        #[cfg(not(feature = "sqlite"))]
+        config::Backend::Sqlite => {
+            anyhow::bail!("persistence.backend: built without the sqlite feature")
+        }
+    })
+}
+
+/// Loads from the wrapped store but only logs writes, for `--dry-run`.
+pub struct DryRun(pub Arc<dyn KeyStore>);
+
+impl KeyStore for DryRun {
+    fn load(&self) -> anyhow::Result<DashMap<Credential, Key>> {
+        self.0.load()
+    }
+
+    fn save(&self, list: &DashMap<Credential, Key>) -> anyhow::Result<()> {
+        log::info!(
+            "[dry-run] Not persisting the key list of {} keys",
+            list.len()
+        );
+        Ok(())
+    }
+
+    fn append_event(
+        &self,
+        _time: SystemTime,
+        _id: Option<&Credential>,
+        decision: Decision,
+        _reader: Option<&str>,
+    ) -> anyhow::Result<()> {
+        log::info!("[dry-run] Not storing the {decision} presentation");
+        Ok(())
+    }
+
+    fn add_usage(&self, rows: &[UsageRow]) -> anyhow::Result<()> {
+        if let Some(row) = rows.first() {
+            log::debug!("[dry-run] Not counting the grant of {}", row.period);
+        }
+        Ok(())
+    }
+
+    fn usage(&self) -> anyhow::Result<Vec<UsageRow>> {
+        self.0.usage()
+    }
+
+    fn prune_usage(&self, _before: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+enum Job {
+    Event {
+        time: SystemTime,
+        id: Option<Credential>,
+        decision: Decision,
+        reader: Option<String>,
+    },
+    Usage(Vec<UsageRow>),
+    /// Answered once every job queued before it is done.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Stores presentations and usage counts on a worker thread, so the event loop never waits for
+/// the disk before it opens the door. Everything else goes straight to the wrapped store; a
+/// save first waits for the queued records, so the one on shutdown doesn't lose any.
+pub struct Background {
+    store: Arc<dyn KeyStore>,
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Background {
+    pub fn spawn(store: Arc<dyn KeyStore>) -> Background {
+        let (jobs, queue) = mpsc::channel();
+        let worker = store.clone();
+        std::thread::spawn(move || {
+            for job in queue {
+                match job {
+                    Job::Event {
+                        time,
+                        id,
+                        decision,
+                        reader,
+                    } => {
+                        if let Err(e) =
+                            worker.append_event(time, id.as_ref(), decision, reader.as_deref())
+                        {
+                            log::error!("Failed to store access event: {e:?}");
+                        }
+                    }
+                    Job::Usage(rows) => {
+                        if let Err(e) = worker.add_usage(&rows) {
+                            log::error!("Failed to count a grant: {e:?}");
+                        }
+                    }
+                    Job::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        Background { store, jobs }
+    }
+
+    fn flush(&self) {
+        let (done, flushed) = mpsc::channel();
+        if self.jobs.send(Job::Flush(done)).is_ok() {
+            let _ = flushed.recv();
+        }
+    }
+}
+
+impl KeyStore for Background {
+    fn load(&self) -> anyhow::Result<DashMap<Credential, Key>> {
+        self.store.load()
+    }
+
+    fn save(&self, list: &DashMap<Credential, Key>) -> anyhow::Result<()> {
+        self.flush();
+        self.store.save(list)
+    }
+
+    fn append_event(
+        &self,
+        time: SystemTime,
+        id: Option<&Credential>,
+        decision: Decision,
+        reader: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let job = Job::Event {
+            time,
+            id: id.copied(),
+            decision,
+            reader: reader.map(str::to_owned),
+        };
+        self.jobs
+            .send(job)
+            .map_err(|_| anyhow::anyhow!("Store worker is gone"))
+    }
+
+    fn add_usage(&self, rows: &[UsageRow]) -> anyhow::Result<()> {
+        self.jobs
+            .send(Job::Usage(rows.to_vec()))
+            .map_err(|_| anyhow::anyhow!("Store worker is gone"))
+    }
+
+    fn usage(&self) -> anyhow::Result<Vec<UsageRow>> {
+        self.store.usage()
+    }
+
+    fn prune_usage(&self, before: &str) -> anyhow::Result<()> {
+        self.store.prune_usage(before)
+    }
+}
+
+/// The versioned flat file written by [`persistence::serialize_1w_devices`].
+#[derive(Default)]
+pub struct FileStore {
+    pub path: PathBuf,
+    /// Previous versions kept next to `path`.
+    pub backups: usize,
+    pub protection: persistence::Protection,
+    /// Held while the usage counts in `<path>.usage` are rewritten.
+    pub usage_lock: Mutex<()>,
+}
+
+impl FileStore {
+    fn usage_path(&self) -> PathBuf {
+        self.path.with_extension("usage")
+    }
+}
+
+impl KeyStore for FileStore {
+    fn load(&self) -> anyhow::Result<DashMap<Credential, Key>> {
+        persistence::deserialize_1w_devices_with_backups(&self.path, self.backups, &self.protection)
+    }
+
+    fn save(&self, list: &DashMap<Credential, Key>) -> anyhow::Result<()> {
+        persistence::serialize_1w_devices_with_backups(
+            list,
+            &self.path,
+            self.backups,
+            &self.protection,
+        )
+    }
+
+    fn append_event(
+        &self,
+        _time: SystemTime,
+        _id: Option<&Credential>,
+        _decision: Decision,
+        _reader: Option<&str>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Appends the rows rather than rewriting the file, which would take a grant two fsyncs;
+    /// rows of the same period and key add up when read, and pruning folds them together.
+    fn add_usage(&self, rows: &[UsageRow]) -> anyhow::Result<()> {
+        let _lock = self.usage_lock.lock().unwrap();
+        let path = self.usage_path();
+        let lines: String = rows
+            .iter()
+            .map(|row| usage_line(&row.period, row.key.as_ref(), row.grants, row.keys))
+            .collect();
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+            .context(format!("Failed to append to {path:?}"))
+    }
+
+    fn usage(&self) -> anyhow::Result<Vec<UsageRow>> {
+        let _lock = self.usage_lock.lock().unwrap();
+        let rows = merge_usage(read_usage(&self.usage_path())?);
+        Ok(rows
+            .into_iter()
+            .map(|((period, key), (grants, keys))| UsageRow {
+                period,
+                key,
+                grants,
+                keys,
+            })
+            .collect())
+    }
+
+    fn prune_usage(&self, before: &str) -> anyhow::Result<()> {
+        let _lock = self.usage_lock.lock().unwrap();
+        let path = self.usage_path();
+        let rows = read_usage(&path)?;
+        let len = rows.len();
+        let rows = merge_usage(
+            rows.into_iter()
+                .filter(|row| !ended_before(&row.period, before)),
+        );
+        if rows.len() == len {
+            return Ok(());
+        }
+        write_usage(&path, rows)
+    }
+}
+
+/// Reads `<period>,<hex id>,<grants>,<keys>` lines, the id left empty for counts over all keys.
+fn read_usage(path: &Path) -> anyhow::Result<Vec<UsageRow>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(format!("Failed to read {path:?}")),
+    };
+    let mut rows = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let mut fields = line.split(',');
+        let row = (|| {
+            let period = fields.next()?.to_owned();
+            let key = match fields.next()? {
+                "" => None,
+                id => Some(Credential::parse(id).ok()?),
+            };
+            Some(UsageRow {
+                period,
+                key,
+                grants: fields.next()?.parse().ok()?,
+                keys: fields.next()?.parse().ok()?,
+            })
+        })();
+        match row {
+            Some(row) => rows.push(row),
+            None => log::warn!("Skipping malformed line {} of {path:?}", idx + 1),
+        }
+    }
+    Ok(rows)
+}
+
+type UsageCounts = BTreeMap<(String, Option<Credential>), (u64, u64)>;
+
+/// Adds up the grants and keys of rows of the same period and key.
+fn merge_usage(rows: impl IntoIterator<Item = UsageRow>) -> UsageCounts {
+    let mut counts = UsageCounts::new();
+    for row in rows {
+        let entry = counts.entry((row.period, row.key)).or_default();
+        entry.0 += row.grants;
+        entry.1 += row.keys;
+    }
+    counts
+}
+
+fn usage_line(period: &str, key: Option<&Credential>, grants: u64, keys: u64) -> String {
+    let key = key.map(Credential::hex).unwrap_or_default();
+    format!("{period},{key},{grants},{keys}\n")
+}
+
+fn write_usage(path: &Path, rows: UsageCounts) -> anyhow::Result<()> {
+    let contents: String = rows
+        .iter()
+        .map(|((period, key), (grants, keys))| usage_line(period, key.as_ref(), *grants, *keys))
+        .collect();
+    persistence::write_atomically(path, |file| Ok(file.write_all(contents.as_bytes())?))
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::SqliteStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite_store {
+    use std::{
+        collections::HashSet,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::{Duration, SystemTime},
+    };
+
+    use anyhow::Context;
+    use dashmap::DashMap;
+
+    use super::{KeyStore, UsageRow};
+    use crate::{
+        audit::Decision,
+        persistence,
+        sqlite::{Connection, Value},
+        usage::ended_before,
+        Credential, Key,
+    };
+
+    /// What every SQLite database file starts with.
+    const HEADER: &[u8] = b"SQLite format 3\0";
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS keys (
+            id BLOB PRIMARY KEY CHECK (length(id) >= 7),
+            name TEXT NOT NULL,
+            expiry INTEGER,
+            last_seen INTEGER,
+            schedule TEXT,
+            key_group TEXT,
+            pin TEXT
+        );
+        CREATE TABLE IF NOT EXISTS events (
+            time TEXT NOT NULL,
+            key_id BLOB,
+            decision TEXT NOT NULL,
+            reader TEXT
+        );
+        CREATE TABLE IF NOT EXISTS usage (
+            period TEXT NOT NULL,
+            key_id BLOB NOT NULL,
+            grants INTEGER NOT NULL,
+            keys INTEGER NOT NULL,
+            PRIMARY KEY (period, key_id)
+        );
+    ";
+
+    /// The `key_id` of usage counts over all keys.
+    const ALL_KEYS: &[u8] = b"";
+
+    /// Keys and the audit trail in one SQLite file. Times are unix seconds, event times RFC3339.
+    pub struct SqliteStore {
+        connection: Mutex<Connection>,
+    }
+
+    impl SqliteStore {
+        /// Opens or creates the database at `path`. A flat key list file found there is
+        /// imported and kept as `<path>.flat`.
+        pub fn open(path: &Path) -> anyhow::Result<SqliteStore> {
+            let imported = match std::fs::read(path) {
+                Ok(data) if !data.is_empty() && !data.starts_with(HEADER) => {
+                    let list = persistence::deserialize_1w_devices(path)
+                        .context(format!("{path:?} is neither SQLite nor a key list file"))?;
+                    let backup = flat_backup_path(path);
+                    std::fs::rename(path, &backup)
+                        .context(format!("Failed to move {path:?} to {backup:?}"))?;
+                    log::info!(
+                        "Importing {} keys from {path:?}, keeping the old file as {backup:?}",
+                        list.len()
+                    );
+                    Some(list)
+                }
+                _ => None,
+            };
+            let connection =
+                Connection::open(path).context(format!("Failed to open database {path:?}"))?;
+            connection.execute_batch(SCHEMA)?;
+            // Databases created before keys could be scheduled, grouped or need a PIN lack the
+            // columns.
+            let columns =
+                connection.query("PRAGMA table_info(keys)", &[], |row| Ok(row.text(1)))?;
+            for column in ["schedule", "key_group", "pin"] {
+                if !columns.iter().any(|existing| existing == column) {
+                    connection
+                        .execute_batch(&format!("ALTER TABLE keys ADD COLUMN {column} TEXT"))?;
+                }
+            }
+            // Before cards got ids of their own, only 7-byte 1-Wire ids fit the keys table.
+            let keys_sql = connection.query(
+                "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'keys'",
+                &[],
+                |row| Ok(row.text(0)),
+            )?;
+            if keys_sql.iter().any(|sql| sql.contains("length(id) = 7")) {
+                connection.execute_batch(
+                    "BEGIN;
+                    ALTER TABLE keys RENAME TO old_keys;
+                    CREATE TABLE keys (
+                        id BLOB PRIMARY KEY CHECK (length(id) >= 7),
+                        name TEXT NOT NULL,
+                        expiry INTEGER,
+                        last_seen INTEGER,
+                        schedule TEXT,
+                        key_group TEXT,
+                        pin TEXT
+                    );
+                    INSERT INTO keys SELECT id, name, expiry, last_seen, schedule, key_group, pin
+                        FROM old_keys;
+                    DROP TABLE old_keys;
+                    COMMIT;",
+                )?;
+            }
+            let store = SqliteStore {
+                connection: Mutex::new(connection),
+            };
+            if let Some(list) = imported {
+                store.save(&list)?;
+            }
+            Ok(store)
+        }
+    }
+
+    impl KeyStore for SqliteStore {
+        fn load(&self) -> anyhow::Result<DashMap<Credential, Key>> {
+            let connection = self.connection.lock().unwrap();
+            let keys = connection.query(
+                "SELECT id, name, expiry, schedule, key_group, pin FROM keys",
+                &[],
+                |row| {
+                    let id = Credential::from_bytes(&row.blob(0))?;
+                    let expiry = row
+                        .integer(2)
+                        .map(|secs| {
+                            u64::try_from(secs)
+                                .ok()
+                                .and_then(|secs| {
+                                    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+                                })
+                                .context(format!("Expiry {secs} is out of range"))
+                        })
+                        .transpose()?;
+                    Ok((
+                        id,
+                        Key {
+                            name: row.text(1),
+                            expiry,
+                            schedule: row.optional_text(3),
+                            group: row.optional_text(4),
+                            pin: row.optional_text(5),
+                        },
+                    ))
+                },
+            )?;
+            Ok(keys.into_iter().collect())
+        }
+
+        fn save(&self, list: &DashMap<Credential, Key>) -> anyhow::Result<()> {
+            let connection = self.connection.lock().unwrap();
+            connection.execute_batch("BEGIN")?;
+            let result = (|| {
+                let stored: HashSet<Vec<u8>> =
+                    HashSet::from_iter(
+                        connection.query("SELECT id FROM keys", &[], |row| Ok(row.blob(0)))?,
+                    );
+                for id in stored {
+                    if !Credential::from_bytes(&id).is_ok_and(|id| list.contains_key(&id)) {
+                        connection.execute("DELETE FROM keys WHERE id = ?", &[Value::Blob(&id)])?;
+                    }
+                }
+                for entry in list.iter() {
+                    let id = entry.key().bytes();
+                    let expiry = entry
+                        .expiry
+                        .map_or(Value::Null, |expiry| Value::Integer(secs(expiry)));
+                    connection.execute(
+                        "INSERT INTO keys (id, name, expiry, schedule, key_group, pin)
+                         VALUES (?, ?, ?, ?, ?, ?)
+                         ON CONFLICT (id) DO UPDATE SET name = excluded.name,
+                             expiry = excluded.expiry, schedule = excluded.schedule,
+                             key_group = excluded.key_group, pin = excluded.pin",
+                        &[
+                            Value::Blob(&id),
+                            Value::Text(&entry.name),
+                            expiry,
+                            entry.schedule.as_deref().map_or(Value::Null, Value::Text),
+                            entry.group.as_deref().map_or(Value::Null, Value::Text),
+                            entry.pin.as_deref().map_or(Value::Null, Value::Text),
+                        ],
+                    )?;
+                }
+                Ok(())
+            })();
+            match result {
+                Ok(()) => connection.execute_batch("COMMIT"),
+                Err(e) => {
+                    let _ = connection.execute_batch("ROLLBACK");
+                    Err(e)
+                }
+            }
+        }
+
+        fn append_event(
+            &self,
+            time: SystemTime,
+            id: Option<&Credential>,
+            decision: Decision,
+            reader: Option<&str>,
+        ) -> anyhow::Result<()> {
+            let connection = self.connection.lock().unwrap();
+            let timestamp = humantime::format_rfc3339_millis(time).to_string();
+            let decision_name = decision.to_string();
+            let id = id.map(Credential::bytes);
+            connection.execute(
+                "INSERT INTO events (time, key_id, decision, reader) VALUES (?, ?, ?, ?)",
+                &[
+                    Value::Text(&timestamp),
+                    id.as_deref().map_or(Value::Null, Value::Blob),
+                    Value::Text(&decision_name),
+                    reader.map_or(Value::Null, Value::Text),
+                ],
+            )?;
+            if let Some(id) = id.as_deref().filter(|_| decision.is_granted()) {
+                connection.execute(
+                    "UPDATE keys SET last_seen = ? WHERE id = ?",
+                    &[Value::Integer(secs(time)), Value::Blob(id)],
+                )?;
+            }
+            Ok(())
+        }
+
+        fn add_usage(&self, rows: &[UsageRow]) -> anyhow::Result<()> {
+            let connection = self.connection.lock().unwrap();
+            // One transaction, so a grant costs a single commit.
+            connection.execute_batch("BEGIN")?;
+            let result = rows.iter().try_for_each(|row| {
+                let key = row.key.as_ref().map(Credential::bytes);
+                connection.execute(
+                    "INSERT INTO usage (period, key_id, grants, keys) VALUES (?, ?, ?, ?)
+                     ON CONFLICT (period, key_id) DO UPDATE SET grants = grants + excluded.grants,
+                         keys = keys + excluded.keys",
+                    &[
+                        Value::Text(&row.period),
+                        Value::Blob(key.as_deref().unwrap_or(ALL_KEYS)),
+                        Value::Integer(row.grants as i64),
+                        Value::Integer(row.keys as i64),
+                    ],
+                )
+            });
+            match result {
+                Ok(()) => connection.execute_batch("COMMIT"),
+                Err(e) => {
+                    let _ = connection.execute_batch("ROLLBACK");
+                    Err(e)
+                }
+            }
+        }
+
+        fn usage(&self) -> anyhow::Result<Vec<UsageRow>> {
+            let connection = self.connection.lock().unwrap();
+            connection.query(
+                "SELECT period, key_id, grants, keys FROM usage ORDER BY period, key_id",
+                &[],
+                |row| {
+                    let key = match row.blob(1) {
+                        id if id.is_empty() => None,
+                        id => Some(Credential::from_bytes(&id)?),
+                    };
+                    Ok(UsageRow {
+                        period: row.text(0),
+                        key,
+                        grants: row.integer(2).unwrap_or_default() as u64,
+                        keys: row.integer(3).unwrap_or_default() as u64,
+                    })
+                },
+            )
+        }
+
+        fn prune_usage(&self, before: &str) -> anyhow::Result<()> {
+            let connection = self.connection.lock().unwrap();
+            let periods = connection.query("SELECT DISTINCT period FROM usage", &[], |row| {
+                Ok(row.text(0))
+            })?;
+            for period in periods.iter().filter(|period| ended_before(period, before)) {
+                connection.execute("DELETE FROM usage WHERE period = ?", &[Value::Text(period)])?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Where a flat key list is moved once imported.
+    fn flat_backup_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".flat");
+        PathBuf::from(name)
+    }
+
+    fn secs(time: SystemTime) -> i64 {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use dashmap::DashMap;
+
+    use super::{Background, KeyStore, SqliteStore, UsageRow};
+    use crate::{
+        audit::Decision,
+        persistence,
+        sqlite::{Connection, Value},
+        testutil::test_dir,
+        Credential, Key,
+    };
+
+    fn sorted(list: DashMap<crate::Credential, Key>) -> Vec<(crate::Credential, Key)> {
+        let mut ids: Vec<_> = list.into_iter().collect();
+        ids.sort_by_key(|(id, _)| *id);
+        ids
+    }
+
+    #[test]
+    fn sqlite_imports_flat_file_test() {
+        let dir = test_dir("store-sqlite");
+        let path = dir.join("keys.db");
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let visitor = Key {
+            name: "Visitor".to_owned(),
+            expiry: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            schedule: Some("daytime".to_owned()),
+            group: Some("keyholder".to_owned()),
+            pin: Some(
+                "pbkdf2-sha256$1$c2FsdA$vAeYWl+il7BODIIDacuBx+v/2y3ADitCX2gqC0kVGxk".to_owned(),
+            ),
+        };
+        let list = DashMap::from_iter([(alice, Key::named("Alice")), (bob, visitor.clone())]);
+        persistence::serialize_1w_devices(&list, &path).unwrap();
+
+        let store = SqliteStore::open(&path).unwrap();
+        assert!(dir.join("keys.db.flat").exists());
+        assert_eq!(
+            sorted(store.load().unwrap()),
+            [(bob, visitor.clone()), (alice, Key::named("Alice"))]
+        );
+
+        store
+            .append_event(SystemTime::now(), Some(&alice), Decision::Granted, None)
+            .unwrap();
+        store
+            .save(&DashMap::from_iter([(bob, visitor.clone())]))
+            .unwrap();
+        drop(store);
+
+        // Reopening finds the database rather than importing again.
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(sorted(store.load().unwrap()), [(bob, visitor)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_migrates_old_keys_table_test() {
+        let dir = test_dir("store-migrate");
+        let path = dir.join("keys.db");
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE keys (
+                    id BLOB PRIMARY KEY CHECK (length(id) = 7),
+                    name TEXT NOT NULL,
+                    expiry INTEGER,
+                    last_seen INTEGER
+                )",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "INSERT INTO keys (id, name) VALUES (?, ?)",
+                &[Value::Blob(&alice.bytes()), Value::Text("Alice")],
+            )
+            .unwrap();
+        drop(connection);
+
+        let store = SqliteStore::open(&path).unwrap();
+        let card = Credential::Wiegand(1234567);
+        store
+            .save(&DashMap::from_iter([
+                (alice, Key::named("Alice")),
+                (card, Key::named("Dana")),
+            ]))
+            .unwrap();
+        assert_eq!(
+            sorted(store.load().unwrap()),
+            [(alice, Key::named("Alice")), (card, Key::named("Dana"))]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn background_store_test() {
+        let dir = test_dir("store-background");
+        let path = dir.join("keys.db");
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let store = Background::spawn(Arc::new(SqliteStore::open(&path).unwrap()));
+        for _ in 0..3 {
+            store
+                .append_event(SystemTime::now(), Some(&alice), Decision::Granted, None)
+                .unwrap();
+        }
+        // The presentations queued before a save are stored by the time it returns.
+        store
+            .save(&DashMap::from_iter([(alice, Key::named("Alice"))]))
+            .unwrap();
+        let connection = Connection::open(&path).unwrap();
+        let decisions = connection
+            .query("SELECT decision FROM events", &[], |row| Ok(row.text(0)))
+            .unwrap();
+        assert_eq!(decisions, ["granted"; 3]);
+
+        // An expiry no time can hold is an error rather than a panic.
+        connection
+            .execute("UPDATE keys SET expiry = ?", &[Value::Integer(-1)])
+            .unwrap();
+        let message = store.load().unwrap_err().to_string();
+        assert!(message.contains("out of range"), "{message}");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sqlite_usage_test() {
+        let dir = test_dir("store-usage");
+        let store = SqliteStore::open(&dir.join("keys.db")).unwrap();
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let row = |period: &str, key, grants, keys| UsageRow {
+            period: period.to_owned(),
+            key,
+            grants,
+            keys,
+        };
+        for added in [
+            row("2024-01-31", Some(alice), 1, 0),
+            row("2024-01-31", Some(alice), 1, 0),
+            row("2024-01", None, 1, 1),
+            row("2024-01", None, 1, 0),
+            row("2024-02-01", None, 1, 1),
+        ] {
+            store.add_usage(&[added]).unwrap();
+        }
+        assert_eq!(
+            store.usage().unwrap(),
+            [
+                row("2024-01", None, 2, 1),
+                row("2024-01-31", Some(alice), 2, 0),
+                row("2024-02-01", None, 1, 1),
+            ]
+        );
+        store.prune_usage("2024-02-01").unwrap();
+        assert_eq!(store.usage().unwrap(), [row("2024-02-01", None, 1, 1)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+};
+
+pub use cellardoor_core::testutil::test_dir;
+
+use crate::{
+    access::{Access, Reader},
+    audit::AuditLog,
+    last_seen::LastSeen,
+    metrics::Metrics,
+    store::FileStore,
+    Credential, Key,
+};
+
+/// An `Access` with a single unconnected reader, `listed` as Alice's keys and everything else
+/// left at its default.
+pub fn access(listed: &[Credential], master: &[Credential], deny: &[Credential]) -> Access {
+    Access {
+        access_list: Arc::new(listed.iter().map(|id| (*id, Key::named("Alice"))).collect()),
+        master_keys: RwLock::new(HashSet::from_iter(master.iter().copied())),
+        deny_keys: RwLock::new(HashSet::from_iter(deny.iter().copied())),
+        allowed_family_codes: Default::default(),
+        readers: vec![Reader::unconnected(None)],
+        metrics: Arc::new(Metrics::default()),
+        audit: Arc::new(AuditLog::new(None, false, Default::default()).unwrap()),
+        last_seen: Arc::new(LastSeen::default()),
+        debounce: Default::default(),
+        denials: Default::default(),
+        passback: Default::default(),
+        hooks: Default::default(),
+        mqtt: Default::default(),
+        lockdown: Default::default(),
+        auto_unlock: Default::default(),
+        enroller: Default::default(),
+        pins: Default::default(),
+        guests: Default::default(),
+        usage: Default::default(),
+        presence: Default::default(),
+        reader_health: Default::default(),
+        open_limit: Default::default(),
+        clock: Default::default(),
+        feedback: Default::default(),
+        sightings: Default::default(),
+        staleness: Default::default(),
+        schedules: Default::default(),
+        events: Default::default(),
+        w1_devices: Default::default(),
+        authenticator: None,
+        store: Arc::new(FileStore::default()),
+        dry_run: false,
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
+
+/// Deadlines serviced by the event loop on each wakeup, so that periodic and delayed work needs
+/// no thread of its own.
+pub struct Timers<T> {
+    heap: BinaryHeap<Reverse<Entry<T>>>,
+    /// Tie breaker keeping timers due at the same instant in the order they were scheduled.
+    scheduled: u64,
+}
+
+struct Entry<T> {
+    at: Instant,
+    seq: u64,
+    timer: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+impl<T> Default for Timers<T> {
+    fn default() -> Self {
+        Timers {
+            heap: BinaryHeap::new(),
+            scheduled: 0,
+        }
+    }
+}
+
+impl<T> Timers<T> {
+    /// Fires `timer` once `at` has passed.
+    pub fn schedule(&mut self, at: Instant, timer: T) {
+        let seq = self.scheduled;
+        self.scheduled += 1;
+        self.heap.push(Reverse(Entry { at, seq, timer }));
+    }
+
+    /// Time until the earliest timer is due, for use as the event loop's timeout.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.heap
+            .peek()
+            .map(|Reverse(entry)| entry.at.saturating_duration_since(now))
+    }
+
+    /// Removes and returns the timers due at `now` with their deadlines, earliest first.
+    pub fn expired(&mut self, now: Instant) -> Vec<(Instant, T)> {
+        let mut expired = Vec::new();
+        while self
+            .heap
+            .peek()
+            .is_some_and(|Reverse(entry)| entry.at <= now)
+        {
+            let Reverse(entry) = self.heap.pop().unwrap();
+            expired.push((entry.at, entry.timer));
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::Timers;
+
+    #[test]
+    fn timer_order_test() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut timers = Timers::default();
+        assert_eq!(timers.timeout(start), None);
+
+        timers.schedule(at(300), "c");
+        timers.schedule(at(100), "a");
+        timers.schedule(at(200), "b1");
+        timers.schedule(at(200), "b2");
+        assert_eq!(timers.timeout(start), Some(Duration::from_millis(100)));
+        assert!(timers.expired(at(99)).is_empty());
+
+        // Due at once, in deadline order and among equal deadlines in scheduling order.
+        let expired: Vec<_> = timers.expired(at(250)).into_iter().map(|e| e.1).collect();
+        assert_eq!(expired, ["a", "b1", "b2"]);
+        assert_eq!(timers.timeout(at(250)), Some(Duration::from_millis(50)));
+
+        // An overdue timer wants an immediate wakeup.
+        assert_eq!(timers.timeout(at(400)), Some(Duration::ZERO));
+        assert_eq!(timers.expired(at(400)), [(at(300), "c")]);
+        assert_eq!(timers.timeout(at(400)), None);
+    }
+}
@@ -0,0 +1,60 @@
+use std::{io::Write, path::Path};
+
+use anyhow::Context;
+use cellardoor_core::export;
+use dashmap::DashMap;
+
+use crate::{config, persistence, store};
+
+/// Writes the persisted access list with last-seen times as CSV to `out`, or stdout. Like
+/// [`import`] it takes the daemon's lock, so it never reads a list halfway through an import.
+pub fn export(config: &config::Config, out: Option<&Path>) -> anyhow::Result<()> {
+    let _lock = persistence::Lock::acquire(config.persistence.lock_path())?;
+    let list = store::open(&config.persistence)?
+        .load()
+        .context("Failed to load the key list")?;
+    let path = config.persistence.last_seen_path();
+    let last_seen = if path.exists() {
+        persistence::deserialize_last_seen(&path)
+            .context(format!("Failed to load last-seen times from {path:?}"))?
+    } else {
+        DashMap::new()
+    };
+    let csv = export::to_csv(&list, &last_seen);
+    match out {
+        Some(out) => persistence::write_atomically(out, |file| Ok(file.write_all(csv.as_bytes())?))
+            .context(format!("Failed to write {out:?}"))?,
+        None => std::io::stdout().write_all(csv.as_bytes())?,
+    }
+    eprintln!("Exported {} keys", list.len());
+    Ok(())
+}
+
+/// Replaces the persisted access list and last-seen times with those in the CSV at `file`.
+/// Invalid records abort the import unless `skip_invalid`, in which case they are left out.
+pub fn import(config: &config::Config, file: &Path, skip_invalid: bool) -> anyhow::Result<()> {
+    // Held until both files are written, so the daemon can't start in between either.
+    let _lock = persistence::Lock::acquire(config.persistence.lock_path())?;
+    let body = std::fs::read_to_string(file).context(format!("Failed to read {file:?}"))?;
+    let import = export::parse_csv(&body);
+    if !import.issues.is_empty() {
+        let issues: Vec<_> = import.issues.iter().map(ToString::to_string).collect();
+        anyhow::ensure!(
+            skip_invalid,
+            "{file:?} has invalid records, nothing was imported:\n{}",
+            issues.join("\n")
+        );
+        for issue in issues {
+            eprintln!("Skipping {issue}");
+        }
+    }
+    let list = DashMap::from_iter(import.keys);
+    store::open(&config.persistence)?
+        .save(&list)
+        .context("Failed to save the key list")?;
+    let path = config.persistence.last_seen_path();
+    persistence::serialize_last_seen(&DashMap::from_iter(import.last_seen), &path)
+        .context(format!("Failed to save last-seen times to {path:?}"))?;
+    eprintln!("Imported {} keys", list.len());
+    Ok(())
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Local, NaiveDate};
+
+use crate::{
+    config,
+    store::{self, KeyStore, UsageRow},
+    Credential,
+};
+
+/// How usage counts are added up.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    Day,
+    Month,
+    Year,
+}
+
+impl GroupBy {
+    const ALL: [GroupBy; 3] = [GroupBy::Day, GroupBy::Month, GroupBy::Year];
+
+    /// How long the names of these periods are.
+    fn len(self) -> usize {
+        match self {
+            GroupBy::Day => 10,
+            GroupBy::Month => 7,
+            GroupBy::Year => 4,
+        }
+    }
+
+    /// The period `day` falls in, e.g. `2024-05` for `2024-05-03` by month.
+    fn period(self, day: &str) -> &str {
+        day.get(..self.len()).unwrap_or(day)
+    }
+}
+
+/// Whether all of `period` lies before the day `before`; a month or year ends with its last day.
+pub fn ended_before(period: &str, before: &str) -> bool {
+    period < before.get(..period.len()).unwrap_or(before)
+}
+
+/// `date` as the period of one day.
+pub fn day(date: NaiveDate) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+/// Unique keys and grants of one period.
+#[derive(serde::Serialize, Debug, PartialEq, Eq)]
+pub struct Period {
+    pub period: String,
+    pub keys: u64,
+    pub grants: u64,
+}
+
+/// Counts grants per calendar day in the store, see `persistence.usage_stats`.
+#[derive(Default)]
+pub struct Usage {
+    /// `None` when grants aren't counted.
+    store: Option<Arc<dyn KeyStore>>,
+    /// `None` when counting per key. Otherwise the keys already counted in the current day,
+    /// month and year, by [`GroupBy::ALL`], which are only ever held in memory.
+    seen: Option<Mutex<Seen>>,
+}
+
+type Seen = [(String, HashSet<Credential>); 3];
+
+impl Usage {
+    /// Without `per_key`, as the hashed and redacted privacy modes want, only counts over all
+    /// keys are stored, per day, month and year. A key granted again after a restart is then
+    /// counted once more as a unique key.
+    pub fn new(store: Arc<dyn KeyStore>, per_key: bool) -> Usage {
+        Usage {
+            store: Some(store),
+            seen: (!per_key).then(Default::default),
+        }
+    }
+
+    /// Counts a grant of `id` at `now`.
+    pub fn granted(&self, id: &Credential, now: DateTime<Local>) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let day = day(now.date_naive());
+        let rows = match &self.seen {
+            None => vec![UsageRow {
+                period: day,
+                key: Some(*id),
+                grants: 1,
+                keys: 0,
+            }],
+            Some(seen) => {
+                let mut seen = seen.lock().unwrap();
+                GroupBy::ALL
+                    .iter()
+                    .zip(seen.iter_mut())
+                    .map(|(group_by, (period, keys))| {
+                        let current = group_by.period(&day);
+                        if period != current {
+                            *period = current.to_owned();
+                            keys.clear();
+                        }
+                        UsageRow {
+                            period: period.clone(),
+                            key: None,
+                            grants: 1,
+                            keys: keys.insert(*id) as u64,
+                        }
+                    })
+                    .collect()
+            }
+        };
+        if let Err(e) = store.add_usage(&rows) {
+            log::error!("Failed to count the grant: {e:?}");
+        }
+    }
+
+    /// Unique keys and grants per period from the day `since` on, oldest first.
+    pub fn stats(
+        &self,
+        since: Option<NaiveDate>,
+        group_by: GroupBy,
+    ) -> anyhow::Result<Vec<Period>> {
+        let Some(store) = &self.store else {
+            anyhow::bail!("persistence.usage_stats is off");
+        };
+        let since = since.map(day);
+        let rows = store.usage()?.into_iter().filter(|row| {
+            since
+                .as_ref()
+                .is_none_or(|since| !ended_before(&row.period, since))
+        });
+        Ok(aggregate(rows, group_by))
+    }
+}
+
+/// Adds up `rows` by `group_by`. Counts over all keys are only kept per day, month and year, so
+/// those of other periods are left out; keys counted both ways, after a change of privacy
+/// mode, count twice.
+fn aggregate(rows: impl Iterator<Item = UsageRow>, group_by: GroupBy) -> Vec<Period> {
+    let mut periods: BTreeMap<String, (HashSet<Credential>, u64, u64)> = BTreeMap::new();
+    for row in rows {
+        let period = group_by.period(&row.period);
+        if row.key.is_none() && row.period.len() != group_by.len() {
+            continue;
+        }
+        let (keys, unique, grants) = periods.entry(period.to_owned()).or_default();
+        match row.key {
+            Some(key) => {
+                keys.insert(key);
+            }
+            None => *unique += row.keys,
+        }
+        *grants += row.grants;
+    }
+    periods
+        .into_iter()
+        .map(|(period, (keys, unique, grants))| Period {
+            period,
+            keys: keys.len() as u64 + unique,
+            grants,
+        })
+        .collect()
+}
+
+/// `cellardoor stats`: prints the usage counts of the persisted store.
+pub fn print(
+    config: &config::Config,
+    since: Option<NaiveDate>,
+    group_by: GroupBy,
+) -> anyhow::Result<()> {
+    let usage = match config.persistence.usage_stats {
+        true => Usage::new(
+            store::open(&config.persistence)?,
+            config.privacy.mode == config::PrivacyMode::Full,
+        ),
+        false => Usage::default(),
+    };
+    let periods = usage.stats(since, group_by)?;
+    println!("{:<10} {:>8} {:>8}", "period", "keys", "grants");
+    for period in &periods {
+        println!(
+            "{:<10} {:>8} {:>8}",
+            period.period, period.keys, period.grants
+        );
+    }
+    let total: u64 = periods.iter().map(|period| period.grants).sum();
+    println!("{:<10} {:>8} {total:>8}", "total", "");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use chrono::{Local, NaiveDate, TimeZone};
+
+    use super::{GroupBy, Period, Usage};
+    use crate::store::{FileStore, KeyStore};
+    use crate::Credential;
+
+    #[test]
+    fn usage_test() {
+        let dir = crate::testutil::test_dir("usage");
+        let alice = Credential::OneWire([0x33, 0, 0, 3, 0x92, 0xc6, 0xea]);
+        let bob = Credential::OneWire([0x01, 0, 0, 0, 0, 0, 0x42]);
+        let at = |month, day| Local.with_ymd_and_hms(2024, month, day, 12, 0, 0).unwrap();
+        let date = |month, day| NaiveDate::from_ymd_opt(2024, month, day);
+        let period = |period: &str, keys, grants| Period {
+            period: period.to_owned(),
+            keys,
+            grants,
+        };
+
+        for per_key in [true, false] {
+            let store = Arc::new(FileStore {
+                path: dir.join(format!("{per_key}.bin")),
+                usage_lock: Mutex::new(()),
+                ..Default::default()
+            });
+            let usage = Usage::new(store.clone(), per_key);
+            for (id, month, day) in [(alice, 1, 30), (alice, 1, 30), (bob, 1, 31), (alice, 2, 1)] {
+                usage.granted(&id, at(month, day));
+            }
+            assert_eq!(
+                usage.stats(None, GroupBy::Month).unwrap(),
+                [period("2024-01", 2, 3), period("2024-02", 1, 1)]
+            );
+            assert_eq!(
+                usage.stats(date(1, 31), GroupBy::Day).unwrap(),
+                [period("2024-01-31", 1, 1), period("2024-02-01", 1, 1)]
+            );
+            assert_eq!(
+                usage.stats(date(1, 1), GroupBy::Year).unwrap(),
+                [period("2024", 2, 4)]
+            );
+            let ids = store.usage().unwrap().iter().any(|row| row.key.is_some());
+            assert_eq!(ids, per_key);
+            // Every grant appends its rows, which pruning then folds together.
+            let path = dir.join(format!("{per_key}.usage"));
+            let lines = || std::fs::read_to_string(&path).unwrap().lines().count();
+            assert_eq!(lines(), if per_key { 4 } else { 12 });
+
+            // A month is kept until all of it is past the retention.
+            store.prune_usage("2024-01-31").unwrap();
+            assert_eq!(lines(), if per_key { 2 } else { 5 });
+            assert_eq!(
+                usage.stats(None, GroupBy::Day).unwrap()[0].period,
+                "2024-01-31"
+            );
+            assert_eq!(usage.stats(None, GroupBy::Month).unwrap().len(), 2);
+        }
+        assert!(Usage::default().stats(None, GroupBy::Day).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{backoff::ErrorThrottle, config};
+
+/// Printed by the kernel instead of a device list when the bus is empty.
+const NO_SLAVES: &str = "not found.";
+
+/// Detects keys by periodically reading the bus master's slave list, for systems without udev.
+///
+/// A key only counts as removed once it has been missing for the debounce period, so one that
+/// flickers on the bus is reported once instead of on every reappearance.
+pub struct Poller {
+    path: PathBuf,
+    interval: Duration,
+    debounce: Duration,
+    /// Devices on the bus and when they were last listed.
+    present: HashMap<String, Instant>,
+    next_poll: Instant,
+    errors: ErrorThrottle,
+}
+
+impl Poller {
+    /// Polls the slave list at `path` with the intervals from `config`.
+    pub fn new(config: &config::Reader, path: PathBuf) -> Poller {
+        Poller {
+            path,
+            interval: config.poll_interval,
+            debounce: config.poll_debounce,
+            present: HashMap::new(),
+            next_poll: Instant::now(),
+            errors: ErrorThrottle::default(),
+        }
+    }
+
+    /// Time until the next poll is due, for use as the event loop's timeout.
+    pub fn timeout(&self) -> Duration {
+        self.next_poll.saturating_duration_since(Instant::now())
+    }
+
+    /// Reads the slave list if a poll is due and returns the devices that newly appeared.
+    pub fn poll(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        if now < self.next_poll {
+            return Vec::new();
+        }
+        self.next_poll = now + self.interval;
+        match std::fs::read_to_string(&self.path) {
+            Ok(listing) => {
+                self.errors.reset();
+                self.update(&listing, now)
+            }
+            Err(e) => {
+                self.errors
+                    .error(format!("Failed to read {:?}: {e}", self.path));
+                Vec::new()
+            }
+        }
+    }
+
+    fn update(&mut self, listing: &str, now: Instant) -> Vec<String> {
+        let mut appeared = Vec::new();
+        for sysname in listing.lines().map(str::trim) {
+            if sysname.is_empty() || sysname == NO_SLAVES {
+                continue;
+            }
+            if self.present.insert(sysname.to_owned(), now).is_none() {
+                appeared.push(sysname.to_owned());
+            }
+        }
+        self.present
+            .retain(|_, listed| now.duration_since(*listed) < self.debounce);
+        appeared
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::Poller;
+    use crate::config;
+
+    #[test]
+    fn flickering_key_is_reported_once_test() {
+        let config = config::Reader {
+            poll_debounce: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let mut poller = Poller::new(&config, config.poll_path.clone());
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        assert_eq!(
+            poller.update("33-00000392c6ea\n", at(0)),
+            ["33-00000392c6ea"]
+        );
+        assert!(poller.update("not found.\n", at(500)).is_empty());
+        assert!(poller.update("33-00000392c6ea\n", at(900)).is_empty());
+        assert_eq!(
+            poller.update("33-00000392c6ea\n01-000000000042\n", at(1000)),
+            ["01-000000000042"]
+        );
+        // Gone for longer than the debounce period, so it counts as a new sighting.
+        assert!(poller.update("", at(2500)).is_empty());
+        assert_eq!(
+            poller.update("33-00000392c6ea\n", at(3000)),
+            ["33-00000392c6ea"]
+        );
+    }
+}
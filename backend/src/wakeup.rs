@@ -0,0 +1,127 @@
+use std::{
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+/// Why a [`Wakeup::wait`] returned.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Wake {
+    Timeout,
+    Refresh,
+    /// A refresh that may shrink the access list beyond the safety limits.
+    ForcedRefresh,
+    Shutdown,
+}
+
+#[derive(Default)]
+struct State {
+    shutdown: bool,
+    refresh: bool,
+    force: bool,
+    fetching: bool,
+}
+
+/// Lets the main thread interrupt the refresh thread's sleep, either to stop it or to make it
+/// fetch the key list right away.
+#[derive(Default)]
+pub struct Wakeup {
+    state: Mutex<State>,
+    cvar: Condvar,
+}
+
+impl Wakeup {
+    pub fn shutdown(&self) {
+        self.state.lock().unwrap().shutdown = true;
+        self.cvar.notify_all();
+    }
+
+    /// Asks for an immediate refresh. Requests arriving while a fetch is already running are
+    /// coalesced into it; returns whether the request was accepted.
+    pub fn request_refresh(&self) -> bool {
+        self.request(false)
+    }
+
+    /// Like [`Wakeup::request_refresh`], but lifts the safety limits for that one fetch.
+    pub fn request_forced_refresh(&self) -> bool {
+        self.request(true)
+    }
+
+    fn request(&self, force: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.fetching {
+            return false;
+        }
+        state.refresh = true;
+        state.force |= force;
+        self.cvar.notify_all();
+        true
+    }
+
+    /// Sleeps for `timeout` unless shutting down, without consuming refresh requests; returns
+    /// whether the full time passed.
+    pub fn sleep(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .cvar
+            .wait_timeout_while(state, timeout, |s| !s.shutdown)
+            .unwrap();
+        !state.shutdown
+    }
+
+    /// Sleeps for `timeout` unless interrupted. Unless shutting down, the caller is considered
+    /// to be fetching until it calls `wait` again.
+    pub fn wait(&self, timeout: Duration) -> Wake {
+        let mut state = self.state.lock().unwrap();
+        state.fetching = false;
+        state = self
+            .cvar
+            .wait_timeout_while(state, timeout, |s| !s.shutdown && !s.refresh)
+            .unwrap()
+            .0;
+
+        if state.shutdown {
+            return Wake::Shutdown;
+        }
+        state.fetching = true;
+        if std::mem::take(&mut state.refresh) {
+            if std::mem::take(&mut state.force) {
+                Wake::ForcedRefresh
+            } else {
+                Wake::Refresh
+            }
+        } else {
+            Wake::Timeout
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Wake, Wakeup};
+
+    #[test]
+    fn refresh_requests_coalesce_test() {
+        let wakeup = Wakeup::default();
+        assert!(wakeup.request_refresh());
+        assert!(wakeup.request_refresh());
+        assert_eq!(wakeup.wait(Duration::from_secs(5)), Wake::Refresh);
+
+        // A fetch is now running, further requests are folded into it.
+        assert!(!wakeup.request_refresh());
+        assert_eq!(wakeup.wait(Duration::ZERO), Wake::Timeout);
+
+        wakeup.shutdown();
+        assert_eq!(wakeup.wait(Duration::from_secs(5)), Wake::Shutdown);
+    }
+
+    #[test]
+    fn forced_refresh_wins_when_coalesced_test() {
+        let wakeup = Wakeup::default();
+        assert!(wakeup.request_refresh());
+        assert!(wakeup.request_forced_refresh());
+        assert_eq!(wakeup.wait(Duration::ZERO), Wake::ForcedRefresh);
+        assert_eq!(wakeup.wait(Duration::ZERO), Wake::Timeout);
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+
+use crate::{
+    config::{self, WebhookEvent},
+    metrics::Metrics,
+};
+
+/// Notifications waiting beyond this many drop the oldest, so a dead endpoint can't pile up
+/// memory.
+const QUEUE_SIZE: usize = 64;
+
+/// Before the first retry of a delivery; each further one waits twice as long.
+const RETRY_PAUSE: Duration = Duration::from_secs(1);
+const MAX_RETRY_PAUSE: Duration = Duration::from_secs(60);
+
+struct Delivery {
+    /// Index into `webhooks`.
+    webhook: usize,
+    event: WebhookEvent,
+    body: String,
+}
+
+#[derive(Default)]
+struct Queue {
+    deliveries: Mutex<VecDeque<Delivery>>,
+    ready: Condvar,
+}
+
+impl Queue {
+    /// Queues `delivery`, returning the oldest one waiting if that had to make room for it.
+    fn push(&self, delivery: Delivery) -> Option<Delivery> {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let dropped = if deliveries.len() >= QUEUE_SIZE {
+            deliveries.pop_front()
+        } else {
+            None
+        };
+        deliveries.push_back(delivery);
+        self.ready.notify_one();
+        dropped
+    }
+
+    /// Waits for the next delivery.
+    fn pop(&self) -> Delivery {
+        let mut deliveries = self.deliveries.lock().unwrap();
+        loop {
+            if let Some(delivery) = deliveries.pop_front() {
+                return delivery;
+            }
+            deliveries = self.ready.wait(deliveries).unwrap();
+        }
+    }
+}
+
+struct Target {
+    config: config::Webhook,
+    client: reqwest::blocking::Client,
+}
+
+/// POSTs events to the configured `webhooks` from a worker thread, so neither a slow endpoint
+/// nor a failing one ever holds up a door decision.
+#[derive(Default)]
+pub struct Webhooks {
+    targets: Arc<Vec<Target>>,
+    /// `None` in a dry run, which only logs the deliveries.
+    queue: Option<Arc<Queue>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Webhooks {
+    pub fn new(
+        configs: Vec<config::Webhook>,
+        metrics: Arc<Metrics>,
+        dry_run: bool,
+    ) -> anyhow::Result<Webhooks> {
+        let targets = configs
+            .into_iter()
+            .enumerate()
+            .map(|(index, config)| {
+                let section = format!("webhooks[{index}]");
+                let client = build_client(&config, &section).context(section)?;
+                Ok(Target { config, client })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let targets = Arc::new(targets);
+        let queue = (!dry_run && !targets.is_empty()).then(|| {
+            let queue = Arc::new(Queue::default());
+            spawn_worker(targets.clone(), queue.clone(), metrics.clone());
+            queue
+        });
+        Ok(Webhooks {
+            targets,
+            queue,
+            metrics,
+        })
+    }
+
+    /// Queues `event` for every webhook that wants it, with `values` for the placeholders of
+    /// its body; `event` and `timestamp` are filled in here.
+    pub fn send(&self, event: WebhookEvent, mut values: Vec<(&'static str, String)>) {
+        if self.targets.is_empty() {
+            return;
+        }
+        let now = humantime::format_rfc3339_seconds(SystemTime::now());
+        values.push(("timestamp", now.to_string()));
+        for (index, target) in self.targets.iter().enumerate() {
+            if !target.config.events.contains(&event) {
+                continue;
+            }
+            let body = match target.config.render(event, &values) {
+                Ok(body) => body,
+                Err(e) => {
+                    log::error!("Failed to render the body of webhooks[{index}]: {e:#}");
+                    continue;
+                }
+            };
+            let Some(queue) = &self.queue else {
+                log::info!(
+                    "[dry-run] Not posting {} to webhooks[{index}]",
+                    event.name()
+                );
+                continue;
+            };
+            let delivery = Delivery {
+                webhook: index,
+                event,
+                body,
+            };
+            if let Some(dropped) = queue.push(delivery) {
+                self.metrics.webhooks_dropped.inc();
+                log::warn!(
+                    "Too many webhook notifications waiting, dropped {} for webhooks[{}]",
+                    dropped.event.name(),
+                    dropped.webhook
+                );
+            }
+        }
+    }
+}
+
+fn build_client(
+    config: &config::Webhook,
+    section: &str,
+) -> anyhow::Result<reqwest::blocking::Client> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    for (name, value) in &config.headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .context(format!("{name:?} is not a header name"))?;
+        let value = HeaderValue::from_str(value).context(format!("{name}: not a header value"))?;
+        headers.insert(name, value);
+    }
+    if config.danger_accept_invalid_certs {
+        log::warn!("{section}.danger_accept_invalid_certs is set, its certificate is not checked");
+    }
+    let builder = reqwest::blocking::Client::builder()
+        .default_headers(headers)
+        .timeout(config.timeout);
+    config
+        .tls(section)?
+        .apply(builder)
+        .build()
+        .context("Failed to build the HTTP client")
+}
+
+fn spawn_worker(targets: Arc<Vec<Target>>, queue: Arc<Queue>, metrics: Arc<Metrics>) {
+    std::thread::spawn(move || loop {
+        let delivery = queue.pop();
+        let target = &targets[delivery.webhook];
+        match deliver(target, &delivery.body, RETRY_PAUSE) {
+            Ok(()) => log::debug!(
+                "Posted {} to webhooks[{}]",
+                delivery.event.name(),
+                delivery.webhook
+            ),
+            Err(e) => {
+                metrics.webhook_failures.inc();
+                log::error!(
+                    "Failed to post {} to webhooks[{}]: {e:#}",
+                    delivery.event.name(),
+                    delivery.webhook
+                );
+            }
+        }
+    });
+}
+
+/// POSTs `body`, trying again up to `retries` times after `pause`, doubled for each retry.
+/// Errors leave out the URL, which often holds a secret.
+fn deliver(target: &Target, body: &str, pause: Duration) -> anyhow::Result<()> {
+    let mut attempt = 0;
+    loop {
+        let result = target
+            .client
+            .post(&target.config.url)
+            .body(body.to_owned())
+            .send()
+            .and_then(reqwest::blocking::Response::error_for_status);
+        let e = match result {
+            Ok(_) => return Ok(()),
+            Err(e) => e.without_url(),
+        };
+        if attempt == target.config.retries {
+            return Err(e).context(format!("Gave up after {} attempts", attempt + 1));
+        }
+        log::debug!("Webhook attempt {} failed: {e}", attempt + 1);
+        std::thread::sleep((pause * 2u32.saturating_pow(attempt)).min(MAX_RETRY_PAUSE));
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        time::Duration,
+    };
+
+    use super::{build_client, deliver, Delivery, Queue, Target, QUEUE_SIZE};
+    use crate::config::{self, WebhookEvent};
+
+    /// Answers one connection per status in turn, passing each request on, and returns the URL.
+    fn serve(statuses: Vec<u16>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/hook?token=secret",
+            listener.local_addr().unwrap()
+        );
+        let (requests, received) = mpsc::channel();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                // Every body in these tests ends with a brace.
+                while !request.ends_with(b"}") {
+                    let len = stream.read(&mut buf).unwrap();
+                    assert!(len > 0, "Request ended early");
+                    request.extend_from_slice(&buf[..len]);
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Mock\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+                requests
+                    .send(String::from_utf8_lossy(&request).into_owned())
+                    .unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn target(url: &str, retries: u32) -> Target {
+        let config: config::Webhook = serde_yaml_ng::from_str(&format!(
+            "url: {url}\nheaders:\n  X-Token: abc\nretries: {retries}"
+        ))
+        .unwrap();
+        let client = build_client(&config, "webhooks[0]").unwrap();
+        Target { config, client }
+    }
+
+    #[test]
+    fn delivery_retries_test() {
+        let (url, received) = serve(vec![503, 200]);
+        deliver(&target(&url, 1), "{}", Duration::from_millis(1)).unwrap();
+        for _ in 0..2 {
+            let request = received.recv().unwrap().to_lowercase();
+            assert!(request.starts_with("post /hook?token=secret "), "{request}");
+            assert!(request.contains("x-token: abc\r\n"), "{request}");
+            assert!(request.contains("content-type: application/json\r\n"));
+            assert!(request.ends_with("\r\n\r\n{}"), "{request}");
+        }
+
+        let (url, received) = serve(vec![500, 500]);
+        let err = deliver(&target(&url, 1), "{}", Duration::from_millis(1)).unwrap_err();
+        let message = format!("{err:#}");
+        assert!(
+            message.starts_with("Gave up after 2 attempts: "),
+            "{message}"
+        );
+        assert!(!message.contains("secret"), "{message}");
+        assert_eq!(received.iter().count(), 2);
+    }
+
+    #[test]
+    fn full_queue_drops_oldest_test() {
+        let queue = Queue::default();
+        let delivery = |webhook| Delivery {
+            webhook,
+            event: WebhookEvent::Granted,
+            body: String::new(),
+        };
+        for index in 0..QUEUE_SIZE {
+            assert!(queue.push(delivery(index)).is_none());
+        }
+        assert_eq!(queue.push(delivery(QUEUE_SIZE)).unwrap().webhook, 0);
+        assert_eq!(queue.pop().webhook, 1);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use mio::{unix::SourceFd, Interest, Registry, Token};
+
+use crate::{config, gpio};
+
+/// A Wiegand card reader on two GPIO lines, decoding their pulses into frames of bits.
+pub struct WiegandReader {
+    line: gpio::Line,
+    d1: u32,
+    decoder: Decoder,
+    /// When edges were last read, from which a pending frame times out.
+    last_read: Instant,
+}
+
+impl WiegandReader {
+    /// Requests both data lines and registers them under `token`.
+    pub fn open(
+        config: &config::Wiegand,
+        registry: &Registry,
+        token: Token,
+    ) -> anyhow::Result<WiegandReader> {
+        let line = gpio::Line::request_falling_edges(
+            &config.chip,
+            &[config.d0, config.d1],
+            "cellardoor-wiegand",
+        )
+        .context(format!(
+            "Failed to request Wiegand lines {} and {} on {:?}",
+            config.d0, config.d1, config.chip
+        ))?;
+        registry.register(&mut SourceFd(&line.as_raw_fd()), token, Interest::READABLE)?;
+        log::info!(
+            "Watching Wiegand reader on lines {} and {}",
+            config.d0,
+            config.d1
+        );
+        Ok(WiegandReader {
+            line,
+            d1: config.d1,
+            decoder: Decoder::new(config.bit_timeout),
+            last_read: Instant::now(),
+        })
+    }
+
+    /// Reads the pulses since the last call and returns the frames a pause between them ended.
+    pub fn ready(&mut self) -> Vec<Vec<bool>> {
+        let edges = self.line.read_edges().unwrap_or_else(|e| {
+            log::error!("Failed to read Wiegand events: {e:?}");
+            Vec::new()
+        });
+        self.last_read = Instant::now();
+        edges
+            .into_iter()
+            .filter_map(|edge| self.decoder.push(edge.offset == self.d1, edge.timestamp))
+            .collect()
+    }
+
+    /// The pending frame, once no pulse followed it for the bit timeout.
+    pub fn check(&mut self, now: Instant) -> Option<Vec<bool>> {
+        self.timeout(now)
+            .is_some_and(|timeout| timeout.is_zero())
+            .then(|| self.decoder.finish())
+            .flatten()
+    }
+
+    /// Time until the pending frame ends, for use as the event loop's timeout.
+    pub fn timeout(&self, now: Instant) -> Option<Duration> {
+        self.decoder
+            .is_pending()
+            .then(|| (self.last_read + self.decoder.bit_timeout).saturating_duration_since(now))
+    }
+}
+
+/// Collects bits into frames, which end at a pause of at least the bit timeout.
+struct Decoder {
+    bit_timeout: Duration,
+    bits: Vec<bool>,
+    /// Kernel timestamp of the last bit.
+    last: Option<Duration>,
+}
+
+/// Longer frames are noise; the longest format we decode has 34 bits.
+const MAX_BITS: usize = 64;
+
+impl Decoder {
+    fn new(bit_timeout: Duration) -> Decoder {
+        Decoder {
+            bit_timeout,
+            bits: Vec::new(),
+            last: None,
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        !self.bits.is_empty()
+    }
+
+    /// Adds the bit pulsed at `at`, returning the previous frame if a pause ended it.
+    fn push(&mut self, bit: bool, at: Duration) -> Option<Vec<bool>> {
+        let ended = self
+            .last
+            .is_some_and(|last| at.saturating_sub(last) >= self.bit_timeout)
+            .then(|| self.finish())
+            .flatten();
+        self.last = Some(at);
+        if self.bits.len() < MAX_BITS {
+            self.bits.push(bit);
+        }
+        ended
+    }
+
+    fn finish(&mut self) -> Option<Vec<bool>> {
+        self.last = None;
+        Some(std::mem::take(&mut self.bits)).filter(|bits| !bits.is_empty())
+    }
+}
+
+/// The card number of a Wiegand 26 or 34 frame: the bits between the leading even parity bit
+/// over the first half and the trailing odd parity bit over the second half.
+pub fn decode(bits: &[bool]) -> anyhow::Result<u64> {
+    anyhow::ensure!(
+        matches!(bits.len(), 26 | 34),
+        "{} bit frame is neither Wiegand 26 nor 34",
+        bits.len()
+    );
+    let half = bits.len() / 2;
+    let ones = |bits: &[bool]| bits.iter().filter(|&&bit| bit).count();
+    anyhow::ensure!(
+        ones(&bits[..half]).is_multiple_of(2),
+        "Even parity of the first half failed"
+    );
+    anyhow::ensure!(
+        !ones(&bits[half..]).is_multiple_of(2),
+        "Odd parity of the second half failed"
+    );
+    Ok(bits[1..bits.len() - 1]
+        .iter()
+        .fold(0, |card, &bit| card << 1 | u64::from(bit)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{decode, Decoder};
+
+    /// `data` as a frame of `len` bits with correct parity.
+    fn frame(data: u64, len: usize) -> Vec<bool> {
+        let mut bits: Vec<bool> = (0..len - 2).rev().map(|bit| data >> bit & 1 == 1).collect();
+        let half = len / 2 - 1;
+        let first = bits[..half].iter().filter(|&&bit| bit).count();
+        let second = bits[half..].iter().filter(|&&bit| bit).count();
+        bits.insert(0, first % 2 == 1);
+        bits.push(second % 2 == 0);
+        bits
+    }
+
+    #[test]
+    fn decode_test() {
+        // Facility 18, card 54919: the 26 bit example printed on many HID cards.
+        assert_eq!(decode(&frame(18 << 16 | 54919, 26)).unwrap(), 1234567);
+        assert_eq!(decode(&frame(0xdead_beef, 34)).unwrap(), 0xdead_beef);
+        assert_eq!(decode(&frame(0, 26)).unwrap(), 0);
+
+        for flipped in [0, 5, 20, 25] {
+            let mut bad = frame(1234567, 26);
+            bad[flipped] = !bad[flipped];
+            assert!(decode(&bad).is_err(), "bit {flipped}");
+        }
+        assert!(decode(&frame(1234567, 26)[..25]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn frames_end_at_pauses_test() {
+        let mut decoder = Decoder::new(Duration::from_millis(25));
+        let ms = Duration::from_millis;
+        for (idx, bit) in [true, false, true].into_iter().enumerate() {
+            assert_eq!(decoder.push(bit, ms(2 * idx as u64)), None);
+        }
+        // A pause ends the frame, and the bit after it starts the next.
+        assert_eq!(decoder.push(false, ms(100)), Some(vec![true, false, true]));
+        assert!(decoder.is_pending());
+        assert_eq!(decoder.finish(), Some(vec![false]));
+        assert_eq!(decoder.finish(), None);
+        assert!(!decoder.is_pending());
+    }
+}
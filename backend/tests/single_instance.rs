@@ -0,0 +1,163 @@
+use std::{path::PathBuf, process::Command};
+
+use cellardoor_core::persistence::Lock;
+
+fn config(name: &str) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("cellardoor-test-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.yaml");
+    std::fs::write(
+        &config,
+        format!(
+            "
+thing:
+  url: https://mos.example
+  token: abc
+  refresh: 60
+persistence:
+  path: {}/keys.bin
+door:
+  chip: /dev/gpiochip0
+  line: 17
+  unlock_ms: 3000
+logging: {{}}
+",
+            dir.display()
+        ),
+    )
+    .unwrap();
+    (dir, config)
+}
+
+fn cellardoor(config: &PathBuf, args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_cellardoor"))
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .env("RUST_BACKTRACE", "0")
+        .output()
+        .unwrap();
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
+
+#[test]
+fn second_instance_test() {
+    let (dir, config) = config("second-instance");
+    std::fs::write(dir.join("keys.csv"), "33-00000392c6ea,Alice,,,,,\n").unwrap();
+    let held = format!("locked by cellardoor with PID {}", std::process::id());
+
+    let lock = Lock::acquire(dir.join("keys.lock")).unwrap();
+    let (ok, stderr) = cellardoor(&config, &[]);
+    assert!(!ok && stderr.contains(&held), "{stderr}");
+    for args in [&["export"][..], &["import", "keys.csv"]] {
+        let (ok, stderr) = cellardoor(&config, args);
+        assert!(!ok && stderr.contains(&held), "{args:?}: {stderr}");
+    }
+    assert!(!dir.join("keys.bin").exists());
+
+    drop(lock);
+    let csv = dir.join("keys.csv");
+    let (ok, stderr) = cellardoor(&config, &["import", csv.to_str().unwrap()]);
+    assert!(ok, "{stderr}");
+    let (ok, stderr) = cellardoor(&config, &["export"]);
+    assert!(ok && stderr.contains("Exported 1 keys"), "{stderr}");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn refresh_once_test() {
+    let (dir, config) = config("refresh-once");
+    let list = dir.join("list.csv");
+    let yaml = std::fs::read_to_string(&config).unwrap().replace(
+        "url: https://mos.example\n  token: abc",
+        &format!("url: {}", list.display()),
+    );
+    let control = format!("control:\n  path: {}/control.sock\n", dir.display());
+    std::fs::write(&config, yaml + &control).unwrap();
+    let refresh = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_cellardoor"))
+            .arg("--config")
+            .arg(&config)
+            .arg("refresh")
+            .args(args)
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+        )
+    };
+
+    let (status, stdout) = refresh(&[]);
+    assert_eq!(status, Some(1), "{stdout}");
+    assert!(stdout.starts_with("Key list refresh failed: "), "{stdout}");
+
+    std::fs::write(
+        &list,
+        "33-00000392c6ea,Alice\n01-000000000042,Bob\n01-000000000043,Carol\n",
+    )
+    .unwrap();
+    let lock = Lock::acquire(dir.join("keys.lock")).unwrap();
+    assert_eq!(refresh(&[]).0, Some(1));
+    drop(lock);
+    assert_eq!(
+        refresh(&[]),
+        (
+            Some(0),
+            "Key list refreshed: 3 added, 0 removed, 3 keys\n".to_owned()
+        )
+    );
+
+    // Two of three keys gone trips max_removal_fraction, unless forced.
+    std::fs::write(&list, "33-00000392c6ea,Alice\n").unwrap();
+    let (status, stdout) = refresh(&[]);
+    assert_eq!(status, Some(2), "{stdout}");
+    assert!(stdout.contains("removes 2 of 3 keys"), "{stdout}");
+    assert_eq!(
+        refresh(&["--force"]),
+        (
+            Some(0),
+            "Key list refreshed: 0 added, 2 removed, 1 keys\n".to_owned()
+        )
+    );
+    assert!(dir.join("keys.fetched").exists());
+
+    // A dry run doesn't ask a daemon listening on the socket, nor touches the files.
+    let _daemon = std::os::unix::net::UnixListener::bind(dir.join("control.sock")).unwrap();
+    std::fs::write(&list, "33-00000392c6ea,Alice\n01-000000000044,Dave\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cellardoor"))
+        .arg("--config")
+        .arg(&config)
+        .args(["--dry-run", "refresh"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Key list refreshed: 1 added, 0 removed, 2 keys\n"
+    );
+    let saved = std::fs::read(dir.join("keys.bin")).unwrap();
+    std::fs::remove_file(dir.join("control.sock")).unwrap();
+    assert_eq!(refresh(&["--force"]).0, Some(0));
+    assert_ne!(std::fs::read(dir.join("keys.bin")).unwrap(), saved);
+
+    // A list that can't be persisted fails the run, here as its temporary file is taken.
+    std::fs::create_dir(dir.join(".keys.bin.tmp")).unwrap();
+    std::fs::write(&list, "33-00000392c6ea,Alice\n01-000000000045,Eve\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cellardoor"))
+        .arg("--config")
+        .arg(&config)
+        .arg("refresh")
+        .output()
+        .unwrap();
+    assert_ne!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Failed to persist the refreshed key list"),
+        "{stderr}"
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}